@@ -122,6 +122,28 @@ typedef struct LlgCommitResult {
   bool is_stop;
 } LlgCommitResult;
 
+/**
+ * Where llg_compute_draft_masks() stopped following the draft,
+ * because llg_commit_token() would backtrack or append forced tokens
+ * after the last accepted draft token.
+ */
+typedef struct LlgDraftSplice {
+  /**
+   * The number of tokens to remove from the end of the output
+   * (before the last accepted draft token), 0 if there is no splice
+   */
+  uint32_t backtrack;
+  /**
+   * The tokens to append to the output instead of the last accepted draft token
+   * This is valid until any call to llg_*() on the current constraint
+   */
+  const uint32_t *tokens;
+  /**
+   * The number of tokens in the tokens array, 0 if there is no splice
+   */
+  uint32_t n_tokens;
+} LlgDraftSplice;
+
 typedef struct LlgConstraintStep {
   /**
    * The constraint to compute mask for.
@@ -246,15 +268,84 @@ struct LlgConstraint *llg_new_constraint_json(const struct LlgConstraintInit *in
 struct LlgConstraint *llg_new_constraint_lark(const struct LlgConstraintInit *init,
                                               const char *lark);
 
+/**
+ * Create a new constraint from a given GBNF (llama.cpp) grammar
+ * Always returns a non-null value. Call llg_get_error() on the result to check for errors.
+ */
+struct LlgConstraint *llg_new_constraint_gbnf(const struct LlgConstraintInit *init,
+                                              const char *gbnf);
+
+/**
+ * Create a new constraint from a given ABNF (RFC 5234) grammar; the first rule is the start rule
+ * Always returns a non-null value. Call llg_get_error() on the result to check for errors.
+ */
+struct LlgConstraint *llg_new_constraint_abnf(const struct LlgConstraintInit *init,
+                                              const char *abnf);
+
 /**
  * Create a new constraint with specified type
- * Type can be one of "regex", "json_schema" (or "json"), "lark", "llguidance" (or "guidance")
+ * Type can be one of "regex", "json_schema" (or "json"), "lark", "gbnf", "abnf",
+ * "llguidance" (or "guidance")
  * Always returns a non-null value. Call llg_get_error() on the result to check for errors.
  */
 struct LlgConstraint *llg_new_constraint_any(const struct LlgConstraintInit *init,
                                              const char *constraint_type,
                                              const char *data);
 
+/**
+ * Check the grammar for errors and warnings, without creating a constraint.
+ * The type and data are as in llg_new_constraint_any().
+ * Writes a JSON array of diagnostics to the output buffer (it's "[]" if the grammar is fine).
+ * Each diagnostic has fields "severity" ("error" or "warning"), "message",
+ * "line" and "column" (1-based; 0 when unknown), "span" ([start, end] byte offsets or null),
+ * and "code" (kind of lint warning, like "unused-rule", or null).
+ * Returns the number of bytes that would be written to output, if output_len was large enough
+ * (including the terminating null); the output is truncated otherwise.
+ * With output_len == 0, output is not written to (and can be null).
+ * # Safety
+ * This function should only be called from C code.
+ */
+size_t llg_validate_grammar(const struct LlgConstraintInit *init,
+                            const char *constraint_type,
+                            const char *data,
+                            char *output,
+                            size_t output_len);
+
+/**
+ * Like llg_validate_grammar(), but also run lints, reporting unused rules and terminals,
+ * rules that can't match anything, and constructs known to be slow.
+ * # Safety
+ * This function should only be called from C code.
+ */
+size_t llg_lint_grammar(const struct LlgConstraintInit *init,
+                        const char *constraint_type,
+                        const char *data,
+                        char *output,
+                        size_t output_len);
+
+/**
+ * Check if the text (arbitrary bytes, not tokens) matches the grammar;
+ * useful for auditing text generated without constraints.
+ * The type and data are as in llg_new_constraint_any().
+ * Writes JSON to the output buffer: "null" if the text matches, or otherwise an object with
+ * "offset" (of the first byte that can't be accepted, or text_len if the text is incomplete),
+ * "partial_lexeme" (the lexeme being scanned at offset), "expected"
+ * (as in llg_expected_terminals()), and "message".
+ * If the grammar fails to compile, the object only has the "message".
+ * Returns the number of bytes that would be written to output, if output_len was large enough
+ * (including the terminating null); the output is truncated otherwise.
+ * With output_len == 0, output is not written to (and can be null).
+ * # Safety
+ * This function should only be called from C code.
+ */
+size_t llg_validate_text(const struct LlgConstraintInit *init,
+                         const char *constraint_type,
+                         const char *data,
+                         const uint8_t *text,
+                         size_t text_len,
+                         char *output,
+                         size_t output_len);
+
 /**
  * Get the error message from the constraint or null if there is no error.
  * After it returns a non-null value, it will always return it until the constraint is freed
@@ -273,6 +364,57 @@ float llg_get_temperature(const struct LlgConstraint *cc);
  */
 bool llg_is_stopped(const struct LlgConstraint *cc);
 
+/**
+ * Report captures while they are generated, in the progress objects logged
+ * with llg_set_log_json_progress(): besides "capture" (the full value, once complete),
+ * there are objects with "object" set to "capture_start",
+ * "capture_delta" (with "str" and "hex" of the new text),
+ * and "capture_end" (with "completed": false if the capture turned out not to be one).
+ * All of them have "name", and all but "capture" have "path" (names of enclosing captures).
+ */
+void llg_set_stream_captures(struct LlgConstraint *cc, bool stream_captures);
+
+/**
+ * Log progress after each compute_mask() and commit_token(): the logs
+ * (see llg_flush_logs()) get "JSON-OUT: " lines with JSON objects,
+ * with "object" set to "capture", "capture_provenance", or "text"
+ * (and more, see llg_set_stream_captures()).
+ */
+void llg_set_log_json_progress(struct LlgConstraint *cc, bool log_json_progress);
+
+/**
+ * Report what the constraint can accept at the current position,
+ * which is useful for debugging grammars and rejected generations.
+ * Writes a JSON object to the output buffer, with fields "lexemes"
+ * (array of objects with "name" and "regex" of lexemes that can come next),
+ * "rules" (array of rules in progress, like "a ::= b • c"), and "eos_allowed".
+ * Returns the number of bytes that would be written to output, if output_len was large enough
+ * (including the terminating null); the output is truncated otherwise.
+ * With output_len == 0, output is not written to (and can be null).
+ * Returns 0 if the constraint is in error state (nothing is written then).
+ * # Safety
+ * This function should only be called from C code.
+ */
+size_t llg_expected_terminals(struct LlgConstraint *cc, char *output, size_t output_len);
+
+/**
+ * Reconstruct parse trees of the text generated so far; at most max_trees of them
+ * if the grammar is ambiguous (the first one is picked deterministically).
+ * Writes a JSON array to the output buffer (empty if the grammar doesn't accept the text yet).
+ * Each node has "name", "start" and "end" (byte offsets), "children",
+ * and for lexemes also "text".
+ * Returns the number of bytes that would be written to output, if output_len was large enough
+ * (including the terminating null); the output is truncated otherwise.
+ * With output_len == 0, output is not written to (and can be null).
+ * Returns 0 if the constraint is in error state (nothing is written then).
+ * # Safety
+ * This function should only be called from C code.
+ */
+size_t llg_parse_trees(struct LlgConstraint *cc,
+                       size_t max_trees,
+                       char *output,
+                       size_t output_len);
+
 /**
  * Compute mask for the next token sampling
  * It typically takes up to a millisecond for a 100k tokenizer, so should be called in background.
@@ -289,6 +431,33 @@ int32_t llg_compute_mask(struct LlgConstraint *cc, struct LlgMaskResult *res_p);
  */
 int32_t llg_commit_token(struct LlgConstraint *cc, LlgToken token, struct LlgCommitResult *res_p);
 
+/**
+ * Compute masks for a draft sequence of tokens (for speculative decoding).
+ * Masks are written to mask_dest, one after another, each mask_byte_len bytes
+ * (a multiple of 4); mask_dest has to have space for n_draft + 1 masks.
+ * The first mask is the one llg_compute_mask() would return,
+ * and the i-th mask is the one after accepting the first i draft tokens.
+ * When the sequence should stop at some position, the mask there allows only the EOS token,
+ * and no further masks are computed.
+ * Returns the number of leading draft tokens accepted by the grammar (n_accepted),
+ * in which case n_accepted + 1 masks were written, or -1 on error.
+ * The constraint is left in the same state as after llg_compute_mask(),
+ * so llg_commit_token() should be called next.
+ * With ff_tokens, no further masks are computed after a draft token
+ * for which llg_commit_token() would backtrack or append forced tokens,
+ * and the mask after it is empty; what llg_commit_token() would do for that
+ * token is then written to *splice_p (which otherwise gets n_tokens == 0).
+ * splice_p can be null.
+ * # Safety
+ * This function should only be called from C code.
+ */
+int32_t llg_compute_draft_masks(struct LlgConstraint *cc,
+                                const LlgToken *draft,
+                                size_t n_draft,
+                                uint32_t *mask_dest,
+                                size_t mask_byte_len,
+                                struct LlgDraftSplice *splice_p);
+
 /**
  * Compute mask for several constraints in parallel.
  * # Safety
@@ -0,0 +1,48 @@
+mod parser;
+
+use anyhow::{ensure, Result};
+
+use crate::{gbnf::compile_rules, grammar_builder::GrammarResult, GrammarBuilder, HashSet};
+
+use parser::parse_abnf;
+
+/// Core rules from RFC 5234, Appendix B.1.
+/// They are added to the grammar, unless it defines rules with the same names.
+const CORE_RULES: &str = r#"
+ALPHA  = %x41-5A / %x61-7A
+BIT    = "0" / "1"
+CHAR   = %x01-7F
+CR     = %x0D
+CRLF   = CR LF
+CTL    = %x00-1F / %x7F
+DIGIT  = %x30-39
+DQUOTE = %x22
+HEXDIG = DIGIT / "A" / "B" / "C" / "D" / "E" / "F"
+HTAB   = %x09
+LF     = %x0A
+LWSP   = *(WSP / CRLF WSP)
+OCTET  = %x00-FF
+SP     = %x20
+VCHAR  = %x21-7E
+WSP    = SP / HTAB
+"#;
+
+/// Compile ABNF grammar (RFC 5234) to llguidance.
+/// The first rule in the grammar is the start rule.
+pub fn abnf_to_llguidance(mut builder: GrammarBuilder, abnf: &str) -> Result<GrammarResult> {
+    let mut rules = parse_abnf(abnf)?.rules;
+    ensure!(!rules.is_empty(), "no rules found in ABNF grammar");
+    let start = rules[0].name.clone();
+
+    let defined = rules.iter().map(|r| r.name.clone()).collect::<HashSet<_>>();
+    for r in parse_abnf(CORE_RULES).unwrap().rules {
+        if !defined.contains(&r.name) {
+            rules.push(r);
+        }
+    }
+
+    let n = std::cmp::min(abnf.len() / 8, 1_000_000);
+    builder.regex.spec.regex_builder.reserve(n);
+
+    compile_rules(builder, rules, &start)
+}
@@ -0,0 +1,495 @@
+use anyhow::{bail, Result};
+
+use crate::{
+    gbnf::{Node, Rule},
+    HashMap,
+};
+
+/// Result of parsing ABNF grammar.
+/// The rules defined with "=/" are already merged into the initial definition.
+pub struct ParsedAbnf {
+    pub rules: Vec<Rule>,
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    /// indentation of the rule definitions; lines indented more continue the previous rule
+    indent: usize,
+}
+
+/// Parse ABNF grammar, as described in RFC 5234 and RFC 7405 (for %s and %i).
+pub fn parse_abnf(text: &str) -> Result<ParsedAbnf> {
+    let mut p = Parser {
+        text,
+        pos: 0,
+        indent: rule_indent(text),
+    };
+    let mut rules: Vec<Rule> = vec![];
+    let mut rule_idx = HashMap::default();
+    p.skip_empty_lines();
+    while !p.is_at_end() {
+        let line = p.line();
+        let column = p.column();
+        let (name, incremental, body) = p.parse_rule()?;
+        match rule_idx.get(&name) {
+            None if incremental => {
+                bail!(
+                    "at {}({}): incremental alternatives (=/) for rule {:?} before its definition",
+                    line,
+                    column,
+                    name
+                );
+            }
+            None => {
+                rule_idx.insert(name.clone(), rules.len());
+                rules.push(Rule {
+                    name,
+                    line,
+                    column,
+                    body,
+                });
+            }
+            Some(_) if !incremental => {
+                bail!("at {}({}): duplicate rule: {:?}", line, column, name);
+            }
+            Some(idx) => {
+                let rule = &mut rules[*idx];
+                let mut alts = match std::mem::replace(&mut rule.body, Node::Alternatives(vec![])) {
+                    Node::Alternatives(alts) => alts,
+                    n => vec![n],
+                };
+                match body {
+                    Node::Alternatives(more) => alts.extend(more),
+                    n => alts.push(n),
+                }
+                rule.body = Node::Alternatives(alts);
+            }
+        }
+        p.skip_empty_lines();
+    }
+    Ok(ParsedAbnf { rules })
+}
+
+fn line_indent(line: &str) -> usize {
+    line.bytes()
+        .take_while(|b| *b == b' ' || *b == b'\t')
+        .count()
+}
+
+fn is_empty_line(line: &str) -> bool {
+    let line = line.trim_start_matches([' ', '\t']);
+    line.is_empty() || line.starts_with(';') || line == "\r"
+}
+
+/// ABNF copied from RFCs is often indented; we take the smallest indentation
+/// of a non-empty line as the indentation of rule definitions.
+fn rule_indent(text: &str) -> usize {
+    text.split('\n')
+        .filter(|l| !is_empty_line(l))
+        .map(line_indent)
+        .min()
+        .unwrap_or(0)
+}
+
+fn is_rulename_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-'
+}
+
+impl Parser<'_> {
+    fn is_at_end(&self) -> bool {
+        self.pos >= self.text.len()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn line(&self) -> usize {
+        self.text[..self.pos].matches('\n').count() + 1
+    }
+
+    fn column(&self) -> usize {
+        let line_start = self.text[..self.pos].rfind('\n').map_or(0, |i| i + 1);
+        self.text[line_start..self.pos].chars().count() + 1
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T> {
+        bail!("at {}({}): {}", self.line(), self.column(), msg)
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if self.peek() == Some(c) {
+            self.advance();
+            Ok(())
+        } else {
+            self.error(&format!("expected {:?}", c))
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some(';') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.advance();
+            }
+        }
+    }
+
+    fn skip_empty_lines(&mut self) {
+        loop {
+            let rest = &self.text[self.pos..];
+            let line = rest.split('\n').next().unwrap();
+            if !is_empty_line(line) {
+                // skip indentation of the rule
+                self.pos += line_indent(line);
+                return;
+            }
+            self.pos += line.len();
+            if self.advance().is_none() {
+                return;
+            }
+        }
+    }
+
+    /// Skip whitespace and comments, including line breaks followed
+    /// by a line indented more than rule definitions (continuation lines).
+    fn skip_space(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\r') => {
+                    self.advance();
+                }
+                Some(';') => self.skip_comment(),
+                Some('\n') => {
+                    let mut next = self.pos + 1;
+                    loop {
+                        let line = self.text[next..].split('\n').next().unwrap();
+                        if !is_empty_line(line) {
+                            if line_indent(line) > self.indent {
+                                self.pos = next;
+                                break;
+                            } else {
+                                // next rule
+                                return;
+                            }
+                        }
+                        next += line.len() + 1;
+                        if next >= self.text.len() {
+                            return;
+                        }
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn parse_rulename(&mut self) -> Result<String> {
+        let start = self.pos;
+        if !self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+            return self.error("expected rule name");
+        }
+        while self.peek().is_some_and(is_rulename_char) {
+            self.advance();
+        }
+        Ok(self.text[start..self.pos].to_ascii_lowercase())
+    }
+
+    fn parse_number(&mut self, radix: u32) -> Result<u32> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_digit(radix)) {
+            self.advance();
+        }
+        if self.pos == start {
+            return self.error("expected number");
+        }
+        match u32::from_str_radix(&self.text[start..self.pos], radix) {
+            Ok(n) => Ok(n),
+            Err(_) => self.error("number too large"),
+        }
+    }
+
+    fn parse_code_point(&mut self, radix: u32) -> Result<char> {
+        let v = self.parse_number(radix)?;
+        match char::from_u32(v) {
+            Some(c) => Ok(c),
+            None => self.error(&format!("invalid code point: {:#x}", v)),
+        }
+    }
+
+    fn parse_rule(&mut self) -> Result<(String, bool, Node)> {
+        let name = self.parse_rulename()?;
+        self.skip_space();
+        self.expect('=')?;
+        let incremental = self.peek() == Some('/');
+        if incremental {
+            self.advance();
+        }
+        self.skip_space();
+        let body = self.parse_alternation()?;
+        match self.peek() {
+            None | Some('\n') => {}
+            Some(c) => return self.error(&format!("unexpected {:?}", c)),
+        }
+        Ok((name, incremental, body))
+    }
+
+    fn parse_alternation(&mut self) -> Result<Node> {
+        let mut alternatives = vec![];
+        loop {
+            alternatives.push(self.parse_concatenation()?);
+            self.skip_space();
+            if self.peek() != Some('/') {
+                break;
+            }
+            self.advance();
+            self.skip_space();
+        }
+        if alternatives.len() == 1 {
+            Ok(alternatives.pop().unwrap())
+        } else {
+            Ok(Node::Alternatives(alternatives))
+        }
+    }
+
+    fn parse_concatenation(&mut self) -> Result<Node> {
+        let mut nodes = vec![];
+        loop {
+            match self.peek() {
+                Some(c) if c.is_ascii_alphanumeric() || "*([\"%<".contains(c) => {
+                    nodes.push(self.parse_repetition()?);
+                }
+                _ => break,
+            }
+            self.skip_space();
+        }
+        if nodes.is_empty() {
+            return self.error("expected an element");
+        }
+        if nodes.len() == 1 {
+            Ok(nodes.pop().unwrap())
+        } else {
+            Ok(Node::Sequence(nodes))
+        }
+    }
+
+    fn parse_repetition(&mut self) -> Result<Node> {
+        let mut min = None;
+        if self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            min = Some(self.parse_number(10)?);
+        }
+        let range = if self.peek() == Some('*') {
+            self.advance();
+            let max = if self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                Some(self.parse_number(10)?)
+            } else {
+                None
+            };
+            Some((min.unwrap_or(0), max))
+        } else {
+            min.map(|n| (n, Some(n)))
+        };
+        let element = self.parse_element()?;
+        match range {
+            None => Ok(element),
+            Some((min, max)) => {
+                if let Some(max) = max {
+                    if max < min {
+                        return self.error(&format!(
+                            "invalid repetition {}*{}, max is less than min",
+                            min, max
+                        ));
+                    }
+                }
+                Ok(Node::Repeat(Box::new(element), min, max))
+            }
+        }
+    }
+
+    fn parse_element(&mut self) -> Result<Node> {
+        match self.peek() {
+            Some('(') => {
+                self.advance();
+                self.skip_space();
+                let r = self.parse_alternation()?;
+                self.expect(')')?;
+                Ok(r)
+            }
+            Some('[') => {
+                self.advance();
+                self.skip_space();
+                let r = self.parse_alternation()?;
+                self.expect(']')?;
+                Ok(Node::Repeat(Box::new(r), 0, Some(1)))
+            }
+            Some('"') => self.parse_char_val(false),
+            Some('%') => {
+                self.advance();
+                match self.advance().map(|c| c.to_ascii_lowercase()) {
+                    Some('s') => self.parse_char_val(true),
+                    Some('i') => self.parse_char_val(false),
+                    Some('x') => self.parse_num_val(16),
+                    Some('d') => self.parse_num_val(10),
+                    Some('b') => self.parse_num_val(2),
+                    _ => self.error("expected one of %x, %d, %b, %s, %i"),
+                }
+            }
+            Some('<') => self.error("prose values (<...>) are not supported"),
+            Some(c) if c.is_ascii_alphabetic() => Ok(Node::RuleRef(self.parse_rulename()?)),
+            _ => self.error("expected an element"),
+        }
+    }
+
+    fn parse_char_val(&mut self, case_sensitive: bool) -> Result<Node> {
+        self.expect('"')?;
+        let start = self.pos;
+        loop {
+            match self.peek() {
+                Some('"') => break,
+                None | Some('\r' | '\n') => return self.error("unterminated string"),
+                Some(_) => {
+                    self.advance();
+                }
+            }
+        }
+        let s = self.text[start..self.pos].to_string();
+        self.advance();
+        if case_sensitive || !s.chars().any(|c| c.is_ascii_alphabetic()) {
+            Ok(Node::Literal(s))
+        } else {
+            Ok(Node::CaseInsensitive(s))
+        }
+    }
+
+    fn parse_num_val(&mut self, radix: u32) -> Result<Node> {
+        let first = self.parse_code_point(radix)?;
+        match self.peek() {
+            Some('-') => {
+                self.advance();
+                let last = self.parse_code_point(radix)?;
+                if last < first {
+                    return self.error(&format!(
+                        "invalid range {:#x}-{:#x}",
+                        first as u32, last as u32
+                    ));
+                }
+                Ok(Node::CharClass {
+                    negated: false,
+                    ranges: vec![(first, last)],
+                })
+            }
+            _ => {
+                let mut s = first.to_string();
+                while self.peek() == Some('.') {
+                    self.advance();
+                    s.push(self.parse_code_point(radix)?);
+                }
+                Ok(Node::Literal(s))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_abnf;
+    use crate::gbnf::Node;
+
+    fn parse(abnf: &str) -> Vec<(String, Node)> {
+        parse_abnf(abnf)
+            .unwrap()
+            .rules
+            .into_iter()
+            .map(|r| (r.name, r.body))
+            .collect()
+    }
+
+    fn rule_ref(s: &str) -> Node {
+        Node::RuleRef(s.to_string())
+    }
+
+    fn lit(s: &str) -> Node {
+        Node::Literal(s.to_string())
+    }
+
+    #[test]
+    fn test_parse_abnf() {
+        let rules = parse(
+            r#"
+   ; indented, like in RFCs
+   Foo-Bar = "GET" / %s"Post" / "1.0" ; comment
+           / %x41.42 / %d48-57
+   baz     = *DIGIT 2*3( "a" foo-bar )
+             [ 3"x" ]
+   foo-bar =/ %b1000001
+"#,
+        );
+        assert_eq!(
+            rules,
+            vec![
+                (
+                    "foo-bar".to_string(),
+                    Node::Alternatives(vec![
+                        Node::CaseInsensitive("GET".to_string()),
+                        lit("Post"),
+                        lit("1.0"),
+                        lit("AB"),
+                        Node::CharClass {
+                            negated: false,
+                            ranges: vec![('0', '9')]
+                        },
+                        lit("A"),
+                    ])
+                ),
+                (
+                    "baz".to_string(),
+                    Node::Sequence(vec![
+                        Node::Repeat(Box::new(rule_ref("digit")), 0, None),
+                        Node::Repeat(
+                            Box::new(Node::Sequence(vec![
+                                Node::CaseInsensitive("a".to_string()),
+                                rule_ref("foo-bar")
+                            ])),
+                            2,
+                            Some(3)
+                        ),
+                        Node::Repeat(
+                            Box::new(Node::Repeat(
+                                Box::new(Node::CaseInsensitive("x".to_string())),
+                                3,
+                                Some(3)
+                            )),
+                            0,
+                            Some(1)
+                        ),
+                    ])
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_abnf_errors() {
+        let err = |s: &str| parse_abnf(s).err().unwrap().to_string();
+        assert_eq!(
+            err("a = <prose>"),
+            "at 1(5): prose values (<...>) are not supported"
+        );
+        assert_eq!(err("a = \"x"), "at 1(7): unterminated string");
+        assert_eq!(err("a = %x41-\n"), "at 1(10): expected number");
+        assert_eq!(
+            err("a = 3*1b"),
+            "at 1(9): invalid repetition 3*1, max is less than min"
+        );
+        assert_eq!(err("a = b\na = c"), "at 2(1): duplicate rule: \"a\"");
+        assert!(err("a =/ b").contains("before its definition"));
+        assert_eq!(err("a = b )"), "at 1(7): unexpected ')'");
+        assert_eq!(err("a = \nb = c"), "at 1(5): expected an element");
+    }
+}
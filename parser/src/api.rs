@@ -44,6 +44,11 @@ pub struct LLGuidanceOptions {
     /// Any Unicode regex will cause an error.
     #[serde(default)]
     pub allow_invalid_utf8: bool,
+
+    /// If set, Lark terminal regexes use Python `re` syntax (as in upstream Lark),
+    /// instead of Rust `regex` syntax.
+    #[serde(default)]
+    pub python_re: bool,
 }
 
 impl LLGuidanceOptions {
@@ -54,6 +59,9 @@ impl LLGuidanceOptions {
         if other.allow_invalid_utf8 {
             self.allow_invalid_utf8 = true;
         }
+        if other.python_re {
+            self.python_re = true;
+        }
     }
 }
 
@@ -69,6 +77,15 @@ pub struct GrammarWithLexer {
     /// The Lark grammar that the grammar should generate.
     /// When this is set, nodes and rx_nodes must be empty.
     pub lark_grammar: Option<String>,
+
+    /// The GBNF (llama.cpp) grammar that the grammar should generate.
+    /// When this is set, nodes and rx_nodes must be empty.
+    pub gbnf_grammar: Option<String>,
+
+    /// The ABNF (RFC 5234) grammar that the grammar should generate.
+    /// The first rule is the start rule.
+    /// When this is set, nodes and rx_nodes must be empty.
+    pub abnf_grammar: Option<String>,
     // #[serde(flatten)]
     // pub options: LLGuidanceOptions,
 }
@@ -80,6 +97,10 @@ impl Debug for GrammarWithLexer {
             "GrammarWithLexer [{}]",
             if self.lark_grammar.is_some() {
                 "lark"
+            } else if self.gbnf_grammar.is_some() {
+                "gbnf"
+            } else if self.abnf_grammar.is_some() {
+                "abnf"
             } else {
                 "json"
             }
@@ -164,11 +185,30 @@ impl Display for GrammarId {
 #[serde(deny_unknown_fields)]
 pub struct RegexExt {
     /// The lexeme should accept any (possibly empty) contiguous sequence of these chunks.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub substring_chunks: Option<Vec<String>>,
     /// Similar to `substring_chunks: s.split(/\s+/)`
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub substring_words: Option<String>,
     /// Similar to `substring_chunks: s.split('')`
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub substring_chars: Option<String>,
+    /// The lexeme should accept any (possibly empty) contiguous sequence of words
+    /// from one of these documents; the words are split as in `substring_words`.
+    /// Captures of the lexeme report which document was quoted
+    /// (see [`crate::TokenParser::get_capture_provenance`]).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub substring_documents: Option<Vec<String>>,
+    /// With one of the `substring_*` fields, allow up to this many edits
+    /// (insertions, deletions or substitutions of a chunk); inserted and substituted
+    /// chunks are any single character for `substring_chars`, and any word, run of whitespace,
+    /// or run of punctuation for `substring_words`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_edits: Option<u32>,
+    /// With one of the `substring_*` fields, allow skipping chunks, i.e.,
+    /// match any sequence of chunks in the original order, not only contiguous ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subsequence: Option<bool>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -215,6 +255,106 @@ impl StopReason {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem (error or warning) found when compiling a grammar.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// 1-based line number in the grammar source; 0 if unknown.
+    pub line: usize,
+    /// 1-based column number (in bytes) in the grammar source; 0 if unknown.
+    pub column: usize,
+    /// Byte range (start, end) in the grammar source, if known.
+    pub span: Option<(usize, usize)>,
+    /// Short identifier of the kind of problem, like "unused-rule"; set by lints.
+    #[serde(default)]
+    pub code: Option<String>,
+}
+
+impl Diagnostic {
+    /// Error without location information.
+    pub fn error(message: String) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message,
+            line: 0,
+            column: 0,
+            span: None,
+            code: None,
+        }
+    }
+
+    /// Warning without location information.
+    pub fn warning(message: String) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Self::error(message)
+        }
+    }
+
+    pub fn with_code(mut self, code: &str) -> Self {
+        self.code = Some(code.to_string());
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.line > 0 {
+            write!(f, "at {}({}): ", self.line, self.column)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Error returned when a grammar fails to compile.
+/// It carries all the diagnostics (errors and warnings) found;
+/// use `e.downcast_ref::<GrammarError>()` to get it from `anyhow::Error`.
+#[derive(Clone, Debug)]
+pub struct GrammarError {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl GrammarError {
+    pub fn errors(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics.iter().filter(|d| d.is_error())
+    }
+
+    /// Return diagnostics carried by the error, or the error itself
+    /// as a diagnostic without location.
+    pub fn diagnostics_of(e: &anyhow::Error) -> Vec<Diagnostic> {
+        match e.downcast_ref::<GrammarError>() {
+            Some(ge) => ge.diagnostics.clone(),
+            None => vec![Diagnostic::error(e.to_string())],
+        }
+    }
+}
+
+impl Display for GrammarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, d) in self.errors().enumerate() {
+            if idx > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", d)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for GrammarError {}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 #[repr(C)]
 pub struct ParserLimits {
@@ -287,6 +427,14 @@ impl TopLevelGrammar {
         Self::from_grammar(GrammarWithLexer::from_json_schema(json_schema))
     }
 
+    pub fn from_gbnf(gbnf_grammar: String) -> Self {
+        Self::from_grammar(GrammarWithLexer::from_gbnf(gbnf_grammar))
+    }
+
+    pub fn from_abnf(abnf_grammar: String) -> Self {
+        Self::from_grammar(GrammarWithLexer::from_abnf(abnf_grammar))
+    }
+
     pub fn from_grammar(grammar: GrammarWithLexer) -> Self {
         TopLevelGrammar {
             grammars: vec![grammar],
@@ -312,6 +460,22 @@ impl GrammarWithLexer {
         }
     }
 
+    pub fn from_gbnf(gbnf_grammar: String) -> Self {
+        GrammarWithLexer {
+            name: Some("gbnf_grammar".to_string()),
+            gbnf_grammar: Some(gbnf_grammar),
+            ..GrammarWithLexer::default()
+        }
+    }
+
+    pub fn from_abnf(abnf_grammar: String) -> Self {
+        GrammarWithLexer {
+            name: Some("abnf_grammar".to_string()),
+            abnf_grammar: Some(abnf_grammar),
+            ..GrammarWithLexer::default()
+        }
+    }
+
     pub fn from_regex(rx: &str) -> Self {
         let rx = lark_regex_quote(rx);
         let mut r = Self::from_lark(format!("start: /{}/", rx));
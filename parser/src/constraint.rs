@@ -1,11 +1,14 @@
 use anyhow::{bail, ensure, Result};
-use toktrie::{StepResult, TokenId};
+use indexmap::IndexMap;
+use toktrie::{SimpleVob, Splice, StepResult, TokenId};
 
 use crate::{
     api::StopReason,
     loginfo,
-    output::{ParserOutput, Reporter},
-    panic_utils, TokenParser,
+    output::{ExpectedTerminals, ParseTree, ParserOutput, Reporter},
+    panic_utils,
+    snapshot::{SnapshotReader, SnapshotWriter},
+    Logger, TokenParser,
 };
 
 #[derive(Clone)]
@@ -17,6 +20,8 @@ pub struct Constraint {
     last_res: StepResult,
     started: bool,
     pending_stop: bool,
+    splices_truncated: bool,
+    max_splice_candidates: usize,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -49,6 +54,17 @@ impl CommitResult {
     }
 }
 
+/// Result of [`Constraint::compute_draft_masks()`].
+#[derive(Debug, Clone, Default)]
+pub struct DraftMasks {
+    /// Number of leading draft tokens allowed by the grammar.
+    pub num_accepted: usize,
+    /// `num_accepted + 1` step results: the one before each accepted token,
+    /// and the one after the last accepted token.
+    /// Results after a stop are not computed, so a stop can only be last.
+    pub results: Vec<StepResult>,
+}
+
 impl Constraint {
     /// Construct a state machine for a sequence constraint.
     pub fn new(parser: TokenParser) -> Self {
@@ -61,6 +77,8 @@ impl Constraint {
             log_json_progress: false,
             temperature: 0.0,
             pending_stop: false,
+            splices_truncated: false,
+            max_splice_candidates: 100,
         }
     }
 
@@ -70,6 +88,76 @@ impl Constraint {
         copy
     }
 
+    /// Serialize the state of the constraint (including the parser) into
+    /// a compact byte blob, for example to migrate the sequence to another process.
+    /// See TokenParser::snapshot().
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut w = SnapshotWriter::new(self.parser.grammar_fingerprint());
+        self.parser.write_snapshot(&mut w);
+        self.reporter.write_snapshot(&self.parser, &mut w);
+        w.bool(self.started);
+        w.bool(self.pending_stop);
+        w.f32(self.temperature);
+
+        // the mask is not stored, but re-computed on restore
+        let res = &self.last_res;
+        w.bool(res.sample_mask.is_some());
+        w.bool(res.temperature.is_some());
+        w.f32(res.temperature.unwrap_or(0.0));
+        w.usize(res.splices.len());
+        for s in &res.splices {
+            w.tokens(&s.when_sampled);
+            w.u64(s.backtrack as u64);
+            w.tokens(&s.ff_tokens);
+        }
+        w.bool(self.splices_truncated);
+
+        w.finish()
+    }
+
+    /// Restore state from snapshot() into a new constraint, created from the same
+    /// grammar and tokenizer.
+    /// Fails when the grammar or tokenizer doesn't match the snapshot.
+    pub fn restore_snapshot(&mut self, snapshot: &[u8]) -> Result<()> {
+        ensure!(
+            !self.started,
+            "restoring snapshot into a constraint that was already used"
+        );
+        let mut r = SnapshotReader::new(snapshot, self.parser.grammar_fingerprint())?;
+        self.parser.read_snapshot(&mut r)?;
+        self.reporter.read_snapshot(&self.parser, &mut r)?;
+        self.started = r.bool()?;
+        self.pending_stop = r.bool()?;
+        self.temperature = r.f32()?;
+
+        let has_mask = r.bool()?;
+        let has_temperature = r.bool()?;
+        let temperature = r.f32()?;
+        let num_splices = r.usize()?;
+        let mut splices = vec![];
+        for _ in 0..num_splices {
+            splices.push(Splice {
+                when_sampled: r.tokens()?,
+                backtrack: u32::try_from(r.u64()?)?,
+                ff_tokens: r.tokens()?,
+            });
+        }
+        self.splices_truncated = r.bool()?;
+        r.finish()?;
+
+        let sample_mask = if has_mask {
+            Some(self.parser.compute_mask()?)
+        } else {
+            None
+        };
+        self.last_res = StepResult {
+            sample_mask,
+            temperature: has_temperature.then_some(temperature),
+            splices,
+        };
+        Ok(())
+    }
+
     fn save_progress_and_result(&mut self, res: StepResult) {
         self.last_res = res;
         if self.log_json_progress {
@@ -127,11 +215,24 @@ impl Constraint {
         self.pending_stop
     }
 
+    /// What can come next at the current position; useful when a generation
+    /// was rejected, or when debugging a grammar.
+    pub fn expected_terminals(&mut self) -> ExpectedTerminals {
+        self.parser.expected_terminals()
+    }
+
+    /// Parse trees of the text generated so far, at most `max_trees` of them
+    /// when the grammar is ambiguous; see [`TokenParser::parse_trees()`].
+    pub fn parse_trees(&self, max_trees: usize) -> Vec<ParseTree> {
+        self.parser.parse_trees(max_trees)
+    }
+
     /// This computes token sampling mask.
     /// It typically takes up to a millisecond for a 100k tokenizer.
     /// It will return an error when the order of calls is violated.
     /// The result will be either:
-    ///     - a mask of allowed tokens to sample, or
+    ///     - a mask of allowed tokens to sample, possibly with conditional splices
+    ///       (when conditional_ff_tokens are enabled in InferenceCapabilities), or
     ///     - an unconditional splice result, indicating that the parser wants to append tokens, or
     ///     - a stop result, indicating that the parser is done
     /// The splice is never returned when ff_tokens are disabled in InferenceCapabilities.
@@ -160,17 +261,242 @@ impl Constraint {
             if mask.is_err() && self.parser.stop_reason() == StopReason::NoExtensionBias {
                 self.save_progress_and_result(StepResult::stop());
             } else {
-                self.save_progress_and_result(StepResult::sample(mask?, self.parser.temperature()));
+                let mask = mask?;
+                let (res, truncated) =
+                    Self::sample_result(&mut self.parser, mask, self.max_splice_candidates);
+                self.splices_truncated = truncated;
+                self.save_progress_and_result(res);
             }
         }
 
         Ok(())
     }
 
+    /// Validate a draft token sequence (for speculative decoding), and compute masks
+    /// at every position of the accepted prefix.
+    /// The first result is the same as from compute_mask(), and the constraint
+    /// is left in the same state as after compute_mask() - commit_token() should be called next.
+    /// With ff_tokens, the walk stops after a draft token for which commit_token()
+    /// would backtrack or append forced tokens, and the last result is then
+    /// the splice commit_token() returns for it.
+    ///
+    /// The draft is walked with the parser itself and then rolled back,
+    /// unless that is not possible (see `can_rollback_from()`, and drafts longer
+    /// than `TokenParser::max_rollback_tokens()`),
+    /// in which case a copy of the parser is used.
+    pub fn compute_draft_masks(&mut self, draft: &[TokenId]) -> Result<DraftMasks> {
+        panic_utils::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            self.compute_draft_masks_inner(draft)
+        }))
+    }
+
+    fn compute_draft_masks_inner(&mut self, draft: &[TokenId]) -> Result<DraftMasks> {
+        self.compute_mask_inner()?;
+        let mut res = DraftMasks {
+            num_accepted: 0,
+            results: vec![self.last_res.clone()],
+        };
+        if self.last_res.is_stop() || draft.is_empty() {
+            return Ok(res);
+        }
+
+        loginfo!(self.parser.logger, "\ncompute_draft_masks({})", draft.len());
+        let can_rollback = Self::can_rollback_from(&self.parser)
+            && draft.len() <= self.parser.max_rollback_tokens();
+        let mut copy;
+        let parser = if can_rollback {
+            &mut self.parser
+        } else {
+            copy = self.parser.clone();
+            &mut copy
+        };
+        let num_tokens = parser.num_tokens();
+        let max_candidates = self.max_splice_candidates;
+
+        // errors and panics (e.g., on token ids outside of the vocabulary)
+        // are caught here, so the parser is rolled back on all paths
+        let walk = panic_utils::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Self::walk_draft(parser, draft, max_candidates, &mut res)
+        }));
+        if can_rollback {
+            parser.undo_tokens(num_tokens)?;
+        }
+        walk?;
+        Ok(res)
+    }
+
+    fn walk_draft(
+        parser: &mut TokenParser,
+        draft: &[TokenId],
+        max_candidates: usize,
+        res: &mut DraftMasks,
+    ) -> Result<()> {
+        let eos = parser.token_env.tok_trie().eos_token();
+        for &t in draft {
+            let last = res.results.last().unwrap();
+            if !last.sample_mask.as_ref().is_some_and(|m| m.is_allowed(t))
+                || parser.tokens_left() == 0
+            {
+                break;
+            }
+            res.num_accepted += 1;
+            if t == eos {
+                // the sequence ends here; EOS may not be recorded as a token
+                res.results.push(StepResult::stop());
+                break;
+            }
+            let bt = parser.consume_token(t)?;
+            if parser.inference_caps.ff_tokens {
+                let ff = parser.consume_ff_tokens()?;
+                if bt > 0 || !ff.is_empty() {
+                    // the output departs from the draft here;
+                    // same splice as in commit_token_inner()
+                    let (bt, tokens) = if bt > 0 {
+                        (bt - 1, ff)
+                    } else {
+                        (0, [&[t][..], &ff].concat())
+                    };
+                    res.results.push(StepResult::splice(bt as u32, tokens));
+                    break;
+                }
+            }
+            let step = if parser.check_stop()? {
+                StepResult::stop()
+            } else {
+                match parser.compute_mask() {
+                    Ok(mask) => Self::sample_result(parser, mask, max_candidates).0,
+                    Err(_) if parser.stop_reason() == StopReason::NoExtensionBias => {
+                        StepResult::stop()
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+            let is_stop = step.is_stop();
+            res.results.push(step);
+            if is_stop {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether tokens consumed from now on can be later rolled back by count.
+    /// Hidden stop=... bytes remove the tokens they are in, which can include tokens
+    /// from before this point, but only if they are part of the current lexeme.
+    fn can_rollback_from(parser: &TokenParser) -> bool {
+        !parser.parser.grammar().lexer_spec().has_stop || !parser.parser.has_pending_lexeme_bytes()
+    }
+
+    /// Result sampling from `mask`, with conditional splices if they are enabled.
+    /// Also returns whether the splices were truncated.
+    fn sample_result(
+        parser: &mut TokenParser,
+        mask: SimpleVob,
+        max_candidates: usize,
+    ) -> (StepResult, bool) {
+        let (splices, truncated) = if parser.inference_caps.conditional_ff_tokens {
+            Self::conditional_splices(parser, &mask, max_candidates)
+        } else {
+            (vec![], false)
+        };
+        let mut res = StepResult::sample(mask, parser.temperature());
+        res.splices = splices;
+        (res, truncated)
+    }
+
+    /// For tokens in the mask that are followed by forced tokens, compute what
+    /// commit_token() would return, and express it as conditional splices.
+    /// Tokens are tried on a copy of the parser, which is rolled back after each one.
+    /// Only some tokens are tried (see `Parser::maybe_forcing_tokens()`),
+    /// at most `max_candidates` of them (see `set_max_splice_candidates()`),
+    /// so commit_token() result is still authoritative.
+    /// Also returns whether the candidates were truncated.
+    fn conditional_splices(
+        parser: &mut TokenParser,
+        mask: &SimpleVob,
+        max_candidates: usize,
+    ) -> (Vec<Splice>, bool) {
+        if max_candidates == 0 {
+            return (vec![], false);
+        }
+        let eos = parser.token_env.tok_trie().eos_token();
+        let mut tokens = vec![];
+        mask.iter_set_entries(|t| {
+            if t as TokenId != eos {
+                tokens.push(t as TokenId)
+            }
+        });
+        let mut candidates = parser.parser.maybe_forcing_tokens(&tokens);
+        let truncated = candidates.len() > max_candidates;
+        if truncated {
+            let msg = format!(
+                "only trying {} of {} conditional splice candidates (max_splice_candidates)",
+                max_candidates,
+                candidates.len()
+            );
+            parser.logger.warn(&msg);
+            candidates.truncate(max_candidates);
+        }
+        if candidates.is_empty() {
+            return (vec![], truncated);
+        }
+
+        let can_rollback = Self::can_rollback_from(parser);
+        let fresh_copy = |p: &TokenParser| {
+            let mut copy = p.clone();
+            copy.logger = Logger::new(0, 0);
+            copy
+        };
+        let mut work = fresh_copy(parser);
+        let num_tokens = work.num_tokens();
+
+        // (backtrack, ff_tokens) -> when_sampled
+        let mut splices: IndexMap<(u32, Vec<TokenId>), Vec<TokenId>> = IndexMap::new();
+        for t in candidates {
+            let r = work.consume_token(t).and_then(|bt| {
+                let ff = work.consume_ff_tokens()?;
+                Ok((bt, ff))
+            });
+            let clean = matches!(r, Ok((0, _)));
+            if let Ok((bt, ff)) = r {
+                // this is what commit_token() returns, except the sampled token
+                // is already there, so it is counted in the backtrack
+                // and not repeated in ff_tokens
+                if bt > 0 || !ff.is_empty() {
+                    splices.entry((bt as u32, ff)).or_default().push(t);
+                }
+            }
+            if clean && can_rollback {
+                let n = work.num_tokens() - num_tokens;
+                if work.rollback(n).is_ok() {
+                    continue;
+                }
+            }
+            work = fresh_copy(parser);
+        }
+
+        let splices = splices
+            .into_iter()
+            .map(|((backtrack, ff_tokens), when_sampled)| Splice {
+                when_sampled,
+                backtrack,
+                ff_tokens,
+            })
+            .collect();
+        (splices, truncated)
+    }
+
     pub fn step_result(&self) -> &StepResult {
         &self.last_res
     }
 
+    /// Whether the conditional splices of the last compute_mask() were computed
+    /// only for some of the candidate tokens, because of
+    /// `set_max_splice_candidates()`.
+    pub fn splices_truncated(&self) -> bool {
+        self.splices_truncated
+    }
+
     fn res_commit_result(&mut self) -> Result<CommitResult> {
         Ok(CommitResult::from_step_result(&self.last_res))
     }
@@ -265,6 +591,21 @@ impl Constraint {
         self.reporter.get_progress(&self.parser, &self.last_res)
     }
 
+    /// Report captures while they are still being generated, as
+    /// CaptureStart/CaptureDelta/CaptureEnd outputs, in addition to the
+    /// final Capture output once the rule completes.
+    pub fn set_stream_captures(&mut self, stream_captures: bool) {
+        self.reporter.set_stream_captures(stream_captures);
+    }
+
+    /// Maximum number of tokens tried when computing conditional splices
+    /// (only when `conditional_ff_tokens` is enabled); 0 disables them.
+    /// Each candidate costs about as much as committing a token.
+    /// Default: 100
+    pub fn set_max_splice_candidates(&mut self, max_splice_candidates: usize) {
+        self.max_splice_candidates = max_splice_candidates;
+    }
+
     /// Logs to be sent to the user.
     pub fn flush_logs(&mut self) -> String {
         self.parser.logger.get_and_clear_logs()
@@ -276,3 +617,885 @@ impl Constraint {
         self.parser.token_env.tok_trie()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{api::TopLevelGrammar, output::CaptureProvenance, test_util::factory_ext};
+    use toktrie::InferenceCapabilities;
+
+    fn constraint(lark: &str) -> Constraint {
+        constraint_ext(lark, &[], InferenceCapabilities::default())
+    }
+
+    fn constraint_ext(lark: &str, extra_words: &[&str], caps: InferenceCapabilities) -> Constraint {
+        constraint_grm(
+            TopLevelGrammar::from_lark(lark.to_string()),
+            extra_words,
+            caps,
+        )
+    }
+
+    fn constraint_grm(
+        grm: TopLevelGrammar,
+        extra_words: &[&str],
+        caps: InferenceCapabilities,
+    ) -> Constraint {
+        Constraint::new(factory_ext(extra_words, caps).create_parser(grm).unwrap())
+    }
+
+    fn mask(r: &StepResult) -> Vec<u32> {
+        let mut v = vec![];
+        r.sample_mask
+            .as_ref()
+            .unwrap()
+            .iter_set_entries(|i| v.push(i as u32));
+        v
+    }
+
+    fn toks(s: &[u8]) -> Vec<u32> {
+        s.iter().map(|&b| b as u32).collect()
+    }
+
+    fn draft_masks(lark: &str, prefix: &[u8], draft: &[u32]) {
+        draft_masks_ext(constraint(lark), prefix, draft);
+    }
+
+    fn splice(r: &StepResult) -> Vec<(Vec<u32>, u32, Vec<u32>)> {
+        r.splices
+            .iter()
+            .map(|s| (s.when_sampled.clone(), s.backtrack, s.ff_tokens.clone()))
+            .collect()
+    }
+
+    fn draft_masks_ext(mut c: Constraint, prefix: &[u8], draft: &[u32]) {
+        let mut expected = c.deep_clone();
+        for &t in prefix {
+            c.compute_mask().unwrap();
+            c.commit_token(Some(t as u32)).unwrap();
+            expected.compute_mask().unwrap();
+            expected.commit_token(Some(t as u32)).unwrap();
+        }
+        let res = c.compute_draft_masks(draft).unwrap();
+        assert_eq!(res.results.len(), res.num_accepted + 1);
+        for (i, r) in res.results.iter().enumerate() {
+            if r.unconditional_splice().is_some() {
+                // checked below, when committing the previous token
+                assert_eq!(i, res.num_accepted);
+                break;
+            }
+            let e = expected.compute_mask().unwrap().clone();
+            assert_eq!(r.is_stop(), e.is_stop());
+            if r.is_stop() {
+                break;
+            }
+            assert_eq!(mask(r), mask(&e));
+            assert_eq!(splice(r), splice(&e));
+            if i < res.num_accepted {
+                let cr = expected.commit_token(Some(draft[i])).unwrap();
+                let next = &res.results[i + 1];
+                if cr.backtrack == 0 && cr.ff_tokens == [draft[i]] {
+                    assert!(next.unconditional_splice().is_none());
+                } else {
+                    assert_eq!(splice(next), vec![(vec![], cr.backtrack, cr.ff_tokens)]);
+                }
+            } else if i < draft.len() {
+                assert!(!e.sample_mask.as_ref().unwrap().is_allowed(draft[i]));
+            }
+        }
+
+        // the constraint is left as after compute_mask()
+        let t = prefix.len() as u32;
+        assert_eq!(c.parser.num_tokens() as u32, t);
+        if !c.step_result().is_stop() {
+            let t = mask(c.step_result())[0];
+            c.commit_token(Some(t)).unwrap();
+            c.compute_mask().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_draft_masks() {
+        let grm = "start: \"[\" /[a-z]+/ \"]\" \"!\"?";
+        draft_masks(grm, b"", &toks(b"[abc]"));
+        draft_masks(grm, b"[a", &toks(b"bc]!"));
+        draft_masks(grm, b"[a", &toks(b"b1c]"));
+        draft_masks(grm, b"[a", &[]);
+        draft_masks(grm, b"[a", &toks(b"b]!!!"));
+        let eos = 256;
+        draft_masks(grm, b"[a", &[toks(b"b]").as_slice(), &[eos, eos]].concat());
+
+        // rollback with stop= and max_tokens=
+        let grm = "start: \"[\" gen \"]\"\ngen[stop=\",\"]: /[a-z]+/";
+        draft_masks(grm, b"[", &toks(b"ab,]"));
+        draft_masks(grm, b"[", &toks(b"ab!"));
+        draft_masks(grm, b"[a", &toks(b"b,]"));
+        let grm = "start: gen \"!\"\ngen[max_tokens=3]: /[a-z]+/";
+        draft_masks(grm, b"a", &toks(b"bc!"));
+        draft_masks(grm, b"a", &toks(b"bcd!"));
+
+        // with ff_tokens, the walk stops at forced tokens
+        let caps = InferenceCapabilities {
+            ff_tokens: true,
+            conditional_ff_tokens: true,
+            backtrack: true,
+            fork: false,
+        };
+        let words = ["ab", "]!", "!!"];
+        let grm = "start: \"[\" /[a-z]+/ \"]!!!\" /[a-z]*/";
+        let ff = |prefix: &[u8], draft: &[u32]| {
+            draft_masks_ext(constraint_ext(grm, &words, caps.clone()), prefix, draft)
+        };
+        ff(b"", &toks(b"[abc]!!!x"));
+        ff(b"[", &[257, b'c' as u32, 258, b'x' as u32]);
+        ff(b"[a", &[b'b' as u32, 258, 259, b'x' as u32]);
+
+        // failed drafts are rolled back as well
+        let grm = "start: \"[\" /[a-z]+/ \"]\"";
+        let mut c = constraint(grm);
+        let mut expected = c.deep_clone();
+        let draft = [toks(b"[ab").as_slice(), &[100_000]].concat();
+        assert!(c.compute_draft_masks(&draft).is_err());
+        assert_eq!(c.parser.num_tokens(), 0);
+        for &t in b"[ab]" {
+            assert_eq!(
+                mask(c.compute_mask().unwrap()),
+                mask(expected.compute_mask().unwrap())
+            );
+            c.commit_token(Some(t as u32)).unwrap();
+            expected.commit_token(Some(t as u32)).unwrap();
+        }
+        assert!(c.compute_mask().unwrap().is_stop());
+
+        // captures made while walking the draft are rolled back
+        let grm = "start: \"[\" x \"]\" /[a-z]*/\nx[capture]: /[a-z]+/";
+        let mut c = constraint(grm);
+        capture_events(&mut c, b"[");
+        c.compute_draft_masks(&toks(b"ab]zz")).unwrap();
+        assert_eq!(capture_events(&mut c, b"ab]q"), vec!["capture x:ab"]);
+        assert_eq!(c.parser.get_capture("x"), Some(&b"ab"[..]));
+        assert_eq!(c.parser.parser.captures().len(), 1);
+    }
+
+    fn check_rollback(lark: &str, tokens: &[u8]) {
+        let mut c = constraint(lark);
+        // copies of the constraint from the last time it had the given number of tokens
+        let mut saved = vec![c.deep_clone()];
+        for &t in tokens {
+            assert!(!c.compute_mask().unwrap().is_stop());
+            c.commit_token(Some(t as u32)).unwrap();
+            let n = c.parser.num_tokens();
+            saved.truncate(n);
+            assert_eq!(saved.len(), n);
+            saved.push(c.deep_clone());
+        }
+
+        for n in 1..saved.len() {
+            let mut p = c.parser.deep_clone();
+            p.rollback(n).unwrap();
+            let mut expected = saved[saved.len() - 1 - n].parser.deep_clone();
+            if expected.is_fresh() {
+                expected.start_without_prompt();
+            }
+            let mask = p.compute_mask().unwrap();
+            assert_eq!(
+                mask,
+                expected.compute_mask().unwrap(),
+                "{lark}; rollback {n}"
+            );
+
+            // continue with the token sampled originally
+            let t = tokens[tokens.len() - n] as u32;
+            if mask.is_allowed(t) {
+                p.consume_token(t).unwrap();
+                expected.consume_token(t).unwrap();
+                assert_eq!(p.compute_mask().unwrap(), expected.compute_mask().unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_rollback() {
+        check_rollback("start: \"[\" /[a-z]+/ \"]\"", b"[ab]");
+        // the stop string is dropped, with the token it's in
+        check_rollback(
+            "start: \"[\" gen \"]\" /[a-z]+/\ngen[stop=\",\"]: /[a-z]+/",
+            b"[ab,]cd",
+        );
+        check_rollback(
+            "start: gen \"!\" /[a-z]+/\ngen[max_tokens=3]: /[a-z]+/",
+            b"abc!de",
+        );
+        check_rollback(
+            "start: gen /[a-z]+/\ngen[stop=\"END\", max_tokens=5]: /[A-Z]+/",
+            b"ABENDxy",
+        );
+
+        // captures are rolled back too
+        let mut c = constraint("start: \"[\" x \"]\"\nx[capture]: /[a-z]+/");
+        c.parser.start_without_prompt();
+        for &t in b"[ab]" {
+            c.parser.compute_mask().unwrap();
+            c.parser.consume_token(t as u32).unwrap();
+        }
+        assert_eq!(c.parser.get_capture("x"), Some(&b"ab"[..]));
+        c.parser.rollback(3).unwrap();
+        assert_eq!(c.parser.get_capture("x"), None);
+        assert!(c.parser.parser.captures().is_empty());
+        for &t in b"c]" {
+            c.parser.compute_mask().unwrap();
+            c.parser.consume_token(t as u32).unwrap();
+        }
+        assert_eq!(c.parser.get_capture("x"), Some(&b"c"[..]));
+        assert_eq!(
+            c.parser.parser.captures(),
+            &[("x".to_string(), b"c".to_vec())]
+        );
+        assert_eq!(c.parser.final_bytes(), b"[c]");
+    }
+
+    #[test]
+    fn test_rollback_max_tokens() {
+        let lark = "start: gen \"!\"\ngen[max_tokens=3]: /[a-z]+/";
+        let mut p = constraint(lark).parser;
+        p.start_without_prompt();
+        let consume = |p: &mut TokenParser, s: &[u8]| {
+            for &t in s {
+                p.compute_mask().unwrap();
+                p.consume_token(t as u32).unwrap();
+            }
+        };
+        // whether gen was ended by max_tokens
+        let at_limit = |p: &mut TokenParser| {
+            let mask = p.compute_mask().unwrap();
+            assert!(mask.is_allowed(b'!' as u32));
+            !mask.is_allowed(b'x' as u32)
+        };
+
+        consume(&mut p, b"abc");
+        assert!(at_limit(&mut p));
+        // the token count of gen is restored
+        p.rollback(2).unwrap();
+        assert!(!at_limit(&mut p));
+        consume(&mut p, b"x");
+        assert!(!at_limit(&mut p));
+        consume(&mut p, b"y");
+        assert!(at_limit(&mut p));
+        p.rollback(3).unwrap();
+        consume(&mut p, b"ab");
+        assert!(!at_limit(&mut p));
+
+        // marks before the trimmed undo log can't be used
+        let old = p.parser.mark();
+        consume(&mut p, b"c!");
+        let new = p.parser.mark();
+        p.parser.trim_undo_log(Some(&new));
+        assert!(p.parser.rollback_to(&old).is_err());
+        assert!(p.parser.rollback_to(&new).is_ok());
+
+        // rollback by bytes is only available without max_tokens
+        #[allow(deprecated)]
+        {
+            assert!(constraint(lark).parser.parser.rollback(0).is_err());
+            let mut p = constraint("start: /[a-z]+/").parser;
+            p.start_without_prompt();
+            consume(&mut p, b"abc");
+            p.parser.rollback(2).unwrap();
+            let mut expected = constraint("start: /[a-z]+/").parser;
+            expected.start_without_prompt();
+            consume(&mut expected, b"a");
+            assert_eq!(p.compute_mask().unwrap(), expected.compute_mask().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_max_rollback_tokens() {
+        let grm = TopLevelGrammar::from_lark("start: x+\nx[capture]: /[a-z]/".to_string());
+        let mut p = constraint_grm(grm.clone(), &[], InferenceCapabilities::default()).parser;
+        p.set_max_rollback_tokens(5);
+        p.start_without_prompt();
+        for i in 0..100 {
+            p.compute_mask().unwrap();
+            p.consume_token(b'a' as u32 + i % 26).unwrap();
+            // old marks and undo entries are dropped;
+            // there are 3 entries per token here, kept for up to 2 * 5 tokens
+            assert!(p.parser.undo_log_len() <= 30);
+        }
+
+        // the last 5 tokens can still be rolled back, and restored from a snapshot
+        let mut r = restored(&grm, &p);
+        for p in [&mut p, &mut r] {
+            p.rollback(5).unwrap();
+            assert_eq!(p.num_tokens(), 95);
+            assert_eq!(p.get_capture("x"), Some(&b"q"[..]));
+            assert!(p.rollback(20).is_err());
+        }
+    }
+
+    fn restored(grm: &TopLevelGrammar, p: &TokenParser) -> TokenParser {
+        let mut r = constraint_grm(grm.clone(), &[], InferenceCapabilities::default()).parser;
+        r.restore_snapshot(&p.snapshot()).unwrap();
+        assert_eq!(r.stop_reason(), p.stop_reason());
+        assert_eq!(r.num_tokens(), p.num_tokens());
+        r
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let grm = TopLevelGrammar::from_lark("start: \"[\" /[a-z]+/ \"]\"".to_string());
+        let new_parser = || {
+            let mut p = constraint_grm(grm.clone(), &[], InferenceCapabilities::default()).parser;
+            p.start_without_prompt();
+            p
+        };
+        let consume = |p: &mut TokenParser, s: &[u8]| {
+            for &t in s {
+                p.compute_mask().unwrap();
+                p.consume_token(t as u32).unwrap();
+            }
+        };
+
+        // rolled-back tokens are not stored
+        let mut p = new_parser();
+        consume(&mut p, b"[abc");
+        p.rollback(2).unwrap();
+        let mut expected = new_parser();
+        consume(&mut expected, b"[a");
+        assert_eq!(p.snapshot(), expected.snapshot());
+        let mut r = restored(&grm, &p);
+        assert_eq!(r.compute_mask().unwrap(), p.compute_mask().unwrap());
+        consume(&mut r, b"x]");
+        assert!(r.check_stop().unwrap());
+
+        // stopped at the end of grammar; rollback still works after restore
+        let mut p = new_parser();
+        consume(&mut p, b"[a]");
+        assert!(p.check_stop().unwrap());
+        let mut r = restored(&grm, &p);
+        r.rollback(1).unwrap();
+        p.rollback(1).unwrap();
+        assert_eq!(r.compute_mask().unwrap(), p.compute_mask().unwrap());
+
+        let mut grm = grm.clone();
+        grm.max_tokens = Some(3);
+        let mut p = constraint_grm(grm.clone(), &[], InferenceCapabilities::default()).parser;
+        p.start_without_prompt();
+        consume(&mut p, b"[ab");
+        p.rollback(1).unwrap();
+        // the rollback gave the token back
+        consume(&mut p, b"b");
+        p.compute_mask().unwrap();
+        assert!(p.consume_token(b'c' as u32).is_err());
+        assert_eq!(p.stop_reason(), StopReason::MaxTokensTotal);
+        let mut r = restored(&grm, &p);
+        assert!(r.compute_mask().is_err());
+
+        // captures and lazy lexemes; rolling back past the snapshot after restore
+        let grm = TopLevelGrammar::from_lark(
+            "start: x (\",\" x)* \";\" y\nx[capture]: /[a-z]+/\ny[capture, lazy]: /[a-z]*q/"
+                .to_string(),
+        );
+        let mut p = constraint_grm(grm.clone(), &[], InferenceCapabilities::default()).parser;
+        p.start_without_prompt();
+        consume(&mut p, b"ab,cd;xy");
+        let r = restored(&grm, &p);
+        assert_eq!(r.get_capture("x"), Some(&b"cd"[..]));
+        for n in [0, 3, 5] {
+            let mut p = p.deep_clone();
+            let mut r = r.deep_clone();
+            p.rollback(n).unwrap();
+            r.rollback(n).unwrap();
+            assert_eq!(r.compute_mask().unwrap(), p.compute_mask().unwrap());
+            assert_eq!(r.parser.captures(), p.parser.captures());
+        }
+        let mut r = r;
+        consume(&mut r, b"zq");
+        assert!(r.check_stop().unwrap());
+        assert_eq!(r.get_capture("y"), Some(&b"xyzq"[..]));
+        let snapshot = p.snapshot();
+        for len in [10, snapshot.len() / 2, snapshot.len() - 1] {
+            let mut r = constraint_grm(grm.clone(), &[], InferenceCapabilities::default()).parser;
+            assert!(r.restore_snapshot(&snapshot[..len]).is_err());
+        }
+
+        // corrupted snapshots are rejected, or give a parser that doesn't panic
+        let bit_flips = |grm: &TopLevelGrammar, text: &[u8]| {
+            let fresh = constraint_grm(grm.clone(), &[], InferenceCapabilities::default()).parser;
+            let mut p = fresh.deep_clone();
+            p.start_without_prompt();
+            consume(&mut p, text);
+            let snapshot = p.snapshot();
+            let mut num_restored = 0;
+            for bit in 0..snapshot.len() * 8 {
+                let mut data = snapshot.clone();
+                data[bit / 8] ^= 1 << (bit % 8);
+                let mut r = fresh.deep_clone();
+                if r.restore_snapshot(&data).is_err() {
+                    continue;
+                }
+                num_restored += 1;
+                for _ in 0..3 {
+                    if let Some(t) = r.compute_mask().ok().and_then(|m| m.first_bit_set()) {
+                        let _ = r.consume_token(t as TokenId);
+                    }
+                }
+                let _ = r.check_stop();
+                let _ = r.rollback(4);
+                let _ = r.compute_mask();
+            }
+            // flipped bits in the bytes and lexemes are not detected
+            assert!(num_restored > 0);
+        };
+        bit_flips(&grm, b"ab,cd;xy");
+        bit_flips(
+            &TopLevelGrammar::from_lark(
+                "start: \"<\" a \">\"\na[ignore=\" \"]: \"x\" b \"y\"\nb[ignore=\"\"]: \"q\" | \"(\" a \")\""
+                    .to_string(),
+            ),
+            b"<x (x q",
+        );
+
+        // regexes differing after the first 512 characters
+        let long = |c: &str| {
+            let lark = format!("start: /{}{}/", "a".repeat(600), c);
+            constraint(&lark).parser.grammar_fingerprint()
+        };
+        assert_ne!(long("b"), long("c"));
+    }
+
+    #[test]
+    fn test_conditional_splices() {
+        let caps = InferenceCapabilities {
+            ff_tokens: true,
+            conditional_ff_tokens: true,
+            backtrack: true,
+            fork: false,
+        };
+        let words = ["\", \"", "age", "\": ", "b\"", "ab", "\"a"];
+        let mut c = constraint_ext(
+            "start: \"{\\\"name\\\": \\\"\" /[a-z]+/ \"\\\", \\\"age\\\": \" /[0-9]+/ \"}\"",
+            &words,
+            caps,
+        );
+        let trie = c.tok_trie().clone();
+        let mut num_splices = 0;
+        for t in trie.greedy_tokenize(b"{\"name\": \"ab\", \"age\": 12}") {
+            let res = c.compute_mask().unwrap().clone();
+            if res.is_stop() {
+                break;
+            }
+            if res.unconditional_splice().is_some() {
+                c.commit_token(None).unwrap();
+                continue;
+            }
+            num_splices += res.splices.len();
+            let mask = res.sample_mask.as_ref().unwrap();
+            mask.iter_set_entries(|s| {
+                let s = s as TokenId;
+                let r = c.deep_clone().commit_token(Some(s)).unwrap();
+                let expected = if r.backtrack == 0 {
+                    assert_eq!(r.ff_tokens[0], s);
+                    (0, r.ff_tokens[1..].to_vec())
+                } else {
+                    (r.backtrack + 1, r.ff_tokens)
+                };
+                let got = res
+                    .find_splice(s)
+                    .map_or((0, vec![]), |s| (s.backtrack, s.ff_tokens.clone()));
+                assert_eq!(got, expected, "{}", trie.token_dbg(s));
+            });
+            if mask.is_allowed(t) {
+                let r = c.commit_token(Some(t)).unwrap();
+                assert!(!r.stop);
+            }
+        }
+        assert!(num_splices > 0);
+    }
+
+    #[test]
+    fn test_max_splice_candidates() {
+        let caps = InferenceCapabilities {
+            ff_tokens: true,
+            conditional_ff_tokens: true,
+            backtrack: true,
+            fork: false,
+        };
+        let words = ["\", \"", "age", "\": ", "b\"", "ab", "\"a"];
+        let grm = "start: \"{\\\"name\\\": \\\"\" /[a-z]+/ \"\\\", \\\"age\\\": \" /[0-9]+/ \"}\"";
+        // splices and truncation for the step after `{"name": "a`
+        let step = |max: usize| {
+            let mut c = constraint_ext(grm, &words, caps.clone());
+            c.set_max_splice_candidates(max);
+            loop {
+                let r = c.compute_mask().unwrap().clone();
+                let mask = r.sample_mask.as_ref().unwrap();
+                if mask.is_allowed(b'a' as TokenId) {
+                    c.commit_token(Some(b'a' as TokenId)).unwrap();
+                    break;
+                }
+                // forced byte
+                c.commit_token(mask.first_bit_set().map(|t| t as TokenId))
+                    .unwrap();
+            }
+            let splices = c.compute_mask().unwrap().splices.len();
+            (c, splices)
+        };
+
+        let (c, all) = step(100);
+        assert!(all > 1);
+        assert!(!c.splices_truncated());
+
+        let (c, one) = step(1);
+        assert_eq!(one, 1);
+        assert!(c.splices_truncated());
+        // the flag survives a snapshot
+        let mut r = constraint_ext(grm, &words, caps.clone());
+        r.restore_snapshot(&c.snapshot()).unwrap();
+        assert!(r.splices_truncated());
+
+        let (c, none) = step(0);
+        assert_eq!(none, 0);
+        assert!(!c.splices_truncated());
+    }
+
+    #[test]
+    fn test_expected_terminals() {
+        let grm = r#"
+            start: "[" item ("," item)* "]"
+            item: NUM | WORD
+            NUM: /[0-9]+/
+            WORD: /[a-z]+/
+            %ignore " "
+        "#;
+        let expected = |prefix: &[u8]| {
+            let mut c = constraint(grm);
+            for &b in prefix {
+                c.compute_mask().unwrap();
+                c.commit_token(Some(b as u32)).unwrap();
+            }
+            c.expected_terminals()
+        };
+        let names = |e: &ExpectedTerminals| {
+            e.lexemes
+                .iter()
+                .map(|l| l.name.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        let e = expected(b"");
+        assert_eq!(e.lexemes.len(), 1);
+        assert_eq!(e.lexemes[0].regex, "'['");
+        assert_eq!(e.rules, vec!["start ::= • '[' item star ']'"]);
+        assert!(!e.eos_allowed);
+
+        let e = expected(b"[ ");
+        assert_eq!(names(&e), "NUM WORD");
+        assert_eq!(e.lexemes[0].regex, "(['0'-'9'])+");
+        assert_eq!(e.rules, vec!["start ::= '[' • item star ']'"]);
+
+        // partial lexeme, which can be followed by ',' or ']'
+        let e = expected(b"[1");
+        assert_eq!(names(&e), "NUM ',' ']'");
+        assert_eq!(e.rules, vec!["start ::= '[' • item star ']'"]);
+
+        let e = expected(b"[1,x]");
+        assert!(e.lexemes.is_empty());
+        assert!(e.rules.is_empty());
+        assert!(e.eos_allowed);
+
+        // terminals with the same regex are merged and list all their names
+        let grm = "start: KEY \"=\" VALUE\nKEY: /[a-z]+/\nVALUE: /[a-z]+/";
+        let mut c = constraint(grm);
+        assert_eq!(names(&c.expected_terminals()), "KEY|VALUE");
+        for &b in b"a=" {
+            c.compute_mask().unwrap();
+            c.commit_token(Some(b as u32)).unwrap();
+        }
+        assert_eq!(names(&c.expected_terminals()), "KEY|VALUE");
+    }
+
+    #[test]
+    fn test_parse_trees() {
+        let trees = |grm: &str, text: &[u8], max_trees: usize| {
+            let mut c = constraint(grm);
+            for &b in text {
+                c.compute_mask().unwrap();
+                c.commit_token(Some(b as u32)).unwrap();
+            }
+            c.parse_trees(max_trees)
+        };
+        let sexprs = |trees: Vec<ParseTree>| trees.iter().map(|t| t.to_sexpr()).collect::<Vec<_>>();
+
+        let grm = r#"
+            start: "[" item ("," item)* "]"
+            item: NUM | WORD
+            NUM: /[0-9]+/
+            WORD: /[a-z]+/
+            %ignore " "
+        "#;
+        let t = trees(grm, b"[1, x ]", 10);
+        assert_eq!(
+            sexprs(t.clone()),
+            vec![r#"(start '[':"[" (item NUM:"1") ',':"," (item WORD:"x") ']':"]")"#]
+        );
+        let x = &t[0].children[3];
+        assert_eq!((x.start, x.end), (4, 5));
+        assert_eq!((x.children[0].start, x.children[0].end), (4, 5));
+        assert_eq!((t[0].start, t[0].end), (0, 7));
+        // not finished yet
+        assert!(trees(grm, b"[1, x", 10).is_empty());
+
+        let grm = r#"
+            start: expr
+            expr: expr "+" expr | NUM
+            NUM: /[0-9]+/
+        "#;
+        let left =
+            r#"(start (expr (expr (expr NUM:"1") '+':"+" (expr NUM:"2")) '+':"+" (expr NUM:"3")))"#;
+        let right =
+            r#"(start (expr (expr NUM:"1") '+':"+" (expr (expr NUM:"2") '+':"+" (expr NUM:"3"))))"#;
+        assert_eq!(sexprs(trees(grm, b"1+2+3", 10)), vec![left, right]);
+        assert_eq!(sexprs(trees(grm, b"1+2+3", 1)), vec![left]);
+        // the last lexeme is still pending in the lexer
+        assert_eq!(
+            sexprs(trees(grm, b"12", 1)),
+            vec![r#"(start (expr NUM:"12"))"#]
+        );
+
+        // optional rules, as ambiguous as they get
+        let grm = "start: a b\na: \"x\"?\nb: \"x\"?";
+        assert_eq!(
+            sexprs(trees(grm, b"x", 10)),
+            vec![r#"(start (a 'x':"x") (b))"#, r#"(start (a) (b 'x':"x"))"#]
+        );
+
+        // user rules named like internal helper nodes are kept
+        let grm = "start: star plus\nstar: \"a\" | \"c\"\nplus: \"b\" | \"d\"";
+        assert_eq!(
+            sexprs(trees(grm, b"ab", 10)),
+            vec![r#"(start (star 'a':"a") (plus 'b':"b"))"#]
+        );
+        let grm = "start: x*\nx: \"a\" | \"b\"";
+        assert_eq!(
+            sexprs(trees(grm, b"ab", 10)),
+            vec![r#"(start (x 'a':"a") (x 'b':"b"))"#]
+        );
+    }
+
+    #[test]
+    fn test_parse_trees_long_list() {
+        let n = 20_000;
+        let grm = "start: \"[\" NUM (\",\" NUM)* \"]\"\nNUM: /[0-9]+/";
+        let mut c = constraint_ext(grm, &[",1"], InferenceCapabilities::default());
+        let item = c.tok_trie().greedy_tokenize(b",1")[0];
+        let mut tokens = toks(b"[1");
+        tokens.extend(std::iter::repeat_n(item, n - 1));
+        tokens.extend(toks(b"]"));
+        for t in tokens {
+            c.compute_mask().unwrap();
+            c.commit_token(Some(t)).unwrap();
+        }
+        let t = c.parse_trees(2);
+        assert_eq!(t.len(), 1);
+        assert_eq!(t[0].children.len(), 2 * n + 1);
+        assert_eq!(t[0].children[2 * n - 1].text.as_deref(), Some("1"));
+        assert_eq!(t[0].end, 2 * n + 1);
+    }
+
+    fn capture_events(c: &mut Constraint, text: &[u8]) -> Vec<String> {
+        let path = |name: String, path: Vec<String>| {
+            path.into_iter()
+                .chain(std::iter::once(name))
+                .collect::<Vec<_>>()
+                .join("/")
+        };
+        let mut res = vec![];
+        for &b in text {
+            c.compute_mask().unwrap();
+            c.commit_token(Some(b as u32)).unwrap();
+            for out in c.flush_progress() {
+                res.push(match out {
+                    ParserOutput::CaptureStart { name, path: p } => {
+                        format!("start {}", path(name, p))
+                    }
+                    ParserOutput::CaptureDelta {
+                        name,
+                        path: p,
+                        bytes,
+                    } => format!("delta {}:{}", path(name, p), bytes.str),
+                    ParserOutput::CaptureEnd {
+                        name,
+                        path: p,
+                        completed,
+                    } => {
+                        let kind = if completed { "end" } else { "abort" };
+                        format!("{} {}", kind, path(name, p))
+                    }
+                    ParserOutput::Capture { name, bytes, .. } => {
+                        format!("capture {}:{}", name, bytes.str)
+                    }
+                    ParserOutput::CaptureProvenance { name, provenance } => format!(
+                        "provenance {}:{}:{}-{}",
+                        name, provenance.document, provenance.start, provenance.end
+                    ),
+                    _ => continue,
+                });
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn test_stream_captures() {
+        let events = |grm: &str, text: &str| {
+            let mut c = constraint(grm);
+            c.set_stream_captures(true);
+            capture_events(&mut c, text.as_bytes())
+        };
+
+        let grm = r#"
+            start: "<" obj ">"
+            obj[capture]: "{" key ":" val "}"
+            key[capture]: /[a-z]+/
+            val[capture]: /[0-9]+/
+        "#;
+        assert_eq!(
+            events(grm, "<{ab:12}>"),
+            vec![
+                "start obj",
+                "delta obj:{",
+                "delta obj:a",
+                "start obj/key",
+                "delta obj/key:a",
+                "delta obj:b",
+                "delta obj/key:b",
+                // ended captures go first, then the open ones, outermost first
+                "end obj/key",
+                "delta obj::",
+                "capture key:ab",
+                "delta obj:1",
+                "start obj/val",
+                "delta obj/val:1",
+                "delta obj:2",
+                "delta obj/val:2",
+                "end obj/val",
+                "delta obj:}",
+                "end obj",
+                "capture val:12",
+                "capture obj:{ab:12}",
+            ]
+        );
+
+        // characters split across tokens are sent whole
+        let grm = "start: \"<\" x \">\"\nx[capture]: /[a-zé]+/";
+        assert_eq!(
+            events(grm, "<aé>"),
+            vec!["start x", "delta x:a", "delta x:é", "end x", "capture x:aé"]
+        );
+
+        // rolled back captures are aborted
+        let mut c = constraint(grm);
+        c.set_stream_captures(true);
+        capture_events(&mut c, b"<ab");
+        c.parser.rollback(2).unwrap();
+        assert_eq!(
+            capture_events(&mut c, b"c"),
+            vec!["abort x", "start x", "delta x:c"]
+        );
+
+        // rolling back text that wasn't reported yet doesn't abort them
+        let mut c = constraint(grm);
+        c.set_stream_captures(true);
+        capture_events(&mut c, b"<ab");
+        c.parser.compute_mask().unwrap();
+        c.parser.consume_token(b'z' as u32).unwrap();
+        c.parser.rollback(1).unwrap();
+        assert_eq!(capture_events(&mut c, b"c"), vec!["delta x:c"]);
+
+        // captures completed after a rollback are reported, even at the same index
+        let mut c = constraint(grm);
+        assert_eq!(capture_events(&mut c, b"<ab>"), vec!["capture x:ab"]);
+        c.parser.rollback(3).unwrap();
+        assert_eq!(capture_events(&mut c, b"c>"), vec!["capture x:c"]);
+
+        // with ambiguity, the capture ends with the first complete value;
+        // streaming stops while x may also be "ab,cd"
+        let grm = r#"
+            start: x "!" | x "," z
+            x[capture]: /[a-z]+/ | /[a-z]+/ "," /[a-z]+/
+            z: /[a-z]+/ "?"
+        "#;
+        assert_eq!(
+            events(grm, "ab,cd!"),
+            vec![
+                "start x",
+                "delta x:a",
+                "delta x:b",
+                "end x",
+                "capture x:ab",
+                "capture x:ab,cd",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stream_captures_snapshot() {
+        let grm = "start: \"<\" x \">\"\nx[capture]: /[a-z]+/";
+        let mut c = constraint(grm);
+        c.set_stream_captures(true);
+        capture_events(&mut c, b"<ab");
+        let mut r = constraint(grm);
+        r.restore_snapshot(&c.snapshot()).unwrap();
+        // the restored constraint streams on from where it was
+        let expected = vec!["delta x:c", "end x", "capture x:abc"];
+        assert_eq!(capture_events(&mut c, b"c>"), expected);
+        assert_eq!(capture_events(&mut r, b"c>"), expected);
+    }
+
+    #[test]
+    fn test_capture_provenance() {
+        let grm = r#"
+            start: x "|" y "."
+            x[capture="q"]: %regex { "substring_documents": ["cat", "a"] }
+            y[capture="q"]: %regex { "substring_documents": ["the cats", "cat"] }
+        "#;
+        let mut p = constraint(grm).parser;
+        p.start_without_prompt();
+        let consume = |p: &mut TokenParser, s: &[u8]| {
+            for &t in s {
+                p.compute_mask().unwrap();
+                p.consume_token(t as u32).unwrap();
+            }
+        };
+        let prov = |document, start, end| {
+            Some(CaptureProvenance {
+                document,
+                start,
+                end,
+            })
+        };
+
+        // "a" inside of "cat", and "cat" inside of "cats", can't be produced by the lexemes;
+        // each capture uses the documents of its own rule
+        consume(&mut p, b"a|cat.");
+        let provenances = (0..p.parser.captures().len())
+            .filter(|&idx| !p.parser.captures()[idx].1.is_empty())
+            .map(|idx| p.capture_provenance(idx))
+            .collect::<Vec<_>>();
+        assert_eq!(provenances, vec![prov(1, 0, 1), prov(1, 0, 3)]);
+        assert_eq!(p.get_capture_provenance("q"), prov(1, 0, 3));
+
+        // and follows the capture in the progress
+        assert_eq!(
+            capture_events(&mut constraint(grm), b"a|cat."),
+            vec![
+                "capture q:a",
+                "provenance q:1:0-1",
+                "capture q:",
+                "capture q:cat",
+                "provenance q:1:0-3",
+            ]
+        );
+    }
+}
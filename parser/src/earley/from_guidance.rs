@@ -2,11 +2,18 @@ use std::fmt::Write;
 use std::{sync::Arc, vec};
 
 use super::grammar::SymIdx;
+use super::lexer::Lexer;
 use super::lexerspec::LexerSpec;
 use super::{CGrammar, Grammar};
-use crate::api::{GrammarId, GrammarInit, GrammarWithLexer, ParserLimits, TopLevelGrammar};
+use crate::abnf::abnf_to_llguidance;
+use crate::api::{
+    Diagnostic, GrammarError, GrammarId, GrammarInit, GrammarWithLexer, ParserLimits,
+    TopLevelGrammar,
+};
 use crate::earley::lexerspec::LexemeClass;
-use crate::lark::lark_to_llguidance;
+use crate::gbnf::gbnf_to_llguidance;
+use crate::grammar_builder::GrammarSources;
+use crate::lark::{export_lark, lark_to_llguidance, lint_lark};
 use crate::Instant;
 use crate::{loginfo, JsonCompileOptions, Logger};
 use crate::{GrammarBuilder, HashMap};
@@ -16,22 +23,32 @@ use toktrie::TokEnv;
 fn process_grammar(ctx: &mut CompileCtx, input: GrammarWithLexer) -> Result<(SymIdx, LexemeClass)> {
     let builder = std::mem::take(&mut ctx.builder).unwrap();
 
+    let num_set = [
+        input.lark_grammar.is_some(),
+        input.json_schema.is_some(),
+        input.gbnf_grammar.is_some(),
+        input.abnf_grammar.is_some(),
+    ]
+    .iter()
+    .filter(|x| **x)
+    .count();
+    ensure!(
+        num_set <= 1,
+        "only one of lark_grammar, json_schema, gbnf_grammar, abnf_grammar can be set"
+    );
+
     let res = if let Some(lark) = input.lark_grammar {
-        ensure!(
-            input.json_schema.is_none(),
-            "cannot have both lark_grammar and json_schema"
-        );
         lark_to_llguidance(builder, &lark)?
+    } else if let Some(gbnf) = input.gbnf_grammar {
+        gbnf_to_llguidance(builder, &gbnf)?
+    } else if let Some(abnf) = input.abnf_grammar {
+        abnf_to_llguidance(builder, &abnf)?
     } else if let Some(mut json_schema) = input.json_schema {
-        let mut opts = JsonCompileOptions::default();
-        if let Some(x_guidance) = json_schema.get("x-guidance") {
-            opts = serde_json::from_value(x_guidance.clone())?;
-            // TODO not removing it causes oneOf to be handled as anyOf in Github_medium---o61004.json
-            json_schema.as_object_mut().unwrap().remove("x-guidance");
-        }
+        // not removing x-guidance causes oneOf to be handled as anyOf in Github_medium---o61004.json
+        let opts = JsonCompileOptions::take_from_schema(&mut json_schema)?;
         opts.json_to_llg(builder, json_schema)?
     } else {
-        bail!("grammar must have either lark_grammar or json_schema");
+        bail!("grammar must have either lark_grammar, json_schema, gbnf_grammar or abnf_grammar");
     };
 
     res.builder.check_limits()?;
@@ -47,10 +64,16 @@ fn process_grammar(ctx: &mut CompileCtx, input: GrammarWithLexer) -> Result<(Sym
 fn process_all_grammars(
     mut ctx: CompileCtx,
     input: TopLevelGrammar,
-) -> Result<(Grammar, LexerSpec)> {
+) -> Result<(Grammar, LexerSpec, Vec<Diagnostic>, Option<GrammarSources>)> {
     for (idx, grm) in input.grammars.iter().enumerate() {
-        if grm.lark_grammar.is_none() && grm.json_schema.is_none() {
-            bail!("grammar must have either lark_grammar or json_schema");
+        if grm.lark_grammar.is_none()
+            && grm.json_schema.is_none()
+            && grm.gbnf_grammar.is_none()
+            && grm.abnf_grammar.is_none()
+        {
+            bail!(
+                "grammar must have either lark_grammar, json_schema, gbnf_grammar or abnf_grammar"
+            );
         }
         if let Some(n) = &grm.name {
             let n = GrammarId::Name(n.to_string());
@@ -72,13 +95,42 @@ fn process_all_grammars(
         .map(|(k, v)| (k, ctx.grammar_roots[v]))
         .collect();
 
-    let builder = ctx.builder.unwrap();
+    let mut builder = ctx.builder.unwrap();
+    let mut sources = builder.regex.sources.take();
+    if let Some(sources) = &mut sources {
+        for (id, (_, class)) in &grammar_by_idx {
+            let GrammarId::Name(n) = id;
+            sources.names.insert(*class, n.clone());
+        }
+    }
     let mut grammar = builder.grammar;
     let mut lexer_spec = builder.regex.spec;
 
     grammar.resolve_grammar_refs(&mut lexer_spec, &grammar_by_idx)?;
 
-    Ok((grammar, lexer_spec))
+    Ok((grammar, lexer_spec, builder.warnings, sources))
+}
+
+fn compile_serialized(
+    input: TopLevelGrammar,
+    tok_env: Option<TokEnv>,
+    limits: ParserLimits,
+    record_sources: bool,
+) -> Result<(Grammar, LexerSpec, Vec<Diagnostic>, Option<GrammarSources>)> {
+    ensure!(!input.grammars.is_empty(), "empty grammars array");
+
+    let mut builder = GrammarBuilder::new(tok_env, limits);
+    if record_sources {
+        builder.record_sources();
+    }
+
+    let ctx = CompileCtx {
+        builder: Some(builder),
+        grammar_by_idx: HashMap::default(),
+        grammar_roots: vec![(SymIdx::BOGUS, LexemeClass::ROOT); input.grammars.len()],
+    };
+
+    process_all_grammars(ctx, input)
 }
 
 struct CompileCtx {
@@ -93,21 +145,37 @@ impl GrammarInit {
         tok_env: Option<TokEnv>,
         limits: ParserLimits,
     ) -> Result<(Grammar, LexerSpec)> {
-        match self {
-            GrammarInit::Internal(g, l) => Ok((g, l)),
+        let (grammar, lexer_spec, _) = self.to_internal_ext(tok_env, limits)?;
+        Ok((grammar, lexer_spec))
+    }
 
+    /// Like [`GrammarInit::to_internal`], but also returns warnings.
+    pub fn to_internal_ext(
+        self,
+        tok_env: Option<TokEnv>,
+        limits: ParserLimits,
+    ) -> Result<(Grammar, LexerSpec, Vec<Diagnostic>)> {
+        match self {
+            GrammarInit::Internal(g, l) => Ok((g, l, vec![])),
             GrammarInit::Serialized(input) => {
-                ensure!(!input.grammars.is_empty(), "empty grammars array");
-
-                let builder = GrammarBuilder::new(tok_env, limits.clone());
-
-                let ctx = CompileCtx {
-                    builder: Some(builder),
-                    grammar_by_idx: HashMap::default(),
-                    grammar_roots: vec![(SymIdx::BOGUS, LexemeClass::ROOT); input.grammars.len()],
-                };
+                let (g, l, warnings, _) = compile_serialized(input, tok_env, limits, false)?;
+                Ok((g, l, warnings))
+            }
+        }
+    }
 
-                process_all_grammars(ctx, input)
+    /// Compile the grammar and render it back as Lark; the result has one
+    /// Lark grammar for each input grammar and each `%json` or similar subgrammar.
+    pub fn to_lark(self, tok_env: Option<TokEnv>, limits: ParserLimits) -> Result<TopLevelGrammar> {
+        match self {
+            GrammarInit::Internal(_, _) => bail!("only serialized grammars can be exported"),
+            GrammarInit::Serialized(input) => {
+                let max_tokens = input.max_tokens;
+                let (g, l, _, sources) = compile_serialized(input, tok_env, limits, true)?;
+                Ok(TopLevelGrammar {
+                    grammars: export_lark(&g, &l, &sources.unwrap())?,
+                    max_tokens,
+                })
             }
         }
     }
@@ -120,10 +188,76 @@ impl GrammarInit {
         extra_lexemes: Vec<String>,
     ) -> Result<Arc<CGrammar>> {
         let t0 = Instant::now();
-        let (grammar, mut lexer_spec) = self.to_internal(tok_env, limits)?;
+        let (grammar, mut lexer_spec, warnings) = self.to_internal_ext(tok_env, limits)?;
+        for w in warnings {
+            logger.warn(&w.to_string());
+        }
         lexer_spec.add_extra_lexemes(&extra_lexemes);
         compile_grammar(t0, grammar, lexer_spec, logger)
     }
+
+    /// Like [`GrammarInit::validate`], but also run lints,
+    /// which report unused, non-productive and known-slow constructs.
+    /// Lint warnings have the `code` field set.
+    pub fn lint(self, tok_env: Option<TokEnv>, limits: ParserLimits) -> Vec<Diagnostic> {
+        let lark_sources = match &self {
+            GrammarInit::Serialized(input) => input
+                .grammars
+                .iter()
+                .filter_map(|g| g.lark_grammar.clone())
+                .collect(),
+            GrammarInit::Internal(_, _) => vec![],
+        };
+        match self.compile_and_check(tok_env, limits) {
+            Ok((grammar, mut diagnostics)) => {
+                for lark in lark_sources {
+                    // the grammar compiled, so it will parse
+                    diagnostics.extend(lint_lark(&lark).unwrap_or_default());
+                }
+                // rules compiled from Lark are already reported, with locations
+                for d in grammar.lint() {
+                    if !diagnostics
+                        .iter()
+                        .any(|e| e.code == d.code && e.message == d.message)
+                    {
+                        diagnostics.push(d);
+                    }
+                }
+                if !grammar.start_is_productive() {
+                    diagnostics.push(
+                        Diagnostic::warning("grammar can't match any input".to_string())
+                            .with_code("non-productive"),
+                    );
+                }
+                diagnostics
+            }
+            Err(e) => GrammarError::diagnostics_of(&e),
+        }
+    }
+
+    /// Compile the grammar, and return all errors and warnings found.
+    /// The list is empty if the grammar is fine.
+    pub fn validate(self, tok_env: Option<TokEnv>, limits: ParserLimits) -> Vec<Diagnostic> {
+        match self.compile_and_check(tok_env, limits) {
+            Ok((_, warnings)) => warnings,
+            Err(e) => GrammarError::diagnostics_of(&e),
+        }
+    }
+
+    /// Compile the grammar all the way to the lexer, as creating a parser would,
+    /// since some errors (e.g., lexer fuel exhaustion) only show up there.
+    /// Returns the grammar before optimization, for lints.
+    fn compile_and_check(
+        self,
+        tok_env: Option<TokEnv>,
+        limits: ParserLimits,
+    ) -> Result<(Grammar, Vec<Diagnostic>)> {
+        let (grammar, lexer_spec, warnings) = self.to_internal_ext(tok_env, limits.clone())?;
+        let mut logger = Logger::new(0, 0);
+        let cgrammar = compile_grammar(Instant::now(), grammar.clone(), lexer_spec, &mut logger)?;
+        Lexer::from(cgrammar.lexer_spec(), &mut limits.clone(), false)?;
+        Ok((grammar, warnings))
+    }
 }
 
 fn compile_grammar(
@@ -1,9 +1,13 @@
 use super::lexerspec::{LexemeClass, LexemeIdx, LexerSpec};
-use crate::api::{GenGrammarOptions, GrammarId, NodeProps};
+use crate::api::{Diagnostic, GenGrammarOptions, GrammarId, NodeProps};
 use crate::HashMap;
 use anyhow::{bail, ensure, Result};
 use std::fmt::Display;
-use std::{fmt::Debug, hash::Hash};
+use std::sync::Arc;
+use std::{
+    fmt::Debug,
+    hash::{Hash, Hasher},
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct SymIdx(u32);
@@ -39,6 +43,10 @@ pub struct SymbolProps {
     pub temperature: f32,
     pub grammar_id: LexemeClass,
     pub is_start: bool,
+    /// Created by the grammar builder (e.g., for `x*`), rather than from a user rule.
+    pub is_helper: bool,
+    /// For captures of `substring_documents` lexemes, the quoted documents.
+    pub capture_documents: Option<Arc<Vec<String>>>,
 }
 
 impl Default for SymbolProps {
@@ -50,6 +58,8 @@ impl Default for SymbolProps {
             temperature: 0.0,
             is_start: false,
             grammar_id: LexemeClass::ROOT,
+            is_helper: false,
+            capture_documents: None,
         }
     }
 }
@@ -72,6 +82,8 @@ impl SymbolProps {
             temperature: self.temperature,
             grammar_id: self.grammar_id,
             is_start: false,
+            is_helper: false,
+            capture_documents: None,
         }
     }
 }
@@ -81,12 +93,8 @@ impl Display for SymbolProps {
         if self.capture_name.is_some() {
             write!(f, " CAPTURE")?;
         }
-        if self.stop_capture_name.is_some() {
-            write!(
-                f,
-                " STOP-CAPTURE={}",
-                self.stop_capture_name.as_ref().unwrap()
-            )?;
+        if let Some(stop_capture_name) = &self.stop_capture_name {
+            write!(f, " STOP-CAPTURE={}", stop_capture_name)?;
         }
         if self.max_tokens < 10000 {
             write!(f, " max_tokens={}", self.max_tokens)?;
@@ -138,6 +146,11 @@ impl Grammar {
         }
     }
 
+    /// Record that captures of `sym` quote one of `documents`.
+    pub fn set_capture_documents(&mut self, sym: SymIdx, documents: Arc<Vec<String>>) {
+        self.sym_data_mut(sym).props.capture_documents = Some(documents);
+    }
+
     pub fn start(&self) -> SymIdx {
         self.symbols[0].idx
     }
@@ -243,6 +256,30 @@ impl Grammar {
         &self.symbols[sym.0 as usize].name
     }
 
+    pub fn sym_lexeme(&self, sym: SymIdx) -> Option<LexemeIdx> {
+        self.sym_data(sym).lexeme
+    }
+
+    pub fn sym_gen_grammar(&self, sym: SymIdx) -> Option<&GenGrammarOptions> {
+        self.sym_data(sym).gen_grammar.as_ref()
+    }
+
+    pub fn sym_rules(&self, sym: SymIdx) -> impl Iterator<Item = &[SymIdx]> {
+        self.sym_data(sym).rules.iter().map(|r| r.rhs.as_slice())
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = SymIdx> + '_ {
+        self.symbols.iter().map(|s| s.idx)
+    }
+
+    /// Start symbols of all (sub)grammars.
+    pub fn start_symbols(&self) -> impl Iterator<Item = SymIdx> + '_ {
+        self.symbols
+            .iter()
+            .filter(|s| s.props.is_start)
+            .map(|s| s.idx)
+    }
+
     fn rule_to_string(&self, rule: &Rule, dot: Option<usize>, is_first: bool) -> String {
         let ldata = self.sym_data(rule.lhs());
         let dot_data = rule
@@ -312,6 +349,24 @@ impl Grammar {
 
         uf_compress_all(&mut definition);
 
+        // when a rule is just an anonymous symbol (like `a: "x"?` in Lark),
+        // the symbol inherits its name, so it shows in parse trees
+        let mut inherited_names = HashMap::default();
+        for sym in &self.symbols {
+            if let Some(r) = definition[sym.idx.as_usize()] {
+                let target = self.sym_data(r);
+                if !is_anonymous_name(&sym.name)
+                    && is_anonymous_name(&target.name)
+                    && !target.is_terminal()
+                {
+                    inherited_names
+                        .entry(r)
+                        .or_insert_with(Vec::new)
+                        .push(sym.name.as_str());
+                }
+            }
+        }
+
         let mut use_count = vec![0; self.symbols.len()];
         for sym in &self.symbols {
             if definition[sym.idx.as_usize()].is_some() {
@@ -412,6 +467,14 @@ impl Grammar {
                 outp.add_rule(lhs, rhs).unwrap();
             }
         }
+        for (r, names) in inherited_names {
+            if let ([name], Some(&sym)) = (
+                names.as_slice(),
+                outp.symbol_by_name.get(&self.sym_data(r).name),
+            ) {
+                outp.name_anonymous_symbol(sym, name);
+            }
+        }
         outp
     }
 
@@ -486,7 +549,7 @@ impl Grammar {
         sym.props = props;
     }
 
-    pub fn fresh_symbol_ext(&mut self, name0: &str, symprops: SymbolProps) -> SymIdx {
+    fn fresh_name(&mut self, name0: &str) -> String {
         let mut name = name0.to_string();
         let mut idx = self.symbol_count_cache.get(&name).cloned().unwrap_or(2);
         while self.symbol_by_name.contains_key(&name) {
@@ -494,7 +557,22 @@ impl Grammar {
             idx += 1;
         }
         self.symbol_count_cache.insert(name0.to_string(), idx);
+        name
+    }
+
+    /// Give `sym` a name, unless it already has one (other than `#123` and similar).
+    pub fn name_anonymous_symbol(&mut self, sym: SymIdx, name0: &str) {
+        if !is_anonymous_name(&self.sym_data(sym).name) {
+            return;
+        }
+        let name = self.fresh_name(name0);
+        let old = std::mem::replace(&mut self.sym_data_mut(sym).name, name.clone());
+        self.symbol_by_name.remove(&old);
+        self.symbol_by_name.insert(name, sym);
+    }
 
+    pub fn fresh_symbol_ext(&mut self, name0: &str, symprops: SymbolProps) -> SymIdx {
+        let name = self.fresh_name(name0);
         let idx = SymIdx(self.symbols.len() as u32);
         self.symbols.push(Symbol {
             name: name.clone(),
@@ -532,6 +610,89 @@ impl Grammar {
         )
     }
 
+    /// Check if the start symbol can match any (finite) input.
+    pub fn start_is_productive(&self) -> bool {
+        self.productive_symbols()[self.start().as_usize()]
+    }
+
+    /// A symbol is productive if it's a terminal, or has a rule with all symbols productive.
+    fn productive_symbols(&self) -> Vec<bool> {
+        let mut productive = vec![false; self.symbols.len()];
+        loop {
+            let mut num_fix = 0;
+            for sym in &self.symbols {
+                let idx = sym.idx.as_usize();
+                if productive[idx] {
+                    continue;
+                }
+                if sym.is_terminal()
+                    || sym
+                        .rules
+                        .iter()
+                        .any(|r| r.rhs.iter().all(|s| productive[s.as_usize()]))
+                {
+                    productive[idx] = true;
+                    num_fix += 1;
+                }
+            }
+            if num_fix == 0 {
+                break;
+            }
+        }
+        productive
+    }
+
+    /// Report rules not reachable from the start symbol of the first grammar,
+    /// and rules that can't match any input (only infinite recursion).
+    /// Unlike Lark lints, this also covers JSON schemas, GBNF, etc.
+    /// Frontends only compile rules reachable from their own start,
+    /// so unreachable rules mostly come from nested grammars that are never referenced.
+    /// Symbols created by the grammar builder are not reported.
+    pub fn lint(&self) -> Vec<Diagnostic> {
+        let productive = self.productive_symbols();
+        let mut reachable = vec![false; self.symbols.len()];
+        let mut todo = vec![self.start()];
+        while let Some(sym) = todo.pop() {
+            if std::mem::replace(&mut reachable[sym.as_usize()], true) {
+                continue;
+            }
+            for r in &self.sym_data(sym).rules {
+                todo.extend(r.rhs.iter().copied());
+            }
+        }
+
+        let mut res: Vec<Diagnostic> = vec![];
+        for sym in &self.symbols {
+            if sym.is_terminal()
+                || sym.props.is_helper
+                || sym.props.is_start
+                || is_anonymous_name(&sym.name)
+            {
+                continue;
+            }
+            let name = base_name(&sym.name);
+            let d = if !reachable[sym.idx.as_usize()] {
+                Diagnostic::warning(format!(
+                    "rule {:?} is not reachable from the main grammar",
+                    name
+                ))
+                .with_code("unused-rule")
+            } else if !productive[sym.idx.as_usize()] {
+                Diagnostic::warning(format!(
+                    "rule {:?} can't match any input; it always leads to infinite recursion",
+                    name
+                ))
+                .with_code("non-productive")
+            } else {
+                continue;
+            };
+            if !res.contains(&d) {
+                res.push(d);
+            }
+        }
+        res
+    }
+
     pub fn to_string(&self, lexer_spec: Option<&LexerSpec>) -> String {
         let mut outp = String::new();
         self.fmt_grammar(lexer_spec, &mut outp).unwrap();
@@ -708,6 +869,22 @@ pub struct CGrammar {
 const RULE_SHIFT: usize = 2;
 
 impl CGrammar {
+    /// Approximate memory used by the grammar (including the lexer spec, but not the lexer).
+    pub fn num_bytes(&self) -> usize {
+        self.symbols
+            .iter()
+            .map(|s| {
+                std::mem::size_of::<CSymbol>()
+                    + s.name.len()
+                    + s.rules.len() * std::mem::size_of::<RhsPtr>()
+            })
+            .sum::<usize>()
+            + (self.rhs_elements.len() + self.rhs_ptr_to_sym_idx.len())
+                * std::mem::size_of::<CSymIdx>()
+            + self.rhs_ptr_to_sym_flags.len() * std::mem::size_of::<SymFlags>()
+            + self.lexer_spec.regex_builder.exprset().num_bytes()
+    }
+
     pub fn lexer_spec(&self) -> &LexerSpec {
         &self.lexer_spec
     }
@@ -738,6 +915,28 @@ impl CGrammar {
         &self.symbols[sym.0 as usize]
     }
 
+    /// The symbol with given index, if there is one.
+    pub fn sym_idx_checked(&self, idx: usize) -> Option<CSymIdx> {
+        (idx < self.symbols.len()).then_some(CSymIdx(idx as u16))
+    }
+
+    /// Check if `rule` points into the rhs of a rule, or at its end.
+    pub fn is_valid_rhs_ptr(&self, rule: RhsPtr) -> bool {
+        let idx = rule.as_index();
+        if idx == 0 || idx >= self.rhs_elements.len() {
+            return false;
+        }
+        // rhs_elements[0] is NULL
+        let mut start = idx;
+        while self.rhs_elements[start - 1] != CSymIdx::NULL {
+            start -= 1;
+        }
+        let start = RhsPtr(start as u32);
+        self.rules_of(self.sym_idx_lhs(start))
+            .binary_search_by_key(&start.as_index(), |r| r.as_index())
+            .is_ok()
+    }
+
     fn sym_data_mut(&mut self, sym: CSymIdx) -> &mut CSymbol {
         &mut self.symbols[sym.0 as usize]
     }
@@ -847,7 +1046,7 @@ impl CGrammar {
                 }
                 outp.rhs_elements.push(CSymIdx::NULL);
             }
-            while outp.rhs_elements.len() % (1 << RULE_SHIFT) != 0 {
+            while !outp.rhs_elements.len().is_multiple_of(1 << RULE_SHIFT) {
                 outp.rhs_elements.push(CSymIdx::NULL);
             }
             let rlen = outp.rhs_elements.len() >> RULE_SHIFT;
@@ -918,6 +1117,36 @@ impl CGrammar {
     }
 }
 
+impl CGrammar {
+    /// Feed the whole compiled grammar into `h`, including the lexer spec.
+    pub(crate) fn hash_into<H: Hasher>(&self, h: &mut H) {
+        self.start_symbol.hash(h);
+        h.write_usize(self.symbols.len());
+        for sym in &self.symbols {
+            sym.name.hash(h);
+            (sym.is_terminal, sym.is_nullable, sym.lexeme).hash(h);
+            let props = &sym.props;
+            props.max_tokens.hash(h);
+            props.capture_name.hash(h);
+            props.stop_capture_name.hash(h);
+            props.temperature.to_bits().hash(h);
+            (props.grammar_id, props.is_start).hash(h);
+            props.capture_documents.hash(h);
+            match &sym.gen_grammar {
+                Some(g) => {
+                    h.write_u8(1);
+                    g.grammar.hash(h);
+                    g.temperature.map(f32::to_bits).hash(h);
+                }
+                None => h.write_u8(0),
+            }
+            sym.rules.hash(h);
+        }
+        self.rhs_elements.hash(h);
+        self.lexer_spec.hash_into(h);
+    }
+}
+
 impl Debug for CGrammar {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for s in &self.symbols {
@@ -947,6 +1176,19 @@ fn rule_to_string(
     format!("{:15} ⇦ {}  {}", lhs, rhs.join(" "), props)
 }
 
+/// Symbols created by the grammar builder have empty names, made unique as `#123`.
+/// Strip the `#2` suffix added by `fresh_name()` to make names unique.
+pub(crate) fn base_name(name: &str) -> &str {
+    match name.rsplit_once('#') {
+        Some((base, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => base,
+        _ => name,
+    }
+}
+
+pub(crate) fn is_anonymous_name(name: &str) -> bool {
+    base_name(name).is_empty()
+}
+
 fn uf_find(map: &mut [Option<SymIdx>], e: SymIdx) -> SymIdx {
     let mut root = e;
     let mut steps = 0;
@@ -957,7 +1199,7 @@ fn uf_find(map: &mut [Option<SymIdx>], e: SymIdx) -> SymIdx {
     if steps > 1 {
         let mut p = e;
         assert!(p != root);
-        while let Some(q) = std::mem::replace(&mut map[p.as_usize()], Some(root)) {
+        while let Some(q) = map[p.as_usize()].replace(root) {
             if q == root {
                 break;
             }
@@ -971,7 +1213,7 @@ fn uf_union(map: &mut [Option<SymIdx>], mut a: SymIdx, mut b: SymIdx) {
     a = uf_find(map, a);
     b = uf_find(map, b);
     if a != b {
-        let r = std::mem::replace(&mut map[a.as_usize()], Some(b));
+        let r = map[a.as_usize()].replace(b);
         assert!(r.is_none());
     }
 }
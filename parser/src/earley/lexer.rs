@@ -1,12 +1,17 @@
-use anyhow::Result;
+use anyhow::{bail, ensure, Result};
 use std::fmt::Debug;
 use toktrie::SimpleVob;
 
-use crate::api::ParserLimits;
+use crate::{
+    api::ParserLimits,
+    mask_cache::StateIds,
+    snapshot::{SnapshotReader, SnapshotWriter},
+    HashMap,
+};
 
 use super::{
     lexerspec::{Lexeme, LexemeIdx, LexerSpec},
-    regexvec::{LexemeSet, MatchingLexemes, NextByte, RegexVec, StateDesc},
+    regexvec::{LexemeSet, MatchingLexemes, NextByte, RegexVec, StateDesc, StateOrigin},
 };
 
 const DEBUG: bool = true;
@@ -26,10 +31,24 @@ pub struct Lexer {
     // this is used to fail states quickly
     allowed_first_byte: SimpleVob,
     spec: LexerSpec,
+    // are there any lexemes with follow sets (lookaheads)?
+    has_follow: bool,
+    // subsets of greedy_accepting left after applying follow sets
+    filtered_lexemes: Vec<MatchingLexemes>,
+    // numbers of states in the StateIds of the mask cache
+    shared_ids: HashMap<StateID, u64>,
 }
 
 pub type StateID = derivre::StateID;
 
+/// Lexer states referenced by a parser snapshot.
+/// They are numbered so that each state comes after the one it was built from.
+#[derive(Default)]
+pub(crate) struct SnapshotStates {
+    numbers: HashMap<usize, usize>,
+    states: Vec<StateID>,
+}
+
 /// PreLexeme contains index of the lexeme but not the bytes.
 #[derive(Debug, Clone, Copy)]
 pub struct PreLexeme {
@@ -79,6 +98,9 @@ impl Lexer {
             dfa,
             allowed_first_byte,
             spec: spec.clone(), // TODO check perf of Rc<> ?
+            has_follow: spec.lexemes.iter().any(|l| l.follow.is_some()),
+            filtered_lexemes: vec![],
+            shared_ids: HashMap::default(),
         };
 
         Ok(lex)
@@ -96,6 +118,18 @@ impl Lexer {
         first_byte.map(|b| self.dfa.transition(s, b)).unwrap_or(s)
     }
 
+    /// Number of `state` in `ids`, if it was already looked up.
+    pub(crate) fn known_shared_id(&self, state: StateID, ids: &StateIds) -> Option<u64> {
+        self.shared_ids
+            .get(&state)
+            .copied()
+            .filter(|&id| ids.is_current(id))
+    }
+
+    pub(crate) fn set_shared_id(&mut self, state: StateID, id: u64) {
+        self.shared_ids.insert(state, id);
+    }
+
     pub fn a_dead_state(&self) -> StateID {
         StateID::DEAD
     }
@@ -136,16 +170,59 @@ impl Lexer {
 
     pub fn try_lexeme_end(&mut self, prev: StateID) -> LexerResult {
         if self.state_info(prev).greedy_accepting.is_some() {
-            LexerResult::Lexeme(PreLexeme::just_idx(MatchingLexemesIdx::GreedyAccepting(
-                prev,
-            )))
+            match self.apply_follow(prev, None) {
+                Some(idx) => LexerResult::Lexeme(PreLexeme::just_idx(idx)),
+                None => LexerResult::Error,
+            }
         } else {
             LexerResult::Error
         }
     }
 
+    /// Greedy lexemes accepted in 'state' are about to be ended by 'byte'
+    /// (or by end of input if None).  Drop the ones whose follow set
+    /// does not allow it.
+    fn apply_follow(&mut self, state: StateID, byte: Option<u8>) -> Option<MatchingLexemesIdx> {
+        let all = MatchingLexemesIdx::GreedyAccepting(state);
+        if !self.has_follow {
+            return Some(all);
+        }
+        let accepting = &self.dfa.state_desc(state).greedy_accepting;
+        let mut res = MatchingLexemes::None;
+        for &idx in accepting.as_slice() {
+            match &self.spec.lexeme_spec(idx).follow {
+                Some(follow) if !follow.allows(byte) => {}
+                _ => res.add(idx),
+            }
+        }
+        if res.len() == accepting.len() {
+            return Some(all);
+        }
+        match res {
+            MatchingLexemes::None => None,
+            MatchingLexemes::One(idx) => Some(MatchingLexemesIdx::Single(idx)),
+            _ => Some(self.filtered_idx(res)),
+        }
+    }
+
+    fn filtered_idx(&mut self, res: MatchingLexemes) -> MatchingLexemesIdx {
+        let pos = match self
+            .filtered_lexemes
+            .iter()
+            .position(|m| m.as_slice() == res.as_slice())
+        {
+            Some(pos) => pos,
+            None => {
+                self.filtered_lexemes.push(res);
+                self.filtered_lexemes.len() - 1
+            }
+        };
+        MatchingLexemesIdx::Filtered(pos as u32)
+    }
+
     pub fn check_for_single_byte_lexeme(&mut self, state: StateID, b: u8) -> Option<PreLexeme> {
-        if self.dfa.next_byte(state) == NextByte::ForcedEOI {
+        // lexemes with follow sets are never forced to end, so the state won't be lowest match
+        if state.has_lowest_match() && self.dfa.next_byte(state) == NextByte::ForcedEOI {
             Some(PreLexeme {
                 idx: MatchingLexemesIdx::GreedyAccepting(state),
                 byte: Some(b),
@@ -182,6 +259,28 @@ impl Lexer {
         forced
     }
 
+    /// Check if after lexing `bytes` from `state`, the next byte may be forced.
+    /// Returns false only when the bytes end inside of a lexeme, which can continue
+    /// with (at least) two different bytes.
+    pub fn may_force_after(&mut self, mut state: StateID, bytes: &[u8]) -> bool {
+        for &b in bytes {
+            match self.advance(state, b, false) {
+                LexerResult::State(s, _) => state = s,
+                // lexeme ends inside of the bytes; parser decides what follows
+                _ => return true,
+            }
+        }
+        if self.dfa.state_desc(state).greedy_accepting.is_some() {
+            return true;
+        }
+        match self.next_byte(state) {
+            NextByte::SomeBytes2([a, b]) => ![a, b]
+                .iter()
+                .all(|&b| matches!(self.advance(state, b, false), LexerResult::State(..))),
+            _ => true,
+        }
+    }
+
     #[inline(always)]
     pub fn advance(&mut self, prev: StateID, byte: u8, enable_logging: bool) -> LexerResult {
         let state = self.dfa.transition(prev, byte);
@@ -199,15 +298,17 @@ impl Lexer {
             if !self.allowed_first_byte.is_allowed(byte as u32) {
                 return LexerResult::Error;
             }
-            let info = self.dfa.state_desc(prev);
             // we take the first token that matched
             // (eg., "while" will match both keyword and identifier, but keyword is first)
-            if info.greedy_accepting.is_some() {
-                LexerResult::Lexeme(PreLexeme {
-                    idx: MatchingLexemesIdx::GreedyAccepting(prev),
-                    byte: Some(byte),
-                    byte_next_row: true,
-                })
+            if self.dfa.state_desc(prev).greedy_accepting.is_some() {
+                match self.apply_follow(prev, Some(byte)) {
+                    Some(idx) => LexerResult::Lexeme(PreLexeme {
+                        idx,
+                        byte: Some(byte),
+                        byte_next_row: true,
+                    }),
+                    None => LexerResult::Error,
+                }
             } else {
                 LexerResult::Error
             }
@@ -236,13 +337,16 @@ impl Lexer {
             MatchingLexemesIdx::LazyAccepting(state_id) => {
                 &self.dfa.state_desc(state_id).lazy_accepting
             }
+            MatchingLexemesIdx::Filtered(idx) => &self.filtered_lexemes[idx as usize],
         }
     }
 
     #[inline(always)]
     pub fn lexeme_props(&self, idx: MatchingLexemesIdx) -> (u32, bool) {
         match idx {
-            MatchingLexemesIdx::Single(_) | MatchingLexemesIdx::GreedyAccepting(_) => (0, false),
+            MatchingLexemesIdx::Single(_)
+            | MatchingLexemesIdx::GreedyAccepting(_)
+            | MatchingLexemesIdx::Filtered(_) => (0, false),
             MatchingLexemesIdx::LazyAccepting(state_id) => {
                 let info = self.dfa.state_desc(state_id);
                 let hidden = info.lazy_hidden_len;
@@ -256,6 +360,164 @@ impl Lexer {
         }
     }
 
+    /// Number of `state` in `states`; the states it was built from are numbered first.
+    pub(crate) fn snapshot_state(&self, states: &mut SnapshotStates, state: StateID) -> usize {
+        let mut pending = vec![];
+        let mut curr = state;
+        while !states.numbers.contains_key(&curr.as_usize()) {
+            pending.push(curr);
+            match self.dfa.state_origin(curr) {
+                StateOrigin::Transition(parent, _) | StateOrigin::Limit(parent, _) => {
+                    curr = *parent
+                }
+                StateOrigin::Fixed | StateOrigin::Initial(_) => break,
+            }
+        }
+        for s in pending.into_iter().rev() {
+            states.numbers.insert(s.as_usize(), states.states.len());
+            states.states.push(s);
+        }
+        states.numbers[&state.as_usize()]
+    }
+
+    pub(crate) fn write_snapshot_states(&self, states: &SnapshotStates, w: &mut SnapshotWriter) {
+        let lexeme_set = |w: &mut SnapshotWriter, set: &LexemeSet| {
+            w.usize(set.iter().count());
+            for idx in set.iter() {
+                w.usize(idx.as_usize());
+            }
+        };
+        w.usize(states.states.len());
+        for &state in &states.states {
+            match self.dfa.state_origin(state) {
+                StateOrigin::Fixed => {
+                    w.u64(0);
+                    w.bool(state == StateID::MISSING);
+                }
+                StateOrigin::Initial(set) => {
+                    w.u64(1);
+                    lexeme_set(w, set);
+                }
+                StateOrigin::Transition(parent, b) => {
+                    w.u64(2);
+                    w.usize(states.numbers[&parent.as_usize()]);
+                    w.u64(*b as u64);
+                }
+                StateOrigin::Limit(parent, set) => {
+                    w.u64(3);
+                    w.usize(states.numbers[&parent.as_usize()]);
+                    lexeme_set(w, set);
+                }
+            }
+        }
+    }
+
+    /// Rebuild the states written by write_snapshot_states() in this lexer.
+    pub(crate) fn read_snapshot_states(&mut self, r: &mut SnapshotReader) -> Result<Vec<StateID>> {
+        let num_states = r.usize()?;
+        let mut states: Vec<StateID> = vec![];
+        for _ in 0..num_states {
+            let state = match r.u64()? {
+                0 => {
+                    if r.bool()? {
+                        StateID::MISSING
+                    } else {
+                        StateID::DEAD
+                    }
+                }
+                1 => {
+                    let set = self.read_lexeme_set(r)?;
+                    self.dfa.initial_state(&set)
+                }
+                2 => {
+                    let parent = read_state(&states, r)?;
+                    let b = u8::try_from(r.u64()?)?;
+                    self.dfa.transition(parent, b)
+                }
+                3 => {
+                    let parent = read_state(&states, r)?;
+                    let set = self.read_lexeme_set(r)?;
+                    self.dfa.limit_state_to(parent, &set)
+                }
+                tag => bail!("invalid lexer state in parser snapshot: {}", tag),
+            };
+            states.push(state);
+        }
+        if let Some(e) = self.dfa.get_error() {
+            bail!("restoring parser snapshot: {}", e);
+        }
+        Ok(states)
+    }
+
+    fn read_lexeme_idx(&self, r: &mut SnapshotReader) -> Result<LexemeIdx> {
+        let idx = r.usize()?;
+        ensure!(
+            idx < self.spec.lexemes.len(),
+            "invalid lexeme in parser snapshot: {}",
+            idx
+        );
+        Ok(LexemeIdx::new(idx))
+    }
+
+    fn read_lexeme_set(&self, r: &mut SnapshotReader) -> Result<LexemeSet> {
+        let mut set = self.spec.alloc_lexeme_set();
+        for _ in 0..r.usize()? {
+            set.add(self.read_lexeme_idx(r)?);
+        }
+        Ok(set)
+    }
+
+    pub(crate) fn write_snapshot_lexemes_idx(
+        &self,
+        states: &mut SnapshotStates,
+        idx: MatchingLexemesIdx,
+        w: &mut SnapshotWriter,
+    ) {
+        match idx {
+            MatchingLexemesIdx::Single(idx) => {
+                w.u64(0);
+                w.usize(idx.as_usize());
+            }
+            MatchingLexemesIdx::GreedyAccepting(state) => {
+                w.u64(1);
+                w.usize(self.snapshot_state(states, state));
+            }
+            MatchingLexemesIdx::LazyAccepting(state) => {
+                w.u64(2);
+                w.usize(self.snapshot_state(states, state));
+            }
+            MatchingLexemesIdx::Filtered(_) => {
+                // filtered sets are numbered in the order they were first seen
+                let lexemes = self.lexemes_from_idx(idx).as_slice();
+                w.u64(3);
+                w.usize(lexemes.len());
+                for idx in lexemes {
+                    w.usize(idx.as_usize());
+                }
+            }
+        }
+    }
+
+    pub(crate) fn read_snapshot_lexemes_idx(
+        &mut self,
+        states: &[StateID],
+        r: &mut SnapshotReader,
+    ) -> Result<MatchingLexemesIdx> {
+        Ok(match r.u64()? {
+            0 => MatchingLexemesIdx::Single(self.read_lexeme_idx(r)?),
+            1 => MatchingLexemesIdx::GreedyAccepting(read_state(states, r)?),
+            2 => MatchingLexemesIdx::LazyAccepting(read_state(states, r)?),
+            3 => {
+                let mut res = MatchingLexemes::None;
+                for _ in 0..r.usize()? {
+                    res.add(self.read_lexeme_idx(r)?);
+                }
+                self.filtered_idx(res)
+            }
+            tag => bail!("invalid lexemes in parser snapshot: {}", tag),
+        })
+    }
+
     pub fn dbg_lexeme(&self, lex: &Lexeme) -> String {
         let set = self.lexemes_from_idx(lex.idx);
 
@@ -268,6 +530,16 @@ impl Lexer {
     }
 }
 
+pub(crate) fn read_state(states: &[StateID], r: &mut SnapshotReader) -> Result<StateID> {
+    let idx = r.usize()?;
+    ensure!(
+        idx < states.len(),
+        "invalid lexer state in parser snapshot: {}",
+        idx
+    );
+    Ok(states[idx])
+}
+
 impl LexerResult {
     #[inline(always)]
     pub fn is_error(&self) -> bool {
@@ -280,4 +552,6 @@ pub enum MatchingLexemesIdx {
     Single(LexemeIdx),
     GreedyAccepting(StateID),
     LazyAccepting(StateID),
+    /// Index into the list of greedy_accepting sets narrowed down by follow sets.
+    Filtered(u32),
 }
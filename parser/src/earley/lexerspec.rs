@@ -1,6 +1,10 @@
 use anyhow::{ensure, Result};
 use derivre::{raw::ExprSet, ExprRef, JsonQuoteOptions, RegexAst, RegexBuilder};
-use std::{fmt::Debug, hash::Hash, ops::RangeInclusive};
+use std::{
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    ops::RangeInclusive,
+};
 use toktrie::{bytes::limit_bytes, SimpleVob, TokTrie, TokenId};
 
 use crate::{api::ParserLimits, id32_type};
@@ -44,6 +48,8 @@ pub struct LexemeSpec {
     pub(crate) idx: LexemeIdx,
     pub(crate) single_set: MatchingLexemes,
     pub(crate) name: String,
+    /// Names of other lexemes that were merged into this one.
+    pub(crate) aliases: Vec<String>,
     pub(crate) rx: RegexAst,
     class: LexemeClass,
     compiled_rx: ExprRef,
@@ -55,6 +61,28 @@ pub struct LexemeSpec {
     pub(crate) is_skip: bool,
     json_options: Option<JsonQuoteOptions>,
     pub(crate) token_ranges: Vec<RangeInclusive<TokenId>>,
+    pub(crate) follow: Option<LexemeFollow>,
+}
+
+/// Restricts what can come right after a greedy lexeme.
+/// This is how trailing lookahead assertions, like `/[a-z]+(?![a-z0-9_])/`,
+/// are implemented: the lexeme is only accepted if the byte that ends it
+/// (and starts the next lexeme) is in `bytes`, or if it is followed
+/// by the end of input and `eos` is set.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LexemeFollow {
+    pub bytes: SimpleVob,
+    pub eos: bool,
+}
+
+impl LexemeFollow {
+    #[inline(always)]
+    pub fn allows(&self, byte: Option<u8>) -> bool {
+        match byte {
+            Some(b) => self.bytes.get(b as usize),
+            None => self.eos,
+        }
+    }
 }
 
 // LexemeIdx is an index into the lexeme table.
@@ -88,6 +116,23 @@ impl LexemeSpec {
         self.max_tokens
     }
 
+    pub fn is_lazy(&self) -> bool {
+        self.lazy
+    }
+
+    pub fn ends_at_eos(&self) -> bool {
+        self.ends_at_eos
+    }
+
+    pub fn json_options(&self) -> Option<&JsonQuoteOptions> {
+        self.json_options.as_ref()
+    }
+
+    /// The name, followed by names of lexemes merged into this one.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.name.as_str()).chain(self.aliases.iter().map(|s| s.as_str()))
+    }
+
     pub fn to_string(&self, max_len: usize, exprset: Option<&ExprSet>) -> String {
         use std::fmt::Write;
         let mut f = String::new();
@@ -105,6 +150,16 @@ impl LexemeSpec {
         if !self.token_ranges.is_empty() {
             write!(f, " tokens={}", token_ranges_to_string(&self.token_ranges)).unwrap();
         }
+        if let Some(follow) = &self.follow {
+            write!(
+                f,
+                " follow={}/{}{}",
+                follow.bytes.num_set(),
+                follow.bytes.len(),
+                if follow.eos { "+eos" } else { "" }
+            )
+            .unwrap();
+        }
         // write!(f, " compiled={:?}", self.compiled_rx).unwrap();
         f
     }
@@ -137,14 +192,20 @@ impl LexerSpec {
         })
     }
 
+    /// Whether `Parser::rollback()` (by bytes) can be used.
+    #[deprecated(note = "Parser::mark() and Parser::rollback_to() work with all lexemes")]
     pub fn can_rollback(&self) -> bool {
         !self.has_stop && !self.has_max_tokens
     }
 
+    /// Fails if `Parser::rollback()` (by bytes) can't be used.
+    #[deprecated(note = "Parser::mark() and Parser::rollback_to() work with all lexemes")]
     pub fn check_rollback(&self) -> Result<()> {
+        #[allow(deprecated)]
+        let ok = self.can_rollback();
         ensure!(
-            self.can_rollback(),
-            "rollback not supported with max_tokens=... or stop=... lexemes; suffix=... is OK"
+            ok,
+            "rollback by bytes not supported with max_tokens=... or stop=... lexemes; use Parser::rollback_to()"
         );
         Ok(())
     }
@@ -233,6 +294,7 @@ impl LexerSpec {
                 rx: lex.compiled_rx,
                 priority: 0,
                 lazy: lex.lazy,
+                has_follow: lex.follow.is_some(),
             })
             .collect();
         RegexVec::new_with_exprset(
@@ -286,7 +348,12 @@ impl LexerSpec {
                 && lex.class == spec.class
                 && lex.max_tokens == spec.max_tokens
                 && lex.token_ranges == spec.token_ranges
+                && lex.follow == spec.follow
         }) {
+            let lex = &mut self.lexemes[idx];
+            if lex.name != spec.name && !lex.aliases.contains(&spec.name) {
+                lex.aliases.push(spec.name);
+            }
             return Ok(LexemeIdx::new(idx));
         }
         let idx = LexemeIdx::new(self.lexemes.len());
@@ -306,6 +373,7 @@ impl LexerSpec {
             idx: LexemeIdx(0),
             single_set: MatchingLexemes::One(LexemeIdx(0)),
             name: "".to_string(),
+            aliases: vec![],
             rx: RegexAst::NoMatch,
             compiled_rx: ExprRef::INVALID,
             lazy: false,
@@ -317,6 +385,7 @@ impl LexerSpec {
             class: self.current_class,
             max_tokens: usize::MAX,
             token_ranges: vec![],
+            follow: None,
         }
     }
 
@@ -378,13 +447,21 @@ impl LexerSpec {
         contextual: bool,
         json_options: Option<JsonQuoteOptions>,
         max_tokens: usize,
+        follow: Option<LexemeFollow>,
     ) -> Result<LexemeIdx> {
+        if let Some(follow) = &follow {
+            ensure!(
+                follow.bytes.len() == 256,
+                "lexeme follow set must have 256 entries"
+            );
+        }
         self.add_lexeme_spec(LexemeSpec {
             name,
             rx,
             contextual,
             json_options,
             max_tokens,
+            follow,
             ..self.empty_spec()
         })
     }
@@ -399,6 +476,7 @@ impl LexerSpec {
                 false,
                 None,
                 usize::MAX,
+                None,
             )
             .expect("adding lexeme");
         }
@@ -435,6 +513,18 @@ impl LexerSpec {
         self.lexemes[idx.as_usize()].to_string(512, Some(self.regex_builder.exprset()))
     }
 
+    /// The regex the lexeme was compiled to, or its token ranges for special tokens.
+    pub fn lexeme_regex_to_string(&self, idx: LexemeIdx) -> String {
+        let lex = &self.lexemes[idx.as_usize()];
+        if !lex.token_ranges.is_empty() {
+            token_ranges_to_string(&lex.token_ranges)
+        } else {
+            self.regex_builder
+                .exprset()
+                .expr_to_string_max_len(lex.compiled_rx, 512)
+        }
+    }
+
     pub fn dbg_lexeme_set_ext(&self, vob: &SimpleVob) -> String {
         format!(
             "LexemesExt(\n    {}\n)",
@@ -446,6 +536,38 @@ impl LexerSpec {
     }
 }
 
+impl LexerSpec {
+    /// Feed everything that affects lexing into `h`, including full regexes
+    /// and follow sets (unlike the Debug output, which is truncated).
+    pub(crate) fn hash_into<H: Hasher>(&self, h: &mut H) {
+        let exprset = self.regex_builder.exprset();
+        h.write_usize(self.lexemes.len());
+        for lex in &self.lexemes {
+            lex.name.hash(h);
+            let mut rx = String::new();
+            lex.rx.write_to_str(&mut rx, usize::MAX, Some(exprset));
+            rx.hash(h);
+            lex.class.hash(h);
+            (lex.ends_at_eos, lex.lazy, lex.contextual).hash(h);
+            (lex.is_suffix, lex.is_skip, lex.max_tokens).hash(h);
+            format!("{:?}", lex.json_options).hash(h);
+            lex.token_ranges.hash(h);
+            match &lex.follow {
+                Some(follow) => {
+                    h.write_u8(1);
+                    follow.bytes.as_slice().hash(h);
+                    follow.eos.hash(h);
+                }
+                None => h.write_u8(0),
+            }
+        }
+        (self.no_forcing, self.allow_initial_skip).hash(h);
+        self.num_extra_lexemes.hash(h);
+        self.skip_by_class.hash(h);
+        (self.has_stop, self.has_max_tokens).hash(h);
+    }
+}
+
 impl Debug for LexerSpec {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "LexerSpec {{ lexemes: [")?;
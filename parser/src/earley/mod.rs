@@ -11,7 +11,7 @@ pub mod regexvec;
 #[allow(unused_imports)]
 pub use grammar::{CGrammar, CSymIdx, Grammar, SymIdx, SymbolProps};
 pub use parser::{
-    BiasComputer, DefaultBiasComputer, Parser, ParserError, ParserMetrics, ParserRecognizer,
-    ParserStats, XorShift,
+    BiasComputer, BytesVersion, CapturePos, DefaultBiasComputer, OpenCapture, Parser, ParserError,
+    ParserMark, ParserMetrics, ParserRecognizer, ParserStats, XorShift,
 };
 pub use slicer::SlicedBiasComputer;
@@ -5,9 +5,11 @@
 // (Retrieved 18 Sep 2024).
 
 use std::{
+    collections::VecDeque,
     fmt::Debug,
     hash::Hash,
     ops::Range,
+    rc::Rc,
     sync::{Arc, Mutex},
 };
 
@@ -20,17 +22,20 @@ use toktrie::{
 };
 
 use crate::{
-    api::{ParserLimits, StopReason},
+    api::{GrammarId, ParserLimits, StopReason},
     earley::{lexer::Lexer, lexerspec::LexemeClass},
     id32_type,
+    mask_cache::{MaskState, StateIds},
+    output::{ExpectedLexeme, ExpectedTerminals, ParseTree},
+    snapshot::{SnapshotReader, SnapshotWriter},
 };
 
 use super::{
-    grammar::{CGrammar, CSymIdx, CSymbol, RhsPtr},
-    lexer::{LexerResult, PreLexeme},
+    grammar::{base_name, is_anonymous_name, CGrammar, CSymIdx, CSymbol, RhsPtr},
+    lexer::{read_state, LexerResult, PreLexeme, SnapshotStates},
     lexerspec::{Lexeme, LexemeIdx, LexemeSpec, LexerSpec},
     perf::ParserPerfCounters,
-    regexvec::{LexemeSet, LexerStats},
+    regexvec::{LexemeSet, LexerStats, RegexVec},
 };
 
 const TRACE: bool = false;
@@ -116,7 +121,7 @@ impl XorShift {
     }
 
     pub fn one_in(&mut self, n: u32) -> bool {
-        self.next() % n == 0
+        self.next().is_multiple_of(n)
     }
 
     pub fn next_alt(&mut self) -> u32 {
@@ -236,6 +241,10 @@ struct Row {
     lexer_start_state: StateID,
 
     lexeme_idx: MatchingLexemesIdx,
+
+    // Number of this row and all the rows before it in the StateIds
+    // of the mask cache; 0 if not looked up yet.
+    id: u64,
 }
 
 impl Row {
@@ -300,6 +309,8 @@ struct Scratch {
     grammar_stack: Vec<GrammarStackNode>,
 
     push_allowed_grammar_ids: SimpleVob,
+    // grammars continuing after a nested grammar they contain has finished
+    push_resumed_grammar_ids: SimpleVob,
     push_allowed_lexemes: LexemeSet,
     push_grm_top: GrammarStackPtr,
     push_lexeme_idx: MatchingLexemesIdx,
@@ -369,32 +380,117 @@ struct LexerState {
     byte: Option<u8>,
 }
 
+// Value overwritten in definitive mode; kept so that rollback_to() can restore it.
+#[derive(Clone, Debug)]
+enum Undo {
+    LexerState(usize, LexerState),
+    RowTokenIdx(usize, usize, usize),
+    // previous value of a capture in capture_map
+    Capture(String, Option<Vec<u8>>),
+}
+
+/// Position of the parser between tokens, obtained from `Parser::mark()`.
+/// The parser can be rolled back to it with `Parser::rollback_to()`,
+/// as long as it wasn't rolled back past it in the meantime,
+/// and `Parser::trim_undo_log()` wasn't called with a later mark.
+#[derive(Clone, Debug)]
+pub struct ParserMark {
+    token_idx: usize,
+    num_bytes: usize,
+    num_applied_bytes: usize,
+    lexer_stack_len: usize,
+    lexer_stack_top_eos: bool,
+    num_captures: usize,
+    // position in the undo log, counting trimmed entries
+    undo_pos: usize,
+}
+
+/// A capture that started, but isn't complete yet; see `Parser::open_captures()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpenCapture {
+    pub name: String,
+    /// Row where the capture starts, to tell apart captures with the same name.
+    pub start_row: usize,
+}
+
+/// Position in the text of open captures: `offset` bytes into the text of row `row`;
+/// see `Parser::capture_text()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CapturePos {
+    pub row: usize,
+    pub offset: usize,
+}
+
+impl CapturePos {
+    pub fn start(capture: &OpenCapture) -> Self {
+        CapturePos {
+            row: capture.start_row,
+            offset: 0,
+        }
+    }
+}
+
+/// The bytes parsed at some point; see `Parser::bytes_unchanged_since()`.
+#[derive(Clone, Copy, Debug)]
+pub struct BytesVersion {
+    num_rollbacks: usize,
+    num_bytes: usize,
+}
+
+// how many rollbacks bytes_unchanged_since() can look past
+const MAX_ROLLBACK_LENS: usize = 16;
+
 #[derive(Clone)]
 struct Captures {
     capture_list: Vec<(String, Vec<u8>)>,
+    // for each entry of capture_list
+    capture_info: Vec<CaptureInfo>,
     capture_map: HashMap<String, Vec<u8>>,
+    // including the ones rolled back since
+    num_pushed: usize,
+}
+
+#[derive(Clone)]
+struct CaptureInfo {
+    // number of captures pushed before this one; not reused after rollback
+    seq: usize,
+    // the capture symbol, if it has documents of a substring_documents lexeme
+    documents_sym: Option<CSymIdx>,
 }
 
 impl Captures {
     fn new() -> Self {
         Captures {
             capture_list: vec![],
+            capture_info: vec![],
             capture_map: HashMap::default(),
+            num_pushed: 0,
         }
     }
 
-    fn push(&mut self, cap: (String, Vec<u8>)) {
+    /// Returns the name and the previous value in capture_map, if the capture was added.
+    fn push(
+        &mut self,
+        cap: (String, Vec<u8>),
+        documents_sym: Option<CSymIdx>,
+    ) -> Option<(String, Option<Vec<u8>>)> {
         let (name, bytes) = cap;
         // in Guidance, the __LIST_APPEND: ones are supposed to be appended not overwritten
         if !name.starts_with("__LIST_APPEND:") {
             if let Some(old) = self.capture_map.get(&name) {
                 if old == &bytes {
-                    return;
+                    return None;
                 }
             }
         }
         self.capture_list.push((name.clone(), bytes.clone()));
-        self.capture_map.insert(name, bytes);
+        self.capture_info.push(CaptureInfo {
+            seq: self.num_pushed,
+            documents_sym,
+        });
+        self.num_pushed += 1;
+        let old = self.capture_map.insert(name.clone(), bytes);
+        Some((name, old))
     }
 }
 
@@ -426,6 +522,15 @@ struct ParserState {
     bytes: Vec<u8>,
     // use u32 to save space
     byte_to_token_idx: Vec<u32>,
+    // Lexer stack entries and row token indices overwritten since the start;
+    // lexer states are otherwise only pushed and rows only added, so this
+    // together with the stack lengths is enough to roll back.
+    // Entries before undo_base were dropped by trim_undo_log().
+    undo_log: Vec<Undo>,
+    undo_base: usize,
+    // lengths of `bytes` after the last few rollbacks; see bytes_unchanged_since()
+    rollback_lens: VecDeque<usize>,
+    num_rollbacks: usize,
 
     last_force_bytes_len: usize,
 
@@ -468,6 +573,7 @@ impl Scratch {
         Scratch {
             push_allowed_lexemes: grammar.lexer_spec().alloc_lexeme_set(),
             push_allowed_grammar_ids: grammar.lexer_spec().alloc_grammar_set(),
+            push_resumed_grammar_ids: grammar.lexer_spec().alloc_grammar_set(),
             push_grm_top: GrammarStackPtr::new(0),
             push_lexeme_idx: MatchingLexemesIdx::Single(LexemeIdx::new(0)),
             grammar,
@@ -500,6 +606,7 @@ impl Scratch {
             grammar_stack_ptr: self.push_grm_top,
             lexer_start_state,
             lexeme_idx: self.push_lexeme_idx,
+            id: 0,
         }
     }
 
@@ -600,6 +707,10 @@ impl ParserState {
             trace_start: Instant::now(),
             token_idx: 0,
             byte_to_token_idx: vec![],
+            undo_log: vec![],
+            undo_base: 0,
+            rollback_lens: VecDeque::new(),
+            num_rollbacks: 0,
             bytes: vec![],
             last_force_bytes_len: usize::MAX,
             max_all_items: usize::MAX,
@@ -742,7 +853,13 @@ impl ParserState {
         if set.is_zero() {
             // nothing allowed
             // we're going to be stopped outside - we better flush the lexer
+            let prev_stack = self.lexer_stack.len();
             let _ = self.flush_lexer();
+            if self.lexer_stack.len() != prev_stack {
+                // this can happen with lexemes with follow sets, which wait for
+                // end of input instead of finishing eagerly
+                self.lexer_stack_top_eos = true;
+            }
         }
 
         let eos = computer.trie().eos_token();
@@ -822,10 +939,302 @@ impl ParserState {
         }
     }
 
+    fn expected_terminals(&mut self) -> ExpectedTerminals {
+        let mut possible = self
+            .lexer()
+            .possible_lexemes(self.lexer_state().lexer_state)
+            .clone();
+        if self.has_pending_lexeme_bytes() {
+            // also include what can come if the current lexeme ends here
+            let after = self.run_speculative("expected_terminals", |s| {
+                if s.flush_lexer() {
+                    Some(
+                        s.lexer()
+                            .possible_lexemes(s.lexer_state().lexer_state)
+                            .clone(),
+                    )
+                } else {
+                    None
+                }
+            });
+            for idx in after.iter().flat_map(|a| a.iter()) {
+                possible.add(idx);
+            }
+        }
+
+        let spec = self.lexer_spec();
+        let lexemes = possible
+            .iter()
+            .filter(|&idx| !spec.lexeme_spec(idx).is_skip)
+            .map(|idx| ExpectedLexeme {
+                name: self.lexeme_display_name(idx),
+                regex: spec.lexeme_regex_to_string(idx),
+            })
+            .collect();
+
+        let start = self.grammar.start();
+        let mut rules = vec![];
+        for pos in self.after_dots() {
+            if self.grammar.sym_idx_dot(pos) == CSymIdx::NULL {
+                continue;
+            }
+            // skip predictions, they only clutter the output
+            let (_, dot) = self.grammar.rule_rhs(pos);
+            if dot == 0 && self.grammar.sym_idx_lhs(pos) != start {
+                continue;
+            }
+            let rule = self.dotted_rule(pos);
+            if !rules.contains(&rule) {
+                rules.push(rule);
+            }
+        }
+
+        ExpectedTerminals {
+            lexemes,
+            rules,
+            eos_allowed: self.is_accepting(),
+        }
+    }
+
+    /// Render the rule like `lhs ::= a • b`, showing anonymous lexemes by their regex
+    /// and anonymous rules as `(…)`.
+    fn dotted_rule(&self, pos: RhsPtr) -> String {
+        let (rhs, dot) = self.grammar.rule_rhs(pos);
+        let mut res = format!(
+            "{} ::=",
+            self.grammar.sym_name(self.grammar.sym_idx_lhs(pos))
+        );
+        for (i, &sym) in rhs.iter().enumerate() {
+            if i == dot {
+                res.push_str(" •");
+            }
+            res.push(' ');
+            let name = self.grammar.sym_name(sym);
+            if let Some(idx) = self.grammar.sym_data(sym).lexeme {
+                res.push_str(&self.lexeme_display_name(idx));
+            } else if is_anonymous_name(name) {
+                res.push_str("(…)");
+            } else {
+                res.push_str(name);
+            }
+        }
+        if dot == rhs.len() {
+            res.push_str(" •");
+        }
+        res
+    }
+
+    /// Anonymous lexemes are shown by their regex; lexemes with the same regex
+    /// are merged, so all their names are listed, like `KEY|VALUE`.
+    fn lexeme_display_name(&self, idx: LexemeIdx) -> String {
+        let spec = self.lexer_spec();
+        let names = spec
+            .lexeme_spec(idx)
+            .names()
+            .filter(|&n| n != "lx")
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            spec.lexeme_regex_to_string(idx)
+        } else {
+            names.join("|")
+        }
+    }
+
+    /// Rows added by ignored lexemes (whitespace) are copies of the previous row.
+    fn row_is_skip(&self, row_idx: usize) -> bool {
+        if row_idx == 0 {
+            return false;
+        }
+        let spec = self.lexer_spec();
+        self.lexer()
+            .lexemes_from_idx(self.row_infos[row_idx - 1].lexeme.idx)
+            .as_slice()
+            .iter()
+            .any(|&lx| spec.lexeme_spec(lx).is_skip)
+    }
+
+    /// Derivations of the whole input, which has to end at a lexeme boundary.
+    fn parse_trees(&self, max_trees: usize) -> Vec<ParseTree> {
+        if max_trees == 0 {
+            return vec![];
+        }
+        let mut builder = TreeBuilder::new(self, max_trees);
+        let start = self.grammar.start();
+        let root_name = base_name(self.grammar.sym_name(start)).to_string();
+        let end = self.num_rows() - 1;
+        let len = builder.offsets[end];
+        builder.derive_bottom_up(end);
+        builder
+            .derive_sym(start, 0, end)
+            .iter()
+            .map(|children| {
+                let mut children = children.to_vec();
+                match children.as_slice() {
+                    // the Lark start rule below the start symbol of the grammar
+                    [ch] if !ch.is_lexeme() && ch.name == root_name => children.pop().unwrap(),
+                    _ => ParseTree {
+                        name: root_name.clone(),
+                        start: 0,
+                        end: len,
+                        text: None,
+                        children,
+                    },
+                }
+            })
+            .collect()
+    }
+
     fn item_to_string(&self, idx: usize) -> String {
         self.scratch.item_to_string(idx)
     }
 
+    /// Bytes of the current lexeme that are part of captures open here,
+    /// and whether the lexeme is part of them.
+    fn pending_capture_bytes(&self, lexer: &mut Lexer) -> (Vec<u8>, bool) {
+        let lexer_state = self.lexer_state().lexer_state;
+        let mut pending = self.curr_row_bytes();
+        let spec = self.lexer_spec();
+        // pending whitespace may end up outside of the capture
+        let use_pending = !pending.is_empty()
+            && !lexer
+                .possible_lexemes(lexer_state)
+                .iter()
+                .any(|idx| spec.lexeme_spec(idx).is_skip);
+        if use_pending {
+            // stop strings are not part of the capture
+            let hidden_len = lexer.possible_hidden_len(lexer_state);
+            pending.truncate(pending.len() - hidden_len);
+        } else {
+            pending.clear();
+        }
+        (pending, use_pending)
+    }
+
+    /// Captures that every possible parse at the current position is inside of,
+    /// outermost first.
+    fn open_captures(&self, lexer: &mut Lexer) -> Vec<OpenCapture> {
+        let curr_idx = self.num_rows() - 1;
+        let lexer_state = self.lexer_state().lexer_state;
+        let (pending, use_pending) = self.pending_capture_bytes(lexer);
+        let possible = lexer.possible_lexemes(lexer_state).clone();
+
+        // (symbol, start row, distance from the current row)
+        let mut common: Option<Vec<(CSymIdx, usize, usize)>> = None;
+        for item in self
+            .curr_row()
+            .item_indices()
+            .map(|i| self.scratch.items[i])
+        {
+            let dot = self.grammar.sym_idx_dot(item.rhs_ptr());
+            let Some(lx) = self.grammar.sym_data(dot).lexeme else {
+                continue;
+            };
+            if use_pending && !possible.contains(lx) {
+                continue;
+            }
+            let mut found = self.capture_ancestors(self.item_lhs(&item), item.start_pos());
+            if use_pending && self.grammar.sym_data(dot).props.capture_name.is_some() {
+                found.push((dot, curr_idx, 0));
+            }
+            let common = common.get_or_insert_with(|| found.clone());
+            common.retain(|(sym, start, _)| found.iter().any(|f| f.0 == *sym && f.1 == *start));
+            if common.is_empty() {
+                break;
+            }
+        }
+
+        let mut common = common.unwrap_or_default();
+        // nothing captured yet
+        common.retain(|&(_, start, _)| start < curr_idx || !pending.is_empty());
+        common.sort_by_key(|&(_, start, depth)| (start, usize::MAX - depth));
+        common
+            .into_iter()
+            .map(|(sym, start, _)| OpenCapture {
+                name: self
+                    .grammar
+                    .sym_data(sym)
+                    .props
+                    .capture_name
+                    .clone()
+                    .unwrap(),
+                start_row: start,
+            })
+            .collect()
+    }
+
+    /// Text of open captures from `from` to the current position, and the position
+    /// where it ends; `None` if `from` is no longer part of the text.
+    fn capture_text(&self, lexer: &mut Lexer, from: CapturePos) -> Option<(Vec<u8>, CapturePos)> {
+        let curr_idx = self.num_rows() - 1;
+        if from.row > curr_idx {
+            return None;
+        }
+        let mut bytes = vec![];
+        for row in from.row..curr_idx {
+            let lexeme_bytes = self.row_infos[row].lexeme.upper_visible_bytes(false);
+            let offset = if row == from.row { from.offset } else { 0 };
+            bytes.extend_from_slice(lexeme_bytes.get(offset..)?);
+        }
+        let (pending, _) = self.pending_capture_bytes(lexer);
+        let offset = if from.row == curr_idx { from.offset } else { 0 };
+        bytes.extend_from_slice(pending.get(offset..)?);
+        let end = CapturePos {
+            row: curr_idx,
+            offset: pending.len(),
+        };
+        Some((self.tok_env.tok_trie().decode_raw_to_decode(&bytes), end))
+    }
+
+    fn bytes_version(&self) -> BytesVersion {
+        BytesVersion {
+            num_rollbacks: self.num_rollbacks,
+            num_bytes: self.bytes.len(),
+        }
+    }
+
+    fn bytes_unchanged_since(&self, version: &BytesVersion) -> bool {
+        let Some(n) = self.num_rollbacks.checked_sub(version.num_rollbacks) else {
+            return false;
+        };
+        n <= self.rollback_lens.len()
+            && self
+                .rollback_lens
+                .iter()
+                .rev()
+                .take(n)
+                .all(|&len| len >= version.num_bytes)
+    }
+
+    fn push_rollback_len(&mut self) {
+        if self.rollback_lens.len() == MAX_ROLLBACK_LENS {
+            self.rollback_lens.pop_front();
+        }
+        self.rollback_lens.push_back(self.bytes.len());
+        self.num_rollbacks += 1;
+    }
+
+    /// Captured symbols among `sym` started at `start`, and the symbols it's part of.
+    fn capture_ancestors(&self, sym: CSymIdx, start: usize) -> Vec<(CSymIdx, usize, usize)> {
+        let mut res = vec![];
+        let mut visited = HashSet::default();
+        let mut todo = VecDeque::from([(sym, start, 1)]);
+        while let Some((sym, start, depth)) = todo.pop_front() {
+            if !visited.insert((sym, start)) {
+                continue;
+            }
+            if self.grammar.sym_data(sym).props.capture_name.is_some() {
+                res.push((sym, start, depth));
+            }
+            for i in self.rows[start].item_indices() {
+                let item = self.scratch.items[i];
+                if self.grammar.sym_idx_dot(item.rhs_ptr()) == sym {
+                    todo.push_back((self.item_lhs(&item), item.start_pos(), depth + 1));
+                }
+            }
+        }
+        res
+    }
+
     fn print_row(&self, row_idx: usize) {
         let row = &self.rows[row_idx];
         println!(
@@ -871,8 +1280,29 @@ impl ParserState {
 
     #[inline(always)]
     fn pop_lexer_states(&mut self, n: usize) {
-        self.lexer_stack
-            .truncate(self.lexer_stack.len().saturating_sub(n));
+        let new_len = self.lexer_stack.len().saturating_sub(n);
+        if self.scratch.definitive {
+            // the popped states may be below a rollback mark
+            for idx in (new_len..self.lexer_stack.len()).rev() {
+                self.undo_log
+                    .push(Undo::LexerState(idx, self.lexer_stack[idx]));
+            }
+        }
+        self.lexer_stack.truncate(new_len);
+    }
+
+    fn update_row_token_idx(&mut self, row_idx: usize, reset: bool) {
+        let info = &mut self.row_infos[row_idx];
+        let prev = (info.token_idx_start, info.token_idx_stop);
+        if reset {
+            info.set_token_idx(self.token_idx);
+        } else {
+            info.apply_token_idx(self.token_idx);
+        }
+        if prev != (info.token_idx_start, info.token_idx_stop) {
+            self.undo_log
+                .push(Undo::RowTokenIdx(row_idx, prev.0, prev.1));
+        }
     }
 
     #[allow(dead_code)]
@@ -931,6 +1361,42 @@ impl ParserState {
         }
     }
 
+    pub fn mark(&self) -> ParserMark {
+        self.assert_definitive();
+        ParserMark {
+            token_idx: self.token_idx,
+            num_bytes: self.bytes.len(),
+            num_applied_bytes: self.byte_to_token_idx.len(),
+            lexer_stack_len: self.lexer_stack.len(),
+            lexer_stack_top_eos: self.lexer_stack_top_eos,
+            num_captures: self.captures.capture_list.len(),
+            undo_pos: self.undo_base + self.undo_log.len(),
+        }
+    }
+
+    /// Check if `mark` is consistent, and not ahead of the parser.
+    fn is_valid_mark(&self, mark: &ParserMark) -> bool {
+        mark.lexer_stack_len > 0
+            && mark.lexer_stack_len <= self.lexer_stack.len()
+            && mark.num_bytes <= self.bytes.len()
+            && mark.num_applied_bytes <= mark.num_bytes
+            && mark.token_idx <= self.token_idx
+            && mark.num_captures <= self.captures.capture_list.len()
+            && mark.undo_pos <= self.undo_base + self.undo_log.len()
+            && self.lexer_bytes_invariant_holds(
+                mark.lexer_stack_len,
+                mark.num_bytes,
+                mark.lexer_stack_top_eos,
+            )
+    }
+
+    pub fn trim_undo_log(&mut self, oldest: Option<&ParserMark>) {
+        let end = self.undo_base + self.undo_log.len();
+        let pos = oldest.map_or(end, |m| m.undo_pos.clamp(self.undo_base, end));
+        self.undo_log.drain(..pos - self.undo_base);
+        self.undo_base = pos;
+    }
+
     pub fn rollback(&mut self, n_bytes: usize) -> Result<()> {
         debug!("rollback: {} bytes", n_bytes);
         ensure!(self.parser_error.is_none(), "rollback: parser error");
@@ -950,10 +1416,82 @@ impl ParserState {
         self.lexer_stack.truncate(new_len + 1);
 
         self.row_infos.truncate(self.num_rows());
+        self.row_infos.last_mut().unwrap().lexeme = Lexeme::bogus();
         self.token_idx = *self.byte_to_token_idx.last().unwrap_or(&0) as usize;
         self.last_force_bytes_len = usize::MAX;
         self.lexer_stack_top_eos = false;
         self.rows_valid_end = self.num_rows();
+        // the undo log no longer matches the state
+        self.trim_undo_log(None);
+        self.push_rollback_len();
+
+        self.assert_definitive();
+        self.check_lexer_bytes_invariant();
+
+        Ok(())
+    }
+
+    pub fn rollback_to(&mut self, mark: &ParserMark) -> Result<()> {
+        debug!(
+            "rollback: to {} bytes, token {}",
+            mark.num_applied_bytes, mark.token_idx
+        );
+        ensure!(self.parser_error.is_none(), "rollback: parser error");
+        self.assert_definitive();
+        ensure!(
+            mark.lexer_stack_len <= self.lexer_stack.len()
+                && mark.num_bytes <= self.bytes.len()
+                && mark.num_applied_bytes <= self.byte_to_token_idx.len()
+                && mark.num_captures <= self.captures.capture_list.len()
+                && mark.undo_pos <= self.undo_base + self.undo_log.len(),
+            "rollback: mark is ahead of the parser"
+        );
+        ensure!(
+            mark.undo_pos >= self.undo_base,
+            "rollback: mark is older than the trimmed undo log"
+        );
+
+        self.byte_to_token_idx.truncate(mark.num_applied_bytes);
+        self.bytes.truncate(mark.num_bytes);
+        self.lexer_stack.truncate(mark.lexer_stack_len);
+
+        // restore overwritten values, oldest last
+        let undo = self.undo_log.split_off(mark.undo_pos - self.undo_base);
+        for u in undo.iter().rev() {
+            if let Undo::LexerState(idx, state) = *u {
+                if idx < self.lexer_stack.len() {
+                    self.lexer_stack[idx] = state;
+                }
+            }
+        }
+        self.row_infos.truncate(self.num_rows());
+        // the lexeme of the last row is pending again
+        self.row_infos.last_mut().unwrap().lexeme = Lexeme::bogus();
+        for u in undo.iter().rev() {
+            if let Undo::RowTokenIdx(idx, start, stop) = *u {
+                if idx < self.row_infos.len() {
+                    self.row_infos[idx].token_idx_start = start;
+                    self.row_infos[idx].token_idx_stop = stop;
+                }
+            }
+        }
+        let captures = &mut self.captures;
+        captures.capture_list.truncate(mark.num_captures);
+        captures.capture_info.truncate(mark.num_captures);
+        for u in undo.into_iter().rev() {
+            if let Undo::Capture(name, old) = u {
+                match old {
+                    Some(bytes) => captures.capture_map.insert(name, bytes),
+                    None => captures.capture_map.remove(&name),
+                };
+            }
+        }
+
+        self.token_idx = mark.token_idx;
+        self.last_force_bytes_len = usize::MAX;
+        self.lexer_stack_top_eos = mark.lexer_stack_top_eos;
+        self.rows_valid_end = self.num_rows();
+        self.push_rollback_len();
 
         self.assert_definitive();
         self.check_lexer_bytes_invariant();
@@ -1056,8 +1594,7 @@ impl ParserState {
             "failed to advance parser after adding bytes ignoring lexer"
         );
         if self.scratch.definitive {
-            let row_idx = self.num_rows() - 1;
-            self.row_infos[row_idx].apply_token_idx(self.token_idx);
+            self.update_row_token_idx(self.num_rows() - 1, false);
         }
         Ok(())
     }
@@ -1091,7 +1628,7 @@ impl ParserState {
 
                 let row_idx = self.num_rows() - 1;
 
-                self.row_infos[row_idx].apply_token_idx(self.token_idx);
+                self.update_row_token_idx(row_idx, false);
 
                 let (ok, bt) = self.try_push_byte_definitive(Some(b));
                 if !ok {
@@ -1160,12 +1697,9 @@ impl ParserState {
 
         for idx in row_to_apply..self.num_rows() {
             // for all rows fully contained (so far) in the new token, reset token idx
-            if self.row_infos[idx].start_byte_idx >= applied_idx0 {
-                self.row_infos[idx].set_token_idx(self.token_idx);
-            } else {
-                // otherwise, just apply it
-                self.row_infos[idx].apply_token_idx(self.token_idx);
-            }
+            // otherwise, just apply it
+            let reset = self.row_infos[idx].start_byte_idx >= applied_idx0;
+            self.update_row_token_idx(idx, reset);
         }
 
         if check_lexer_max_tokens {
@@ -1227,7 +1761,10 @@ impl ParserState {
                         return Ok(0);
                     }
                 } else {
-                    self.lexer_stack.last_mut().unwrap().lexer_state = new_state;
+                    let top = self.lexer_stack.len() - 1;
+                    self.undo_log
+                        .push(Undo::LexerState(top, self.lexer_stack[top]));
+                    self.lexer_stack[top].lexer_state = new_state;
                 }
             }
         }
@@ -1388,9 +1925,25 @@ impl ParserState {
         }
     }
 
+    fn lexer_bytes_invariant_holds(
+        &self,
+        lexer_stack_len: usize,
+        num_bytes: usize,
+        eos: bool,
+    ) -> bool {
+        let spec = self.lexer_spec();
+        // hidden stop bytes and forced ends of max_tokens lexemes
+        // push lexer states without bytes
+        spec.has_stop || spec.has_max_tokens || lexer_stack_len == num_bytes + 1 + eos as usize
+    }
+
     fn check_lexer_bytes_invariant(&self) {
         let off = if self.lexer_stack_top_eos { 2 } else { 1 };
-        if self.lexer_stack.len() != self.bytes.len() + off {
+        if !self.lexer_bytes_invariant_holds(
+            self.lexer_stack.len(),
+            self.bytes.len(),
+            self.lexer_stack_top_eos,
+        ) {
             panic!(
                 "lexer_stack={:?} bytes={:?} {}!={}+{off}",
                 self.lexer_stack,
@@ -1405,9 +1958,7 @@ impl ParserState {
         // debug!("trie_started: rows={} lexer={}", self.num_rows(), self.lexer_stack.len());
         self.assert_definitive();
 
-        if self.lexer_spec().can_rollback() {
-            self.check_lexer_bytes_invariant();
-        }
+        self.check_lexer_bytes_invariant();
 
         self.trie_lexer_stack = self.lexer_stack.len();
         self.trie_grammar_stack = self.scratch.grammar_stack.len();
@@ -1469,7 +2020,10 @@ impl ParserState {
 
         let curr = self.lexer_state();
 
-        let res = if byte.is_none() {
+        let res = if let Some(byte) = byte {
+            self.stats.definitive_bytes += 1;
+            self.lexer_mut().advance(curr.lexer_state, byte, true)
+        } else {
             let lexeme = self.lexer_mut().force_lexeme_end(curr.lexer_state);
             if lexeme.is_error() {
                 debug!(
@@ -1478,10 +2032,6 @@ impl ParserState {
                 );
             }
             lexeme
-        } else {
-            self.stats.definitive_bytes += 1;
-            self.lexer_mut()
-                .advance(curr.lexer_state, byte.unwrap(), true)
         };
 
         if res.is_error() {
@@ -1657,6 +2207,12 @@ impl ParserState {
 
         // no process_agenda() in the normal case
 
+        if max_token_ptr.is_none() {
+            // the items didn't change, so neither did the grammars they belong to
+            // (the SKIP lexeme may be of a grammar below a nested one that just started)
+            self.scratch.push_grm_top = self.rows[self.num_rows() - 1].grammar_stack_ptr;
+        }
+
         if let Some(ptr) = max_token_ptr {
             // but we have to do it if we hit the max tokens case
             self.process_max_tokens(ptr, lexeme);
@@ -1723,6 +2279,12 @@ impl ParserState {
         self.push_row(self.num_rows(), lexeme)
     }
 
+    fn push_capture(&mut self, cap: (String, Vec<u8>), documents_sym: Option<CSymIdx>) {
+        if let Some((name, old)) = self.captures.push(cap, documents_sym) {
+            self.undo_log.push(Undo::Capture(name, old));
+        }
+    }
+
     fn mk_capture(&self, var_name: &str, bytes: &[u8]) -> (String, Vec<u8>) {
         debug!(
             "      capture: {} {:?}",
@@ -1742,7 +2304,8 @@ impl ParserState {
         is_lexeme: bool,
         capture_start: usize,
     ) {
-        let sym_data = self.grammar.sym_data(lhs);
+        let grammar = self.grammar.clone();
+        let sym_data = grammar.sym_data(lhs);
 
         debug!(
             "      process_one_capture: {} {}-{} {}",
@@ -1754,7 +2317,7 @@ impl ParserState {
 
         if let Some(var_name) = sym_data.props.stop_capture_name.as_ref() {
             let bytes = lexeme.hidden_bytes();
-            self.captures.push(self.mk_capture(var_name, bytes));
+            self.push_capture(self.mk_capture(var_name, bytes), None);
         }
 
         if let Some(var_name) = sym_data.props.capture_name.as_ref() {
@@ -1769,7 +2332,10 @@ impl ParserState {
             if is_lexeme || capture_start < curr_idx {
                 bytes.extend_from_slice(lexeme.upper_visible_bytes(is_lexeme));
             }
-            self.captures.push(self.mk_capture(var_name, &bytes));
+            self.push_capture(
+                self.mk_capture(var_name, &bytes),
+                sym_data.props.capture_documents.as_ref().map(|_| lhs),
+            );
         }
     }
 
@@ -1803,6 +2369,7 @@ impl ParserState {
 
         self.scratch.push_allowed_lexemes.clear();
         self.scratch.push_allowed_grammar_ids.set_all(false);
+        self.scratch.push_resumed_grammar_ids.set_all(false);
 
         let lexemes_end = if self.scratch.row_start == 0 {
             // initial push - no lexemes scanned yet
@@ -1843,6 +2410,13 @@ impl ParserState {
                 if item.start_pos() < curr_idx {
                     // if item.start_pos() == curr_idx, then we handled it below in the nullable check
 
+                    let lhs_data = self.grammar.sym_data(lhs);
+                    if lhs_data.gen_grammar.is_some() {
+                        self.scratch
+                            .push_resumed_grammar_ids
+                            .set(lhs_data.props.grammar_id.as_usize(), true);
+                    }
+
                     // The main completion inference rule (slide 21 in Kallmeyer 2018)
                     for i in self.rows[item.start_pos()].item_indices() {
                         let item = self.scratch.items[i];
@@ -1866,15 +2440,23 @@ impl ParserState {
                 if sym_data.is_nullable {
                     self.scratch
                         .add_unique(item.advance_dot(), item_idx, "null");
-                    if self.scratch.definitive && sym_data.props.capture_name.is_some() {
-                        // nullable capture
-                        let var_name = sym_data.props.capture_name.as_ref().unwrap();
-                        debug!("      capture: {} NULL", var_name);
-                        self.captures.push((var_name.clone(), vec![]));
+                    if self.scratch.definitive {
+                        if let Some(var_name) = &sym_data.props.capture_name {
+                            // nullable capture
+                            debug!("      capture: {} NULL", var_name);
+                            let cap = (var_name.clone(), vec![]);
+                            if let Some((name, old)) = self.captures.push(cap, None) {
+                                self.undo_log.push(Undo::Capture(name, old));
+                            }
+                        }
                     }
                 }
 
                 if sym_data.gen_grammar.is_some() {
+                    // the grammar can skip whitespace before the nested one
+                    self.scratch
+                        .push_allowed_grammar_ids
+                        .set(sym_data.props.grammar_id.as_usize(), true);
                     let mut node = self.mk_grammar_stack_node(sym_data, curr_idx);
                     self.scratch
                         .add_unique(node.start_item, item_idx, "gen_grammar");
@@ -1906,14 +2488,24 @@ impl ParserState {
             let lex_start = if let Some(l) = lex_start {
                 l
             } else {
-                // accept a SKIP lexeme, if the grammar didn't finish
-                if self
-                    .scratch
-                    .push_allowed_grammar_ids
-                    .get(grammar_id.as_usize())
-                {
-                    let skip = self.lexer_spec().skip_id(grammar_id);
-                    self.scratch.push_allowed_lexemes.add(skip);
+                // accept a SKIP lexeme of the innermost grammar that didn't finish:
+                // the one of the last lexeme, or one containing a nested grammar that just ended
+                let mut ptr = self.scratch.push_grm_top;
+                loop {
+                    let node = &self.scratch.grammar_stack[ptr.as_usize()];
+                    let id = node.grammar_id;
+                    if self.scratch.push_allowed_grammar_ids.get(id.as_usize())
+                        && (id == grammar_id
+                            || self.scratch.push_resumed_grammar_ids.get(id.as_usize()))
+                    {
+                        let skip = self.lexer_spec().skip_id(id);
+                        self.scratch.push_allowed_lexemes.add(skip);
+                        break;
+                    }
+                    if ptr.as_usize() == 0 {
+                        break;
+                    }
+                    ptr = node.back_ptr;
                 }
 
                 self.shared_box
@@ -1974,6 +2566,130 @@ impl ParserState {
         }
     }
 
+    /// Number of the rows up to `row_idx` in `ids`; see [`StateIds`].
+    fn rows_id(&mut self, ids: &mut StateIds, row_idx: usize) -> Option<u64> {
+        let mut first = row_idx + 1;
+        while first > 0 && self.rows[first - 1].id == 0 {
+            first -= 1;
+        }
+        for idx in first..=row_idx {
+            let row = &self.rows[idx];
+            let (lexeme_idx, lexer_start_state) = (row.lexeme_idx, row.lexer_start_state);
+            let mut sig = vec![if idx == 0 { 0 } else { self.rows[idx - 1].id }];
+            let mut ptr = row.grammar_stack_ptr;
+            loop {
+                let node = &self.scratch.grammar_stack[ptr.as_usize()];
+                sig.push(node.start_item.data);
+                sig.push(node.grammar_id.as_usize() as u64);
+                if ptr.as_usize() == 0 {
+                    break;
+                }
+                ptr = node.back_ptr;
+            }
+            let lexer = self.lexer();
+            let lexemes = lexer.lexemes_from_idx(lexeme_idx).as_slice();
+            let (hidden_len, is_suffix) = lexer.lexeme_props(lexeme_idx);
+            // lengths keep the parts apart
+            sig.push(sig.len() as u64);
+            sig.extend(lexemes.iter().map(|idx| idx.as_usize() as u64));
+            sig.push(lexemes.len() as u64);
+            sig.push(hidden_len as u64);
+            sig.push(is_suffix as u64);
+            sig.push(self.shared_start_state(ids, lexer_start_state)?);
+            let row = &self.rows[idx];
+            sig.extend(row.item_indices().map(|i| self.scratch.items[i].data));
+            self.rows[idx].id = ids.row_id(sig);
+        }
+        Some(self.rows[row_idx].id)
+    }
+
+    /// Number of the start state of a row in `ids`;
+    /// these are the initial states of the DFA for the allowed lexemes.
+    fn shared_start_state(&mut self, ids: &mut StateIds, state: StateID) -> Option<u64> {
+        let lexer = self.lexer_mut();
+        if let Some(id) = lexer.known_shared_id(state, ids) {
+            return Some(id);
+        }
+        let lexemes = lexer.possible_lexemes(state).clone();
+        if lexer.start_state(&lexemes) != state {
+            return None;
+        }
+        let id = ids.initial_state(&lexemes)?;
+        lexer.set_shared_id(state, id);
+        Some(id)
+    }
+
+    /// Number of the current lexer state in `ids`, found by following
+    /// the bytes of the pending lexeme from the start state of the row
+    /// (or from the last state looked up before).
+    fn shared_lexer_state(&mut self, ids: &mut StateIds) -> Option<u64> {
+        let mut i = self.lexer_stack.len() - 1;
+        let row_idx = self.lexer_stack[i].row_idx;
+        let (mut state, mut id) = loop {
+            let state = self.lexer_stack[i].lexer_state;
+            if let Some(id) = self.lexer().known_shared_id(state, ids) {
+                i += 1;
+                break (state, id);
+            }
+            if i == 0 || self.lexer_stack[i - 1].row_idx != row_idx {
+                let start = self.rows[row_idx as usize].lexer_start_state;
+                break (start, self.shared_start_state(ids, start)?);
+            }
+            i -= 1;
+        };
+        for k in i..self.lexer_stack.len() {
+            let entry = self.lexer_stack[k];
+            if entry.lexer_state == state {
+                continue;
+            }
+            // only follow transitions we can check
+            let byte = entry.byte?;
+            if self.lexer_mut().dfa.transition(state, byte) != entry.lexer_state {
+                return None;
+            }
+            id = ids.transition(id, byte)?;
+            state = entry.lexer_state;
+            self.lexer_mut().set_shared_id(state, id);
+        }
+        Some(id)
+    }
+
+    /// Everything the mask depends on, except for the token prefix.
+    /// The partial lexeme is represented by the DFA state only (not its bytes),
+    /// so e.g. all positions inside of a JSON string share the key.
+    /// None when the mask also depends on the number of tokens (max_tokens),
+    /// or when the state cannot be numbered.
+    fn mask_state(&mut self, ids: &mut StateIds) -> Option<MaskState> {
+        if self.parser_error.is_some() || self.lexer_spec().has_max_tokens {
+            return None;
+        }
+        let top = self.lexer_state();
+        Some(MaskState {
+            rows_id: self.rows_id(ids, top.row_idx as usize)?,
+            lexer_state: self.shared_lexer_state(ids)?,
+            pending_bytes: self.has_pending_lexeme_bytes(),
+            top_eos: self.lexer_stack_top_eos,
+        })
+    }
+
+    fn maybe_forcing_tokens(&mut self, tokens: &[TokenId]) -> Vec<TokenId> {
+        self.assert_definitive();
+        let tok_env = self.tok_env.clone();
+        let trie = tok_env.tok_trie();
+        // forced bytes were already pushed, and the tokens start with them
+        let num_forced = self.bytes.len() - self.byte_to_token_idx.len();
+        let state = self.lexer_state().lexer_state;
+        let lexer = self.lexer_mut();
+        tokens
+            .iter()
+            .copied()
+            .filter(|&t| {
+                let bytes = trie.token(t);
+                bytes.len() <= num_forced || lexer.may_force_after(state, &bytes[num_forced..])
+            })
+            .collect()
+    }
+
     fn process_max_tokens(&mut self, ptr: GrammarStackPtr, lexeme: &Lexeme) {
         if self.scratch.definitive {
             debug!("  process_max_tokens");
@@ -2568,6 +3284,341 @@ fn item_to_string(g: &CGrammar, item: &Item) -> String {
     format!("{} @{}", g.rule_to_string(item.rhs_ptr()), item.start_pos(),)
 }
 
+/// Children of a node, as a list linked from the last child back,
+/// so that derivations of a rule share the children before the dot instead of copying them.
+#[derive(Clone, Default)]
+struct Children(Option<Rc<ChildrenCons>>);
+
+struct ChildrenCons {
+    prev: Children,
+    last: Rc<ParseTree>,
+}
+
+impl Children {
+    fn one(tree: ParseTree) -> Self {
+        Children::default().push(Rc::new(tree))
+    }
+
+    fn push(&self, tree: Rc<ParseTree>) -> Self {
+        Children(Some(Rc::new(ChildrenCons {
+            prev: self.clone(),
+            last: tree,
+        })))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+
+    /// The children, last one first.
+    fn iter_rev(&self) -> impl Iterator<Item = &Rc<ParseTree>> {
+        std::iter::successors(self.0.as_deref(), |cons| cons.prev.0.as_deref())
+            .map(|cons| &cons.last)
+    }
+
+    fn concat(&self, tail: &Children) -> Self {
+        if self.is_empty() {
+            return tail.clone();
+        }
+        let tail = tail.iter_rev().collect::<Vec<_>>();
+        tail.into_iter()
+            .rev()
+            .fold(self.clone(), |res, tree| res.push(tree.clone()))
+    }
+
+    fn to_vec(&self) -> Vec<ParseTree> {
+        let mut res = self
+            .iter_rev()
+            .map(|tree| tree.as_ref().clone())
+            .collect::<Vec<_>>();
+        res.reverse();
+        res
+    }
+}
+
+impl Drop for Children {
+    // dropping long lists recursively would overflow the stack
+    fn drop(&mut self) {
+        let mut next = self.0.take();
+        while let Some(cons) = next {
+            next = match Rc::try_unwrap(cons) {
+                Ok(mut cons) => cons.prev.0.take(),
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+/// Alternative lists of children, to be attached to the parent node.
+type Derivations = Rc<Vec<Children>>;
+
+/// Reconstructs derivations by walking back from complete items in the Earley chart.
+/// Ambiguities are resolved by preferring rules in grammar order,
+/// and then splits where the earlier symbols of the rule are longer.
+struct TreeBuilder<'a> {
+    state: &'a ParserState,
+    max_trees: usize,
+    /// Byte offset of each row in the visible text.
+    offsets: Vec<usize>,
+    items: HashSet<(usize, Item)>,
+    /// Start rows and rules of complete items, by (end row, symbol).
+    complete: HashMap<(usize, CSymIdx), Vec<(usize, RhsPtr)>>,
+    /// Children (after inlining helper symbols) of (symbol, start row, end row).
+    memo: HashMap<(CSymIdx, usize, usize), Derivations>,
+    in_progress: HashSet<(CSymIdx, usize, usize)>,
+    /// Start symbols of nested grammars; the node is named after the grammar instead.
+    nested_starts: HashSet<CSymIdx>,
+}
+
+impl<'a> TreeBuilder<'a> {
+    fn new(state: &'a ParserState, max_trees: usize) -> Self {
+        let grammar = &state.grammar;
+        let num_rows = state.num_rows();
+        let mut offsets = vec![0; num_rows];
+        let mut items = HashSet::default();
+        let mut complete: HashMap<_, Vec<_>> = HashMap::default();
+        let mut nested_starts = HashSet::default();
+        for row_idx in 0..num_rows {
+            if row_idx > 0 {
+                offsets[row_idx] =
+                    offsets[row_idx - 1] + state.row_infos[row_idx - 1].lexeme.num_visible_bytes();
+            }
+            for i in state.rows[row_idx].item_indices() {
+                let item = state.scratch.items[i];
+                items.insert((row_idx, item));
+                let lhs = grammar.sym_idx_lhs(item.rhs_ptr());
+                if grammar.sym_idx_dot(item.rhs_ptr()) == CSymIdx::NULL {
+                    complete
+                        .entry((row_idx, lhs))
+                        .or_default()
+                        .push((item.start_pos(), item.rhs_ptr()));
+                }
+                let sym_data = grammar.sym_data(lhs);
+                if sym_data.gen_grammar.is_some() {
+                    let (rhs, _) = grammar.rule_rhs(sym_data.rules[0]);
+                    nested_starts.insert(rhs[0]);
+                }
+            }
+        }
+        TreeBuilder {
+            state,
+            max_trees,
+            offsets,
+            items,
+            complete,
+            memo: HashMap::default(),
+            in_progress: HashSet::default(),
+            nested_starts,
+        }
+    }
+
+    /// Rows after whitespace only repeat items of the row before it.
+    fn unskip(&self, start: usize, mut end: usize) -> usize {
+        while end > start && self.state.row_is_skip(end) {
+            end -= 1;
+        }
+        end
+    }
+
+    /// Rules get their own node, unless they are anonymous or helpers
+    /// introduced by the grammar builder (like repetitions).
+    fn node_name(&self, sym: CSymIdx) -> Option<&'a str> {
+        let grammar = &self.state.grammar;
+        let sym_data = grammar.sym_data(sym);
+        if let Some(gen) = &sym_data.gen_grammar {
+            // %json grammars are numbered like `%json---12`
+            let GrammarId::Name(name) = &gen.grammar;
+            return name.split("---").next();
+        }
+        if sym == grammar.start()
+            || sym_data.lexeme.is_some()
+            || sym_data.props.is_helper
+            || self.nested_starts.contains(&sym)
+        {
+            return None;
+        }
+        match base_name(grammar.sym_name(sym)) {
+            "" => None,
+            name => Some(name),
+        }
+    }
+
+    fn leaf(&self, lx: LexemeIdx, row_idx: usize) -> ParseTree {
+        let lexeme = &self.state.row_infos[row_idx].lexeme;
+        ParseTree {
+            name: self.state.lexeme_display_name(lx),
+            start: self.offsets[row_idx],
+            end: self.offsets[row_idx + 1],
+            text: Some(String::from_utf8_lossy(lexeme.visible_bytes()).to_string()),
+            children: vec![],
+        }
+    }
+
+    fn wrap(&self, sym: CSymIdx, start: usize, children: Children) -> Children {
+        let Some(name) = self.node_name(sym) else {
+            return children;
+        };
+        let children = children.to_vec();
+        let (first, last) = match (children.first(), children.last()) {
+            (Some(first), Some(last)) => (first.start, last.end),
+            _ => (self.offsets[start], self.offsets[start]),
+        };
+        Children::one(ParseTree {
+            name: name.to_string(),
+            start: first,
+            end: last,
+            text: None,
+            children,
+        })
+    }
+
+    /// Derives all complete items up to `last_row`, the shorter ones first.
+    /// The recursion then finds the shorter spans in the memo, so it doesn't go deeper
+    /// than the rules nest, however long the repetitions are.
+    fn derive_bottom_up(&mut self, last_row: usize) {
+        let mut spans = self
+            .complete
+            .iter()
+            .filter(|((end, _), _)| *end <= last_row && !self.state.row_is_skip(*end))
+            .flat_map(|(&(end, sym), rules)| rules.iter().map(move |&(start, _)| (end, start, sym)))
+            .collect::<Vec<_>>();
+        spans.sort_by_key(|&(end, start, sym)| (end, std::cmp::Reverse(start), sym.as_index()));
+        spans.dedup();
+        for (end, start, sym) in spans {
+            self.derive_sym(sym, start, end);
+        }
+    }
+
+    /// Possible derivations of `sym` between the rows, each as a list of children
+    /// to be attached to the parent node.
+    fn derive_sym(&mut self, sym: CSymIdx, start: usize, end: usize) -> Derivations {
+        let end = self.unskip(start, end);
+        let key = (sym, start, end);
+        if let Some(r) = self.memo.get(&key) {
+            return r.clone();
+        }
+        if !self.in_progress.insert(key) {
+            // a cycle of unit or nullable rules
+            return Rc::new(vec![]);
+        }
+
+        let state = self.state;
+        let grammar = &state.grammar;
+        let sym_data = grammar.sym_data(sym);
+        let mut res = vec![];
+        if let Some(lx) = sym_data.lexeme {
+            if end == start + 1
+                && state
+                    .lexer()
+                    .lexemes_from_idx(state.row_infos[start].lexeme.idx)
+                    .contains(lx)
+            {
+                res.push(Children::one(self.leaf(lx, start)));
+            }
+        } else {
+            let mut complete = self
+                .complete
+                .get(&(end, sym))
+                .into_iter()
+                .flatten()
+                .filter(|&&(rule_start, _)| rule_start == start)
+                .map(|&(_, rule)| rule)
+                .collect::<Vec<_>>();
+            complete.sort_by_key(|rule| rule.as_index());
+            'rules: for rule in complete {
+                for children in self.derive_rhs(rule, start, end) {
+                    res.push(self.wrap(sym, start, children));
+                    if res.len() >= self.max_trees {
+                        break 'rules;
+                    }
+                }
+            }
+            if res.is_empty() && start == end && sym_data.is_nullable {
+                res.push(self.wrap(sym, start, Children::default()));
+            }
+            if res.is_empty() && start < end && sym_data.gen_grammar.is_some() {
+                // the nested grammar was cut short by max_tokens; keep just the lexemes
+                let leaves = (start..end)
+                    .filter(|&row_idx| !state.row_is_skip(row_idx + 1))
+                    .filter_map(|row_idx| {
+                        let idx = state.row_infos[row_idx].lexeme.idx;
+                        let lx = state.lexer().lexemes_from_idx(idx).first()?;
+                        Some(Rc::new(self.leaf(lx, row_idx)))
+                    })
+                    .fold(Children::default(), |res, leaf| res.push(leaf));
+                res.push(self.wrap(sym, start, leaves));
+            }
+        }
+
+        self.in_progress.remove(&key);
+        let res = Rc::new(res);
+        self.memo.insert(key, res.clone());
+        res
+    }
+
+    /// Rows where a derivation of `sym` ending at `end` can start, latest first.
+    fn split_points(&self, sym: CSymIdx, start: usize, end: usize) -> Vec<usize> {
+        let sym_data = self.state.grammar.sym_data(sym);
+        let mut res = if sym_data.lexeme.is_some() {
+            vec![end.saturating_sub(1)]
+        } else if sym_data.gen_grammar.is_some() {
+            // these can be cut short, and then there is no complete item
+            (start..=end).collect()
+        } else {
+            let mut res = self
+                .complete
+                .get(&(end, sym))
+                .into_iter()
+                .flatten()
+                .map(|&(mid, _)| mid)
+                .collect::<Vec<_>>();
+            if sym_data.is_nullable {
+                res.push(end);
+            }
+            res
+        };
+        res.retain(|&mid| start <= mid && mid <= end);
+        res.sort_unstable_by(|a, b| b.cmp(a));
+        res.dedup();
+        res
+    }
+
+    /// Possible children of the rule up to the dot in `rule`, spanning the rows.
+    fn derive_rhs(&mut self, rule: RhsPtr, start: usize, end: usize) -> Vec<Children> {
+        let end = self.unskip(start, end);
+        let (_, dot) = self.state.grammar.rule_rhs(rule);
+        if dot == 0 {
+            return if start == end {
+                vec![Children::default()]
+            } else {
+                vec![]
+            };
+        }
+        let prev = RhsPtr::from_index(rule.as_index() as u32 - 1);
+        let sym = self.state.grammar.sym_idx_dot(prev);
+        let prev_item = Item::new(prev, start);
+        let mut res = vec![];
+        for mid in self.split_points(sym, start, end) {
+            if !self.items.contains(&(mid, prev_item)) {
+                continue;
+            }
+            let tails = self.derive_sym(sym, mid, end);
+            if tails.is_empty() {
+                continue;
+            }
+            for head in self.derive_rhs(prev, start, mid) {
+                for tail in tails.iter() {
+                    res.push(head.concat(tail));
+                    if res.len() >= self.max_trees {
+                        return res;
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+
 pub enum ParserError {
     LexerError(String),
     ParserError(String),
@@ -2589,6 +3640,407 @@ impl ParserError {
     }
 }
 
+impl ParserMark {
+    pub(crate) fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        w.usize(self.token_idx);
+        w.usize(self.num_bytes);
+        w.usize(self.num_applied_bytes);
+        w.usize(self.lexer_stack_len);
+        w.bool(self.lexer_stack_top_eos);
+        w.usize(self.num_captures);
+        w.usize(self.undo_pos);
+    }
+
+    pub(crate) fn read_snapshot(r: &mut SnapshotReader) -> Result<Self> {
+        Ok(ParserMark {
+            token_idx: r.usize()?,
+            num_bytes: r.usize()?,
+            num_applied_bytes: r.usize()?,
+            lexer_stack_len: r.usize()?,
+            lexer_stack_top_eos: r.bool()?,
+            num_captures: r.usize()?,
+            undo_pos: r.usize()?,
+        })
+    }
+}
+
+// Snapshots; lexer states are written as indices into a table
+// of states, which is written first (see SnapshotStates).
+impl ParserState {
+    fn write_snapshot(&self, lexer: &Lexer, w: &mut SnapshotWriter) {
+        let mut states = SnapshotStates::default();
+        let mut body = SnapshotWriter::nested();
+        let w_lexer_state =
+            |states: &mut SnapshotStates, w: &mut SnapshotWriter, s: &LexerState| {
+                w.u64(s.row_idx as u64);
+                w.usize(lexer.snapshot_state(states, s.lexer_state));
+                w.bool(s.byte.is_some());
+                w.u64(s.byte.unwrap_or(0) as u64);
+            };
+
+        // rows past these (and their items) are left over from computing masks
+        // or from rolled back tokens
+        let num_rows = self
+            .undo_log
+            .iter()
+            .filter_map(|u| match u {
+                Undo::LexerState(_, s) => Some(s.row_idx as usize + 1),
+                _ => None,
+            })
+            .fold(self.num_rows().max(self.rows_valid_end), usize::max);
+        let rows = &self.rows[..num_rows];
+        let num_items = rows.iter().map(|r| r.last_item as usize).max().unwrap();
+        body.usize(num_items);
+        for item in &self.scratch.items[..num_items] {
+            body.u64(item.data);
+        }
+        let num_nodes = rows
+            .iter()
+            .map(|r| r.grammar_stack_ptr.as_usize() + 1)
+            .max()
+            .unwrap();
+        body.usize(num_nodes);
+        for node in &self.scratch.grammar_stack[..num_nodes] {
+            body.usize(node.back_ptr.as_usize());
+            body.u64(node.token_horizon as u64);
+            body.usize(node.grammar_id.as_usize());
+            body.u64(node.start_item.data);
+            body.usize(node.start_item_idx);
+        }
+
+        body.usize(rows.len());
+        for row in rows {
+            body.u64(row.first_item as u64);
+            body.u64(row.last_item as u64);
+            body.usize(row.grammar_stack_ptr.as_usize());
+            body.usize(lexer.snapshot_state(&mut states, row.lexer_start_state));
+            lexer.write_snapshot_lexemes_idx(&mut states, row.lexeme_idx, &mut body);
+        }
+        body.usize(self.rows_valid_end);
+
+        body.usize(self.lexer_stack.len());
+        for s in &self.lexer_stack {
+            w_lexer_state(&mut states, &mut body, s);
+        }
+        body.bool(self.lexer_stack_top_eos);
+
+        body.usize(self.row_infos.len());
+        for info in &self.row_infos {
+            body.usize(info.start_byte_idx);
+            lexer.write_snapshot_lexemes_idx(&mut states, info.lexeme.idx, &mut body);
+            body.bytes(info.lexeme.all_bytes());
+            body.usize(info.lexeme.num_hidden_bytes());
+            body.bool(info.lexeme.is_suffix());
+            body.usize(info.token_idx_start);
+            body.usize(info.token_idx_stop);
+        }
+        body.usize(self.token_idx);
+        body.bytes(&self.bytes);
+        body.usize(self.byte_to_token_idx.len());
+        for &idx in &self.byte_to_token_idx {
+            body.u64(idx as u64);
+        }
+
+        body.usize(self.undo_log.len());
+        for u in &self.undo_log {
+            match u {
+                Undo::LexerState(idx, s) => {
+                    body.u64(0);
+                    body.usize(*idx);
+                    w_lexer_state(&mut states, &mut body, s);
+                }
+                Undo::RowTokenIdx(idx, start, stop) => {
+                    body.u64(1);
+                    body.usize(*idx);
+                    body.usize(*start);
+                    body.usize(*stop);
+                }
+                Undo::Capture(name, old) => {
+                    body.u64(2);
+                    body.string(name);
+                    body.bool(old.is_some());
+                    body.bytes(old.as_deref().unwrap_or_default());
+                }
+            }
+        }
+        body.usize(self.undo_base);
+        body.opt_string(&self.parser_error);
+
+        let captures = &self.captures;
+        body.usize(captures.capture_list.len());
+        for ((name, bytes), info) in captures.capture_list.iter().zip(&captures.capture_info) {
+            body.string(name);
+            body.bytes(bytes);
+            body.usize(info.seq);
+            // the documents are looked up in the grammar
+            body.bool(info.documents_sym.is_some());
+            body.usize(info.documents_sym.map_or(0, |s| s.as_index()));
+        }
+        // sorted, so that equal states give equal snapshots
+        let mut capture_map = captures.capture_map.iter().collect::<Vec<_>>();
+        capture_map.sort();
+        body.usize(capture_map.len());
+        for (name, bytes) in capture_map {
+            body.string(name);
+            body.bytes(bytes);
+        }
+        body.usize(captures.num_pushed);
+
+        lexer.write_snapshot_states(&states, w);
+        w.append(body);
+    }
+
+    /// Check if `item` starts a nested grammar of class `grammar_id`, see mk_grammar_stack_node().
+    fn is_grammar_start_item(&self, item: Item, grammar_id: LexemeClass) -> bool {
+        let rule = item.rhs_ptr();
+        if !self.grammar.is_valid_rhs_ptr(rule) {
+            return false;
+        }
+        let sym_data = self.grammar.sym_data(self.grammar.sym_idx_lhs(rule));
+        sym_data.gen_grammar.is_some()
+            && sym_data.rules == [rule]
+            && self.grammar.sym_data_dot(rule).props.grammar_id == grammar_id
+    }
+
+    /// Replace the state of this parser with the one written by write_snapshot().
+    fn read_snapshot(&mut self, r: &mut SnapshotReader) -> Result<()> {
+        let states = self.lexer_mut().read_snapshot_states(r)?;
+        let r_lexer_state = |r: &mut SnapshotReader| -> Result<LexerState> {
+            let row_idx = u32::try_from(r.u64()?)?;
+            let lexer_state = read_state(&states, r)?;
+            let has_byte = r.bool()?;
+            let byte = u8::try_from(r.u64()?)?;
+            Ok(LexerState {
+                row_idx,
+                lexer_state,
+                byte: has_byte.then_some(byte),
+            })
+        };
+
+        let num_items = r.usize()?;
+        let mut items = Vec::with_capacity(num_items.min(r.remaining()));
+        for _ in 0..num_items {
+            items.push(Item { data: r.u64()? });
+        }
+        let mut grammar_stack = vec![];
+        for _ in 0..r.usize()? {
+            grammar_stack.push(GrammarStackNode {
+                back_ptr: GrammarStackPtr::new(r.usize()?),
+                token_horizon: u32::try_from(r.u64()?)?,
+                grammar_id: LexemeClass::new(u8::try_from(r.u64()?)? as usize),
+                start_item: Item { data: r.u64()? },
+                start_item_idx: r.usize()?,
+            });
+        }
+        let num_classes = self.lexer_spec().alloc_grammar_set().len();
+        ensure!(
+            !grammar_stack.is_empty()
+                && grammar_stack.iter().enumerate().all(|(i, n)| {
+                    n.grammar_id.as_usize() < num_classes
+                        && if i == 0 {
+                            n.back_ptr.as_usize() == 0
+                                && n.grammar_id == LexemeClass::ROOT
+                                && n.start_item.data == 0
+                        } else {
+                            n.back_ptr.as_usize() < i
+                                && n.start_item_idx < items.len()
+                                && self.is_grammar_start_item(n.start_item, n.grammar_id)
+                        }
+                }),
+            "invalid grammar stack in parser snapshot"
+        );
+
+        let mut rows = vec![];
+        for _ in 0..r.usize()? {
+            let row = Row {
+                first_item: u32::try_from(r.u64()?)?,
+                last_item: u32::try_from(r.u64()?)?,
+                grammar_stack_ptr: GrammarStackPtr::new(r.usize()?),
+                lexer_start_state: read_state(&states, r)?,
+                lexeme_idx: self.lexer_mut().read_snapshot_lexemes_idx(&states, r)?,
+                id: 0,
+            };
+            ensure!(
+                row.first_item <= row.last_item
+                    && row.last_item as usize <= items.len()
+                    && row.grammar_stack_ptr.as_usize() < grammar_stack.len(),
+                "invalid row in parser snapshot"
+            );
+            rows.push(row);
+        }
+        let rows_valid_end = r.usize()?;
+        // lexemes allowed in a row have to belong to the grammars on its stack
+        for row in &rows {
+            let mut classes = vec![];
+            let mut ptr = row.grammar_stack_ptr.as_usize();
+            loop {
+                let node = &grammar_stack[ptr];
+                classes.push(node.grammar_id);
+                if ptr == 0 {
+                    break;
+                }
+                ptr = node.back_ptr.as_usize();
+            }
+            let spec = self.lexer_spec();
+            ensure!(
+                self.lexer()
+                    .possible_lexemes(row.lexer_start_state)
+                    .iter()
+                    .all(|lx| classes.contains(&spec.lexeme_spec(lx).class())),
+                "invalid row in parser snapshot"
+            );
+        }
+        let item_ok = |item: &Item, row_idx: usize| {
+            item.start_pos() <= row_idx && self.grammar.is_valid_rhs_ptr(item.rhs_ptr())
+        };
+        ensure!(
+            rows.iter().enumerate().all(|(row_idx, row)| {
+                items[row.item_indices()]
+                    .iter()
+                    .all(|item| item_ok(item, row_idx))
+            }) && items.iter().all(|item| item_ok(item, rows.len()))
+                && grammar_stack[1..]
+                    .iter()
+                    .all(|n| n.start_item.start_pos() < rows.len()),
+            "invalid item in parser snapshot"
+        );
+
+        let mut lexer_stack = vec![];
+        for _ in 0..r.usize()? {
+            let s = r_lexer_state(r)?;
+            ensure!(
+                (s.row_idx as usize) < rows.len(),
+                "invalid lexer state in parser snapshot"
+            );
+            lexer_stack.push(s);
+        }
+        ensure!(
+            !lexer_stack.is_empty() && rows_valid_end <= rows.len(),
+            "invalid lexer stack in parser snapshot"
+        );
+        let lexer_stack_top_eos = r.bool()?;
+
+        let mut row_infos = vec![];
+        for _ in 0..r.usize()? {
+            let start_byte_idx = r.usize()?;
+            let idx = self.lexer_mut().read_snapshot_lexemes_idx(&states, r)?;
+            let bytes = r.bytes()?.to_vec();
+            let hidden_len = r.usize()?;
+            ensure!(
+                hidden_len <= bytes.len(),
+                "invalid lexeme in parser snapshot"
+            );
+            let is_suffix = r.bool()?;
+            row_infos.push(RowInfo {
+                start_byte_idx,
+                lexeme: Lexeme::new(idx, bytes, hidden_len as u32, is_suffix),
+                token_idx_start: r.usize()?,
+                token_idx_stop: r.usize()?,
+            });
+        }
+        let token_idx = r.usize()?;
+        let bytes = r.bytes()?.to_vec();
+        let mut byte_to_token_idx = vec![];
+        for _ in 0..r.usize()? {
+            byte_to_token_idx.push(u32::try_from(r.u64()?)?);
+        }
+
+        let mut undo_log = vec![];
+        for _ in 0..r.usize()? {
+            undo_log.push(match r.u64()? {
+                0 => {
+                    let idx = r.usize()?;
+                    let s = r_lexer_state(r)?;
+                    ensure!(
+                        (s.row_idx as usize) < rows.len(),
+                        "invalid lexer state in parser snapshot"
+                    );
+                    Undo::LexerState(idx, s)
+                }
+                1 => Undo::RowTokenIdx(r.usize()?, r.usize()?, r.usize()?),
+                2 => {
+                    let name = r.string()?;
+                    let is_some = r.bool()?;
+                    let old = r.bytes()?.to_vec();
+                    Undo::Capture(name, is_some.then_some(old))
+                }
+                tag => bail!("invalid undo entry in parser snapshot: {}", tag),
+            });
+        }
+        let undo_base = r.usize()?;
+        let parser_error = r.opt_string()?;
+
+        let mut captures = Captures::new();
+        for _ in 0..r.usize()? {
+            captures
+                .capture_list
+                .push((r.string()?, r.bytes()?.to_vec()));
+            let seq = r.usize()?;
+            let has_documents = r.bool()?;
+            let sym = r.usize()?;
+            let documents_sym = if has_documents {
+                let sym = self.grammar.sym_idx_checked(sym);
+                ensure!(
+                    sym.is_some_and(|s| self.grammar.sym_data(s).props.capture_documents.is_some()),
+                    "invalid capture in parser snapshot"
+                );
+                sym
+            } else {
+                None
+            };
+            captures
+                .capture_info
+                .push(CaptureInfo { seq, documents_sym });
+        }
+        for _ in 0..r.usize()? {
+            captures
+                .capture_map
+                .insert(r.string()?, r.bytes()?.to_vec());
+        }
+        captures.num_pushed = r.usize()?;
+
+        let num_rows = lexer_stack.last().unwrap().row_idx as usize + 1;
+        ensure!(
+            num_rows == row_infos.len()
+                && row_infos.len() <= rows.len()
+                && row_infos
+                    .iter()
+                    .all(|info| info.start_byte_idx <= bytes.len() + 1)
+                && lexer_stack.windows(2).all(|w| w[0].row_idx <= w[1].row_idx)
+                && undo_log.iter().all(|u| match u {
+                    Undo::LexerState(_, s) => (s.row_idx as usize) < row_infos.len(),
+                    _ => true,
+                })
+                && byte_to_token_idx.len() <= bytes.len()
+                && byte_to_token_idx
+                    .iter()
+                    .all(|&idx| idx as usize <= token_idx)
+                && undo_base.checked_add(undo_log.len()).is_some()
+                && captures.num_pushed >= captures.capture_list.len(),
+            "invalid parser snapshot"
+        );
+
+        self.scratch.items = items;
+        self.scratch.new_row(0);
+        self.scratch.grammar_stack = grammar_stack;
+        self.rows = rows;
+        self.rows_valid_end = rows_valid_end;
+        self.lexer_stack = lexer_stack;
+        self.lexer_stack_top_eos = lexer_stack_top_eos;
+        self.row_infos = row_infos;
+        self.token_idx = token_idx;
+        self.bytes = bytes;
+        self.byte_to_token_idx = byte_to_token_idx;
+        self.undo_log = undo_log;
+        self.undo_base = undo_base;
+        self.last_force_bytes_len = usize::MAX;
+        self.parser_error = parser_error;
+        self.captures = captures;
+        self.check_lexer_bytes_invariant();
+        Ok(())
+    }
+}
+
 impl Parser {
     pub fn new(tok_env: TokEnv, grammar: Arc<CGrammar>, limits: ParserLimits) -> Result<Self> {
         let (state, lexer) = ParserState::new(tok_env, grammar, limits)?;
@@ -2605,14 +4057,86 @@ impl Parser {
         self.with_shared(|state| state.compute_bias(computer, start))
     }
 
+    /// Key for caching masks across parsers of the same grammar
+    /// (see [`Parser::compute_bias`]); None if masks cannot be cached.
+    pub(crate) fn mask_state(&mut self, ids: &Mutex<StateIds>) -> Option<MaskState> {
+        self.with_shared(|state| state.mask_state(&mut ids.lock().unwrap()))
+    }
+
+    /// A copy of the DFA of the lexer, for numbering its states across parsers.
+    pub(crate) fn lexer_dfa(&self) -> RegexVec {
+        self.shared.lock().unwrap().lexer().dfa.clone()
+    }
+
+    /// Filter tokens to those after which some bytes may be forced
+    /// (a cheap over-approximation, done only by the lexer).
+    pub fn maybe_forcing_tokens(&mut self, tokens: &[TokenId]) -> Vec<TokenId> {
+        self.with_shared(|state| state.maybe_forcing_tokens(tokens))
+    }
+
     pub fn captures(&self) -> &[(String, Vec<u8>)] {
         &self.state.captures.capture_list
     }
 
+    /// Captures in progress, outermost first.
+    /// If the parse is ambiguous, only captures common to all alternatives are included.
+    pub fn open_captures(&self) -> Vec<OpenCapture> {
+        let mut shared = self.shared.lock().unwrap();
+        self.state.open_captures(shared.lexer_mut())
+    }
+
+    /// Text captured by open captures since `from` (use [`CapturePos::start()`]
+    /// for the whole text so far), and the position to continue from next time.
+    /// Returns `None` if the text at `from` was rolled back.
+    pub fn capture_text(&self, from: CapturePos) -> Option<(Vec<u8>, CapturePos)> {
+        let mut shared = self.shared.lock().unwrap();
+        self.state.capture_text(shared.lexer_mut(), from)
+    }
+
+    pub fn bytes_version(&self) -> BytesVersion {
+        self.state.bytes_version()
+    }
+
+    /// Whether the bytes parsed at `version` are still there, i.e., the parser wasn't
+    /// rolled back past them since. Conservatively false after many rollbacks.
+    pub fn bytes_unchanged_since(&self, version: &BytesVersion) -> bool {
+        self.state.bytes_unchanged_since(version)
+    }
+
     pub fn get_capture(&self, name: &str) -> Option<&[u8]> {
         self.state.captures.capture_map.get(name).map(|v| &v[..])
     }
 
+    /// Documents quoted by the `substring_documents` lexeme of `captures()[idx]`, if any.
+    pub fn capture_documents(&self, idx: usize) -> Option<&[String]> {
+        let sym = self.state.captures.capture_info[idx].documents_sym?;
+        self.grammar()
+            .sym_data(sym)
+            .props
+            .capture_documents
+            .as_deref()
+            .map(|d| &d[..])
+    }
+
+    /// Number of captures pushed so far, including ones that were rolled back since.
+    pub(crate) fn num_captures_pushed(&self) -> usize {
+        self.state.captures.num_pushed
+    }
+
+    /// Number of captures pushed before `captures()[idx]`.
+    pub(crate) fn capture_seq(&self, idx: usize) -> usize {
+        self.state.captures.capture_info[idx].seq
+    }
+
+    /// Index of the first entry in `captures()` pushed after
+    /// the first `num_pushed` (see [`Self::num_captures_pushed()`]).
+    pub(crate) fn captures_pushed_after(&self, num_pushed: usize) -> usize {
+        self.state
+            .captures
+            .capture_info
+            .partition_point(|c| c.seq < num_pushed)
+    }
+
     pub fn stats(&self) -> &ParserStats {
         &self.state.stats
     }
@@ -2721,7 +4245,40 @@ impl Parser {
         r
     }
 
+    /// Returns the current position of the parser, for rollback_to().
+    pub fn mark(&self) -> ParserMark {
+        self.state.mark()
+    }
+
+    /// Rolls the parser back to a position returned by mark().
+    pub fn rollback_to(&mut self, mark: &ParserMark) -> Result<()> {
+        self.with_shared(|state| state.rollback_to(mark))
+    }
+
+    /// Check if `mark` (read from a snapshot) can be passed to rollback_to().
+    pub(crate) fn is_valid_mark(&self, mark: &ParserMark) -> bool {
+        self.state.is_valid_mark(mark)
+    }
+
+    /// Drops the information needed to roll back to positions before `oldest`
+    /// (or to any earlier position, if `None`), so that it doesn't accumulate
+    /// when no such mark is kept.
+    pub fn trim_undo_log(&mut self, oldest: Option<&ParserMark>) {
+        self.state.trim_undo_log(oldest)
+    }
+
+    /// Number of entries kept for rollback_to().
+    #[cfg(test)]
+    pub(crate) fn undo_log_len(&self) -> usize {
+        self.state.undo_log.len()
+    }
+
+    /// Rolls back the last `n_bytes` applied bytes.
+    /// Not supported with stop=... or max_tokens=... lexemes,
+    /// and invalidates all marks.
+    #[deprecated(note = "use mark() and rollback_to(), which work with all lexemes")]
     pub fn rollback(&mut self, n_bytes: usize) -> Result<()> {
+        #[allow(deprecated)]
         self.state.lexer_spec().check_rollback()?;
         self.with_shared(|state| state.rollback(n_bytes))
     }
@@ -2761,6 +4318,25 @@ impl Parser {
         self.with_shared(|state| state.is_accepting())
     }
 
+    /// Lexemes and rules that can continue from the current position,
+    /// and whether EOS is allowed; for debugging grammars and rejected generations.
+    pub fn expected_terminals(&mut self) -> ExpectedTerminals {
+        self.with_shared(|state| state.expected_terminals())
+    }
+
+    /// Up to `max_trees` derivations of the text so far, or none if the grammar
+    /// doesn't accept it (yet).
+    pub fn parse_trees(&self, max_trees: usize) -> Vec<ParseTree> {
+        let mut copy = self.deep_clone();
+        copy.with_shared(|state| {
+            if state.flush_lexer() && state.row_is_accepting() {
+                state.parse_trees(max_trees)
+            } else {
+                vec![]
+            }
+        })
+    }
+
     pub fn currently_forced_bytes(&self) -> &[u8] {
         &self.state.bytes[self.state.byte_to_token_idx.len()..]
     }
@@ -2769,6 +4345,11 @@ impl Parser {
         self.state.has_pending_lexeme_bytes()
     }
 
+    /// Bytes of the lexeme currently being scanned (empty at lexeme boundary).
+    pub fn pending_lexeme_bytes(&self) -> Vec<u8> {
+        self.state.curr_row_bytes()
+    }
+
     pub fn grammar(&self) -> &CGrammar {
         &self.state.grammar
     }
@@ -2787,4 +4368,14 @@ impl Parser {
         copy.shared = Arc::new(Mutex::new(shared.clone()));
         copy
     }
+
+    pub(crate) fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        let shared = self.shared.lock().unwrap();
+        self.state.write_snapshot(shared.lexer(), w);
+    }
+
+    /// Replace the state of this parser with the one from write_snapshot().
+    pub(crate) fn read_snapshot(&mut self, r: &mut SnapshotReader) -> Result<()> {
+        self.with_shared(|state| state.read_snapshot(r))
+    }
 }
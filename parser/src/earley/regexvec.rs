@@ -208,17 +208,31 @@ pub struct RegexVec {
     #[allow(dead_code)]
     rx_lexemes: Vec<RxLexeme>,
     lazy: LexemeSet,
+    has_follow: LexemeSet,
     rx_list: Vec<ExprRef>,
     special_token_rx: Option<ExprRef>,
     rx_sets: VecHashCons,
     state_table: Vec<StateID>,
     state_descs: Vec<StateDesc>,
+    origins: Vec<StateOrigin>,
     num_transitions: usize,
     num_ast_nodes: usize,
     max_states: usize,
     fuel: u64,
 }
 
+/// How a state was first constructed.
+/// Replaying it on another RegexVec for the same lexemes yields an equivalent
+/// state, even though the state numbers differ.
+#[derive(Clone, Debug)]
+pub enum StateOrigin {
+    /// DEAD or MISSING
+    Fixed,
+    Initial(LexemeSet),
+    Transition(StateID, u8),
+    Limit(StateID, LexemeSet),
+}
+
 #[derive(Clone, Debug)]
 pub struct StateDesc {
     pub state: StateID,
@@ -258,7 +272,11 @@ impl RegexVec {
                 Self::push_rx(&mut vec_desc, idx, rx);
             }
         }
-        self.insert_state(vec_desc)
+        self.insert_state(vec_desc, || StateOrigin::Initial(selected.clone()))
+    }
+
+    pub fn state_origin(&self, state: StateID) -> &StateOrigin {
+        &self.origins[state.as_usize()]
     }
 
     #[inline(always)]
@@ -427,7 +445,10 @@ impl RegexVec {
             // If all the greedy lexemes so far are matches.
             if all_eoi {
                 // If this greedy lexeme is at end of lexeme ...
-                if self.next_byte.next_byte(&self.exprs, e) == NextByte::ForcedEOI {
+                // (lexemes with follow set need to see the next byte, so they never are)
+                if !self.has_follow.contains(idx)
+                    && self.next_byte.next_byte(&self.exprs, e) == NextByte::ForcedEOI
+                {
                     // then, if we have not yet found a matching greedy lexeme, set
                     // this one to be our lowest match ...
                     eois.add(idx);
@@ -475,7 +496,9 @@ impl RegexVec {
                 Self::push_rx(&mut vec_desc, idx, e);
             }
         }
-        self.insert_state(vec_desc)
+        self.insert_state(vec_desc, || {
+            StateOrigin::Limit(state, allowed_lexemes.clone())
+        })
     }
 
     pub fn total_fuel_spent(&self) -> u64 {
@@ -547,6 +570,7 @@ impl RegexVec {
 pub(crate) struct RxLexeme {
     pub rx: ExprRef,
     pub lazy: bool,
+    pub has_follow: bool,
     #[allow(dead_code)]
     pub priority: i32,
 }
@@ -597,10 +621,14 @@ impl RegexVec {
         }
 
         let mut lazy = LexemeSet::new(rx_lexemes.len());
+        let mut has_follow = LexemeSet::new(rx_lexemes.len());
         for (idx, r) in rx_lexemes.iter().enumerate() {
             if r.lazy {
                 lazy.add(LexemeIdx::new(idx));
             }
+            if r.has_follow {
+                has_follow.add(LexemeIdx::new(idx));
+            }
         }
 
         let rx_sets = StateID::new_hash_cons();
@@ -610,6 +638,7 @@ impl RegexVec {
             special_token_rx,
             relevance,
             lazy,
+            has_follow,
             rx_lexemes,
             exprs: exprset,
             alpha,
@@ -617,6 +646,7 @@ impl RegexVec {
             rx_sets,
             state_table: vec![],
             state_descs: vec![],
+            origins: vec![],
             num_transitions: 0,
             num_ast_nodes,
             fuel: u64::MAX,
@@ -625,9 +655,9 @@ impl RegexVec {
 
         assert!(r.lazy.len() == r.rx_list.len());
 
-        r.insert_state(vec![]);
+        r.insert_state(vec![], || StateOrigin::Fixed);
         // also append state for the "MISSING"
-        r.append_state(r.state_descs[0].clone());
+        r.append_state(r.state_descs[0].clone(), StateOrigin::Fixed);
         // in fact, transition from MISSING and DEAD should both lead to DEAD
         r.state_table.fill(StateID::DEAD);
         assert!(r.alpha.len() > 0);
@@ -638,25 +668,26 @@ impl RegexVec {
         self.rx_list[idx.as_usize()]
     }
 
-    fn append_state(&mut self, state_desc: StateDesc) {
+    fn append_state(&mut self, state_desc: StateDesc, origin: StateOrigin) {
         let mut new_states = vec![StateID::MISSING; self.alpha.len()];
         self.state_table.append(&mut new_states);
         self.state_descs.push(state_desc);
+        self.origins.push(origin);
         if self.state_descs.len() >= self.max_states {
             self.alpha.enter_error_state();
         }
     }
 
-    fn insert_state(&mut self, lst: Vec<u32>) -> StateID {
+    fn insert_state(&mut self, lst: Vec<u32>, origin: impl FnOnce() -> StateOrigin) -> StateID {
         // does this help?
         // if lst.len() == 0 {
         //     return StateID::DEAD;
         // }
-        assert!(lst.len() % 2 == 0);
+        assert!(lst.len().is_multiple_of(2));
         let id = StateID::new(self.rx_sets.insert(&lst));
         if id.as_usize() >= self.state_descs.len() {
             let state_desc = self.compute_state_desc(id);
-            self.append_state(state_desc);
+            self.append_state(state_desc, origin());
         }
         if self.state_desc(id).lazy_accepting.is_some() {
             id._set_lowest_match()
@@ -752,7 +783,7 @@ impl RegexVec {
         //     //     eprintln!("expr{}: {}", idx, self.exprs.expr_to_string(e));
         //     // }
         // }
-        let new_state = self.insert_state(vec_desc);
+        let new_state = self.insert_state(vec_desc, || StateOrigin::Transition(state, b));
         self.num_transitions += 1;
         self.state_table[idx] = new_state;
         new_state
@@ -185,7 +185,7 @@ impl BiasComputer for SlicedBiasComputer {
     fn compute_bias(&self, rec: &mut ParserRecognizer<'_>, start: &[u8]) -> SimpleVob {
         let mut set = self.trie().alloc_token_set();
         let lexer_state = rec.lexer_state();
-        if self.slices.len() > 0
+        if !self.slices.is_empty()
             && start.is_empty()
             && rec.lexer_mut().subsume_possible(lexer_state)
         {
@@ -1,14 +1,99 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, OnceLock};
 
 use anyhow::Result;
 use toktrie::{InferenceCapabilities, TokEnv};
 
 use crate::{
-    api::{GrammarInit, ParserLimits, TopLevelGrammar},
+    api::{Diagnostic, GrammarInit, ParserLimits, TopLevelGrammar},
     earley::{SlicedBiasComputer, XorShift},
-    Logger, TokenParser,
+    json::canonical_schema,
+    lark::normalize_lark,
+    mask_cache::{MaskCache, MaskCacheStats},
+    output::ValidateTextError,
+    HashMap, Logger, TokenParser,
 };
 
+/// Statistics of the compiled grammar cache in [`ParserFactory`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GrammarCacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+    /// Number of grammars currently in the cache.
+    pub entries: usize,
+    /// Approximate memory used by the cached grammars.
+    pub bytes: usize,
+    /// Limit on `bytes`; 0 means the cache is disabled.
+    pub max_bytes: usize,
+}
+
+struct CacheEntry {
+    // fresh parser, with lexer pre-warmed by computing the first mask;
+    // parsers are deep copies of it, each with its own lexer,
+    // except with a mask cache, where the lexer is shared with all of them
+    parser: TokenParser,
+    num_bytes: usize,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct GrammarCache {
+    entries: HashMap<String, CacheEntry>,
+    clock: u64,
+    stats: GrammarCacheStats,
+}
+
+impl GrammarCache {
+    fn lookup(&mut self, key: &str) -> Option<TokenParser> {
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some(e) => {
+                e.last_used = self.clock;
+                self.stats.hits += 1;
+                Some(e.parser.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, key: String, parser: TokenParser) {
+        let num_bytes =
+            key.len() + parser.parser.grammar().num_bytes() + parser.parser.lexer_stats().num_bytes;
+        if num_bytes > self.stats.max_bytes || self.entries.contains_key(&key) {
+            return;
+        }
+        self.clock += 1;
+        self.stats.bytes += num_bytes;
+        self.entries.insert(
+            key,
+            CacheEntry {
+                parser,
+                num_bytes,
+                last_used: self.clock,
+            },
+        );
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.stats.bytes > self.stats.max_bytes {
+            let key = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone())
+                .unwrap();
+            let e = self.entries.remove(&key).unwrap();
+            self.stats.bytes -= e.num_bytes;
+            self.stats.evictions += 1;
+        }
+        self.stats.entries = self.entries.len();
+    }
+}
+
 pub struct ParserFactory {
     tok_env: TokEnv,
     slicer: Arc<SlicedBiasComputer>,
@@ -17,6 +102,10 @@ pub struct ParserFactory {
     buffer_log_level: u32,
     limits: ParserLimits,
     seed: Mutex<XorShift>,
+    grammar_cache: Mutex<GrammarCache>,
+    mask_cache_size: usize,
+    // see TokenParser::grammar_fingerprint()
+    tokenizer_fingerprint: Arc<OnceLock<u64>>,
 }
 
 impl ParserFactory {
@@ -34,6 +123,9 @@ impl ParserFactory {
             buffer_log_level: 0,
             seed: Mutex::new(XorShift::default()),
             limits: ParserLimits::default(),
+            grammar_cache: Mutex::new(GrammarCache::default()),
+            mask_cache_size: 0,
+            tokenizer_fingerprint: Arc::new(OnceLock::new()),
         })
     }
 
@@ -65,6 +157,60 @@ impl ParserFactory {
         self
     }
 
+    /// Cache compiled grammars (keyed by the canonicalized grammar and limits)
+    /// up to about `max_bytes` of memory, evicting least recently used ones.
+    /// Grammars differing only in formatting share the cache entry:
+    /// whitespace and comments in Lark, trailing whitespace and blank lines
+    /// in GBNF and ABNF, and the order of keys in JSON schemas and options
+    /// (except for "properties", where the order is significant).
+    /// The cache is disabled by default (`max_bytes == 0`).
+    /// Compilation warnings are only logged when the grammar is compiled.
+    pub fn set_grammar_cache_size(&mut self, max_bytes: usize) -> &mut Self {
+        let mut cache = self.grammar_cache.lock().unwrap();
+        cache.stats.max_bytes = max_bytes;
+        cache.evict();
+        drop(cache);
+        self
+    }
+
+    pub fn grammar_cache_stats(&self) -> GrammarCacheStats {
+        self.grammar_cache.lock().unwrap().stats.clone()
+    }
+
+    /// Memoize masks across parsers created from the same cached grammar,
+    /// up to about `max_bytes` of masks per grammar.
+    /// The masks are dropped together with the grammar when it is evicted
+    /// from the grammar cache, so this requires [`Self::set_grammar_cache_size`].
+    /// Only applies to grammars compiled after this call; 0 (the default) disables it.
+    ///
+    /// Every parser has its own lexer; states of the lexers are numbered
+    /// by a copy of the lexer shared by parsers of the grammar, which takes up
+    /// to about `max_bytes` as well.
+    /// Grammars using `max_tokens` are not cached.
+    pub fn set_mask_cache_size(&mut self, max_bytes: usize) -> &mut Self {
+        self.mask_cache_size = max_bytes;
+        self
+    }
+
+    /// Sum of statistics of mask caches of the grammars currently in the cache.
+    pub fn mask_cache_stats(&self) -> MaskCacheStats {
+        let cache = self.grammar_cache.lock().unwrap();
+        let mut stats = MaskCacheStats::default();
+        for e in cache.entries.values() {
+            if let Some(mc) = &e.parser.mask_cache {
+                stats.add(&mc.stats());
+            }
+        }
+        stats
+    }
+
+    pub fn clear_grammar_cache(&self) {
+        let mut cache = self.grammar_cache.lock().unwrap();
+        cache.entries.clear();
+        cache.stats.bytes = 0;
+        cache.stats.entries = 0;
+    }
+
     pub fn extra_lexemes(&self) -> Vec<String> {
         self.slicer.extra_lexemes()
     }
@@ -114,6 +260,40 @@ impl ParserFactory {
         )
     }
 
+    /// Check the grammar for errors and warnings, without creating a parser.
+    pub fn validate_grammar(&self, grammar: TopLevelGrammar) -> Vec<Diagnostic> {
+        GrammarInit::Serialized(grammar).validate(Some(self.tok_env.clone()), self.limits.clone())
+    }
+
+    /// Like [`ParserFactory::validate_grammar`], but also run lints for
+    /// unused, non-productive and known-slow constructs.
+    pub fn lint_grammar(&self, grammar: TopLevelGrammar) -> Vec<Diagnostic> {
+        GrammarInit::Serialized(grammar).lint(Some(self.tok_env.clone()), self.limits.clone())
+    }
+
+    /// Check if `text` matches the grammar; useful for auditing text generated without
+    /// constraints. Fails either with a compilation error, or a
+    /// [`TextValidationError`](crate::output::TextValidationError)
+    /// if the text doesn't match.
+    pub fn validate_text(
+        &self,
+        grammar: TopLevelGrammar,
+        text: &[u8],
+    ) -> std::result::Result<(), ValidateTextError> {
+        let parser = self
+            .create_parser(grammar)
+            .map_err(ValidateTextError::Grammar)?;
+        parser.validate_text(text)?;
+        Ok(())
+    }
+
+    /// Compile the grammar and render it back as Lark source,
+    /// one Lark grammar per input grammar or subgrammar
+    /// (see [`GrammarInit::to_lark`]).
+    pub fn export_lark(&self, grammar: TopLevelGrammar) -> Result<TopLevelGrammar> {
+        GrammarInit::Serialized(grammar).to_lark(Some(self.tok_env.clone()), self.limits.clone())
+    }
+
     pub fn create_parser_from_init_default(&self, init: GrammarInit) -> Result<TokenParser> {
         self.create_parser_from_init(init, self.buffer_log_level, self.stderr_log_level)
     }
@@ -123,6 +303,54 @@ impl ParserFactory {
         init: GrammarInit,
         buffer_log_level: u32,
         stderr_log_level: u32,
+    ) -> Result<TokenParser> {
+        let key = match &init {
+            GrammarInit::Serialized(grammar)
+                if self.grammar_cache.lock().unwrap().stats.max_bytes > 0 =>
+            {
+                Some(cache_key(grammar, &self.limits)?)
+            }
+            _ => None,
+        };
+        let key = match key {
+            Some(key) => key,
+            None => return self.compile_parser(init, buffer_log_level, stderr_log_level),
+        };
+
+        let cached = self.grammar_cache.lock().unwrap().lookup(&key);
+        if let Some(cached) = cached {
+            // the lexer is copied outside of the cache lock
+            let mut parser = cached.deep_clone();
+            parser.logger = Logger::new(buffer_log_level, stderr_log_level);
+            self.post_process_parser(&mut parser);
+            return Ok(parser);
+        }
+
+        let mut parser = self.compile_parser(init, buffer_log_level, stderr_log_level)?;
+
+        // Shallow clone shares the lexer with the returned parser,
+        // so computing the first mask warms up the lexer for both.
+        let mut warm = parser.clone();
+        warm.logger = Logger::new(0, 0);
+        warm.start_without_prompt();
+        let _ = warm.compute_mask();
+
+        if self.mask_cache_size > 0 && !parser.parser.grammar().lexer_spec().has_max_tokens {
+            let dfa = parser.parser.lexer_dfa();
+            parser.mask_cache = Some(Arc::new(MaskCache::new(self.mask_cache_size, dfa)));
+        }
+        self.grammar_cache
+            .lock()
+            .unwrap()
+            .insert(key, parser.deep_clone());
+        Ok(parser)
+    }
+
+    fn compile_parser(
+        &self,
+        init: GrammarInit,
+        buffer_log_level: u32,
+        stderr_log_level: u32,
     ) -> Result<TokenParser> {
         let mut parser = TokenParser::from_init(
             self.tok_env.clone(),
@@ -132,7 +360,430 @@ impl ParserFactory {
             self.limits.clone(),
             self.extra_lexemes(),
         )?;
+        parser.tokenizer_fingerprint = self.tokenizer_fingerprint.clone();
         self.post_process_parser(&mut parser);
         Ok(parser)
     }
 }
+
+/// Key of the grammar in the grammar cache.
+fn cache_key(grammar: &TopLevelGrammar, limits: &ParserLimits) -> Result<String> {
+    let mut grammar = grammar.clone();
+    for g in grammar.grammars.iter_mut() {
+        if let Some(schema) = &g.json_schema {
+            g.json_schema = Some(canonical_schema(schema));
+        }
+        if let Some(lark) = &g.lark_grammar {
+            // on lexer errors, compilation fails anyway
+            if let Ok(lark) = normalize_lark(lark) {
+                g.lark_grammar = Some(lark);
+            }
+        }
+        for text in [&mut g.gbnf_grammar, &mut g.abnf_grammar]
+            .into_iter()
+            .flatten()
+        {
+            *text = text
+                .lines()
+                .map(|l| l.trim_end())
+                .filter(|l| !l.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
+    }
+    Ok(serde_json::to_string(&(grammar, limits))?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::GrammarWithLexer;
+    use crate::output::{TextValidationError, ValidateTextError};
+    use crate::test_util::{factory, lark};
+
+    #[test]
+    fn test_grammar_cache() {
+        let mut f = factory();
+        f.create_parser(lark("start: \"a\"")).unwrap();
+        assert_eq!(f.grammar_cache_stats(), GrammarCacheStats::default());
+
+        f.set_grammar_cache_size(10_000_000);
+        let mut p1 = f.create_parser(lark("start: \"a\"+")).unwrap();
+        let mut p2 = f.create_parser(lark("start: \"a\"+")).unwrap();
+        f.create_parser(lark("start: \"b\"+")).unwrap();
+        let stats = f.grammar_cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 2));
+        assert!(stats.bytes > 0);
+
+        // cached parsers are independent
+        p1.start_without_prompt();
+        p1.consume_token(b'a' as u32).unwrap();
+        p2.start_without_prompt();
+        assert_eq!(p1.num_tokens(), 1);
+        assert_eq!(p2.num_tokens(), 0);
+        assert!(p2.compute_mask().unwrap().is_allowed(b'a' as u32));
+
+        // limits are part of the key
+        f.limits_mut().max_items_in_row += 1;
+        f.create_parser(lark("start: \"a\"+")).unwrap();
+        assert_eq!(f.grammar_cache_stats().misses, 3);
+
+        // errors are not cached
+        assert!(f.create_parser(lark("start: foo")).is_err());
+        assert_eq!(f.grammar_cache_stats().entries, 3);
+
+        // least recently used is evicted first
+        f.limits_mut().max_items_in_row -= 1;
+        let max_bytes = stats.bytes;
+        f.create_parser(lark("start: \"b\"+")).unwrap();
+        f.set_grammar_cache_size(max_bytes);
+        let stats = f.grammar_cache_stats();
+        assert!(stats.evictions >= 1);
+        assert!(stats.bytes <= max_bytes);
+        f.create_parser(lark("start: \"b\"+")).unwrap();
+        assert_eq!(f.grammar_cache_stats().hits, stats.hits + 1);
+
+        f.clear_grammar_cache();
+        assert_eq!(f.grammar_cache_stats().entries, 0);
+        f.set_grammar_cache_size(0);
+        let stats = f.grammar_cache_stats();
+        f.create_parser(lark("start: \"b\"+")).unwrap();
+        assert_eq!(f.grammar_cache_stats(), stats);
+    }
+
+    #[test]
+    fn test_grammar_cache_key() {
+        let mut f = factory();
+        f.set_grammar_cache_size(10_000_000);
+        let hits = |f: &ParserFactory| f.grammar_cache_stats().hits;
+
+        f.create_parser(lark(
+            "start: \"a\"+ b\nb: %json {\"type\": \"integer\", \"minimum\": 0}",
+        ))
+        .unwrap();
+        f.create_parser(lark(
+            "// formatting only\r\nstart :  \"a\" +   b  # b\n\n\nb:%json {\n  \"minimum\": 0,\n  \"type\": \"integer\"\n}\n",
+        ))
+        .unwrap();
+        assert_eq!(hits(&f), 1);
+        f.create_parser(lark(
+            "start: \"a\"+ b\nb: %json {\"type\": \"integer\", \"minimum\": 1}",
+        ))
+        .unwrap();
+        assert_eq!(hits(&f), 1);
+
+        let schema = |s: &str| TopLevelGrammar::from_json_schema(serde_json::from_str(s).unwrap());
+        f.create_parser(schema(
+            r#"{"type": "object", "properties": {"a": {"type": "string", "maxLength": 3}, "b": {}}}"#,
+        ))
+        .unwrap();
+        f.create_parser(schema(
+            r#"{"properties": {"a": {"maxLength": 3, "type": "string"}, "b": {}}, "type": "object"}"#,
+        ))
+        .unwrap();
+        assert_eq!(hits(&f), 2);
+        // order of properties is significant
+        f.create_parser(schema(
+            r#"{"type": "object", "properties": {"b": {}, "a": {"type": "string", "maxLength": 3}}}"#,
+        ))
+        .unwrap();
+        assert_eq!(hits(&f), 2);
+
+        f.create_parser(TopLevelGrammar::from_gbnf("root ::= \"a\"+\n".to_string()))
+            .unwrap();
+        f.create_parser(TopLevelGrammar::from_gbnf(
+            "root ::= \"a\"+   \r\n\r\n".to_string(),
+        ))
+        .unwrap();
+        assert_eq!(hits(&f), 3);
+        assert_eq!(f.grammar_cache_stats().entries, 5);
+    }
+
+    #[test]
+    fn test_mask_cache() {
+        let grm = || lark("start: (\"[\" /[a-z]+/ \"]\")+");
+        let mut f = factory();
+        let mut plain = f.create_parser(grm()).unwrap();
+        f.set_grammar_cache_size(10_000_000);
+        f.set_mask_cache_size(1_000_000);
+        let mut p1 = f.create_parser(grm()).unwrap();
+        let mut p2 = f.create_parser(grm()).unwrap();
+        assert!(f.mask_cache_stats().max_bytes > 0);
+
+        // max_tokens makes the mask depend on the token count
+        f.create_parser(lark("start: x\nx[max_tokens=3]: /[a-z]+/"))
+            .unwrap();
+        assert_eq!(f.mask_cache_stats().max_bytes, 1_000_000);
+
+        plain.start_without_prompt();
+        p1.start_without_prompt();
+        p2.start_without_prompt();
+        for &b in b"[ab][c" {
+            p1.compute_mask().unwrap();
+            p1.consume_token(b as u32).unwrap();
+        }
+        let hits = f.mask_cache_stats().hits;
+        for &b in b"[ab][cd]" {
+            let m0 = plain.compute_mask().unwrap();
+            assert_eq!(p2.compute_mask().unwrap(), m0);
+            plain.consume_token(b as u32).unwrap();
+            p2.consume_token(b as u32).unwrap();
+        }
+        let stats = f.mask_cache_stats();
+        assert!(stats.hits >= hits + 6);
+        assert!(stats.entries > 0 && stats.bytes > 0);
+        assert!(stats.hit_rate() > 0.0);
+
+        // deep copies keep using the cache
+        let copy = p2.deep_clone();
+        assert!(copy.mask_cache.is_some());
+
+        // masks go away with the grammar
+        f.clear_grammar_cache();
+        assert_eq!(f.mask_cache_stats(), MaskCacheStats::default());
+    }
+
+    #[test]
+    fn test_mask_cache_json_strings() {
+        let mut f = factory();
+        f.set_grammar_cache_size(10_000_000);
+        f.set_mask_cache_size(10_000_000);
+        let grm = r#"start: %json {
+            "type": "object",
+            "properties": {"summary": {"type": "string"}},
+            "required": ["summary"]
+        }"#;
+        let prefix = r#"{"summary":""#;
+        for (i, text) in ["abc def", "xyz", "Hello, World!"].iter().enumerate() {
+            let mut p = f.create_parser(lark(grm)).unwrap();
+            p.start_without_prompt();
+            let text = format!("{prefix}{text}\"}}");
+            for (j, &b) in text.as_bytes().iter().enumerate() {
+                let hits = f.mask_cache_stats().hits;
+                p.compute_mask().unwrap();
+                // inside of the string, the mask doesn't depend on its contents
+                if i > 0 && j > prefix.len() && j < text.len() - 2 {
+                    assert_eq!(f.mask_cache_stats().hits, hits + 1, "{text:?} {j}");
+                }
+                p.consume_token(b as u32).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_mask_cache_matches_plain() {
+        let check = |grm: &str, texts: &[&str]| {
+            let mut f = factory();
+            f.set_grammar_cache_size(10_000_000);
+            f.set_mask_cache_size(10_000_000);
+            for text in texts {
+                let mut plain = factory().create_parser(lark(grm)).unwrap();
+                let mut cached = f.create_parser(lark(grm)).unwrap();
+                plain.start_without_prompt();
+                cached.start_without_prompt();
+                for &b in text.as_bytes() {
+                    let m = plain.compute_mask().unwrap();
+                    assert_eq!(cached.compute_mask().unwrap(), m, "{grm:?} {text:?}");
+                    plain.consume_token(b as u32).unwrap();
+                    cached.consume_token(b as u32).unwrap();
+                }
+            }
+            assert!(f.mask_cache_stats().hits > 0);
+        };
+
+        check(
+            "start: (\"[\" /[a-z]+/ \"]\")+",
+            &["[ab][c]", "[a][bc]", "[abc]"],
+        );
+        check(
+            "start: A B+\nA: /[a-z]+(?![0-9])/\nB: /[0-9é]/\n%ignore /[ \\t]+/",
+            &["ab é", "abc 1 2", "a é1"],
+        );
+        check(
+            "start: \"<\" x y\nx[lazy]: /[a-z]*>/\ny[stop=\"!\"]: /[a-z]*/",
+            &["<ab>cd!", "<a>b!", "<abc>d!"],
+        );
+        check(
+            "start: %json {\"type\": \"object\", \"properties\": {\"a\": {\"type\": \"string\"}}}",
+            &["{\"a\":\"xy\"}", "{\"a\": \"x\"}", "{ }"],
+        );
+    }
+
+    #[test]
+    fn test_lazy_fingerprint() {
+        let f = factory();
+        let p1 = f.create_parser(lark("start: \"a\"")).unwrap();
+        let p2 = f.create_parser(lark("start: \"b\"")).unwrap();
+        // nothing is hashed until a snapshot is needed
+        assert!(f.tokenizer_fingerprint.get().is_none());
+        assert!(p1.grammar_fingerprint.get().is_none());
+
+        let fp = p1.grammar_fingerprint();
+        let tok = *f.tokenizer_fingerprint.get().unwrap();
+        assert_eq!(p1.clone().grammar_fingerprint.get(), Some(&fp));
+        assert_ne!(p2.grammar_fingerprint(), fp);
+        // the vocabulary is hashed once per factory
+        assert_eq!(*p2.tokenizer_fingerprint.get().unwrap(), tok);
+    }
+
+    #[test]
+    fn test_lint_compiled_grammar() {
+        let f = factory();
+        let codes = |g: TopLevelGrammar| {
+            f.lint_grammar(g)
+                .into_iter()
+                .map(|d| (d.code.unwrap_or_default(), d.message))
+                .collect::<Vec<_>>()
+        };
+
+        let d = codes(TopLevelGrammar::from_gbnf(
+            "root ::= \"a\" | bad\nbad ::= \"x\" bad\n".to_string(),
+        ));
+        assert_eq!(d.len(), 1);
+        assert_eq!(d[0].0, "non-productive");
+        assert!(d[0].1.contains("\"bad\""));
+
+        let d = codes(TopLevelGrammar::from_json_schema(serde_json::json!({
+            "$defs": {"a": {"type": "array", "items": {"$ref": "#/$defs/a"}, "minItems": 1}},
+            "anyOf": [{"type": "null"}, {"$ref": "#/$defs/a"}]
+        })));
+        assert_eq!(d.len(), 1);
+        assert_eq!(d[0].0, "non-productive");
+        assert!(d[0].1.contains("$defs/a"));
+
+        // already reported by the Lark lints, with a location
+        let d = f.lint_grammar(lark("start: a | \"b\"\na: \"x\" a\n"));
+        assert_eq!(d.len(), 1);
+        assert!(d[0].line > 0);
+
+        let mut grm = lark("start: \"a\"");
+        let mut other = GrammarWithLexer::from_lark("start: x\nx: \"a\" | \"b\"".to_string());
+        other.name = Some("other".to_string());
+        grm.grammars.push(other);
+        // "other" is never referenced, so its rules are unused
+        let d = codes(grm);
+        assert_eq!(d.len(), 2);
+        assert!(d.iter().all(|(c, _)| c == "unused-rule"));
+        assert!(d.iter().any(|(_, m)| m.contains("\"x\"")));
+
+        assert!(codes(lark("start: x*\nx: \"a\" | \"b\"")).is_empty());
+        assert!(codes(lark("start: %json {\"type\": \"object\"}")).is_empty());
+    }
+
+    #[test]
+    fn test_validate_lexer_errors() {
+        let mut f = factory();
+        // the grammar is small, but checking if the lexeme is non-empty is expensive
+        f.limits_mut().initial_lexer_fuel = 5000;
+        let grm = TopLevelGrammar::from_json_schema(serde_json::json!({
+            "type": "string", "pattern": "^[ab]*a[ab]{6}$", "minLength": 20, "maxLength": 40
+        }));
+        let d = f.validate_grammar(grm.clone());
+        assert_eq!(d.len(), 1);
+        assert!(d[0].message.contains("fuel exhausted"));
+        assert!(f.create_parser(grm).is_err());
+    }
+
+    #[test]
+    fn test_lookahead_lexing() {
+        let f = factory();
+        let ok = |grm: &str, text: &str| f.validate_text(lark(grm), text.as_bytes()).is_ok();
+
+        let grm = "start: A B\nA: /[a-z]+(?![0-9])/\nB: /[0-9é]*/";
+        assert!(ok(grm, "abc"));
+        assert!(ok(grm, "abcé"));
+        assert!(!ok(grm, "abc1"));
+
+        let grm = "start: A B\nA: /[a-z]+(?=[^a-z])/\nB: /[0-9é]+/";
+        assert!(ok(grm, "abcé1"));
+        assert!(ok(grm, "abc1"));
+        assert!(!ok(grm, "abc"));
+
+        let grm = "start: KW B\nKW: /(?i)if(?![a-z])/\nB: /[a-z(]+/";
+        assert!(ok(grm, "IF("));
+        assert!(!ok(grm, "ifx"));
+
+        let e = f
+            .validate_text(lark("start: A\nA: /[a-z]+(?!\\w)/"), b"abc")
+            .unwrap_err();
+        assert!(e.to_string().contains("(?-u:\\w)"));
+    }
+
+    #[test]
+    fn test_validate_text() {
+        let f = factory();
+        let check = |grm: &str, text: &str| {
+            f.validate_text(lark(grm), text.as_bytes())
+                .err()
+                .map(|e| match e {
+                    ValidateTextError::Text(e) => e,
+                    e => panic!("{}", e),
+                })
+        };
+        let expected = |e: &TextValidationError| {
+            e.expected
+                .lexemes
+                .iter()
+                .map(|l| l.regex.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        let grm = r#"
+            start: "[" NUM ("," NUM)* "]"
+            NUM: /[0-9]+/
+        "#;
+        assert_eq!(check(grm, "[1,23]"), None);
+
+        let e = check(grm, "[1,2x]").unwrap();
+        assert_eq!(e.offset, 4);
+        assert_eq!(e.partial_lexeme, "2");
+        assert_eq!(expected(&e), "(['0'-'9'])+ ',' ']'");
+        assert_eq!(e.expected.lexemes[0].name, "NUM");
+        assert_eq!(e.expected.rules, vec!["star ::= star ',' • NUM"]);
+        assert_eq!(e.message, "unexpected 'x' at offset 4");
+
+        // non-ASCII text is decoded in the message
+        let e = check(grm, "[1,é]").unwrap();
+        assert_eq!(e.message, "unexpected 'é' at offset 3");
+        let e = check(grm, "[1,\u{2}]").unwrap();
+        assert_eq!(e.message, "unexpected '\\u{2}' at offset 3");
+
+        let e = check(grm, "[1,").unwrap();
+        assert_eq!((e.offset, e.partial_lexeme.as_str()), (3, ""));
+        assert_eq!(expected(&e), "(['0'-'9'])+");
+
+        let e = check(grm, "[1]]").unwrap();
+        assert_eq!(e.offset, 3);
+        assert!(e.expected.lexemes.is_empty());
+        assert!(e.expected.eos_allowed);
+
+        // bytes are tokens here
+        let grm = r#"
+            start: x "!"
+            x[max_tokens=3]: /[a-z]+/
+        "#;
+        assert_eq!(check(grm, "abc!"), None);
+        assert_eq!(check(grm, "abcd!").unwrap().offset, 3);
+
+        let grm = r#"
+            start: x "!"
+            x[stop="END"]: /[a-z]*/
+        "#;
+        assert_eq!(check(grm, "abcEND!"), None);
+        let e = check(grm, "abc!").unwrap();
+        assert_eq!((e.offset, e.partial_lexeme.as_str()), (3, "abc"));
+
+        // compilation errors are passed through
+        let e = f.validate_text(lark("start: foo"), b"").unwrap_err();
+        assert!(matches!(e, ValidateTextError::Grammar(_)));
+
+        // bytes that are not valid UTF-8
+        let e = f
+            .create_parser(lark("start: \"a\" /[a-z]+/"))
+            .unwrap()
+            .validate_text(b"a\xff")
+            .unwrap_err();
+        assert_eq!(e.message, "unexpected '\\xff' at offset 1");
+    }
+}
@@ -5,10 +5,14 @@ use std::{
 };
 
 use anyhow::{bail, ensure, Result};
-use toktrie::{InferenceCapabilities, TokEnv, TokRxInfo, TokTrie, TokenizerEnv};
+use serde::Serialize;
+use toktrie::{
+    InferenceCapabilities, StepResult, TokEnv, TokRxInfo, TokTrie, TokenId, TokenizerEnv,
+};
 
 use crate::{
-    api::{ParserLimits, TopLevelGrammar},
+    api::{Diagnostic, GrammarInit, ParserLimits, TopLevelGrammar},
+    output::ValidateTextError,
     CommitResult, Constraint, Logger, ParserFactory, StopController, TokenParser,
 };
 
@@ -350,6 +354,21 @@ impl LlgCommitResult {
     }
 }
 
+/// Where llg_compute_draft_masks() stopped following the draft,
+/// because llg_commit_token() would backtrack or append forced tokens
+/// after the last accepted draft token.
+#[repr(C)]
+pub struct LlgDraftSplice {
+    /// The number of tokens to remove from the end of the output
+    /// (before the last accepted draft token), 0 if there is no splice
+    pub backtrack: u32,
+    /// The tokens to append to the output instead of the last accepted draft token
+    /// This is valid until any call to llg_*() on the current constraint
+    pub tokens: *const u32,
+    /// The number of tokens in the tokens array, 0 if there is no splice
+    pub n_tokens: u32,
+}
+
 unsafe fn c_str_to_str<'a>(c_str: *const c_char, info: &str) -> Result<&'a str> {
     CStr::from_ptr(c_str)
         .to_str()
@@ -368,19 +387,52 @@ fn new_constraint_lark(init: &LlgConstraintInit, lark: *const c_char) -> Result<
     init.build_constraint(grammar)
 }
 
-fn new_constraint_json(init: &LlgConstraintInit, json_schema: *const c_char) -> Result<Constraint> {
+fn new_constraint_gbnf(init: &LlgConstraintInit, gbnf: *const c_char) -> Result<Constraint> {
+    let gbnf = unsafe { c_str_to_str(gbnf, "gbnf") }?;
+    let grammar = TopLevelGrammar::from_gbnf(gbnf.to_string());
+    init.build_constraint(grammar)
+}
+
+fn new_constraint_abnf(init: &LlgConstraintInit, abnf: *const c_char) -> Result<Constraint> {
+    let abnf = unsafe { c_str_to_str(abnf, "abnf") }?;
+    let grammar = TopLevelGrammar::from_abnf(abnf.to_string());
+    init.build_constraint(grammar)
+}
+
+fn json_schema_grammar(json_schema: *const c_char) -> Result<TopLevelGrammar> {
     let json_schema = unsafe { c_str_to_str(json_schema, "json_schema") }?;
     let json_schema = serde_json::from_str(json_schema)
         .map_err(|e| anyhow::anyhow!("Invalid JSON in json_schema: {e}"))?;
-    let grammar = TopLevelGrammar::from_json_schema(json_schema);
-    init.build_constraint(grammar)
+    Ok(TopLevelGrammar::from_json_schema(json_schema))
 }
 
-fn new_constraint(init: &LlgConstraintInit, grammar_json: *const c_char) -> Result<Constraint> {
+fn new_constraint_json(init: &LlgConstraintInit, json_schema: *const c_char) -> Result<Constraint> {
+    init.build_constraint(json_schema_grammar(json_schema)?)
+}
+
+fn guidance_grammar(grammar_json: *const c_char) -> Result<TopLevelGrammar> {
     let grammar_json = unsafe { c_str_to_str(grammar_json, "grammar_json") }?;
     let grammar: TopLevelGrammar = serde_json::from_str(grammar_json)
         .map_err(|e| anyhow::anyhow!("Invalid JSON in grammar_json: {e}"))?;
-    init.build_constraint(grammar)
+    Ok(grammar)
+}
+
+fn new_constraint(init: &LlgConstraintInit, grammar_json: *const c_char) -> Result<Constraint> {
+    init.build_constraint(guidance_grammar(grammar_json)?)
+}
+
+fn grammar_any(constraint_type: *const c_char, data: *const c_char) -> Result<TopLevelGrammar> {
+    let tp = unsafe { c_str_to_str(constraint_type, "constraint_type") }?;
+    let text = || unsafe { c_str_to_str(data, tp) };
+    match tp {
+        "regex" => Ok(TopLevelGrammar::from_regex(text()?)),
+        "json" | "json_schema" => json_schema_grammar(data),
+        "lark" => Ok(TopLevelGrammar::from_lark(text()?.to_string())),
+        "gbnf" => Ok(TopLevelGrammar::from_gbnf(text()?.to_string())),
+        "abnf" => Ok(TopLevelGrammar::from_abnf(text()?.to_string())),
+        "llguidance" | "guidance" => guidance_grammar(data),
+        _ => bail!("unknown constraint type: {tp}"),
+    }
 }
 
 fn new_constraint_any(
@@ -388,14 +440,7 @@ fn new_constraint_any(
     constraint_type: *const c_char,
     data: *const c_char,
 ) -> Result<Constraint> {
-    let tp = unsafe { c_str_to_str(constraint_type, "constraint_type") }?;
-    match tp {
-        "regex" => new_constraint_regex(init, data),
-        "json" | "json_schema" => new_constraint_json(init, data),
-        "lark" => new_constraint_lark(init, data),
-        "llguidance" | "guidance" => new_constraint(init, data),
-        _ => bail!("unknown constraint type: {tp}"),
-    }
+    init.build_constraint(grammar_any(constraint_type, data)?)
 }
 
 impl LlgConstraint {
@@ -490,8 +535,29 @@ pub extern "C" fn llg_new_constraint_lark(
     constraint_to_llg(new_constraint_lark(init, lark))
 }
 
+/// Create a new constraint from a given GBNF (llama.cpp) grammar
+/// Always returns a non-null value. Call llg_get_error() on the result to check for errors.
+#[no_mangle]
+pub extern "C" fn llg_new_constraint_gbnf(
+    init: &LlgConstraintInit,
+    gbnf: *const c_char,
+) -> *mut LlgConstraint {
+    constraint_to_llg(new_constraint_gbnf(init, gbnf))
+}
+
+/// Create a new constraint from a given ABNF (RFC 5234) grammar; the first rule is the start rule
+/// Always returns a non-null value. Call llg_get_error() on the result to check for errors.
+#[no_mangle]
+pub extern "C" fn llg_new_constraint_abnf(
+    init: &LlgConstraintInit,
+    abnf: *const c_char,
+) -> *mut LlgConstraint {
+    constraint_to_llg(new_constraint_abnf(init, abnf))
+}
+
 /// Create a new constraint with specified type
-/// Type can be one of "regex", "json_schema" (or "json"), "lark", "llguidance" (or "guidance")
+/// Type can be one of "regex", "json_schema" (or "json"), "lark", "gbnf", "abnf",
+/// "llguidance" (or "guidance")
 /// Always returns a non-null value. Call llg_get_error() on the result to check for errors.
 #[no_mangle]
 pub extern "C" fn llg_new_constraint_any(
@@ -502,6 +568,112 @@ pub extern "C" fn llg_new_constraint_any(
     constraint_to_llg(new_constraint_any(init, constraint_type, data))
 }
 
+/// Check the grammar for errors and warnings, without creating a constraint.
+/// The type and data are as in llg_new_constraint_any().
+/// Writes a JSON array of diagnostics to the output buffer (it's "[]" if the grammar is fine).
+/// Each diagnostic has fields "severity" ("error" or "warning"), "message",
+/// "line" and "column" (1-based; 0 when unknown), "span" ([start, end] byte offsets or null),
+/// and "code" (kind of lint warning, like "unused-rule", or null).
+/// Returns the number of bytes that would be written to output, if output_len was large enough
+/// (including the terminating null); the output is truncated otherwise.
+/// With output_len == 0, output is not written to (and can be null).
+/// # Safety
+/// This function should only be called from C code.
+#[no_mangle]
+pub unsafe extern "C" fn llg_validate_grammar(
+    init: &LlgConstraintInit,
+    constraint_type: *const c_char,
+    data: *const c_char,
+    output: *mut c_char,
+    output_len: usize,
+) -> usize {
+    let diagnostics = match grammar_any(constraint_type, data) {
+        Ok(grammar) => {
+            GrammarInit::Serialized(grammar).validate(init.tok_env().ok(), init.limits.clone())
+        }
+        Err(e) => vec![Diagnostic::error(e.to_string())],
+    };
+    unsafe { write_json(&diagnostics, output, output_len) }
+}
+
+/// Like llg_validate_grammar(), but also run lints, reporting unused rules and terminals,
+/// rules that can't match anything, and constructs known to be slow.
+/// # Safety
+/// This function should only be called from C code.
+#[no_mangle]
+pub unsafe extern "C" fn llg_lint_grammar(
+    init: &LlgConstraintInit,
+    constraint_type: *const c_char,
+    data: *const c_char,
+    output: *mut c_char,
+    output_len: usize,
+) -> usize {
+    let diagnostics = match grammar_any(constraint_type, data) {
+        Ok(grammar) => {
+            GrammarInit::Serialized(grammar).lint(init.tok_env().ok(), init.limits.clone())
+        }
+        Err(e) => vec![Diagnostic::error(e.to_string())],
+    };
+    unsafe { write_json(&diagnostics, output, output_len) }
+}
+
+/// Check if the text (arbitrary bytes, not tokens) matches the grammar;
+/// useful for auditing text generated without constraints.
+/// The type and data are as in llg_new_constraint_any().
+/// Writes JSON to the output buffer: "null" if the text matches, or otherwise an object with
+/// "offset" (of the first byte that can't be accepted, or text_len if the text is incomplete),
+/// "partial_lexeme" (the lexeme being scanned at offset), "expected"
+/// (as in llg_expected_terminals()), and "message".
+/// If the grammar fails to compile, the object only has the "message".
+/// Returns the number of bytes that would be written to output, if output_len was large enough
+/// (including the terminating null); the output is truncated otherwise.
+/// With output_len == 0, output is not written to (and can be null).
+/// # Safety
+/// This function should only be called from C code.
+#[no_mangle]
+pub unsafe extern "C" fn llg_validate_text(
+    init: &LlgConstraintInit,
+    constraint_type: *const c_char,
+    data: *const c_char,
+    text: *const u8,
+    text_len: usize,
+    output: *mut c_char,
+    output_len: usize,
+) -> usize {
+    let text = if text_len == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(text, text_len) }
+    };
+    let res = grammar_any(constraint_type, data)
+        .and_then(|g| init.build_parser(g, vec![]))
+        .map_err(ValidateTextError::Grammar)
+        .and_then(|parser| Ok(parser.validate_text(text)?));
+    let res = match res {
+        Ok(()) => serde_json::Value::Null,
+        Err(ValidateTextError::Text(e)) => serde_json::to_value(e).unwrap(),
+        Err(ValidateTextError::Grammar(e)) => serde_json::json!({ "message": e.to_string() }),
+    };
+    unsafe { write_json(&res, output, output_len) }
+}
+
+/// Write null-terminated JSON to output, truncating it if needed.
+/// Returns the size needed for the whole JSON; with output_len == 0,
+/// output is not touched (and may be null), so that the size can be queried.
+unsafe fn write_json(value: &impl Serialize, output: *mut c_char, output_len: usize) -> usize {
+    let s = serde_json::to_string(value).unwrap();
+    let s = s.as_bytes();
+    if output_len == 0 {
+        return s.len() + 1;
+    }
+    let len = std::cmp::min(s.len(), output_len - 1);
+    unsafe {
+        std::ptr::copy_nonoverlapping(s.as_ptr(), output as *mut u8, len);
+        *output.add(len) = 0;
+    }
+    s.len() + 1
+}
+
 /// Get the error message from the constraint or null if there is no error.
 /// After it returns a non-null value, it will always return it until the constraint is freed
 /// using llg_free_constraint() (at which point the pointer will be invalid).
@@ -525,6 +697,83 @@ pub extern "C" fn llg_is_stopped(cc: &LlgConstraint) -> bool {
         .is_none_or(|c| c.step_result().is_stop())
 }
 
+/// Report captures while they are generated, in the progress objects logged
+/// with llg_set_log_json_progress(): besides "capture" (the full value, once complete),
+/// there are objects with "object" set to "capture_start",
+/// "capture_delta" (with "str" and "hex" of the new text),
+/// and "capture_end" (with "completed": false if the capture turned out not to be one).
+/// All of them have "name", and all but "capture" have "path" (names of enclosing captures).
+#[no_mangle]
+pub extern "C" fn llg_set_stream_captures(cc: &mut LlgConstraint, stream_captures: bool) {
+    if let Some(c) = &mut cc.constraint {
+        c.set_stream_captures(stream_captures);
+    }
+}
+
+/// Log progress after each compute_mask() and commit_token(): the logs
+/// (see llg_flush_logs()) get "JSON-OUT: " lines with JSON objects,
+/// with "object" set to "capture", "capture_provenance", or "text"
+/// (and more, see llg_set_stream_captures()).
+#[no_mangle]
+pub extern "C" fn llg_set_log_json_progress(cc: &mut LlgConstraint, log_json_progress: bool) {
+    if let Some(c) = &mut cc.constraint {
+        c.log_json_progress = log_json_progress;
+    }
+}
+
+/// Report what the constraint can accept at the current position,
+/// which is useful for debugging grammars and rejected generations.
+/// Writes a JSON object to the output buffer, with fields "lexemes"
+/// (array of objects with "name" and "regex" of lexemes that can come next),
+/// "rules" (array of rules in progress, like "a ::= b • c"), and "eos_allowed".
+/// Returns the number of bytes that would be written to output, if output_len was large enough
+/// (including the terminating null); the output is truncated otherwise.
+/// With output_len == 0, output is not written to (and can be null).
+/// Returns 0 if the constraint is in error state (nothing is written then).
+/// # Safety
+/// This function should only be called from C code.
+#[no_mangle]
+pub unsafe extern "C" fn llg_expected_terminals(
+    cc: &mut LlgConstraint,
+    output: *mut c_char,
+    output_len: usize,
+) -> usize {
+    match &mut cc.constraint {
+        Some(constraint) => {
+            let res = constraint.expected_terminals();
+            unsafe { write_json(&res, output, output_len) }
+        }
+        None => 0,
+    }
+}
+
+/// Reconstruct parse trees of the text generated so far; at most max_trees of them
+/// if the grammar is ambiguous (the first one is picked deterministically).
+/// Writes a JSON array to the output buffer (empty if the grammar doesn't accept the text yet).
+/// Each node has "name", "start" and "end" (byte offsets), "children",
+/// and for lexemes also "text".
+/// Returns the number of bytes that would be written to output, if output_len was large enough
+/// (including the terminating null); the output is truncated otherwise.
+/// With output_len == 0, output is not written to (and can be null).
+/// Returns 0 if the constraint is in error state (nothing is written then).
+/// # Safety
+/// This function should only be called from C code.
+#[no_mangle]
+pub unsafe extern "C" fn llg_parse_trees(
+    cc: &mut LlgConstraint,
+    max_trees: usize,
+    output: *mut c_char,
+    output_len: usize,
+) -> usize {
+    match &cc.constraint {
+        Some(constraint) => {
+            let res = constraint.parse_trees(max_trees);
+            unsafe { write_json(&res, output, output_len) }
+        }
+        None => 0,
+    }
+}
+
 /// Compute mask for the next token sampling
 /// It typically takes up to a millisecond for a 100k tokenizer, so should be called in background.
 /// Returns 0 on success and -1 on error (use llg_get_error() to get the exact error).
@@ -580,6 +829,105 @@ pub extern "C" fn llg_commit_token(
     cc.get_error_code()
 }
 
+/// Compute masks for a draft sequence of tokens (for speculative decoding).
+/// Masks are written to mask_dest, one after another, each mask_byte_len bytes
+/// (a multiple of 4); mask_dest has to have space for n_draft + 1 masks.
+/// The first mask is the one llg_compute_mask() would return,
+/// and the i-th mask is the one after accepting the first i draft tokens.
+/// When the sequence should stop at some position, the mask there allows only the EOS token,
+/// and no further masks are computed.
+/// Returns the number of leading draft tokens accepted by the grammar (n_accepted),
+/// in which case n_accepted + 1 masks were written, or -1 on error.
+/// The constraint is left in the same state as after llg_compute_mask(),
+/// so llg_commit_token() should be called next.
+/// With ff_tokens, no further masks are computed after a draft token
+/// for which llg_commit_token() would backtrack or append forced tokens,
+/// and the mask after it is empty; what llg_commit_token() would do for that
+/// token is then written to *splice_p (which otherwise gets n_tokens == 0).
+/// splice_p can be null.
+/// # Safety
+/// This function should only be called from C code.
+#[no_mangle]
+pub unsafe extern "C" fn llg_compute_draft_masks(
+    cc: &mut LlgConstraint,
+    draft: *const LlgToken,
+    n_draft: usize,
+    mask_dest: *mut u32,
+    mask_byte_len: usize,
+    splice_p: *mut LlgDraftSplice,
+) -> i32 {
+    if let Some(constraint) = &mut cc.constraint {
+        let draft = if n_draft == 0 {
+            &[]
+        } else {
+            unsafe { std::slice::from_raw_parts(draft, n_draft) }
+        };
+        let eos = constraint.tok_trie().eos_token();
+        match constraint.compute_draft_masks(draft) {
+            Ok(r) => {
+                let mask_elts = mask_byte_len / 4;
+                for (i, res) in r.results.iter().enumerate() {
+                    let dest = unsafe { mask_dest.add(i * mask_elts) };
+                    unsafe { write_mask(res, eos, dest, mask_elts) };
+                }
+                // store it, so it survives until the next call to llg_*()
+                cc.last_commit_result = r
+                    .results
+                    .last()
+                    .and_then(|res| res.unconditional_splice())
+                    .map_or_else(CommitResult::default, |s| CommitResult {
+                        stop: false,
+                        backtrack: s.backtrack,
+                        ff_tokens: s.ff_tokens.clone(),
+                    });
+                if let Some(splice) = unsafe { splice_p.as_mut() } {
+                    let c = &cc.last_commit_result;
+                    *splice = LlgDraftSplice {
+                        backtrack: c.backtrack,
+                        tokens: if c.ff_tokens.is_empty() {
+                            std::ptr::null()
+                        } else {
+                            c.ff_tokens.as_ptr()
+                        },
+                        n_tokens: c.ff_tokens.len() as u32,
+                    };
+                }
+                return r.num_accepted as i32;
+            }
+            Err(e) => cc.set_error(&e.to_string()),
+        }
+    }
+    cc.get_error_code()
+}
+
+/// Write the mask from the result to mask_dest; on stop, only allow EOS.
+pub(crate) unsafe fn write_mask(
+    res: &StepResult,
+    eos: TokenId,
+    mask_dest: *mut u32,
+    mask_elts: usize,
+) {
+    let mut num_copied = 0;
+    if let Some(m) = res.sample_mask.as_ref() {
+        num_copied = std::cmp::min(m.as_slice().len(), mask_elts);
+        unsafe {
+            std::ptr::copy_nonoverlapping(m.as_ptr(), mask_dest, num_copied);
+        }
+    }
+    let left = mask_elts - num_copied;
+    if left > 0 {
+        unsafe {
+            std::ptr::write_bytes(mask_dest.add(num_copied), 0, left);
+        }
+    }
+    let eos = eos as usize;
+    if res.is_stop() && eos / 32 < mask_elts {
+        unsafe {
+            *mask_dest.add(eos / 32) |= 1 << (eos % 32);
+        }
+    }
+}
+
 /// Compute mask for several constraints in parallel.
 /// # Safety
 /// This function should only be called from C code.
@@ -833,3 +1181,84 @@ pub unsafe extern "C" fn llg_free_stop_controller(stop_ctrl: *mut LlgStopControl
         drop(Box::from_raw(stop_ctrl));
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{factory_ext, lark};
+
+    #[test]
+    fn test_draft_masks_splice() {
+        let caps = InferenceCapabilities {
+            ff_tokens: true,
+            conditional_ff_tokens: false,
+            backtrack: true,
+            fork: false,
+        };
+        let f = factory_ext(&["ab", "]!"], caps);
+        let grm = lark("start: \"[\" /[a-z]+/ \"]!!!\" /[a-z]*/");
+        let mut cc = LlgConstraint {
+            constraint: Some(Constraint::new(f.create_parser(grm).unwrap())),
+            ..Default::default()
+        };
+        let vocab_size = f.tok_env().tok_trie().vocab_size();
+        let mask_byte_len = vocab_size.div_ceil(32) * 4;
+
+        // the walk stops after "]", which forces "!!!"
+        let draft = [b'[' as u32, 257, b']' as u32, b'x' as u32];
+        let mut masks = vec![0u32; (draft.len() + 1) * mask_byte_len / 4];
+        let mut splice = LlgDraftSplice {
+            backtrack: 0,
+            tokens: std::ptr::null(),
+            n_tokens: 0,
+        };
+        let n_accepted = unsafe {
+            llg_compute_draft_masks(
+                &mut cc,
+                draft.as_ptr(),
+                draft.len(),
+                masks.as_mut_ptr(),
+                mask_byte_len,
+                &mut splice,
+            )
+        };
+        assert_eq!(n_accepted, 3);
+        let spliced =
+            unsafe { std::slice::from_raw_parts(splice.tokens, splice.n_tokens as usize) }.to_vec();
+        // the mask after the splice is empty
+        let last = &masks[3 * mask_byte_len / 4..4 * mask_byte_len / 4];
+        assert!(last.iter().all(|&m| m == 0));
+
+        // llg_commit_token() returns the same splice
+        let mut res = LlgCommitResult::from_commit_result(&CommitResult::default());
+        let mut mask_res = LlgMaskResult {
+            sample_mask: std::ptr::null(),
+            temperature: 0.0,
+            is_stop: false,
+        };
+        for &t in &draft[..3] {
+            assert_eq!(llg_compute_mask(&mut cc, &mut mask_res), 0);
+            assert_eq!(llg_commit_token(&mut cc, t, &mut res), 0);
+        }
+        let committed = unsafe { std::slice::from_raw_parts(res.tokens, res.n_tokens as usize) };
+        assert_eq!(splice.backtrack, 0);
+        assert_eq!(spliced, committed);
+        assert_eq!(spliced[0], b']' as u32);
+        assert!(spliced.len() > 1);
+
+        // without a splice, n_tokens is 0
+        let draft = [b'x' as u32];
+        let n_accepted = unsafe {
+            llg_compute_draft_masks(
+                &mut cc,
+                draft.as_ptr(),
+                draft.len(),
+                masks.as_mut_ptr(),
+                mask_byte_len,
+                &mut splice,
+            )
+        };
+        assert_eq!(n_accepted, 1);
+        assert_eq!(splice.n_tokens, 0);
+    }
+}
@@ -0,0 +1,30 @@
+/// A GBNF (llama.cpp) or ABNF (RFC 5234) grammar expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    /// "..." - possibly empty
+    Literal(String),
+    /// "..." in ABNF - ASCII letters match case-insensitively
+    CaseInsensitive(String),
+    /// [a-z0-9_] or [^"\\]
+    CharClass {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+    /// .
+    AnyChar,
+    /// reference to another rule
+    RuleRef(String),
+    Sequence(Vec<Node>),
+    Alternatives(Vec<Node>),
+    /// x*, x+, x?, x{m}, x{m,}, x{m,n}
+    Repeat(Box<Node>, u32, Option<u32>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    /// Rule names are case-sensitive; the ABNF parser lower-cases them.
+    pub name: String,
+    pub line: usize,
+    pub column: usize,
+    pub body: Node,
+}
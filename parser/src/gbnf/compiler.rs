@@ -0,0 +1,731 @@
+use anyhow::{anyhow, ensure, Result};
+use derivre::{ExprRef, RegexAst};
+
+use crate::{
+    api::LLGuidanceOptions,
+    grammar_builder::{GrammarResult, RegexId},
+    GrammarBuilder, HashMap, HashSet, NodeRef,
+};
+
+use super::{
+    ast::{Node, Rule},
+    parser::parse_gbnf,
+};
+
+struct Compiler {
+    builder: GrammarBuilder,
+    start: String,
+    rules: HashMap<String, Rule>,
+    // rules that do not (transitively) reference themselves, compiled to lexemes
+    regular: HashSet<String>,
+    node_ids: HashMap<String, NodeRef>,
+    regex_ids: HashMap<String, RegexId>,
+    in_progress: HashSet<String>,
+}
+
+pub fn gbnf_to_llguidance(mut builder: GrammarBuilder, gbnf: &str) -> Result<GrammarResult> {
+    let parsed = parse_gbnf(gbnf)?;
+
+    let n = std::cmp::min(gbnf.len() / 8, 1_000_000);
+    builder.regex.spec.regex_builder.reserve(n);
+
+    compile_rules(builder, parsed.rules, "root")
+}
+
+/// Compile a list of BNF-like rules, starting at rule `start`.
+pub fn compile_rules(
+    builder: GrammarBuilder,
+    rule_list: Vec<Rule>,
+    start: &str,
+) -> Result<GrammarResult> {
+    let mut rules = HashMap::default();
+    for rule in rule_list {
+        ensure!(
+            !rules.contains_key(&rule.name),
+            "at {}({}): duplicate rule: {:?}",
+            rule.line,
+            rule.column,
+            rule.name
+        );
+        rules.insert(rule.name.clone(), rule);
+    }
+    ensure!(rules.contains_key(start), "no {} rule found", start);
+
+    for rule in rules.values() {
+        let mut refs = vec![];
+        rule_refs(&rule.body, &mut refs);
+        for r in refs {
+            ensure!(
+                rules.contains_key(r),
+                "at {}({}): rule {:?} not found (referenced from {:?})",
+                rule.line,
+                rule.column,
+                r,
+                rule.name
+            );
+        }
+    }
+
+    let mut regular = regular_rules(&rules, start);
+    remove_extensible(&rules, &mut regular)?;
+
+    let c = Compiler {
+        builder,
+        start: start.to_string(),
+        rules,
+        regular,
+        node_ids: HashMap::default(),
+        regex_ids: HashMap::default(),
+        in_progress: HashSet::default(),
+    };
+    c.execute()
+}
+
+fn rule_refs<'a>(node: &'a Node, acc: &mut Vec<&'a str>) {
+    match node {
+        Node::RuleRef(name) => acc.push(name),
+        Node::Sequence(nodes) | Node::Alternatives(nodes) => {
+            for n in nodes {
+                rule_refs(n, acc);
+            }
+        }
+        Node::Repeat(n, _, _) => rule_refs(n, acc),
+        Node::Literal(_) | Node::CaseInsensitive(_) | Node::CharClass { .. } | Node::AnyChar => {}
+    }
+}
+
+/// Find rules that only reference (transitively) other non-recursive rules.
+/// These define regular languages and are turned into lexemes,
+/// which is much faster than going through the Earley parser.
+/// The start rule is never turned into a lexeme.
+fn regular_rules(rules: &HashMap<String, Rule>, start: &str) -> HashSet<String> {
+    let mut regular = HashSet::default();
+    loop {
+        let mut num_fix = 0;
+        for rule in rules.values() {
+            if rule.name == start || regular.contains(&rule.name) {
+                continue;
+            }
+            let mut refs = vec![];
+            rule_refs(&rule.body, &mut refs);
+            if refs.iter().all(|r| regular.contains(*r)) {
+                regular.insert(rule.name.clone());
+                num_fix += 1;
+            }
+        }
+        if num_fix == 0 {
+            break;
+        }
+    }
+    regular
+}
+
+/// Character ranges, sorted and non-overlapping (see `normalize()`).
+type CharSet = Vec<(char, char)>;
+
+const ALL_CHARS: (char, char) = ('\0', char::MAX);
+
+fn next_char(c: char) -> Option<char> {
+    match c {
+        '\u{d7ff}' => Some('\u{e000}'),
+        _ => char::from_u32(c as u32 + 1),
+    }
+}
+
+fn prev_char(c: char) -> Option<char> {
+    match c {
+        '\u{e000}' => Some('\u{d7ff}'),
+        _ => char::from_u32((c as u32).checked_sub(1)?),
+    }
+}
+
+fn normalize(set: &mut CharSet) {
+    set.sort();
+    let mut res: CharSet = Vec::with_capacity(set.len());
+    for &(lo, hi) in set.iter() {
+        match res.last_mut() {
+            Some(last) if next_char(last.1).is_none_or(|n| lo <= n) => last.1 = last.1.max(hi),
+            _ => res.push((lo, hi)),
+        }
+    }
+    *set = res;
+}
+
+fn union(set: &mut CharSet, other: &[(char, char)]) {
+    set.extend_from_slice(other);
+    normalize(set);
+}
+
+fn complement(set: &[(char, char)]) -> CharSet {
+    let mut res = vec![];
+    let mut start = Some('\0');
+    for &(lo, hi) in set {
+        if let (Some(s), Some(p)) = (start, prev_char(lo)) {
+            if s <= p {
+                res.push((s, p));
+            }
+        }
+        start = next_char(hi);
+    }
+    if let Some(s) = start {
+        res.push((s, char::MAX));
+    }
+    res
+}
+
+fn overlap(a: &[(char, char)], b: &[(char, char)]) -> bool {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].1 < b[j].0 {
+            i += 1;
+        } else if b[j].1 < a[i].0 {
+            j += 1;
+        } else {
+            return true;
+        }
+    }
+    false
+}
+
+/// First characters of the strings matched by rules, and whether rules match the empty string.
+#[derive(Default)]
+struct FirstSets {
+    first: HashMap<String, CharSet>,
+    nullable: HashSet<String>,
+}
+
+impl FirstSets {
+    fn new(rules: &HashMap<String, Rule>) -> Self {
+        let mut res = Self::default();
+        loop {
+            let mut changed = false;
+            for rule in rules.values() {
+                let (first, nullable) = res.of(&rule.body);
+                if nullable && res.nullable.insert(rule.name.clone()) {
+                    changed = true;
+                }
+                let prev = res.first.entry(rule.name.clone()).or_default();
+                if *prev != first {
+                    *prev = first;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return res;
+            }
+        }
+    }
+
+    fn of(&self, node: &Node) -> (CharSet, bool) {
+        match node {
+            Node::Literal(s) => (s.chars().take(1).map(|c| (c, c)).collect(), s.is_empty()),
+            Node::CaseInsensitive(s) => {
+                let mut first: CharSet = s
+                    .chars()
+                    .take(1)
+                    .flat_map(|c| [c.to_ascii_lowercase(), c.to_ascii_uppercase()])
+                    .map(|c| (c, c))
+                    .collect();
+                normalize(&mut first);
+                (first, s.is_empty())
+            }
+            Node::CharClass { negated, ranges } => {
+                let mut first = ranges.clone();
+                normalize(&mut first);
+                if *negated {
+                    first = complement(&first);
+                }
+                (first, false)
+            }
+            Node::AnyChar => (vec![ALL_CHARS], false),
+            Node::RuleRef(name) => (
+                self.first.get(name).cloned().unwrap_or_default(),
+                self.nullable.contains(name),
+            ),
+            Node::Sequence(nodes) => {
+                let mut first = vec![];
+                for n in nodes {
+                    let (f, nullable) = self.of(n);
+                    union(&mut first, &f);
+                    if !nullable {
+                        return (first, false);
+                    }
+                }
+                (first, true)
+            }
+            Node::Alternatives(nodes) => {
+                let mut first = vec![];
+                let mut nullable = false;
+                for n in nodes {
+                    let (f, n) = self.of(n);
+                    union(&mut first, &f);
+                    nullable |= n;
+                }
+                (first, nullable)
+            }
+            Node::Repeat(_, _, Some(0)) => (vec![], true),
+            Node::Repeat(n, min, _) => {
+                let (first, nullable) = self.of(n);
+                (first, nullable || *min == 0)
+            }
+        }
+    }
+}
+
+/// Add characters that can follow rule references in `node` to `follows`,
+/// given that `follow` can follow `node`. Returns whether `follows` changed.
+fn add_follows(
+    node: &Node,
+    follow: &[(char, char)],
+    firsts: &FirstSets,
+    follows: &mut HashMap<String, CharSet>,
+) -> bool {
+    match node {
+        Node::RuleRef(name) => {
+            let set = follows.entry(name.clone()).or_default();
+            let mut new = set.clone();
+            union(&mut new, follow);
+            let changed = new != *set;
+            *set = new;
+            changed
+        }
+        Node::Sequence(nodes) => {
+            let mut changed = false;
+            let mut follow = follow.to_vec();
+            for n in nodes.iter().rev() {
+                changed |= add_follows(n, &follow, firsts, follows);
+                let (first, nullable) = firsts.of(n);
+                if nullable {
+                    union(&mut follow, &first);
+                } else {
+                    follow = first;
+                }
+            }
+            changed
+        }
+        Node::Alternatives(nodes) => {
+            let mut changed = false;
+            for n in nodes {
+                changed |= add_follows(n, follow, firsts, follows);
+            }
+            changed
+        }
+        Node::Repeat(n, _, max) => {
+            let mut follow = follow.to_vec();
+            if max.is_none_or(|m| m > 1) {
+                union(&mut follow, &firsts.of(n).0);
+            }
+            add_follows(n, &follow, firsts, follows)
+        }
+        Node::Literal(_) | Node::CaseInsensitive(_) | Node::CharClass { .. } | Node::AnyChar => {
+            false
+        }
+    }
+}
+
+/// All characters used in the rule (and rules it references).
+fn rule_chars(rules: &HashMap<String, Rule>, name: &str) -> CharSet {
+    fn walk<'a>(
+        rules: &'a HashMap<String, Rule>,
+        node: &'a Node,
+        visited: &mut HashSet<&'a str>,
+        acc: &mut CharSet,
+    ) {
+        match node {
+            Node::RuleRef(name) => {
+                if visited.insert(name) {
+                    walk(rules, &rules[name].body, visited, acc);
+                }
+            }
+            Node::Sequence(nodes) | Node::Alternatives(nodes) => {
+                for n in nodes {
+                    walk(rules, n, visited, acc);
+                }
+            }
+            Node::Repeat(n, _, _) => walk(rules, n, visited, acc),
+            Node::Literal(s) | Node::CaseInsensitive(s) => {
+                for c in s.chars() {
+                    acc.push((c.to_ascii_lowercase(), c.to_ascii_lowercase()));
+                    acc.push((c.to_ascii_uppercase(), c.to_ascii_uppercase()));
+                }
+            }
+            Node::CharClass {
+                negated: false,
+                ranges,
+            } => acc.extend_from_slice(ranges),
+            Node::CharClass { negated: true, .. } | Node::AnyChar => acc.push(ALL_CHARS),
+        }
+    }
+    let mut acc = vec![];
+    walk(rules, &rules[name].body, &mut HashSet::default(), &mut acc);
+    normalize(&mut acc);
+    acc
+}
+
+/// Builds regexes of rules in a separate builder, to check if a lexeme
+/// can be extended with a character, without cluttering the grammar's builder.
+struct ExtensionCheck {
+    builder: derivre::RegexBuilder,
+    ids: HashMap<String, ExprRef>,
+}
+
+impl ExtensionCheck {
+    fn new() -> Self {
+        Self {
+            builder: derivre::RegexBuilder::new(),
+            ids: HashMap::default(),
+        }
+    }
+
+    fn rule(&mut self, rules: &HashMap<String, Rule>, name: &str) -> Result<ExprRef> {
+        if let Some(id) = self.ids.get(name) {
+            return Ok(*id);
+        }
+        let ast = self.ast(rules, &rules[name].body)?;
+        let id = self.builder.mk(&ast)?;
+        self.ids.insert(name.to_string(), id);
+        Ok(id)
+    }
+
+    fn ast(&mut self, rules: &HashMap<String, Rule>, node: &Node) -> Result<RegexAst> {
+        let map = |c: &mut Self, nodes: &[Node]| {
+            nodes
+                .iter()
+                .map(|n| c.ast(rules, n))
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(match node {
+            Node::Literal(s) => RegexAst::Literal(s.clone()),
+            Node::CaseInsensitive(s) => case_insensitive_ast(s),
+            Node::CharClass { negated, ranges } => char_class_ast(*negated, ranges),
+            Node::AnyChar => RegexAst::Regex("(?s:.)".to_string()),
+            Node::RuleRef(name) => RegexAst::ExprRef(self.rule(rules, name)?),
+            Node::Sequence(nodes) => RegexAst::Concat(map(self, nodes)?),
+            Node::Alternatives(nodes) => RegexAst::Or(map(self, nodes)?),
+            Node::Repeat(n, min, max) => {
+                RegexAst::Repeat(Box::new(self.ast(rules, n)?), *min, max.unwrap_or(u32::MAX))
+            }
+        })
+    }
+
+    /// Check if a string matched by the rule, followed by a character from `follow`,
+    /// is a prefix of another string matched by the rule.
+    fn can_extend(
+        &mut self,
+        rules: &HashMap<String, Rule>,
+        name: &str,
+        follow: &[(char, char)],
+    ) -> Result<bool> {
+        let id = self.rule(rules, name)?;
+        let extended = self.builder.mk(&RegexAst::And(vec![
+            RegexAst::ExprRef(id),
+            RegexAst::Concat(vec![
+                RegexAst::ExprRef(id),
+                char_class_ast(false, follow),
+                RegexAst::Regex("(?s:.*)".to_string()),
+            ]),
+        ]))?;
+        // if it's too expensive to tell, assume it can
+        Ok(self
+            .builder
+            .to_regex_limited(extended, 10_000)
+            .map_or(true, |mut rx| !rx.always_empty()))
+    }
+}
+
+/// Lexemes are greedy: the lexer doesn't end a lexeme when the next character extends it.
+/// llama.cpp has no lexemes, so for example with `root ::= a "x"` and `a ::= "x"+`
+/// it accepts `xx`, which it wouldn't if `a` was a lexeme.
+/// Remove rules from `regular` if they can be extended with a character that can follow
+/// them in rules that are not lexemes, so that they are parsed as rules instead.
+fn remove_extensible(rules: &HashMap<String, Rule>, regular: &mut HashSet<String>) -> Result<()> {
+    let firsts = FirstSets::new(rules);
+    let mut check = ExtensionCheck::new();
+    loop {
+        let mut follows: HashMap<String, CharSet> = HashMap::default();
+        loop {
+            let mut changed = false;
+            for rule in rules.values() {
+                if !regular.contains(&rule.name) {
+                    let follow = follows.get(&rule.name).cloned().unwrap_or_default();
+                    changed |= add_follows(&rule.body, &follow, &firsts, &mut follows);
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        let mut extensible = vec![];
+        for (name, follow) in follows.iter_mut() {
+            if !regular.contains(name) || follow.is_empty() {
+                continue;
+            }
+            // the lexer works on bytes, and non-ASCII characters with the same
+            // first byte can extend the lexeme as well
+            if follow.last().is_some_and(|&(_, hi)| hi >= '\u{80}') {
+                union(follow, &[('\u{80}', char::MAX)]);
+            }
+            if overlap(&rule_chars(rules, name), follow) && check.can_extend(rules, name, follow)? {
+                extensible.push(name.clone());
+            }
+        }
+        if extensible.is_empty() {
+            return Ok(());
+        }
+        for name in extensible {
+            regular.remove(&name);
+        }
+    }
+}
+
+fn case_insensitive_ast(s: &str) -> RegexAst {
+    let chars = s
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphabetic() {
+                RegexAst::Or(vec![
+                    RegexAst::Literal(c.to_ascii_lowercase().to_string()),
+                    RegexAst::Literal(c.to_ascii_uppercase().to_string()),
+                ])
+            } else {
+                RegexAst::Literal(c.to_string())
+            }
+        })
+        .collect();
+    RegexAst::Concat(chars)
+}
+
+fn char_class_ast(negated: bool, ranges: &[(char, char)]) -> RegexAst {
+    if ranges.is_empty() {
+        // [] matches nothing, and [^] matches anything
+        if negated {
+            RegexAst::Regex("(?s:.)".to_string())
+        } else {
+            RegexAst::NoMatch
+        }
+    } else {
+        RegexAst::Regex(char_class_regex(negated, ranges))
+    }
+}
+
+fn char_class_regex(negated: bool, ranges: &[(char, char)]) -> String {
+    let mut rx = String::from(if negated { "[^" } else { "[" });
+    for (start, end) in ranges {
+        rx.push_str(&format!(
+            "\\x{{{:x}}}-\\x{{{:x}}}",
+            *start as u32, *end as u32
+        ));
+    }
+    rx.push(']');
+    rx
+}
+
+impl Compiler {
+    fn do_token(&mut self, node: &Node) -> Result<RegexId> {
+        self.builder.check_limits()?;
+        let id = match node {
+            Node::Literal(s) => self.builder.regex.literal(s.clone()),
+            Node::CaseInsensitive(s) => self.builder.regex.add_ast(case_insensitive_ast(s))?,
+            Node::CharClass { negated, ranges } => self
+                .builder
+                .regex
+                .add_ast(char_class_ast(*negated, ranges))?,
+            Node::AnyChar => self.builder.regex.regex("(?s:.)")?,
+            Node::RuleRef(name) => self.do_token_rule(name)?,
+            Node::Sequence(nodes) => {
+                let ids = nodes
+                    .iter()
+                    .map(|n| self.do_token(n))
+                    .collect::<Result<Vec<_>>>()?;
+                self.builder.regex.concat(ids)
+            }
+            Node::Alternatives(nodes) => {
+                let ids = nodes
+                    .iter()
+                    .map(|n| self.do_token(n))
+                    .collect::<Result<Vec<_>>>()?;
+                self.builder.regex.select(ids)
+            }
+            Node::Repeat(n, min, max) => {
+                let id = self.do_token(n)?;
+                self.builder.regex.repeat(id, *min, *max)
+            }
+        };
+        Ok(id)
+    }
+
+    fn do_token_rule(&mut self, name: &str) -> Result<RegexId> {
+        if let Some(id) = self.regex_ids.get(name) {
+            return Ok(*id);
+        }
+        let body = self.rules[name].body.clone();
+        let id = self.do_token(&body)?;
+        self.regex_ids.insert(name.to_string(), id);
+        Ok(id)
+    }
+
+    fn do_node(&mut self, node: &Node) -> Result<NodeRef> {
+        self.builder.check_limits()?;
+        match node {
+            Node::Literal(s) => Ok(self.builder.string(s)),
+            Node::CaseInsensitive(_) | Node::CharClass { .. } | Node::AnyChar => {
+                let id = self.do_token(node)?;
+                Ok(self.builder.lexeme(id))
+            }
+            Node::RuleRef(name) => self.do_rule(name),
+            Node::Sequence(nodes) => {
+                let ids = nodes
+                    .iter()
+                    .map(|n| self.do_node(n))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(self.builder.join(&ids))
+            }
+            Node::Alternatives(nodes) => {
+                let ids = nodes
+                    .iter()
+                    .map(|n| self.do_node(n))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(self.builder.select(&ids))
+            }
+            Node::Repeat(n, min, max) => {
+                let id = self.do_node(n)?;
+                Ok(self
+                    .builder
+                    .repeat(id, *min as usize, max.map(|m| m as usize)))
+            }
+        }
+    }
+
+    fn do_rule(&mut self, name: &str) -> Result<NodeRef> {
+        if let Some(id) = self.node_ids.get(name) {
+            return Ok(*id);
+        }
+        if self.in_progress.contains(name) {
+            let id = self.builder.new_node(name);
+            self.node_ids.insert(name.to_string(), id);
+            return Ok(id);
+        }
+        self.in_progress.insert(name.to_string());
+
+        let id = if self.regular.contains(name) {
+            let rx = self.do_token_rule(name)?;
+            self.builder.lexeme(rx)
+        } else {
+            let rule = &self.rules[name];
+            let (line, column) = (rule.line, rule.column);
+            let body = rule.body.clone();
+            self.do_node(&body).map_err(|e| {
+                if e.to_string().starts_with("at ") {
+                    e
+                } else {
+                    anyhow!("at {}({}): {}", line, column, e)
+                }
+            })?
+        };
+
+        if let Some(placeholder) = self.node_ids.get(name) {
+            self.builder.set_placeholder(*placeholder, id);
+        }
+        self.node_ids.insert(name.to_string(), id);
+        self.in_progress.remove(name);
+        Ok(id)
+    }
+
+    fn execute(mut self) -> Result<GrammarResult> {
+        let id = self
+            .builder
+            .add_grammar(LLGuidanceOptions::default(), RegexAst::NoMatch)?;
+        let start = self.start.clone();
+        let root = self.do_rule(&start)?;
+        self.builder.set_start_node(root);
+        Ok(self.builder.finalize(id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{api::TopLevelGrammar, output::ValidateTextError, test_util::factory};
+
+    fn gbnf(s: &str) -> TopLevelGrammar {
+        TopLevelGrammar::from_gbnf(s.to_string())
+    }
+
+    /// Whether rule `name` is compiled to a lexeme.
+    fn lexeme(grm: &str, name: &str) -> bool {
+        let rules: HashMap<String, Rule> = parse_gbnf(grm)
+            .unwrap()
+            .rules
+            .into_iter()
+            .map(|r| (r.name.clone(), r))
+            .collect();
+        let mut regular = regular_rules(&rules, "root");
+        remove_extensible(&rules, &mut regular).unwrap();
+        regular.contains(name)
+    }
+
+    #[test]
+    fn test_regular_rules() {
+        let f = factory();
+        let ok = |grm: &str, text: &str| f.validate_text(gbnf(grm), text.as_bytes()).is_ok();
+
+        // `a` would take all the x's as a lexeme, leaving none for the root rule,
+        // so it's parsed as a rule, like in llama.cpp
+        let grm = "root ::= a \"x\"\na ::= \"x\"+";
+        assert!(ok(grm, "xxx"));
+        assert!(ok(grm, "xx"));
+        assert!(!lexeme(grm, "a"));
+        // also through another regular rule, and after it's no longer a lexeme
+        let grm = "root ::= b \"x\"\nb ::= a \"y\"?\na ::= \"x\"+";
+        assert!(ok(grm, "xxx"));
+        assert!(ok(grm, "xxyx"));
+        assert!(!lexeme(grm, "a") && !lexeme(grm, "b"));
+        let grm = "root ::= b \"!\"\nb ::= a \"y\"?\na ::= \"x\"+";
+        assert!(lexeme(grm, "b"));
+        // UTF-8 characters starting with the same byte
+        let grm = "root ::= a \"è\"\na ::= \"é\"+";
+        assert!(ok(grm, "éè"));
+        assert!(!lexeme(grm, "a"));
+
+        // rules that can't be extended by what follows are lexemes
+        let grm = "root ::= a \",\" a\na ::= \"\\\"\" [^\"]* \"\\\"\"";
+        assert!(ok(grm, "\"a,b\",\"\""));
+        assert!(lexeme(grm, "a"));
+        let grm = "root ::= (a \" \")+\na ::= [a-z]+";
+        assert!(ok(grm, "ab c "));
+        assert!(lexeme(grm, "a"));
+
+        // recursive rules, and rules referencing them, are parsed by rules
+        let grm = "root ::= a \"x\"\na ::= \"x\" a | \"x\"";
+        assert!(ok(grm, "xxx"));
+        assert!(ok(grm, "xx"));
+        let grm = "root ::= b \"x\"\nb ::= a\na ::= \"x\" a | \"x\"";
+        assert!(ok(grm, "xxx"));
+        let grm = "root ::= a \"x\"\na ::= \"x\" b | \"x\"\nb ::= a";
+        assert!(ok(grm, "xxx"));
+
+        // the root rule is not a lexeme, even if regular
+        let grm = "root ::= \"x\"+";
+        assert!(ok(grm, "xxx"));
+        assert!(!ok(grm, "xxy"));
+    }
+
+    #[test]
+    fn test_rule_errors() {
+        let f = factory();
+        let err = |grm: &str| match f.validate_text(gbnf(grm), b"") {
+            Err(ValidateTextError::Grammar(e)) => e.to_string(),
+            r => panic!("expected grammar error, got {:?}", r),
+        };
+        assert_eq!(
+            err("root ::= a\na ::= \"x\"\n  a ::= \"y\""),
+            "at 3(3): duplicate rule: \"a\""
+        );
+        assert_eq!(
+            err("root ::= a\n  a ::= b"),
+            "at 2(3): rule \"b\" not found (referenced from \"a\")"
+        );
+        assert_eq!(err("a ::= \"x\""), "no root rule found");
+    }
+}
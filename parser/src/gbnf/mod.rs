@@ -0,0 +1,6 @@
+mod ast;
+mod compiler;
+mod parser;
+
+pub(crate) use ast::{Node, Rule};
+pub use compiler::{compile_rules, gbnf_to_llguidance};
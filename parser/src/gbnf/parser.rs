@@ -0,0 +1,392 @@
+use anyhow::{bail, Result};
+
+use super::ast::{Node, Rule};
+
+pub struct ParsedGbnf {
+    pub rules: Vec<Rule>,
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+/// Parse GBNF grammar, as described in
+/// https://github.com/ggerganov/llama.cpp/blob/master/grammars/README.md
+pub fn parse_gbnf(text: &str) -> Result<ParsedGbnf> {
+    let mut p = Parser { text, pos: 0 };
+    let mut rules = vec![];
+    p.skip_space(true);
+    while !p.is_at_end() {
+        rules.push(p.parse_rule()?);
+        p.skip_space(true);
+    }
+    Ok(ParsedGbnf { rules })
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-'
+}
+
+impl Parser<'_> {
+    fn is_at_end(&self) -> bool {
+        self.pos >= self.text.len()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn line(&self) -> usize {
+        self.text[..self.pos].matches('\n').count() + 1
+    }
+
+    fn column(&self) -> usize {
+        let line_start = self.text[..self.pos].rfind('\n').map_or(0, |i| i + 1);
+        self.text[line_start..self.pos].chars().count() + 1
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T> {
+        bail!("at {}({}): {}", self.line(), self.column(), msg)
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if self.peek() == Some(c) {
+            self.advance();
+            Ok(())
+        } else {
+            self.error(&format!("expected {:?}", c))
+        }
+    }
+
+    fn skip_space(&mut self, allow_newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => {
+                    self.advance();
+                }
+                '\r' | '\n' if allow_newlines => {
+                    self.advance();
+                }
+                '#' => {
+                    while !matches!(self.peek(), None | Some('\r' | '\n')) {
+                        self.advance();
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_word_char) {
+            self.advance();
+        }
+        if self.pos == start {
+            return self.error("expected rule name");
+        }
+        Ok(self.text[start..self.pos].to_string())
+    }
+
+    fn parse_int(&mut self) -> Result<u32> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.advance();
+        }
+        if self.pos == start {
+            return self.error("expected integer");
+        }
+        match self.text[start..self.pos].parse() {
+            Ok(n) => Ok(n),
+            Err(_) => self.error("integer too large"),
+        }
+    }
+
+    fn parse_hex(&mut self, len: usize) -> Result<char> {
+        let start = self.pos;
+        for _ in 0..len {
+            if !self.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+                return self.error(&format!("expected {} hex digits in escape sequence", len));
+            }
+            self.advance();
+        }
+        let v = u32::from_str_radix(&self.text[start..self.pos], 16).unwrap();
+        match char::from_u32(v) {
+            Some(c) => Ok(c),
+            None => self.error(&format!("invalid code point in escape sequence: {:#x}", v)),
+        }
+    }
+
+    /// Parse a single (possibly escaped) character of a literal or character class.
+    fn parse_char(&mut self) -> Result<char> {
+        match self.advance() {
+            None => self.error("unexpected end of input"),
+            Some('\\') => match self.advance() {
+                Some('x') => self.parse_hex(2),
+                Some('u') => self.parse_hex(4),
+                Some('U') => self.parse_hex(8),
+                Some('n') => Ok('\n'),
+                Some('r') => Ok('\r'),
+                Some('t') => Ok('\t'),
+                Some(c @ ('\\' | '"' | '[' | ']' | '-' | '^')) => Ok(c),
+                Some(c) => self.error(&format!("invalid escape sequence \\{}", c)),
+                None => self.error("unexpected end of input"),
+            },
+            Some(c) => Ok(c),
+        }
+    }
+
+    fn parse_literal(&mut self) -> Result<Node> {
+        self.expect('"')?;
+        let mut s = String::new();
+        while self.peek() != Some('"') {
+            s.push(self.parse_char()?);
+        }
+        self.advance();
+        Ok(Node::Literal(s))
+    }
+
+    fn parse_char_class(&mut self) -> Result<Node> {
+        self.expect('[')?;
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.advance();
+        }
+        let mut ranges = vec![];
+        while self.peek() != Some(']') {
+            let start = self.parse_char()?;
+            let mut end = start;
+            if self.peek() == Some('-') && !self.text[self.pos + 1..].starts_with(']') {
+                self.advance();
+                end = self.parse_char()?;
+                if end < start {
+                    return self.error(&format!(
+                        "invalid character range {:?}-{:?} in character class",
+                        start, end
+                    ));
+                }
+            }
+            ranges.push((start, end));
+        }
+        self.advance();
+        Ok(Node::CharClass { negated, ranges })
+    }
+
+    fn parse_rule(&mut self) -> Result<Rule> {
+        let line = self.line();
+        let column = self.column();
+        let name = self.parse_name()?;
+        self.skip_space(false);
+        if !self.text[self.pos..].starts_with("::=") {
+            return self.error("expected ::=");
+        }
+        self.pos += 3;
+        self.skip_space(true);
+        let body = self.parse_alternatives(false)?;
+        match self.peek() {
+            None | Some('\r' | '\n') => {}
+            Some(c) => return self.error(&format!("unexpected {:?}", c)),
+        }
+        Ok(Rule {
+            name,
+            line,
+            column,
+            body,
+        })
+    }
+
+    fn parse_alternatives(&mut self, is_nested: bool) -> Result<Node> {
+        let mut alternatives = vec![];
+        loop {
+            alternatives.push(self.parse_sequence(is_nested)?);
+            if self.peek() != Some('|') {
+                break;
+            }
+            self.advance();
+            self.skip_space(true);
+        }
+        if alternatives.len() == 1 {
+            Ok(alternatives.pop().unwrap())
+        } else {
+            Ok(Node::Alternatives(alternatives))
+        }
+    }
+
+    fn parse_sequence(&mut self, is_nested: bool) -> Result<Node> {
+        let mut nodes = vec![];
+        while let Some(c) = self.peek() {
+            let node = match c {
+                '"' => self.parse_literal()?,
+                '[' => self.parse_char_class()?,
+                '(' => {
+                    self.advance();
+                    self.skip_space(true);
+                    let r = self.parse_alternatives(true)?;
+                    self.expect(')')?;
+                    r
+                }
+                '.' => {
+                    self.advance();
+                    Node::AnyChar
+                }
+                '*' | '+' | '?' | '{' => {
+                    let Some(last) = nodes.pop() else {
+                        return self.error(&format!("expecting preceding item to {:?}", c));
+                    };
+                    self.parse_repetition(last)?
+                }
+                _ if is_word_char(c) => {
+                    let name = self.parse_name()?;
+                    self.skip_space(is_nested);
+                    if self.text[self.pos..].starts_with("::=") {
+                        return self.error(&format!(
+                            "missing newline before definition of rule {:?}",
+                            name
+                        ));
+                    }
+                    Node::RuleRef(name)
+                }
+                _ => break,
+            };
+            nodes.push(node);
+            self.skip_space(is_nested);
+        }
+        if nodes.len() == 1 {
+            Ok(nodes.pop().unwrap())
+        } else {
+            Ok(Node::Sequence(nodes))
+        }
+    }
+
+    fn parse_repetition(&mut self, node: Node) -> Result<Node> {
+        let (min, max) = match self.advance() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            _ => {
+                self.skip_space(true);
+                let min = self.parse_int()?;
+                self.skip_space(true);
+                let max = if self.peek() == Some(',') {
+                    self.advance();
+                    self.skip_space(true);
+                    if self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                        let max = self.parse_int()?;
+                        self.skip_space(true);
+                        Some(max)
+                    } else {
+                        None
+                    }
+                } else {
+                    Some(min)
+                };
+                self.expect('}')?;
+                if let Some(max) = max {
+                    if max < min {
+                        return self.error(&format!(
+                            "invalid repetition {{{},{}}}, max is less than min",
+                            min, max
+                        ));
+                    }
+                }
+                (min, max)
+            }
+        };
+        Ok(Node::Repeat(Box::new(node), min, max))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_gbnf, Node};
+
+    fn parse_one(rule: &str) -> Node {
+        let mut p = parse_gbnf(rule).unwrap();
+        assert_eq!(p.rules.len(), 1);
+        p.rules.pop().unwrap().body
+    }
+
+    fn lit(s: &str) -> Node {
+        Node::Literal(s.to_string())
+    }
+
+    fn rule_ref(s: &str) -> Node {
+        Node::RuleRef(s.to_string())
+    }
+
+    #[test]
+    fn test_parse_gbnf() {
+        assert_eq!(parse_one(r#"root ::= "a\x41\u0042\n\"""#), lit("aAB\n\""));
+        assert_eq!(
+            parse_one("root ::= [^a-z_\\]] . foo-bar"),
+            Node::Sequence(vec![
+                Node::CharClass {
+                    negated: true,
+                    ranges: vec![('a', 'z'), ('_', '_'), (']', ']')]
+                },
+                Node::AnyChar,
+                rule_ref("foo-bar"),
+            ])
+        );
+        assert_eq!(
+            parse_one("root ::= [a-]"),
+            Node::CharClass {
+                negated: false,
+                ranges: vec![('a', 'a'), ('-', '-')]
+            }
+        );
+        assert_eq!(
+            parse_one("root ::= ( \"a\" |\n \"b\"\n )* x{2} y{1,} z{0, 3}"),
+            Node::Sequence(vec![
+                Node::Repeat(
+                    Box::new(Node::Alternatives(vec![lit("a"), lit("b")])),
+                    0,
+                    None
+                ),
+                Node::Repeat(Box::new(rule_ref("x")), 2, Some(2)),
+                Node::Repeat(Box::new(rule_ref("y")), 1, None),
+                Node::Repeat(Box::new(rule_ref("z")), 0, Some(3)),
+            ])
+        );
+
+        let p = parse_gbnf("# comment\nroot ::= a | # c2\n  b\n\na ::= \"x\"+ # c3\nb ::= \"\"\n")
+            .unwrap();
+        let names = p.rules.iter().map(|r| r.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["root", "a", "b"]);
+        assert_eq!((p.rules[0].line, p.rules[0].column), (2, 1));
+        assert_eq!(
+            p.rules[0].body,
+            Node::Alternatives(vec![rule_ref("a"), rule_ref("b")])
+        );
+        assert_eq!(p.rules[2].body, lit(""));
+    }
+
+    #[test]
+    fn test_parse_gbnf_errors() {
+        let err = |s: &str| parse_gbnf(s).err().unwrap().to_string();
+        assert_eq!(err("root ::= \"a"), "at 1(12): unexpected end of input");
+        assert_eq!(err("root = \"a\""), "at 1(6): expected ::=");
+        assert_eq!(
+            err("root ::= [z-a]"),
+            "at 1(14): invalid character range 'z'-'a' in character class"
+        );
+        assert_eq!(err("root ::= \"a\" ) \"b\""), "at 1(14): unexpected ')'");
+        assert_eq!(
+            err("root ::= * \"a\""),
+            "at 1(10): expecting preceding item to '*'"
+        );
+        assert_eq!(
+            err("root ::= \"\\q\""),
+            "at 1(13): invalid escape sequence \\q"
+        );
+        assert!(err("root ::= a{3,1}").contains("max is less than min"));
+        assert!(err("root ::= a b ::= c").contains("missing newline"));
+    }
+}
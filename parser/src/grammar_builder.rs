@@ -1,17 +1,17 @@
 use crate::{
     api::{LLGuidanceOptions, ParserLimits},
     earley::{
-        lexerspec::{token_ranges_to_string, LexemeClass, LexemeIdx, LexerSpec},
+        lexerspec::{token_ranges_to_string, LexemeClass, LexemeFollow, LexemeIdx, LexerSpec},
         Grammar, SymIdx, SymbolProps,
     },
     HashMap,
 };
 use anyhow::{anyhow, bail, ensure, Result};
 use derivre::{ExprRef, RegexAst};
-use std::ops::RangeInclusive;
+use std::{ops::RangeInclusive, sync::Arc};
 use toktrie::{bytes::limit_str, TokEnv};
 
-use crate::api::{GenGrammarOptions, GenOptions, NodeProps};
+use crate::api::{Diagnostic, GenGrammarOptions, GenOptions, NodeProps, RegexExt};
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
 pub struct NodeRef {
@@ -28,6 +28,11 @@ impl NodeRef {
 
 const K: usize = 4;
 
+/// Alternatives of at least this many string literals are compiled
+/// as a single lexeme (see `RegexBuilder::literal_set()`),
+/// instead of a lexeme per literal.
+pub(crate) const LITERAL_SET_MIN_SIZE: usize = 16;
+
 pub struct GrammarBuilder {
     pub(crate) grammar: Grammar,
     curr_grammar_id: LexemeClass,
@@ -39,6 +44,9 @@ pub struct GrammarBuilder {
     strings: HashMap<String, NodeRef>,
     at_most_cache: HashMap<(NodeRef, usize), NodeRef>,
     repeat_exact_cache: HashMap<(NodeRef, usize), NodeRef>,
+
+    /// Non-fatal problems found by grammar front-ends.
+    pub(crate) warnings: Vec<Diagnostic>,
 }
 
 pub struct GrammarResult {
@@ -48,6 +56,43 @@ pub struct GrammarResult {
 
 pub struct RegexBuilder {
     pub(crate) spec: LexerSpec,
+    followed: HashMap<RegexId, (RegexId, LexemeFollow)>,
+    pub(crate) sources: Option<GrammarSources>,
+}
+
+/// Records how regexes were constructed, so that the grammar can be
+/// rendered back to Lark (see `lark::export`).
+/// This is only enabled with `GrammarBuilder::record_sources()`.
+#[derive(Default)]
+pub(crate) struct GrammarSources {
+    pub asts: HashMap<RegexId, RegexAst>,
+    pub exts: HashMap<RegexId, RegexExt>,
+    /// Options passed to `add_grammar()`, indexed by lexeme class.
+    pub options: Vec<LLGuidanceOptions>,
+    /// Names of top-level grammars.
+    pub names: HashMap<LexemeClass, String>,
+    /// Names of Lark terminals.
+    pub names_by_rx: HashMap<RegexId, String>,
+    /// Names of Lark rules.
+    pub rule_names: HashMap<SymIdx, String>,
+}
+
+impl GrammarSources {
+    fn add_ast(&mut self, id: RegexId, ast: &RegexAst) {
+        // derivre may return one of the arguments (eg., for a single-element concat);
+        // don't record these, as they would be self-referential
+        if matches!(ast, RegexAst::ExprRef(_))
+            || ast
+                .get_args()
+                .iter()
+                .any(|a| matches!(a, RegexAst::ExprRef(r) if *r == id))
+        {
+            return;
+        }
+        if !self.asts.contains_key(&id) && !self.exts.contains_key(&id) {
+            self.asts.insert(id, ast.clone());
+        }
+    }
 }
 
 pub type RegexId = derivre::ExprRef;
@@ -66,21 +111,72 @@ impl RegexBuilder {
     pub fn new() -> Self {
         Self {
             spec: LexerSpec::new().unwrap(),
+            followed: HashMap::default(),
+            sources: None,
         }
     }
 
     pub fn add_ast(&mut self, ast: RegexAst) -> Result<RegexId> {
-        self.spec.regex_builder.mk(&ast)
+        let id = self.spec.regex_builder.mk(&ast)?;
+        if let Some(sources) = &mut self.sources {
+            sources.add_ast(id, &ast);
+        }
+        Ok(id)
     }
 
     pub fn regex(&mut self, rx: &str) -> Result<RegexId> {
-        self.spec.regex_builder.mk_regex(rx)
+        let id = self.spec.regex_builder.mk_regex(rx)?;
+        if let Some(sources) = &mut self.sources {
+            sources.add_ast(id, &RegexAst::Regex(rx.to_string()));
+        }
+        Ok(id)
+    }
+
+    /// Record that `id` was constructed from `%regex { ... }`.
+    pub(crate) fn add_ext_source(&mut self, id: RegexId, ext: &RegexExt) {
+        if let Some(sources) = &mut self.sources {
+            if !sources.asts.contains_key(&id) {
+                sources.exts.entry(id).or_insert_with(|| ext.clone());
+            }
+        }
+    }
+
+    /// Remember the source name of `id`, if recording sources.
+    pub(crate) fn record_name(&mut self, id: RegexId, name: &str) {
+        // lexemes with lookahead only keep the body
+        let body = self.followed.get(&id).map(|(body, _)| *body);
+        if let Some(sources) = &mut self.sources {
+            for id in [Some(id), body].into_iter().flatten() {
+                sources
+                    .names_by_rx
+                    .entry(id)
+                    .or_insert_with(|| name.to_string());
+            }
+        }
     }
 
     pub fn literal(&mut self, s: String) -> RegexId {
         self.add_ast(RegexAst::Literal(s)).unwrap()
     }
 
+    /// Matches any of the literals.
+    /// The regex is built directly as a byte trie, so this is much cheaper
+    /// than `select()` of literals, when there are thousands of them.
+    pub fn literal_set(&mut self, mut literals: Vec<String>) -> Result<RegexId> {
+        literals.sort();
+        literals.dedup();
+        let branches = literals
+            .iter()
+            .map(|s| (s.as_bytes().to_vec(), ExprRef::EMPTY_STRING))
+            .collect();
+        let id = self.spec.regex_builder.mk_prefix_tree(branches)?;
+        if let Some(sources) = &mut self.sources {
+            let ast = RegexAst::Or(literals.into_iter().map(RegexAst::Literal).collect());
+            sources.add_ast(id, &ast);
+        }
+        Ok(id)
+    }
+
     pub fn concat(&mut self, nodes: Vec<RegexId>) -> RegexId {
         if nodes.len() == 1 {
             return nodes[0];
@@ -134,6 +230,35 @@ impl RegexBuilder {
     pub fn or(&mut self, nodes: Vec<RegexId>) -> RegexId {
         self.select(nodes)
     }
+
+    /// Returns a regex for `body` that, when used as a lexeme, only matches
+    /// if followed by something allowed by `follow` (see `LexemeFollow`).
+    /// The result can't be combined with other regexes, only passed to
+    /// `GrammarBuilder::lexeme()` and friends.
+    pub fn followed_by(&mut self, body: RegexId, follow: LexemeFollow) -> Result<RegexId> {
+        ensure!(
+            follow.bytes.len() == 256,
+            "follow set must have 256 entries"
+        );
+        let bytes = follow.bytes.as_slice()[0..256 / 32].to_vec();
+        let id = self.add_ast(RegexAst::Concat(vec![
+            RegexAst::ExprRef(body),
+            RegexAst::LookAhead(Box::new(RegexAst::ByteSet(bytes))),
+        ]))?;
+        if let Some((_, prev)) = self.followed.get(&id) {
+            ensure!(
+                prev.eos == follow.eos,
+                "conflicting lookaheads: they only differ in handling of end of input"
+            );
+        }
+        self.followed.insert(id, (body, follow));
+        Ok(id)
+    }
+
+    /// If `rx` was constructed with `followed_by()`, return its body and follow set.
+    pub fn get_follow(&self, rx: RegexId) -> Option<&(RegexId, LexemeFollow)> {
+        self.followed.get(&rx)
+    }
 }
 
 impl GrammarBuilder {
@@ -148,9 +273,33 @@ impl GrammarBuilder {
             repeat_exact_cache: HashMap::default(),
             limits,
             tok_env,
+            warnings: vec![],
+        }
+    }
+
+    /// Keep track of how regexes are constructed, so that the grammar
+    /// can be later exported to Lark.
+    pub(crate) fn record_sources(&mut self) {
+        self.regex.sources = Some(GrammarSources::default());
+    }
+
+    /// Name `node` after the rule it was compiled from, unless it already has a name.
+    /// Also remember the source name, if recording sources.
+    pub(crate) fn record_rule_name(&mut self, node: NodeRef, name: &str) {
+        self.grammar.name_anonymous_symbol(node.idx, name);
+        if let Some(sources) = &mut self.regex.sources {
+            sources
+                .rule_names
+                .entry(node.idx)
+                .or_insert_with(|| name.to_string());
         }
     }
 
+    /// Record that captures of `node` quote one of `documents`.
+    pub(crate) fn set_capture_documents(&mut self, node: NodeRef, documents: Arc<Vec<String>>) {
+        self.grammar.set_capture_documents(node.idx, documents);
+    }
+
     pub fn check_limits(&self) -> Result<()> {
         ensure!(
             self.regex.spec.cost() <= self.limits.initial_lexer_fuel,
@@ -172,6 +321,9 @@ impl GrammarBuilder {
         self.check_limits()?;
 
         let grammar_id = self.regex.spec.new_lexeme_class(skip)?;
+        if let Some(sources) = &mut self.regex.sources {
+            sources.options.push(options.clone());
+        }
 
         self.strings.clear();
         self.at_most_cache.clear();
@@ -211,7 +363,7 @@ impl GrammarBuilder {
             return *r;
         }
         let r = if s.is_empty() {
-            let r = self.new_node("empty");
+            let r = self.new_helper_node("empty");
             self.grammar.add_rule(r.idx, vec![]).unwrap();
             r
         } else {
@@ -224,6 +376,7 @@ impl GrammarBuilder {
                     false,
                     None,
                     usize::MAX,
+                    None,
                 )
                 .unwrap();
             self.lexeme_to_node(lx_id)
@@ -257,6 +410,44 @@ impl GrammarBuilder {
         Ok(self.lexeme_to_node(id))
     }
 
+    /// Matches any single token, except for the ones in `excluded` and the EOS token.
+    pub fn token_ranges_negated(
+        &mut self,
+        mut excluded: Vec<RangeInclusive<u32>>,
+    ) -> Result<NodeRef> {
+        self.check_limits()?;
+
+        let name = token_ranges_to_string(&excluded).replacen("<[", "<[^", 1);
+
+        let trie = self.tok_env("token ranges")?.tok_trie();
+        let vocab_size = trie.vocab_size() as u32;
+        let eos = trie.eos_token();
+        for r in &excluded {
+            ensure!(r.start() <= r.end(), "Invalid token range: {:?}", r);
+        }
+        excluded.push(eos..=eos);
+        excluded.sort_by_key(|r| *r.start());
+
+        let mut token_ranges = vec![];
+        let mut next = 0;
+        for r in &excluded {
+            if *r.start() > next && next < vocab_size {
+                token_ranges.push(next..=std::cmp::min(*r.start(), vocab_size) - 1);
+            }
+            next = std::cmp::max(next, r.end().saturating_add(1));
+        }
+        if next < vocab_size {
+            token_ranges.push(next..=vocab_size - 1);
+        }
+        ensure!(
+            !token_ranges.is_empty(),
+            "negated token range excludes all tokens"
+        );
+
+        let id = self.regex.spec.add_special_token(name, token_ranges)?;
+        Ok(self.lexeme_to_node(id))
+    }
+
     pub fn special_token(&mut self, token: &str) -> Result<NodeRef> {
         self.check_limits()?;
 
@@ -281,7 +472,7 @@ impl GrammarBuilder {
         if props.max_tokens.is_some() {
             self.regex.spec.has_max_tokens = true;
         }
-        let r = self.new_node("gg");
+        let r = self.new_helper_node("gg");
         self.grammar.apply_node_props(r.idx, props);
         self.grammar.make_gen_grammar(r.idx, data).unwrap();
         r
@@ -321,26 +512,21 @@ impl GrammarBuilder {
         self.lexeme_ext(rx, None, NodeProps::default())
     }
 
+    /// Like lexeme(), but the lexeme is named (this shows up in debug output
+    /// and in expected terminals).
+    pub fn named_lexeme(&mut self, rx: ExprRef, name: &str) -> NodeRef {
+        let idx = self.add_lexeme(rx, name, usize::MAX);
+        self.lexeme_to_node(idx)
+    }
+
     pub fn lexeme_ext(
         &mut self,
         rx: ExprRef,
         temperature: Option<f32>,
         props: NodeProps,
     ) -> NodeRef {
-        let idx = self
-            .regex
-            .spec
-            .add_greedy_lexeme(
-                props
-                    .capture_name
-                    .clone()
-                    .unwrap_or_else(|| "lx".to_string()),
-                RegexAst::ExprRef(rx),
-                false,
-                None,
-                props.max_tokens.unwrap_or(usize::MAX),
-            )
-            .unwrap();
+        let name = props.capture_name.as_deref().unwrap_or("lx");
+        let idx = self.add_lexeme(rx, name, props.max_tokens.unwrap_or(usize::MAX));
         let r = self.lexeme_to_node(idx);
         self.grammar.apply_node_props(r.idx, props);
         if let Some(t) = temperature {
@@ -349,6 +535,24 @@ impl GrammarBuilder {
         r
     }
 
+    fn add_lexeme(&mut self, rx: ExprRef, name: &str, max_tokens: usize) -> LexemeIdx {
+        let (rx, follow) = match self.regex.get_follow(rx) {
+            Some((body, follow)) => (*body, Some(follow.clone())),
+            None => (rx, None),
+        };
+        self.regex
+            .spec
+            .add_greedy_lexeme(
+                name.to_string(),
+                RegexAst::ExprRef(rx),
+                false,
+                None,
+                max_tokens,
+                follow,
+            )
+            .unwrap()
+    }
+
     fn child_nodes(&mut self, options: &[NodeRef]) -> Vec<SymIdx> {
         options
             .iter()
@@ -422,14 +626,14 @@ impl GrammarBuilder {
     }
 
     pub fn one_or_more(&mut self, elt: NodeRef) -> NodeRef {
-        let p = self.new_node("plus");
+        let p = self.new_helper_node("plus");
         self.grammar.add_rule(p.idx, vec![elt.idx]).unwrap();
         self.grammar.add_rule(p.idx, vec![p.idx, elt.idx]).unwrap();
         p
     }
 
     pub fn zero_or_more(&mut self, elt: NodeRef) -> NodeRef {
-        let p = self.new_node("star");
+        let p = self.new_helper_node("star");
         self.grammar.add_rule(p.idx, vec![]).unwrap();
         self.grammar.add_rule(p.idx, vec![p.idx, elt.idx]).unwrap();
         p
@@ -615,6 +819,12 @@ impl GrammarBuilder {
         }
     }
 
+    fn new_helper_node(&mut self, name: &str) -> NodeRef {
+        let r = self.new_node(name);
+        self.grammar.sym_props_mut(r.idx).is_helper = true;
+        r
+    }
+
     pub fn set_placeholder(&mut self, placeholder: NodeRef, node: NodeRef) {
         let _ = self.child_nodes(&[placeholder, node]); // validate
         self.grammar
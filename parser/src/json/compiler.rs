@@ -1,5 +1,6 @@
 use crate::api::LLGuidanceOptions;
 use crate::grammar_builder::GrammarResult;
+use crate::normalize::case_insensitive_literal_regex;
 use crate::HashMap;
 use anyhow::{anyhow, Context, Result};
 use derivre::{JsonQuoteOptions, RegexAst};
@@ -8,7 +9,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
 use super::numeric::{check_number_bounds, rx_float_range, rx_int_range, Decimal};
-use super::schema::{build_schema, Schema};
+use super::schema::{build_schema, Schema, SchemaBuilderOptions};
 use super::RetrieveWrapper;
 
 use crate::{GrammarBuilder, NodeRef};
@@ -24,6 +25,9 @@ pub struct JsonCompileOptions {
     pub key_separator: String,
     pub whitespace_flexible: bool,
     pub coerce_one_of: bool,
+    /// Match string `enum` and `const` values case-insensitively and in any
+    /// Unicode normalization form (NFC/NFD).
+    pub case_insensitive_enums: bool,
     #[serde(skip)]
     pub retriever: Option<RetrieveWrapper>,
 }
@@ -71,6 +75,7 @@ impl Default for JsonCompileOptions {
             key_separator: ":".to_string(),
             whitespace_flexible: true,
             coerce_one_of: false,
+            case_insensitive_enums: false,
             retriever: None,
         }
     }
@@ -97,6 +102,18 @@ impl JsonCompileOptions {
         compiler.execute(schema)
     }
 
+    /// Read options from the "x-guidance" property of the schema (if any),
+    /// and remove it from the schema.
+    pub fn take_from_schema(schema: &mut Value) -> Result<Self> {
+        match schema
+            .as_object_mut()
+            .and_then(|obj| obj.remove("x-guidance"))
+        {
+            Some(x_guidance) => Ok(serde_json::from_value(x_guidance)?),
+            None => Ok(Self::default()),
+        }
+    }
+
     pub fn apply_to(&self, schema: &mut Value) {
         schema.as_object_mut().unwrap().insert(
             "x-guidance".to_string(),
@@ -127,7 +144,12 @@ impl Compiler {
             .builder
             .add_grammar(LLGuidanceOptions::default(), skip)?;
 
-        let (compiled_schema, definitions) = build_schema(schema, self.options.retriever.clone())?;
+        let schema_options = SchemaBuilderOptions {
+            case_insensitive_enums: self.options.case_insensitive_enums,
+            ..Default::default()
+        };
+        let (compiled_schema, definitions) =
+            build_schema(schema, self.options.retriever.clone(), schema_options)?;
 
         let root = self.gen_json(&compiled_schema)?;
         self.builder.set_start_node(root);
@@ -634,6 +656,10 @@ impl Compiler {
         if let Some(mut ast) = regex {
             let mut positive = false;
 
+            if self.options.case_insensitive_enums {
+                ast = case_insensitive_literals(ast);
+            }
+
             fn mk_rx_repr(ast: &RegexAst) -> String {
                 let mut rx_repr = String::new();
                 ast.write_to_str(&mut rx_repr, 1_000, None);
@@ -811,6 +837,20 @@ impl Compiler {
     }
 }
 
+/// Replace string literals (coming from `enum` and `const`) with
+/// case- and normalization-insensitive regexes.
+fn case_insensitive_literals(ast: RegexAst) -> RegexAst {
+    let map = |asts: Vec<RegexAst>| asts.into_iter().map(case_insensitive_literals).collect();
+    match ast {
+        RegexAst::Literal(s) => RegexAst::Regex(case_insensitive_literal_regex(&s)),
+        RegexAst::Or(asts) => RegexAst::Or(map(asts)),
+        RegexAst::And(asts) => RegexAst::And(map(asts)),
+        RegexAst::Concat(asts) => RegexAst::Concat(map(asts)),
+        RegexAst::Not(ast) => RegexAst::Not(Box::new(case_insensitive_literals(*ast))),
+        _ => ast,
+    }
+}
+
 fn always_non_empty(ast: &RegexAst) -> bool {
     match ast {
         RegexAst::Or(asts) => asts.iter().any(always_non_empty),
@@ -30,6 +30,49 @@ pub fn json_merge(a: &mut Value, b: &Value) {
     }
 }
 
+/// Sort keys of the schema, so that schemas differing only in key order compare equal.
+/// The order of "properties" determines the order of fields in the output and is kept,
+/// and so are values of "const", "enum", "default", and "examples".
+pub(crate) fn canonical_schema(schema: &Value) -> Value {
+    match schema {
+        Value::Object(obj) => {
+            let mut keys: Vec<&String> = obj.keys().collect();
+            keys.sort();
+            let mut res = serde_json::Map::new();
+            for k in keys {
+                let v = &obj[k];
+                let v = match (k.as_str(), v) {
+                    ("const" | "enum" | "default" | "examples", _) => v.clone(),
+                    ("properties", Value::Object(props)) => Value::Object(
+                        props
+                            .iter()
+                            .map(|(name, s)| (name.clone(), canonical_schema(s)))
+                            .collect(),
+                    ),
+                    (
+                        "$defs" | "definitions" | "patternProperties" | "dependentSchemas",
+                        Value::Object(defs),
+                    ) => {
+                        let mut names: Vec<&String> = defs.keys().collect();
+                        names.sort();
+                        Value::Object(
+                            names
+                                .into_iter()
+                                .map(|name| (name.clone(), canonical_schema(&defs[name])))
+                                .collect(),
+                        )
+                    }
+                    _ => canonical_schema(v),
+                };
+                res.insert(k.clone(), v);
+            }
+            Value::Object(res)
+        }
+        Value::Array(arr) => Value::Array(arr.iter().map(canonical_schema).collect()),
+        _ => schema.clone(),
+    }
+}
+
 pub trait Retrieve: Send + Sync {
     fn retrieve(&self, uri: &str) -> Result<Value, Box<dyn std::error::Error + Send + Sync>>;
 }
@@ -1,4 +1,4 @@
-use crate::HashMap;
+use crate::{grammar_builder::LITERAL_SET_MIN_SIZE, normalize::case_fold_key, HashMap};
 use anyhow::{anyhow, bail, Result};
 use derivre::RegexAst;
 use indexmap::{IndexMap, IndexSet};
@@ -129,7 +129,7 @@ impl Schema {
     }
 
     /// Shallowly normalize the schema, removing any unnecessary nesting or empty options.
-    fn normalize(self) -> Schema {
+    fn normalize(self, ctx: &Context) -> Schema {
         match self {
             Schema::AnyOf { options } => {
                 let mut unsats = Vec::new();
@@ -193,7 +193,7 @@ impl Schema {
                     valid
                         .iter()
                         .skip(i + 1) // "upper diagonal"
-                        .all(|y| x.is_verifiably_disjoint_from(y))
+                        .all(|y| x.is_verifiably_disjoint_from(y, ctx))
                 }) {
                     Schema::AnyOf { options: valid }
                 } else {
@@ -325,7 +325,7 @@ impl Schema {
                     prefix2.resize_with(len, || items2.as_deref().cloned().unwrap_or(Schema::Any));
                     prefix1
                         .into_iter()
-                        .zip(prefix2.into_iter())
+                        .zip(prefix2)
                         .map(|(item1, item2)| item1.intersect(item2, ctx))
                         .collect::<Result<Vec<_>>>()?
                 },
@@ -378,10 +378,10 @@ impl Schema {
                 reason: "incompatible types".to_string(),
             },
         };
-        Ok(merged.normalize())
+        Ok(merged.normalize(ctx))
     }
 
-    fn is_verifiably_disjoint_from(&self, other: &Schema) -> bool {
+    fn is_verifiably_disjoint_from(&self, other: &Schema, ctx: &Context) -> bool {
         match (self, other) {
             (Schema::Unsatisfiable { .. }, _) => true,
             (_, Schema::Unsatisfiable { .. }) => true,
@@ -396,16 +396,16 @@ impl Schema {
             }
             (Schema::AnyOf { options }, _) => options
                 .iter()
-                .all(|opt| opt.is_verifiably_disjoint_from(other)),
+                .all(|opt| opt.is_verifiably_disjoint_from(other, ctx)),
             (_, Schema::AnyOf { options }) => options
                 .iter()
-                .all(|opt| self.is_verifiably_disjoint_from(opt)),
+                .all(|opt| self.is_verifiably_disjoint_from(opt, ctx)),
             (Schema::OneOf { options }, _) => options
                 .iter()
-                .all(|opt| opt.is_verifiably_disjoint_from(other)),
+                .all(|opt| opt.is_verifiably_disjoint_from(other, ctx)),
             (_, Schema::OneOf { options }) => options
                 .iter()
-                .all(|opt| self.is_verifiably_disjoint_from(opt)),
+                .all(|opt| self.is_verifiably_disjoint_from(opt, ctx)),
             // TODO: could actually compile the regexes and check for overlap
             (
                 Schema::String {
@@ -416,7 +416,13 @@ impl Schema {
                     regex: Some(RegexAst::Literal(lit2)),
                     ..
                 },
-            ) => lit1 != lit2,
+            ) => {
+                if ctx.options.case_insensitive_enums {
+                    case_fold_key(lit1) != case_fold_key(lit2)
+                } else {
+                    lit1 != lit2
+                }
+            }
             (
                 Schema::Object {
                     properties: props1,
@@ -435,7 +441,7 @@ impl Schema {
                 let prop2 = props2
                     .get(key)
                     .unwrap_or(add2.as_deref().unwrap_or(&Schema::Any));
-                prop1.is_verifiably_disjoint_from(prop2)
+                prop1.is_verifiably_disjoint_from(prop2, ctx)
             }),
             _ => {
                 // Except for in the cases above, it should suffice to check that the types are different
@@ -448,17 +454,24 @@ impl Schema {
 #[derive(Clone)]
 pub struct SchemaBuilderOptions {
     pub max_size: usize,
+    /// String literals are matched case-insensitively (and in any normalization form),
+    /// so they are only disjoint if they differ after case folding.
+    pub case_insensitive_enums: bool,
 }
 
 impl Default for SchemaBuilderOptions {
     fn default() -> Self {
-        SchemaBuilderOptions { max_size: 50_000 }
+        SchemaBuilderOptions {
+            max_size: 50_000,
+            case_insensitive_enums: false,
+        }
     }
 }
 
 pub fn build_schema(
     contents: Value,
     retriever: Option<RetrieveWrapper>,
+    options: SchemaBuilderOptions,
 ) -> Result<(Schema, HashMap<String, Schema>)> {
     if let Some(b) = contents.as_bool() {
         if b {
@@ -469,7 +482,8 @@ pub fn build_schema(
     }
 
     let pre_ctx = PreContext::new(contents, retriever)?;
-    let ctx = Context::new(&pre_ctx)?;
+    let mut ctx = Context::new(&pre_ctx)?;
+    ctx.options = options;
 
     let root_resource = ctx.lookup_resource(&pre_ctx.base_uri)?;
     let schema = compile_resource(&ctx, root_resource)?;
@@ -482,7 +496,7 @@ fn compile_resource(ctx: &Context, resource: ResourceRef) -> Result<Schema> {
 }
 
 fn compile_contents(ctx: &Context, contents: &Value) -> Result<Schema> {
-    compile_contents_inner(ctx, contents).map(|schema| schema.normalize())
+    compile_contents_inner(ctx, contents).map(|schema| schema.normalize(ctx))
 }
 
 fn compile_contents_inner(ctx: &Context, contents: &Value) -> Result<Schema> {
@@ -547,6 +561,19 @@ fn compile_contents_map(ctx: &Context, mut schemadict: HashMap<&str, &Value>) ->
         if matches!(siblings, Schema::Unsatisfiable { .. }) {
             return Ok(siblings);
         }
+        if instances.len() >= LITERAL_SET_MIN_SIZE && instances.iter().all(|v| v.is_string()) {
+            // large enums of strings (country codes etc.) are compiled as a single lexeme
+            let literals = instances
+                .iter()
+                .map(|v| RegexAst::Literal(v.as_str().unwrap().to_string()))
+                .collect();
+            let schema = Schema::String {
+                min_length: 0,
+                max_length: None,
+                regex: Some(RegexAst::Or(literals)),
+            };
+            return schema.intersect(siblings, ctx);
+        }
         let options = instances
             .iter()
             .map(compile_const)
@@ -568,13 +595,7 @@ fn compile_contents_map(ctx: &Context, mut schemadict: HashMap<&str, &Value>) ->
             .iter()
             .map(|value| compile_resource(ctx, ctx.as_resource_ref(value)))
             .collect::<Result<Vec<_>>>()?;
-        let merged = intersect(
-            ctx,
-            vec![siblings]
-                .into_iter()
-                .chain(options.into_iter())
-                .collect(),
-        )?;
+        let merged = intersect(ctx, vec![siblings].into_iter().chain(options).collect())?;
         return Ok(merged);
     }
 
@@ -610,7 +631,7 @@ fn compile_contents_map(ctx: &Context, mut schemadict: HashMap<&str, &Value>) ->
             .map(|value| compile_resource(ctx, ctx.as_resource_ref(value)))
             .map(|res| res.and_then(|schema| siblings.clone().intersect(schema, ctx)))
             .collect::<Result<Vec<_>>>()?;
-        return Ok(Schema::OneOf { options }.normalize());
+        return Ok(Schema::OneOf { options }.normalize(ctx));
     }
 
     if let Some(reference) = schemadict.remove("$ref") {
@@ -1073,7 +1094,7 @@ fn opt_min<T: PartialOrd>(a: Option<T>, b: Option<T>) -> Option<T> {
 mod test_retriever {
     use crate::json::{Retrieve, RetrieveWrapper};
 
-    use super::{build_schema, Schema};
+    use super::{build_schema, Schema, SchemaBuilderOptions};
     use serde_json::{json, Value};
     use std::{fmt, sync::Arc};
 
@@ -1117,7 +1138,8 @@ mod test_retriever {
             .collect(),
         };
         let wrapper = RetrieveWrapper::new(Arc::new(retriever));
-        let (schema, defs) = build_schema(schema, Some(wrapper)).unwrap();
+        let (schema, defs) =
+            build_schema(schema, Some(wrapper), SchemaBuilderOptions::default()).unwrap();
         match schema {
             Schema::Ref { uri } => {
                 assert_eq!(uri, key);
@@ -1136,4 +1158,19 @@ mod test_retriever {
             _ => panic!("Unexpected schema: {:?}", val),
         }
     }
+
+    #[test]
+    fn test_case_insensitive_one_of() {
+        let schema = json!({"oneOf": [{"const": "Foo"}, {"const": "foo"}]});
+        let build = |case_insensitive_enums| {
+            let options = SchemaBuilderOptions {
+                case_insensitive_enums,
+                ..Default::default()
+            };
+            build_schema(schema.clone(), None, options).unwrap().0
+        };
+        assert!(matches!(build(false), Schema::AnyOf { .. }));
+        // both match "FOO", so they are not disjoint
+        assert!(matches!(build(true), Schema::OneOf { .. }));
+    }
 }
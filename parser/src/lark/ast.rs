@@ -39,6 +39,8 @@ pub struct Rule {
     pub temperature: Option<f32>,
     pub capture_name: Option<String>,
     pub stop_capture_name: Option<String>,
+    /// Replaces %ignore inside of this rule.
+    pub ignore: Option<Value>,
 }
 
 /// Represents a token definition.
@@ -91,6 +93,23 @@ impl Expansions {
             None
         }
     }
+
+    /// If all alternatives are plain, non-empty string literals
+    /// (like `"a" | "b"`), return them.
+    pub fn literal_alternatives(&self) -> Option<Vec<String>> {
+        self.1
+            .iter()
+            .map(|alias| match &alias.expansion.0[..] {
+                [Expr {
+                    atom: Atom::Value(Value::LiteralString(s, flags)),
+                    op: None,
+                    range: None,
+                    ..
+                }] if flags.is_empty() && !s.is_empty() && alias.alias.is_none() => Some(s.clone()),
+                _ => None,
+            })
+            .collect()
+    }
 }
 
 /// Represents an alias in the grammar.
@@ -108,6 +127,7 @@ pub struct Expansion(pub Vec<Expr>);
 /// Represents an expression.
 #[derive(Debug, Clone)]
 pub struct Expr {
+    pub loc: Location,
     pub atom: Atom,
     pub op: Option<Op>,
     pub range: Option<(i32, i32)>,
@@ -156,3 +176,16 @@ impl Rule {
         }
     }
 }
+
+/// Call `f` on every expression in `exp`, including nested ones.
+pub fn walk_expansions<'a>(exp: &'a Expansions, f: &mut impl FnMut(&'a Expr)) {
+    for alias in &exp.1 {
+        for expr in &alias.expansion.0 {
+            f(expr);
+            match &expr.atom {
+                Atom::Group(inner) | Atom::Maybe(inner) => walk_expansions(inner, f),
+                Atom::Value(_) => {}
+            }
+        }
+    }
+}
@@ -1,13 +1,20 @@
 use crate::{
-    grammar_builder::{GrammarResult, RegexId},
-    substring::substring,
+    grammar_builder::{GrammarResult, RegexId, LITERAL_SET_MIN_SIZE},
+    normalize::case_insensitive_literal_regex,
+    substring::{fuzzy_substring, substring, Gaps},
     HashMap, HashSet,
 };
+use std::{ops::RangeInclusive, sync::Arc};
+
 use anyhow::{anyhow, bail, ensure, Result};
 use derivre::RegexAst;
 
 use crate::{
-    api::{GenGrammarOptions, GenOptions, GrammarId, LLGuidanceOptions, NodeProps, RegexExt},
+    api::{
+        Diagnostic, GenGrammarOptions, GenOptions, GrammarError, GrammarId, LLGuidanceOptions,
+        NodeProps, RegexExt, Severity,
+    },
+    earley::SymIdx,
     json::json_merge,
     substring::{chunk_into_chars, chunk_into_words},
     GrammarBuilder, JsonCompileOptions, NodeRef,
@@ -17,7 +24,9 @@ use super::{
     ast::*,
     common::lookup_common_regex,
     lexer::Location,
+    lookaround::compile_lookarounds,
     parser::{parse_lark, ParsedLark},
+    python_re::python_re_to_rust,
 };
 
 #[derive(Debug)]
@@ -47,6 +56,16 @@ struct Compiler {
     regex_ids: HashMap<String, RegexId>,
     in_progress: HashSet<String>,
     pending_json_grammars: Vec<(NodeRef, Location, serde_json::Value)>,
+    // references to rules with ignore=, to be linked to their grammars
+    pending_scopes: Vec<(NodeRef, String)>,
+    // the rule with ignore= whose grammar is being compiled, if any
+    scope: Option<String>,
+    // some rule has ignore=, so rules can be compiled once per grammar
+    has_scopes: bool,
+    diagnostics: Vec<Diagnostic>,
+    python_re: bool,
+    // documents of substring_documents regexes
+    documents: HashMap<RegexId, Arc<Vec<String>>>,
 }
 
 fn compile_lark(builder: GrammarBuilder, parsed: ParsedLark) -> Result<GrammarResult> {
@@ -58,6 +77,12 @@ fn compile_lark(builder: GrammarBuilder, parsed: ParsedLark) -> Result<GrammarRe
         regex_ids: HashMap::default(),
         in_progress: HashSet::default(),
         pending_json_grammars: vec![],
+        pending_scopes: vec![],
+        scope: None,
+        has_scopes: false,
+        diagnostics: vec![],
+        python_re: false,
+        documents: HashMap::default(),
     };
     c.execute()
 }
@@ -108,16 +133,23 @@ impl Compiler {
         if self.in_progress.contains(name) {
             bail!("circular reference in token {:?} definition", name);
         }
-        self.in_progress.insert(name.to_string());
         let token = self
             .grammar
             .tokens
             .remove(name)
             .ok_or_else(|| anyhow!("unknown name: {:?}", name))?;
-        let id = self.do_token_expansions(token.expansions)?;
-        self.regex_ids.insert(name.to_string(), id);
+        self.in_progress.insert(name.to_string());
+        let res = self.do_token_expansions(token.expansions);
         self.in_progress.remove(name);
-        Ok(id)
+        // on error, the token is replaced with one that matches nothing,
+        // so that the error is only reported once
+        let id = match &res {
+            Ok(id) => *id,
+            Err(_) => self.builder.regex.add_ast(RegexAst::NoMatch)?,
+        };
+        self.regex_ids.insert(name.to_string(), id);
+        self.builder.regex.record_name(id, name);
+        res
     }
 
     fn mk_regex(&mut self, info: &str, rx: String) -> Result<RegexId> {
@@ -133,6 +165,7 @@ impl Compiler {
             Atom::Group(expansions) => self.do_token_expansions(expansions),
             Atom::Maybe(expansions) => {
                 let id = self.do_token_expansions(expansions)?;
+                self.check_no_lookahead(id, "inside [...]")?;
                 Ok(self.builder.regex.optional(id))
             }
             Atom::Value(value) => match value {
@@ -163,24 +196,41 @@ impl Compiler {
                 Value::Name(n) => self.do_token(&n),
                 Value::LiteralString(val, flags) => {
                     if flags.contains("i") {
-                        self.mk_regex(
-                            "string with i-flag",
-                            format!("(?i){}", regex_syntax::escape(&val)),
-                        )
+                        self.mk_regex("string with i-flag", case_insensitive_literal_regex(&val))
                     } else {
                         Ok(self.builder.regex.literal(val))
                     }
                 }
                 Value::LiteralRegex(val, flags) => {
-                    ensure!(!flags.contains("l"), "l-flag is not supported in regexes");
-                    let rx = if flags.is_empty() {
-                        val
+                    let rx = if self.python_re {
+                        python_re_to_rust(&val, &flags)?
                     } else {
-                        format!("(?{}){}", flags, val)
+                        ensure!(!flags.contains("l"), "l-flag is not supported in regexes");
+                        if flags.is_empty() {
+                            val
+                        } else {
+                            format!("(?{}){}", flags, val)
+                        }
                     };
-                    self.mk_regex("regex", rx)
+                    let (ast, follow) = compile_lookarounds(&rx)?;
+                    let id = self
+                        .builder
+                        .regex
+                        .add_ast(ast)
+                        .map_err(|e| anyhow!("invalid regex {rx:?} (in regex): {e}"))?;
+                    match follow {
+                        Some(follow) => self.builder.regex.followed_by(id, follow),
+                        None => Ok(id),
+                    }
+                }
+                Value::RegexExt(s) => {
+                    let documents = s.substring_documents.clone();
+                    let id = compile_lark_regex(&mut self.builder, s)?;
+                    if let Some(documents) = documents {
+                        self.documents.insert(id, Arc::new(documents));
+                    }
+                    Ok(id)
                 }
-                Value::RegexExt(s) => compile_lark_regex(&mut self.builder, s),
                 Value::SpecialToken(s) => {
                     bail!("special tokens (like {:?}) cannot be used in terminals", s);
                 }
@@ -199,7 +249,15 @@ impl Compiler {
     }
 
     fn do_token_expr(&mut self, expr: Expr) -> Result<RegexId> {
+        let loc = expr.loc.clone();
+        self.do_token_expr_inner(expr).map_err(|e| loc.augment(e))
+    }
+
+    fn do_token_expr_inner(&mut self, expr: Expr) -> Result<RegexId> {
         let atom = self.do_token_atom(expr.atom)?;
+        if expr.range.is_some() || expr.op.is_some() {
+            self.check_no_lookahead(atom, "with repetition operators")?;
+        }
         if let Some(range) = &expr.range {
             ensure!(expr.op.is_none(), "ranges not supported with operators");
             ensure!(range.0 >= 0, "range start must be >= 0, got {:?}", range);
@@ -234,23 +292,62 @@ impl Compiler {
 
     fn do_token_expansions(&mut self, expansions: Expansions) -> Result<RegexId> {
         self.builder.check_limits()?;
+        if let Some(literals) = self.literal_set(&expansions) {
+            return self.builder.regex.literal_set(literals);
+        }
         let options = expansions
             .1
             .into_iter()
             .map(|alias| {
-                let args = alias
+                let mut args = alias
                     .expansion
                     .0
                     .into_iter()
                     .map(|e| self.do_token_expr(e))
                     .collect::<Result<Vec<_>>>()?;
+                if args.len() > 1 {
+                    for &arg in &args[0..args.len() - 1] {
+                        self.check_no_lookahead(arg, "in the middle of a terminal")?;
+                    }
+                    // lookahead at the end of terminal carries over to the whole terminal
+                    let last = args.len() - 1;
+                    if let Some((body, follow)) = self.builder.regex.get_follow(args[last]) {
+                        let follow = follow.clone();
+                        args[last] = *body;
+                        let id = self.builder.regex.concat(args);
+                        return self.builder.regex.followed_by(id, follow);
+                    }
+                }
                 Ok(self.builder.regex.concat(args))
             })
             .collect::<Result<Vec<_>>>()
             .map_err(|e| expansions.0.augment(e))?;
+        if options.len() > 1 {
+            for &opt in &options {
+                self.check_no_lookahead(opt, "in alternatives")
+                    .map_err(|e| expansions.0.augment(e))?;
+            }
+        }
         Ok(self.builder.regex.select(options))
     }
 
+    /// Large alternatives of string literals are compiled as a single lexeme.
+    fn literal_set(&self, expansions: &Expansions) -> Option<Vec<String>> {
+        if expansions.1.len() < LITERAL_SET_MIN_SIZE {
+            return None;
+        }
+        expansions.literal_alternatives()
+    }
+
+    fn check_no_lookahead(&self, rx_id: RegexId, ctx: &str) -> Result<()> {
+        ensure!(
+            self.builder.regex.get_follow(rx_id).is_none(),
+            "terminals with lookahead assertions can't be used {}",
+            ctx
+        );
+        Ok(())
+    }
+
     fn lift_regex(&mut self, rx_id: RegexId) -> Result<NodeRef> {
         Ok(self.builder.lexeme(rx_id))
     }
@@ -277,32 +374,17 @@ impl Compiler {
                     Value::Name(n) => {
                         if self.is_rule(n) {
                             return self.do_rule(n);
+                        } else if self.is_token_seq(n) {
+                            return self.do_token_seq(n);
                         } else {
                             // OK -> treat as token
                         }
                     }
                     Value::SpecialToken(s) => {
                         if s.starts_with("<[") && s.ends_with("]>") {
-                            let s = &s[2..s.len() - 2];
-                            let mut ranges = vec![];
-                            for range in s.split(",") {
-                                let ends: Vec<&str> = range.split('-').map(|s| s.trim()).collect();
-                                ensure!(
-                                    ends.len() == 1 || ends.len() == 2,
-                                    "invalid token range: {:?}",
-                                    range
-                                );
-                                if ends.len() == 1 && ends[0].is_empty() {
-                                    continue;
-                                }
-                                let start = ends[0].parse::<u32>()?;
-                                let end = if ends.len() == 2 {
-                                    ends[1].parse::<u32>()?
-                                } else {
-                                    start
-                                };
-                                ensure!(start <= end, "invalid token range: {:?}", range);
-                                ranges.push(start..=end);
+                            let (negated, ranges) = parse_token_ranges(&s[2..s.len() - 2])?;
+                            if negated {
+                                return self.builder.token_ranges_negated(ranges);
                             }
                             ensure!(!ranges.is_empty(), "empty token range");
                             return self.builder.token_ranges(ranges);
@@ -349,14 +431,22 @@ impl Compiler {
                         bail!("template usage not supported yet");
                     }
                 };
+                let name = match &value {
+                    Value::Name(n) => Some(n.clone()),
+                    _ => None,
+                };
                 let rx = self.do_token_atom(Atom::Value(value))?;
-                Ok(self.lift_regex(rx)?)
+                match name {
+                    Some(n) => Ok(self.builder.named_lexeme(rx, &n)),
+                    None => self.lift_regex(rx),
+                }
             }
         }
     }
 
-    fn do_expr(&mut self, loc: &Location, expr: Expr) -> Result<NodeRef> {
-        let atom = self.do_atom(loc, expr.atom)?;
+    fn do_expr(&mut self, expr: Expr) -> Result<NodeRef> {
+        let loc = expr.loc.clone();
+        let atom = self.do_atom(&loc, expr.atom)?;
 
         if let Some((a, b)) = expr.range {
             ensure!(expr.op.is_none(), "ranges not supported with operators");
@@ -388,57 +478,191 @@ impl Compiler {
 
     fn do_expansions(&mut self, expansions: Expansions) -> Result<NodeRef> {
         self.builder.check_limits()?;
+        if let Some(literals) = self.literal_set(&expansions) {
+            let rx_id = self.builder.regex.literal_set(literals)?;
+            return self.lift_regex(rx_id);
+        }
         let loc = expansions.0;
-        let options = expansions
-            .1
-            .into_iter()
-            .map(|alias| {
-                let args = alias
-                    .expansion
-                    .0
-                    .into_iter()
-                    .map(|e| self.do_expr(&loc, e))
-                    .collect::<Result<Vec<_>>>()?;
-                Ok(self.builder.join(&args))
-            })
-            .collect::<Result<Vec<_>>>()
-            .map_err(|e| loc.augment(e))?;
+        let mut options = vec![];
+        for alias in expansions.1 {
+            if let Some(name) = &alias.alias {
+                self.warn(&loc, format!("aliases (like -> {}) are ignored", name));
+            }
+            let mut args = vec![];
+            for expr in alias.expansion.0 {
+                let expr_loc = expr.loc.clone();
+                match self.do_expr(expr) {
+                    Ok(id) => args.push(id),
+                    Err(e) => self.record(&expr_loc, e)?,
+                }
+            }
+            options.push(self.builder.join(&args));
+        }
         Ok(self.builder.select(&options))
     }
 
+    /// Record error and continue compilation, unless the error is fatal.
+    fn record(&mut self, loc: &Location, e: anyhow::Error) -> Result<()> {
+        // exceeding limits is fatal
+        self.builder.check_limits()?;
+        match e.downcast::<GrammarError>() {
+            Ok(ge) => self.diagnostics.extend(ge.diagnostics),
+            Err(e) => self
+                .diagnostics
+                .push(loc.diagnostic(Severity::Error, e.to_string())),
+        }
+        Ok(())
+    }
+
+    fn warn(&mut self, loc: &Location, message: String) {
+        self.diagnostics
+            .push(loc.diagnostic(Severity::Warning, message));
+    }
+
     fn is_rule(&self, name: &str) -> bool {
         self.node_ids.contains_key(name)
             || self.in_progress.contains(name)
             || self.grammar.rules.contains_key(name)
     }
 
+    /// Check if the token uses special tokens (like `<[123]>`), directly or
+    /// through other tokens.
+    fn is_token_seq(&self, name: &str) -> bool {
+        let mut visited = HashSet::default();
+        let mut todo = vec![name];
+        while let Some(name) = todo.pop() {
+            if !visited.insert(name) {
+                continue;
+            }
+            let Some(token) = self.grammar.tokens.get(name) else {
+                continue;
+            };
+            let mut found = false;
+            walk_expansions(&token.expansions, &mut |expr| match &expr.atom {
+                Atom::Value(Value::SpecialToken(_)) => found = true,
+                Atom::Value(Value::Name(n)) => todo.push(n),
+                _ => {}
+            });
+            if found {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Special tokens can't be part of a lexeme, so terminals using them
+    /// are compiled like rules.
+    fn do_token_seq(&mut self, name: &str) -> Result<NodeRef> {
+        let token = self.grammar.tokens.get(name).unwrap().clone();
+        self.in_progress.insert(name.to_string());
+        let res = self.do_expansions(token.expansions);
+        self.in_progress.remove(name);
+        let id = res?;
+        if let Some(placeholder) = self.node_ids.get(name) {
+            self.builder.set_placeholder(*placeholder, id);
+        }
+        self.builder.record_rule_name(id, name);
+        self.node_ids.insert(name.to_string(), id);
+        Ok(id)
+    }
+
     fn do_rule(&mut self, name: &str) -> Result<NodeRef> {
         if let Some(id) = self.node_ids.get(name) {
             return Ok(*id);
         }
+        if self.scope.as_deref() != Some(name)
+            && self
+                .grammar
+                .rules
+                .get(name)
+                .is_some_and(|r| r.ignore.is_some())
+        {
+            // compiled later, as a separate grammar
+            let gg = self.builder.gen_grammar(
+                GenGrammarOptions {
+                    grammar: GrammarId::Name(name.to_string()),
+                    temperature: None,
+                },
+                NodeProps::default(),
+            );
+            self.pending_scopes.push((gg, name.to_string()));
+            self.node_ids.insert(name.to_string(), gg);
+            return Ok(gg);
+        }
         if self.in_progress.contains(name) {
             let id = self.builder.new_node(name);
             self.node_ids.insert(name.to_string(), id);
             return Ok(id);
         }
+        // rules with ignore= can be compiled more than once
+        let rule = if self.has_scopes {
+            self.grammar.rules.get(name).cloned()
+        } else {
+            self.grammar.rules.remove(name)
+        };
+        let rule = match rule {
+            Some(rule) => rule,
+            None => bail!("rule {:?} not found", name),
+        };
+        let loc = rule.expansions.0.clone();
+        let captured = rule.capture_name.as_ref().map(|_| rule.expansions.clone());
         self.in_progress.insert(name.to_string());
 
-        let id = self.do_rule_core(name)?;
+        let id = match self.do_rule_core(rule).and_then(|id| {
+            if let Some(expansions) = captured {
+                self.record_capture_documents(&expansions, id)?;
+            }
+            Ok(id)
+        }) {
+            Ok(id) => id,
+            Err(e) => {
+                self.record(&loc, e)?;
+                self.builder.empty()
+            }
+        };
 
         if let Some(placeholder) = self.node_ids.get(name) {
             self.builder.set_placeholder(*placeholder, id);
         }
+        self.builder.record_rule_name(id, name);
         self.node_ids.insert(name.to_string(), id);
         self.in_progress.remove(name);
         Ok(id)
     }
 
-    fn do_rule_core(&mut self, name: &str) -> Result<NodeRef> {
-        let rule = self
-            .grammar
-            .rules
-            .remove(name)
-            .ok_or_else(|| anyhow!("rule {:?} not found", name))?;
+    /// If the expansions of a capturing rule are a `substring_documents` terminal,
+    /// attach the documents to its capture symbol `id`, for provenance.
+    fn record_capture_documents(&mut self, expansions: &Expansions, id: NodeRef) -> Result<()> {
+        if let Some(documents) = self.quoted_documents(expansions)? {
+            self.builder.set_capture_documents(id, documents);
+        }
+        Ok(())
+    }
+
+    /// Documents quoted by `expansions`, if it's a single `substring_documents` terminal.
+    fn quoted_documents(&mut self, expansions: &Expansions) -> Result<Option<Arc<Vec<String>>>> {
+        let id = match expansions.single_atom() {
+            Some(Atom::Value(Value::RegexExt(ext))) => {
+                return Ok(ext.substring_documents.clone().map(Arc::new));
+            }
+            Some(Atom::Value(Value::Name(n))) if !self.is_rule(n) && !self.is_token_seq(n) => {
+                self.do_token(n)?
+            }
+            _ => return Ok(None),
+        };
+        Ok(self.documents.get(&id).cloned())
+    }
+
+    fn do_rule_core(&mut self, rule: Rule) -> Result<NodeRef> {
+        if rule.cond_inline || rule.pin_terminals {
+            self.warn(
+                &rule.expansions.0,
+                format!(
+                    "rule modifiers (? and !) are ignored (in rule {:?})",
+                    rule.name
+                ),
+            );
+        }
 
         let props = NodeProps {
             max_tokens: rule.max_tokens,
@@ -456,6 +680,8 @@ impl Compiler {
             let lazy = rule.is_lazy();
             let rx_id = self.do_token_expansions(rule.expansions)?;
             let stop_id = self.do_token_atom(stop_val)?;
+            self.check_no_lookahead(rx_id, "with stop= or suffix=")?;
+            self.check_no_lookahead(stop_id, "as stop= or suffix=")?;
 
             self.builder.gen(
                 GenOptions {
@@ -491,10 +717,10 @@ impl Compiler {
                     _ => {
                         // try as terminal
                         let rx_id = self.do_token_expansions(rule.expansions).map_err(|e| {
-                            anyhow::anyhow!(
-                                "{}; temperature= and max_tokens= only \
-                                supported on TERMINALS and @subgrammars",
-                                e
+                            append_to_error(
+                                e,
+                                "; temperature= and max_tokens= only \
+                                    supported on TERMINALS and @subgrammars",
                             )
                         })?;
                         return Ok(self.builder.lexeme_ext(rx_id, rule.temperature, props));
@@ -511,7 +737,7 @@ impl Compiler {
                     NodeProps {
                         max_tokens: Some(max_tokens),
                         // assume the user also wants capture
-                        capture_name: Some(name.to_string()),
+                        capture_name: Some(rule.name.clone()),
                         ..Default::default()
                     },
                 )
@@ -524,48 +750,178 @@ impl Compiler {
         Ok(id)
     }
 
+    /// Compile rule with ignore= as a separate grammar (lexeme class),
+    /// with its own skip regex, and return its start symbol.
+    fn do_scope(&mut self, name: &str, opts: &LLGuidanceOptions) -> Result<SymIdx> {
+        let rule = &self.grammar.rules[name];
+        let loc = rule.expansions.0.clone();
+        let skip = match rule.ignore.clone().unwrap() {
+            Value::LiteralString(s, _) if s.is_empty() => RegexAst::NoMatch,
+            v => match self.do_token_atom(Atom::Value(v)).and_then(|id| {
+                self.check_no_lookahead(id, "in ignore=")?;
+                Ok(id)
+            }) {
+                Ok(id) => RegexAst::ExprRef(id),
+                Err(e) => {
+                    self.record(&loc, e)?;
+                    RegexAst::NoMatch
+                }
+            },
+        };
+
+        // nodes are specific to a grammar; regexes are shared
+        self.node_ids.clear();
+        self.scope = Some(name.to_string());
+        let start = self.builder.add_grammar(opts.clone(), skip)?;
+        let id = self.do_rule(name)?;
+        self.builder.set_start_node(id);
+        Ok(start)
+    }
+
     fn execute(mut self) -> Result<GrammarResult> {
         let mut grm = Grammar::default();
         for item in std::mem::take(&mut self.parsed.items) {
             let loc = item.location().clone();
-            grm.process_item(item).map_err(|e| loc.augment(e))?;
+            if let Err(e) = grm.process_item(item) {
+                self.record(&loc, e)?;
+            }
         }
         let start_name = "start";
-        ensure!(
-            grm.rules.contains_key(start_name),
-            "no {} rule found",
-            start_name
-        );
+        let has_start = grm.rules.contains_key(start_name);
+        if !has_start {
+            self.diagnostics
+                .push(Diagnostic::error(format!("no {} rule found", start_name)));
+        }
         let ignore = std::mem::take(&mut grm.ignore);
+        self.has_scopes = grm.rules.values().any(|r| r.ignore.is_some());
         self.grammar = grm;
 
         let opts: LLGuidanceOptions =
-            serde_json::from_value(self.grammar.llguidance_options.clone())
-                .map_err(|e| anyhow!("failed to parse %llguidance declaration: {}", e))?;
+            match serde_json::from_value(self.grammar.llguidance_options.clone()) {
+                Ok(opts) => opts,
+                Err(e) => {
+                    self.diagnostics.push(Diagnostic::error(format!(
+                        "failed to parse %llguidance declaration: {}",
+                        e
+                    )));
+                    LLGuidanceOptions::default()
+                }
+            };
+        self.python_re = opts.python_re;
 
-        let ignore = ignore
-            .into_iter()
-            .map(|exp| Ok(RegexAst::ExprRef(self.do_token_expansions(exp)?)))
-            .collect::<Result<Vec<_>>>()?;
-        let id = self.builder.add_grammar(opts, RegexAst::Or(ignore))?;
+        let mut ignore_rx = vec![];
+        for exp in ignore {
+            let loc = exp.0.clone();
+            match self.do_token_expansions(exp).and_then(|id| {
+                self.check_no_lookahead(id, "in %ignore")
+                    .map_err(|e| loc.augment(e))?;
+                Ok(id)
+            }) {
+                Ok(id) => ignore_rx.push(RegexAst::ExprRef(id)),
+                Err(e) => self.record(&loc, e)?,
+            }
+        }
+        let id = self
+            .builder
+            .add_grammar(opts.clone(), RegexAst::Or(ignore_rx))?;
 
-        let start = self.do_rule(start_name)?;
-        self.builder.set_start_node(start);
+        if has_start {
+            let start = self.do_rule(start_name)?;
+            self.builder.set_start_node(start);
+        }
+
+        let mut scopes: HashMap<String, SymIdx> = HashMap::default();
+        while let Some((gg, name)) = self.pending_scopes.pop() {
+            let start = match scopes.get(&name) {
+                Some(&start) => start,
+                None => {
+                    let start = self.do_scope(&name, &opts)?;
+                    scopes.insert(name, start);
+                    start
+                }
+            };
+            self.builder.link_gen_grammar(gg, start)?;
+        }
 
+        let mut diagnostics = self.diagnostics;
         let mut builder = self.builder;
-        for (gg, loc, json_schema) in self.pending_json_grammars {
-            let opts = JsonCompileOptions::default();
-            let res = opts
-                .json_to_llg_no_validate(builder, json_schema)
-                .map_err(|e| loc.augment(anyhow!("failed to compile JSON schema: {}", e)))?;
-            builder = res.builder;
-            builder.link_gen_grammar(gg, res.start_node)?;
+        for (gg, loc, mut json_schema) in self.pending_json_grammars {
+            let opts = match JsonCompileOptions::take_from_schema(&mut json_schema) {
+                Ok(opts) => opts,
+                Err(e) => {
+                    diagnostics.push(
+                        loc.diagnostic(Severity::Error, format!("invalid %json options: {}", e)),
+                    );
+                    continue;
+                }
+            };
+            match opts.json_to_llg_no_validate(builder, json_schema) {
+                Ok(res) => {
+                    builder = res.builder;
+                    builder.link_gen_grammar(gg, res.start_node)?;
+                }
+                Err(e) => {
+                    // the builder is gone; report what we have so far
+                    diagnostics.push(loc.diagnostic(
+                        Severity::Error,
+                        format!("failed to compile JSON schema: {}", e),
+                    ));
+                    return Err(GrammarError { diagnostics }.into());
+                }
+            }
         }
 
+        if diagnostics.iter().any(|d| d.is_error()) {
+            return Err(GrammarError { diagnostics }.into());
+        }
+        builder.warnings.extend(diagnostics);
+
         Ok(builder.finalize(id))
     }
 }
 
+/// Parse the inside of `<[...]>`, like `^1,5-7`; returns whether it's negated.
+fn parse_token_ranges(s: &str) -> Result<(bool, Vec<RangeInclusive<u32>>)> {
+    let (negated, s) = match s.strip_prefix('^') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let mut ranges = vec![];
+    for range in s.split(",") {
+        let ends: Vec<&str> = range.split('-').map(|s| s.trim()).collect();
+        ensure!(
+            ends.len() == 1 || ends.len() == 2,
+            "invalid token range: {:?}",
+            range
+        );
+        if ends.len() == 1 && ends[0].is_empty() {
+            continue;
+        }
+        let start = ends[0].parse::<u32>()?;
+        let end = if ends.len() == 2 {
+            ends[1].parse::<u32>()?
+        } else {
+            start
+        };
+        ensure!(start <= end, "invalid token range: {:?}", range);
+        ranges.push(start..=end);
+    }
+    Ok((negated, ranges))
+}
+
+/// Append text to error message, keeping location information, if any.
+fn append_to_error(e: anyhow::Error, suffix: &str) -> anyhow::Error {
+    match e.downcast::<GrammarError>() {
+        Ok(mut ge) => {
+            if let Some(d) = ge.diagnostics.iter_mut().rev().find(|d| d.is_error()) {
+                d.message.push_str(suffix);
+            }
+            ge.into()
+        }
+        Err(e) => anyhow!("{}{}", e, suffix),
+    }
+}
+
 impl Grammar {
     fn add_token_def(&mut self, loc: &Location, local_name: String, regex: &str) -> Result<()> {
         ensure!(
@@ -582,6 +938,7 @@ impl Grammar {
                 loc.clone(),
                 vec![Alias {
                     expansion: Expansion(vec![Expr {
+                        loc: loc.clone(),
                         atom: Atom::Value(Value::LiteralRegex(regex.to_string(), "".to_string())),
                         op: None,
                         range: None,
@@ -602,7 +959,7 @@ impl Grammar {
             Statement::Import { path, alias } => {
                 let regex = lookup_common_regex(&path)?;
                 let local_name =
-                    alias.unwrap_or_else(|| path.split('.').last().unwrap().to_string());
+                    alias.unwrap_or_else(|| path.split('.').next_back().unwrap().to_string());
                 self.add_token_def(loc, local_name, regex)?;
             }
             Statement::MultiImport { path, names } => {
@@ -613,11 +970,11 @@ impl Grammar {
                 }
             }
             Statement::LLGuidance(json_value) => {
-                // merge-in at the JSON level
-                json_merge(&mut self.llguidance_options, &json_value);
-                // but also check if it's valid format and all the right types
-                let _v: LLGuidanceOptions = serde_json::from_value(json_value)
+                // check if it's valid format and all the right types
+                let _v: LLGuidanceOptions = serde_json::from_value(json_value.clone())
                     .map_err(|e| anyhow!("failed to parse %llguidance declaration: {}", e))?;
+                // but merge-in at the JSON level
+                json_merge(&mut self.llguidance_options, &json_value);
             }
             Statement::OverrideRule(_) => {
                 bail!("override statement not supported yet");
@@ -670,6 +1027,9 @@ fn compile_lark_regex(builder: &mut GrammarBuilder, l: RegexExt) -> Result<Regex
     if l.substring_chars.is_some() {
         fields_set.push("substring_chars");
     }
+    if l.substring_documents.is_some() {
+        fields_set.push("substring_documents");
+    }
     if fields_set.is_empty() {
         bail!("no fields set on %regex");
     }
@@ -679,15 +1039,336 @@ fn compile_lark_regex(builder: &mut GrammarBuilder, l: RegexExt) -> Result<Regex
 
     let bld = &mut builder.regex.spec.regex_builder;
 
-    let eref = if let Some(s) = l.substring_words {
-        substring(bld, chunk_into_words(&s))?
-    } else if let Some(s) = l.substring_chars {
-        substring(bld, chunk_into_chars(&s))?
-    } else if let Some(s) = l.substring_chunks {
-        substring(bld, s.iter().map(|s| s.as_str()).collect())?
+    let word_wildcard = Some(r"\w+|\s+|[^\w\s]+");
+    let (documents, wildcard, gaps) = if let Some(s) = &l.substring_words {
+        (vec![chunk_into_words(s)], word_wildcard, Gaps::Words)
+    } else if let Some(s) = &l.substring_chars {
+        (vec![chunk_into_chars(s)], Some(r"(?s:.)"), Gaps::AnyChunk)
+    } else if let Some(s) = &l.substring_chunks {
+        (
+            vec![s.iter().map(|s| s.as_str()).collect()],
+            None,
+            Gaps::AnyChunk,
+        )
+    } else if let Some(docs) = &l.substring_documents {
+        ensure!(!docs.is_empty(), "substring_documents can't be empty");
+        (
+            docs.iter().map(|d| chunk_into_words(d)).collect(),
+            word_wildcard,
+            Gaps::Words,
+        )
     } else {
         unreachable!()
     };
+    let max_edits = l.max_edits.unwrap_or(0);
+    let gaps = if l.subsequence.unwrap_or(false) {
+        gaps
+    } else {
+        Gaps::None
+    };
+    ensure!(
+        max_edits == 0 || wildcard.is_some(),
+        "max_edits is only supported with substring_words or substring_chars"
+    );
+    let mut alts = vec![];
+    for chunks in documents {
+        let eref = if max_edits == 0 && gaps == Gaps::None {
+            substring(bld, chunks)?
+        } else {
+            fuzzy_substring(bld, chunks, wildcard, max_edits, gaps)?
+        };
+        alts.push(RegexAst::ExprRef(eref));
+    }
+    let eref = if alts.len() == 1 {
+        bld.mk(&alts[0])?
+    } else {
+        bld.mk(&RegexAst::Or(alts))?
+    };
+    builder.regex.add_ext_source(eref, &l);
 
     Ok(eref)
 }
+
+#[cfg(test)]
+mod test {
+    use super::{lark_to_llguidance, parse_token_ranges};
+    use crate::{
+        api::{Diagnostic, GrammarError, ParserLimits, Severity},
+        test_util::{factory, lark},
+        GrammarBuilder,
+    };
+
+    fn diagnostics(lark: &str) -> Vec<Diagnostic> {
+        let builder = GrammarBuilder::new(None, ParserLimits::default());
+        match lark_to_llguidance(builder, lark) {
+            Ok(res) => res.builder.warnings,
+            Err(e) => GrammarError::diagnostics_of(&e),
+        }
+    }
+
+    fn spans(lark: &str) -> Vec<(Severity, usize, usize, &str)> {
+        diagnostics(lark)
+            .iter()
+            .map(|d| {
+                let (a, b) = d.span.unwrap();
+                (d.severity, d.line, d.column, &lark[a..b])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_token_ranges() {
+        assert_eq!(
+            parse_token_ranges("1, 5-7").unwrap(),
+            (false, vec![1..=1, 5..=7])
+        );
+        assert_eq!(parse_token_ranges("^0-9").unwrap(), (true, vec![0..=9]));
+        assert!(parse_token_ranges("^3-1").is_err());
+        assert!(parse_token_ranges("1-2-3").is_err());
+
+        // terminals with special tokens can't be used inside of regex operators
+        let lark = "start: \"a\"\n%ignore B\nB: <[1]>";
+        assert!(diagnostics(lark)[0]
+            .message
+            .contains("cannot be used in terminals"));
+    }
+
+    #[test]
+    fn test_literal_set() {
+        let num_lexemes = |lark: &str| {
+            let res = lark_to_llguidance(GrammarBuilder::new(None, ParserLimits::default()), lark)
+                .unwrap();
+            res.builder.regex.spec.lexemes.len()
+        };
+        let alts = |n: usize| {
+            (0..n)
+                .map(|i| format!("\"x{i}\""))
+                .collect::<Vec<_>>()
+                .join(" | ")
+        };
+        let base = num_lexemes("start: \"x\"");
+        // a single lexeme for all the literals, instead of one per literal
+        assert_eq!(num_lexemes(&format!("start: {}", alts(1000))), base);
+        assert_eq!(num_lexemes(&format!("start: A\nA: {}", alts(1000))), base);
+        assert_eq!(num_lexemes(&format!("start: {}", alts(3))), base + 2);
+        // not only literals
+        assert!(num_lexemes(&format!("start: {} | \"y\"*", alts(1000))) > 1000);
+    }
+
+    #[test]
+    fn test_substring_documents() {
+        let lark = r#"
+start: q1 q2 q3
+q1[capture]: QUOTE
+q2[capture="x", stop="."]: %regex { "substring_documents": ["c"] }
+q3[capture]: QUOTE "!"
+QUOTE: %regex { "substring_documents": ["a b", "b c"] }
+"#;
+        let res =
+            lark_to_llguidance(GrammarBuilder::new(None, ParserLimits::default()), lark).unwrap();
+        let grammar = &res.builder.grammar;
+        let docs = |name: &str| {
+            grammar
+                .symbols()
+                .map(|s| grammar.sym_props(s))
+                .find(|p| p.capture_name.as_deref() == Some(name))
+                .and_then(|p| p.capture_documents.as_ref())
+                .map(|d| d.join("|"))
+        };
+        assert_eq!(docs("q1").as_deref(), Some("a b|b c"));
+        assert_eq!(docs("x").as_deref(), Some("c"));
+        assert_eq!(docs("q3"), None);
+
+        assert!(
+            diagnostics("start: %regex { \"substring_documents\": [] }")[0]
+                .message
+                .contains("can't be empty")
+        );
+    }
+
+    #[test]
+    fn test_substring_options() {
+        assert!(diagnostics(
+            "start: %regex { \"substring_chars\": \"abc\", \"max_edits\": 2, \"subsequence\": true }"
+        )
+        .is_empty());
+        assert!(
+            diagnostics("start: %regex { \"substring_chunks\": [\"a\"], \"max_edits\": 1 }")[0]
+                .message
+                .contains("substring_words or substring_chars")
+        );
+
+        let f = factory();
+        for field in [
+            r#""substring_words": "The quick brown fox""#,
+            r#""substring_documents": ["The quick brown fox"]"#,
+        ] {
+            let grm = format!("start: %regex {{ {field}, \"subsequence\": true }}");
+            let ok = |text: &str| f.validate_text(lark(&grm), text.as_bytes()).is_ok();
+            assert!(ok("The brown fox"));
+            assert!(ok("quick fox"));
+            assert!(!ok("fox quick"));
+            // only words are skipped, not the whitespace between them
+            assert!(!ok("Thequick"));
+            assert!(!ok("The  brown"));
+        }
+    }
+
+    #[test]
+    fn test_multiple_errors() {
+        let lark = "start: A B c\nA: %json {}\nB: \"x\"{3,1}\nc: \"a\" foo\n";
+        assert_eq!(
+            spans(lark),
+            vec![
+                (Severity::Error, 2, 4, "%json {}"),
+                (Severity::Error, 3, 4, "\"x\"{3,1}"),
+                (Severity::Error, 4, 8, "foo"),
+            ]
+        );
+        let d = diagnostics(lark);
+        assert!(d[0].message.contains("cannot be used in terminals"));
+        assert!(d[1].message.contains("must be >= start"));
+        assert!(d[2].message.contains("unknown name"));
+
+        // syntax errors are reported for every broken item
+        let lark = "start: \"a\" ) b\nb: :\nc: \"x\"\n";
+        let d = diagnostics(lark);
+        assert_eq!(d.len(), 2);
+        assert_eq!((d[0].line, d[0].column), (1, 12));
+        assert_eq!((d[1].line, d[1].column), (2, 4));
+
+        let d = diagnostics("foo: \"a\"\n");
+        assert_eq!(
+            d,
+            vec![Diagnostic::error("no start rule found".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_json_options() {
+        let ok = |lark: &str| assert_eq!(diagnostics(lark), vec![], "{}", lark);
+        ok(r#"start: %json(coerce_one_of=true, key_separator=": ") { "type": "object" }"#);
+        ok(r#"start: %json () { "type": "object" }"#);
+        ok(
+            r#"start: %json ( whitespace_flexible = false ) { "x-guidance": { "coerce_one_of": true } }"#,
+        );
+
+        let err = |lark: &str, msg: &str| {
+            let d = diagnostics(lark);
+            assert!(d.len() == 1 && d[0].message.contains(msg), "{:?}", d);
+        };
+        err(
+            r#"start: %json(foo=1) {}"#,
+            "invalid %json options: unknown field `foo`",
+        );
+        err(r#"start: %json(coerce_one_of=1) {}"#, "invalid type");
+        err(
+            r#"start: %json(coerce_one_of=true) true"#,
+            "only be used with an object schema",
+        );
+        err(
+            r#"start: %json(coerce_one_of=true, coerce_one_of=false) {}"#,
+            "duplicate option",
+        );
+        err(r#"start: %json(coerce_one_of) {}"#, "expecting '='");
+        err(r#"start: %json(coerce_one_of=tru) {}"#, "invalid value");
+        err(r#"start: %json(coerce_one_of=true {}"#, "invalid value");
+    }
+
+    #[test]
+    fn test_scoped_ignore() {
+        let lark = r#"
+start: "<" a ">" b
+a[ignore=WS]: "x" b "y" | "z"
+b[ignore=""]: "(" a ")" | "q"
+WS: /[ \t]+/
+%ignore /\n/
+"#;
+        assert_eq!(diagnostics(lark), vec![]);
+
+        let lark = "start: a\na[ignore=/x(?!y)/]: \"a\"\n";
+        assert_eq!(spans(lark), vec![(Severity::Error, 2, 21, "\"a\"")]);
+        assert!(diagnostics(lark)[0].message.contains("in ignore="));
+
+        let lark = "start: a\na[ignore=FOO]: \"a\"\n";
+        assert!(diagnostics(lark)[0].message.contains("FOO"));
+
+        let lark = "start: a\na[ignore=\" \", ignore=\"\\t\"]: \"a\"\n";
+        assert!(diagnostics(lark)[0]
+            .message
+            .contains("multiple ignore= values"));
+    }
+
+    #[test]
+    fn test_scoped_ignore_lexing() {
+        let f = factory();
+        let ok = |grm: &str, text: &str| f.validate_text(lark(grm), text.as_bytes()).is_ok();
+
+        let grm = r#"
+start: "<" a ">" "q"
+a[ignore=WS]: "x" b "y"
+b[ignore=""]: "q" | "(" a ")"
+WS: " "
+"#;
+        assert!(ok(grm, "<xqy>q"));
+        // inside of the scope, including around the nested rule
+        assert!(ok(grm, "<x q y>q"));
+        assert!(ok(grm, "<xq y>q"));
+        assert!(ok(grm, "<x  qy>q"));
+        // not before or after the scope
+        assert!(!ok(grm, "< xqy>q"));
+        assert!(!ok(grm, "<xqy >q"));
+        assert!(!ok(grm, "<xqy> q"));
+        // not inside of the nested rule, but again in the rule nested in it
+        assert!(ok(grm, "<x(xqy)y>q"));
+        assert!(ok(grm, "<x(x q y)y>q"));
+        assert!(!ok(grm, "<x( xqy)y>q"));
+        assert!(!ok(grm, "<x(xqy )y>q"));
+
+        // global %ignore outside of the scope
+        let grm = r#"
+start: "<" (a | "z") ">"
+a[ignore=""]: "x" "y"
+%ignore " "
+"#;
+        assert!(ok(grm, "< xy >"));
+        assert!(ok(grm, "< z >"));
+        assert!(!ok(grm, "<x y>"));
+    }
+
+    #[test]
+    fn test_python_re() {
+        let lark = r#"
+%llguidance { "python_re": true }
+start: A B C D
+A: /[[:alpha:]]+\Z/
+B: /(?x) (?P<stop> [0-9]{,3} ) # digits
+   /
+C: /\w+/a
+D: /(?a:\d)\u00e9{/
+"#;
+        assert_eq!(diagnostics(lark), vec![]);
+
+        let lark = "%llguidance { \"python_re\": true }\nstart: A\nA: /(a)\\1/\n";
+        assert_eq!(spans(lark), vec![(Severity::Error, 3, 4, "/(a)\\1/")]);
+        assert!(diagnostics(lark)[0]
+            .message
+            .contains("backreference \\1 can't be represented as a DFA"));
+
+        // without python_re, this is a POSIX class
+        assert!(diagnostics("start: /[[:alpha:]]/\n").is_empty());
+        assert!(!diagnostics("start: /(?P<x>a)(?P=x)/\n").is_empty());
+    }
+
+    #[test]
+    fn test_warnings() {
+        let lark = "start: x | y\nx: \"a\" -> xx\n?y: \"b\"\n";
+        assert_eq!(
+            spans(lark),
+            vec![
+                (Severity::Warning, 2, 4, "\"a\" -> xx"),
+                (Severity::Warning, 3, 5, "\"b\""),
+            ]
+        );
+    }
+}
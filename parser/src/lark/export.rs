@@ -0,0 +1,1160 @@
+//! Rendering of compiled grammars back to Lark syntax.
+//!
+//! The exporter works on the output of `GrammarBuilder` (the same for Lark, JSON schemas,
+//! GBNF etc.) and thus needs to know how the regexes were constructed
+//! (see `GrammarBuilder::record_sources()`).
+//! Every lexeme class (nested grammar) is rendered as a separate Lark grammar.
+
+use std::fmt::Write;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use derivre::{ExprRef, RegexAst};
+
+use crate::{
+    api::{GrammarWithLexer, LLGuidanceOptions},
+    earley::{
+        lexerspec::{LexemeClass, LexemeIdx, LexemeSpec, LexerSpec},
+        Grammar, SymIdx,
+    },
+    grammar_builder::GrammarSources,
+    HashMap, HashSet,
+};
+
+use super::compiler::lark_regex_quote;
+
+/// Binding strength of a rendered expression, weakest first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Prec {
+    /// `a | b`
+    Alt,
+    /// `a b`
+    Concat,
+    /// `a*`; can't take another postfix operator
+    Expr,
+    /// `(a)`, `"abc"`, `[a-z]`, etc.
+    Atom,
+}
+
+#[derive(Clone)]
+struct Text {
+    text: String,
+    prec: Prec,
+}
+
+impl Text {
+    fn new(text: String, prec: Prec) -> Self {
+        Text { text, prec }
+    }
+
+    fn at_least(&self, prec: Prec, open: &str) -> String {
+        if self.prec >= prec {
+            self.text.clone()
+        } else {
+            format!("{}{})", open, self.text)
+        }
+    }
+}
+
+/// A regex rendered both as Rust regex (if possible) and as Lark terminal expression.
+#[derive(Clone)]
+struct Rendered {
+    regex: Option<Text>,
+    /// Set if the regex only matches this string.
+    literal: Option<String>,
+    lark: Text,
+}
+
+const NO_MATCH_RX: &str = r"[^\s\S]";
+
+fn repeat_op(min: u32, max: u32) -> String {
+    match (min, max) {
+        (0, u32::MAX) => "*".to_string(),
+        (1, u32::MAX) => "+".to_string(),
+        (0, 1) => "?".to_string(),
+        (min, u32::MAX) => format!("{{{},}}", min),
+        (min, max) if min == max => format!("{{{}}}", min),
+        (min, max) => format!("{{{},{}}}", min, max),
+    }
+}
+
+fn byte_rx(b: u8) -> Result<String> {
+    ensure!(b < 0x80, "non-ASCII byte \\x{:02x} can't be exported", b);
+    if b.is_ascii_alphanumeric() {
+        Ok((b as char).to_string())
+    } else {
+        Ok(format!("\\x{:02X}", b))
+    }
+}
+
+/// Contents of a character class matching `bytes` (all ASCII), without brackets.
+fn class_items(bytes: &[u8]) -> Result<String> {
+    let mut res = String::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let mut j = i;
+        while j + 1 < bytes.len() && bytes[j + 1] == bytes[j] + 1 {
+            j += 1;
+        }
+        res.push_str(&byte_rx(bytes[i])?);
+        if j > i {
+            if j > i + 1 {
+                res.push('-');
+            }
+            res.push_str(&byte_rx(bytes[j])?);
+        }
+        i = j + 1;
+    }
+    Ok(res)
+}
+
+fn byteset_rx(bytes: &[u8]) -> Result<String> {
+    match bytes {
+        [] => Ok(NO_MATCH_RX.to_string()),
+        [b] if *b < 0x80 => Ok(regex_syntax::escape(&(*b as char).to_string())),
+        _ => Ok(format!("[{}]", class_items(bytes)?)),
+    }
+}
+
+/// Quote string as Lark (JSON) string literal.
+fn lark_string(s: &str) -> String {
+    // the Lark lexer doesn't allow unescaped DEL
+    serde_json::to_string(s).unwrap().replace('\x7f', "\\u007f")
+}
+
+/// Rewrite a symbol name so it's a valid Lark rule (`upper == false`)
+/// or terminal (`upper == true`) name.
+fn sanitize_name(name: &str, upper: bool, default: &str) -> String {
+    let mut res = String::new();
+    for c in name.chars() {
+        let c = if upper {
+            c.to_ascii_uppercase()
+        } else {
+            c.to_ascii_lowercase()
+        };
+        if c.is_ascii_alphanumeric() {
+            res.push(c);
+        } else if !res.is_empty() && !res.ends_with('_') {
+            res.push('_');
+        }
+    }
+    let res = res.trim_end_matches('_');
+    if res.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        res.to_string()
+    } else if res.is_empty() {
+        default.to_string()
+    } else {
+        format!("{}_{}", default, res)
+    }
+}
+
+fn fresh_name(used: &mut HashSet<String>, base: String) -> String {
+    let mut name = base.clone();
+    let mut idx = 2;
+    while used.contains(&name) {
+        name = format!("{}_{}", base, idx);
+        idx += 1;
+    }
+    used.insert(name.clone());
+    name
+}
+
+struct RegexRenderer<'a> {
+    sources: &'a GrammarSources,
+    cache: HashMap<ExprRef, Rendered>,
+    in_progress: HashSet<ExprRef>,
+}
+
+impl RegexRenderer<'_> {
+    fn from_regex(regex: Text, literal: Option<String>) -> Rendered {
+        let lark = if let Some(lit) = &literal {
+            Text::new(lark_string(lit), Prec::Atom)
+        } else if regex.text.is_empty() {
+            Text::new("\"\"".to_string(), Prec::Atom)
+        } else {
+            Text::new(format!("/{}/", lark_regex_quote(&regex.text)), Prec::Atom)
+        };
+        Rendered {
+            regex: Some(regex),
+            literal,
+            lark,
+        }
+    }
+
+    fn lark_only(lark: Text) -> Rendered {
+        Rendered {
+            regex: None,
+            literal: None,
+            lark,
+        }
+    }
+
+    fn expr_ref(&mut self, id: ExprRef) -> Result<Rendered> {
+        if let Some(r) = self.cache.get(&id) {
+            return Ok(r.clone());
+        }
+        let sources = self.sources;
+        let res = if id == ExprRef::EMPTY_STRING {
+            self.render(&RegexAst::EmptyString)?
+        } else if id == ExprRef::NO_MATCH {
+            self.render(&RegexAst::NoMatch)?
+        } else if let Some(ast) = sources.asts.get(&id) {
+            ensure!(self.in_progress.insert(id), "circular regex definition");
+            let r = self.render(ast);
+            self.in_progress.remove(&id);
+            r?
+        } else if let Some(ext) = sources.exts.get(&id) {
+            Self::lark_only(Text::new(
+                format!("%regex {}", serde_json::to_string(ext)?),
+                Prec::Atom,
+            ))
+        } else {
+            bail!("can't export regex; unknown origin");
+        };
+        self.cache.insert(id, res.clone());
+        Ok(res)
+    }
+
+    fn render(&mut self, ast: &RegexAst) -> Result<Rendered> {
+        if let RegexAst::ExprRef(id) = ast {
+            return self.expr_ref(*id);
+        }
+
+        let args = ast
+            .get_args()
+            .iter()
+            .map(|a| self.render(a))
+            .collect::<Result<Vec<_>>>()?;
+        let all_regex = args.iter().all(|a| a.regex.is_some());
+        let regexes = || args.iter().map(|a| a.regex.as_ref().unwrap());
+
+        let r = match ast {
+            RegexAst::EmptyString => {
+                Self::from_regex(Text::new(String::new(), Prec::Atom), Some(String::new()))
+            }
+            RegexAst::NoMatch => {
+                Self::from_regex(Text::new(NO_MATCH_RX.to_string(), Prec::Atom), None)
+            }
+            RegexAst::Regex(s) => {
+                // top-level alternatives and flags like (?i) need to be wrapped
+                let prec = if s.contains('|') || s.contains("(?") {
+                    Prec::Alt
+                } else {
+                    Prec::Concat
+                };
+                Self::from_regex(Text::new(s.clone(), prec), None)
+            }
+            RegexAst::Literal(s) => Self::from_regex(
+                Text::new(
+                    regex_syntax::escape(s),
+                    if s.chars().count() == 1 {
+                        Prec::Atom
+                    } else {
+                        Prec::Concat
+                    },
+                ),
+                Some(s.clone()),
+            ),
+            RegexAst::ByteLiteral(bytes) => {
+                let s = String::from_utf8(bytes.clone())
+                    .map_err(|_| anyhow!("invalid UTF-8 in byte literal can't be exported"))?;
+                self.render(&RegexAst::Literal(s))?
+            }
+            RegexAst::Byte(b) => {
+                ensure!(*b < 0x80, "non-ASCII byte \\x{:02x} can't be exported", b);
+                self.render(&RegexAst::Literal((*b as char).to_string()))?
+            }
+            RegexAst::ByteSet(words) => {
+                let bytes = (0..=255u8)
+                    .filter(|b| words[*b as usize / 32] & (1 << (b % 32)) != 0)
+                    .collect::<Vec<_>>();
+                Self::from_regex(Text::new(byteset_rx(&bytes)?, Prec::Atom), None)
+            }
+            RegexAst::Concat(_) if all_regex => {
+                let literal = args
+                    .iter()
+                    .map(|a| a.literal.clone())
+                    .collect::<Option<Vec<_>>>()
+                    .map(|v| v.concat());
+                let parts = regexes()
+                    .filter(|r| !r.text.is_empty())
+                    .map(|r| r.at_least(Prec::Concat, "(?:"))
+                    .collect::<Vec<_>>();
+                let prec = match parts.len() {
+                    0 => Prec::Atom,
+                    1 => regexes().find(|r| !r.text.is_empty()).unwrap().prec,
+                    _ => Prec::Concat,
+                };
+                Self::from_regex(Text::new(parts.concat(), prec), literal)
+            }
+            RegexAst::Concat(_) => Self::lark_only(Text::new(
+                args.iter()
+                    .map(|a| a.lark.at_least(Prec::Concat, "("))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                Prec::Concat,
+            )),
+            RegexAst::Or(_) if args.is_empty() => self.render(&RegexAst::NoMatch)?,
+            RegexAst::Or(_) if args.len() == 1 => args[0].clone(),
+            RegexAst::Or(_) if all_regex => Self::from_regex(
+                Text::new(
+                    regexes()
+                        .map(|r| r.text.clone())
+                        .collect::<Vec<_>>()
+                        .join("|"),
+                    Prec::Alt,
+                ),
+                None,
+            ),
+            RegexAst::Or(_) => Self::lark_only(Text::new(
+                args.iter()
+                    .map(|a| a.lark.at_least(Prec::Concat, "("))
+                    .collect::<Vec<_>>()
+                    .join(" | "),
+                Prec::Alt,
+            )),
+            RegexAst::Repeat(_, min, max) if all_regex => {
+                let inner = regexes().next().unwrap();
+                Self::from_regex(
+                    Text::new(
+                        format!(
+                            "{}{}",
+                            inner.at_least(Prec::Atom, "(?:"),
+                            repeat_op(*min, *max)
+                        ),
+                        Prec::Expr,
+                    ),
+                    None,
+                )
+            }
+            RegexAst::Repeat(_, min, max) => Self::lark_only(Text::new(
+                format!(
+                    "{}{}",
+                    args[0].lark.at_least(Prec::Atom, "("),
+                    repeat_op(*min, *max)
+                ),
+                Prec::Expr,
+            )),
+            // there is no Lark syntax for these
+            RegexAst::And(_) => bail!("intersection of regexes (&) can't be exported"),
+            RegexAst::Not(_) => bail!("negation of regexes (~) can't be exported"),
+            RegexAst::JsonQuote(_, _) => bail!("JSON-quoted regexes can't be exported"),
+            RegexAst::MultipleOf(_, _) => bail!("multipleOf regexes can't be exported"),
+            RegexAst::LookAhead(_) => {
+                bail!("lookahead can only be exported at the end of a lexeme")
+            }
+            RegexAst::ExprRef(_) => unreachable!(),
+        };
+
+        Ok(r)
+    }
+}
+
+struct ClassExporter<'a, 'b> {
+    grammar: &'a Grammar,
+    spec: &'a LexerSpec,
+    rx: &'b mut RegexRenderer<'a>,
+    grammar_names: &'b HashMap<LexemeClass, String>,
+    class_temperature: &'b HashMap<LexemeClass, f32>,
+
+    num_uses: HashMap<SymIdx, usize>,
+    rule_names: HashMap<SymIdx, String>,
+    terminal_names: HashMap<LexemeIdx, String>,
+    used_rule_names: HashSet<String>,
+    used_terminal_names: HashSet<String>,
+    pending: Vec<SymIdx>,
+    rules: Vec<String>,
+    terminals: Vec<String>,
+}
+
+impl<'a> ClassExporter<'a, '_> {
+    fn lexeme(&self, sym: SymIdx) -> Option<&'a LexemeSpec> {
+        self.grammar
+            .sym_lexeme(sym)
+            .map(|idx| self.spec.lexeme_spec(idx))
+    }
+
+    fn is_gen_lexeme(lex: &LexemeSpec) -> bool {
+        lex.is_lazy() || lex.ends_at_eos()
+    }
+
+    /// Lexeme temperature, unless it comes from @subgrammar reference.
+    fn lexeme_temperature(&self, sym: SymIdx) -> Option<f32> {
+        let t = self.grammar.sym_props(sym).temperature;
+        let class = self.grammar.sym_props(sym).grammar_id;
+        if t == 0.0 || self.class_temperature.get(&class) == Some(&t) {
+            None
+        } else {
+            Some(t)
+        }
+    }
+
+    /// Check if the symbol can be referenced without defining a rule for it.
+    fn needs_rule(&self, sym: SymIdx) -> bool {
+        let props = self.grammar.sym_props(sym);
+        if props.is_special() {
+            return true;
+        }
+        if let Some(lex) = self.lexeme(sym) {
+            Self::is_gen_lexeme(lex) || self.lexeme_temperature(sym).is_some()
+        } else if let Some(gg) = self.grammar.sym_gen_grammar(sym) {
+            gg.temperature.is_some()
+        } else {
+            false
+        }
+    }
+
+    /// If `sym` is `T | ""` where `T` is a nullable lexeme (see `Grammar::make_terminal()`),
+    /// return `T`.
+    fn nullable_lexeme(&self, sym: SymIdx) -> Option<SymIdx> {
+        if self.grammar.sym_props(sym).is_special() || self.grammar.sym_lexeme(sym).is_some() {
+            return None;
+        }
+        let rules = self.grammar.sym_rules(sym).collect::<Vec<_>>();
+        match rules.as_slice() {
+            [[inner], []] => {
+                let lx = self.grammar.sym_lexeme(*inner)?;
+                if self.spec.is_nullable(lx) {
+                    Some(*inner)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Check if the symbol has a user-visible name (as opposed to `#123` and similar).
+    fn has_name(&self, sym: SymIdx) -> bool {
+        let name = self.grammar.sym_name(sym);
+        self.rx.sources.rule_names.contains_key(&sym) || !(name.is_empty() || name.starts_with('#'))
+    }
+
+    fn can_inline(&self, sym: SymIdx, allow_named: bool) -> bool {
+        (allow_named || !self.has_name(sym))
+            && !self.rule_names.contains_key(&sym)
+            && self.num_uses.get(&sym) == Some(&1)
+            && !self.needs_rule(sym)
+            && self.grammar.sym_lexeme(sym).is_none()
+            && self.grammar.sym_gen_grammar(sym).is_none()
+            && self.grammar.sym_rules(sym).next().is_some()
+    }
+
+    fn count_uses(&mut self, start: SymIdx) {
+        let mut todo = vec![start];
+        let mut seen = HashSet::default();
+        seen.insert(start);
+        while let Some(sym) = todo.pop() {
+            if self.grammar.sym_gen_grammar(sym).is_some() {
+                continue;
+            }
+            let used = self
+                .grammar
+                .sym_rules(sym)
+                .flatten()
+                .map(|s| self.resolve_alias(*s))
+                .collect::<Vec<_>>();
+            for s in used {
+                {
+                    *self.num_uses.entry(s).or_insert(0) += 1;
+                    if seen.insert(s) {
+                        todo.push(s);
+                    }
+                }
+            }
+        }
+    }
+
+    fn rule_name(&mut self, sym: SymIdx) -> String {
+        self.rule_name_from(sym, sym)
+    }
+
+    /// Name of the rule for `sym`, derived from the name of `name_sym`.
+    fn rule_name_from(&mut self, sym: SymIdx, name_sym: SymIdx) -> String {
+        if let Some(name) = self.rule_names.get(&sym) {
+            return name.clone();
+        }
+        let name = match self.rx.sources.rule_names.get(&name_sym) {
+            Some(n) => n.as_str(),
+            None if self.has_name(name_sym) => self.grammar.sym_name(name_sym),
+            None => "r",
+        };
+        let base = sanitize_name(name, false, "r");
+        let name = fresh_name(&mut self.used_rule_names, base);
+        self.rule_names.insert(sym, name.clone());
+        self.pending.push(sym);
+        name
+    }
+
+    fn terminal_name(&mut self, base: &str) -> String {
+        let base = sanitize_name(base, true, "T");
+        fresh_name(&mut self.used_terminal_names, base)
+    }
+
+    /// Render a lexeme, defining a terminal for it if it's not short.
+    fn lexeme_ref(&mut self, idx: LexemeIdx) -> Result<String> {
+        if let Some(name) = self.terminal_names.get(&idx) {
+            return Ok(name.clone());
+        }
+        let lex = self.spec.lexeme_spec(idx);
+        if !lex.token_ranges.is_empty() {
+            return Ok(lex.name.clone());
+        }
+        ensure!(
+            lex.json_options().is_none(),
+            "JSON-quoted lexemes can't be exported"
+        );
+        let mut body = self.rx.render(&lex.rx)?;
+        if let Some(follow) = &lex.follow {
+            // bytes to put in the lookahead class; (?!...) is used when EOS is allowed
+            let bytes = (0..=255u8)
+                .filter(|&b| follow.allows(Some(b)) != follow.eos)
+                .collect::<Vec<_>>();
+            let class = if bytes.iter().all(|&b| b < 0x80) {
+                byteset_rx(&bytes)?
+            } else {
+                let others = (0..0x80u8)
+                    .filter(|b| !bytes.contains(b))
+                    .collect::<Vec<_>>();
+                ensure!(
+                    bytes.len() + others.len() == 256,
+                    "lookahead splitting UTF-8 characters can't be exported"
+                );
+                if others.is_empty() {
+                    r"[\s\S]".to_string()
+                } else {
+                    format!("[^{}]", class_items(&others)?)
+                }
+            };
+            let rx = body.regex.as_ref().ok_or_else(|| {
+                anyhow!("lookahead can only be exported for lexemes that are plain regexes")
+            })?;
+            let la = format!("(?{}{})", if follow.eos { "!" } else { "=" }, class);
+            body = RegexRenderer::from_regex(
+                Text::new(
+                    format!("{}{}", rx.at_least(Prec::Concat, "(?:"), la),
+                    Prec::Concat,
+                ),
+                None,
+            );
+        }
+        if body.lark.prec == Prec::Atom && body.lark.text.len() <= 40 && lex.follow.is_none() {
+            return Ok(body.lark.text);
+        }
+        let base = match &lex.rx {
+            RegexAst::ExprRef(id) => self.rx.sources.names_by_rx.get(id),
+            _ => None,
+        };
+        let name = self.terminal_name(base.unwrap_or(&lex.name));
+        self.terminals.push(format!("{}: {}", name, body.lark.text));
+        self.terminal_names.insert(idx, name.clone());
+        Ok(name)
+    }
+
+    /// Skip over symbols that are just aliases for other symbols (`a: b`).
+    fn resolve_alias(&self, mut sym: SymIdx) -> SymIdx {
+        let mut seen = HashSet::default();
+        while seen.insert(sym)
+            && !self.grammar.sym_props(sym).is_special()
+            && self.grammar.sym_lexeme(sym).is_none()
+            && self.grammar.sym_gen_grammar(sym).is_none()
+        {
+            let rules = self.grammar.sym_rules(sym).collect::<Vec<_>>();
+            match rules.as_slice() {
+                [[inner]] if !self.has_name(sym) || self.has_name(*inner) => sym = *inner,
+                _ => break,
+            }
+        }
+        sym
+    }
+
+    /// Render a reference to `sym` inside of a rule.
+    fn sym_ref(&mut self, sym: SymIdx) -> Result<String> {
+        let sym = self.resolve_alias(sym);
+        if let Some(inner) = self.nullable_lexeme(sym) {
+            // the wrapper has the name of the original rule
+            if self.needs_rule(inner) {
+                return Ok(self.rule_name_from(inner, sym));
+            }
+            return self.sym_ref(inner);
+        }
+        if self.needs_rule(sym) {
+            return Ok(self.rule_name(sym));
+        }
+        if let Some(idx) = self.grammar.sym_lexeme(sym) {
+            return self.lexeme_ref(idx);
+        }
+        if self.grammar.sym_gen_grammar(sym).is_some() {
+            return self.grammar_ref(sym);
+        }
+        if self.can_inline(sym, false) {
+            let rules = self.grammar.sym_rules(sym).collect::<Vec<_>>();
+            return match rules.as_slice() {
+                [rhs] => self.alternative(rhs),
+                [rhs, []] | [[], rhs] if !rhs.is_empty() => {
+                    Ok(format!("[{}]", self.alternative(rhs)?))
+                }
+                _ => Ok(format!("({})", self.alternatives(sym, " | ")?)),
+            };
+        }
+        Ok(self.rule_name(sym))
+    }
+
+    fn grammar_ref(&mut self, sym: SymIdx) -> Result<String> {
+        let gg = self.grammar.sym_gen_grammar(sym).unwrap();
+        let target = self.grammar.sym_rules(sym).next().and_then(|r| r.first());
+        let name = match target {
+            Some(&t) => &self.grammar_names[&self.grammar.sym_props(t).grammar_id],
+            None => bail!("unresolved grammar reference {}", gg.grammar),
+        };
+        Ok(format!("@{}", name))
+    }
+
+    fn alternative(&mut self, rhs: &[SymIdx]) -> Result<String> {
+        if rhs.is_empty() {
+            return Ok("\"\"".to_string());
+        }
+        Ok(rhs
+            .iter()
+            .map(|s| self.sym_ref(*s))
+            .collect::<Result<Vec<_>>>()?
+            .join(" "))
+    }
+
+    fn alternatives(&mut self, sym: SymIdx, sep: &str) -> Result<String> {
+        let rules = self.grammar.sym_rules(sym).collect::<Vec<_>>();
+        if rules.is_empty() {
+            return Ok(format!("/{}/", NO_MATCH_RX));
+        }
+        Ok(rules
+            .iter()
+            .map(|rhs| self.alternative(rhs))
+            .collect::<Result<Vec<_>>>()?
+            .join(sep))
+    }
+
+    /// Render a `stop=` or `suffix=` value.
+    fn stop_value(&mut self, rule_name: &str, stop: &RegexAst) -> Result<String> {
+        let r = self.rx.render(stop)?;
+        if r.lark.prec == Prec::Atom && !r.lark.text.starts_with('(') {
+            Ok(r.lark.text)
+        } else {
+            let name = self.terminal_name(&format!("{}_stop", rule_name));
+            self.terminals.push(format!("{}: {}", name, r.lark.text));
+            Ok(name)
+        }
+    }
+
+    fn rule_def(&mut self, sym: SymIdx) -> Result<String> {
+        let name = self.rule_names[&sym].clone();
+        let props = self.grammar.sym_props(sym).clone();
+        let mut attrs = vec![];
+        let mut max_tokens = props.max_tokens;
+        let mut temperature = None;
+
+        let body = if let Some(lex) = self.lexeme(sym) {
+            max_tokens = max_tokens.min(lex.max_tokens());
+            temperature = self.lexeme_temperature(sym);
+            if Self::is_gen_lexeme(lex) {
+                let (body, stop) = match &lex.rx {
+                    RegexAst::Concat(args)
+                        if args.len() == 2 && matches!(args[1], RegexAst::LookAhead(_)) =>
+                    {
+                        match &args[1] {
+                            RegexAst::LookAhead(stop) => (&args[0], Some(stop.as_ref())),
+                            _ => unreachable!(),
+                        }
+                    }
+                    rx => (rx, None),
+                };
+                let stop_empty = match stop {
+                    None => true,
+                    Some(s) => self.rx.render(s)?.literal.as_deref() == Some(""),
+                };
+                if lex.is_suffix {
+                    if stop_empty {
+                        attrs.push("lazy".to_string());
+                    } else {
+                        let v = self.stop_value(&name, stop.unwrap())?;
+                        attrs.push(format!("suffix={}", v));
+                    }
+                } else if lex.is_lazy() {
+                    match stop {
+                        Some(stop) if !stop_empty => {
+                            let v = self.stop_value(&name, stop)?;
+                            attrs.push(format!("stop={}", v));
+                        }
+                        _ => attrs.push("lazy".to_string()),
+                    }
+                } else {
+                    ensure!(
+                        stop.is_none(),
+                        "greedy lexemes with stop condition can't be exported"
+                    );
+                    attrs.push("stop=\"\"".to_string());
+                }
+                if let Some(sc) = &props.stop_capture_name {
+                    attrs.push(format!("stop_capture={}", lark_string(sc)));
+                }
+                self.rx.render(body)?.lark.text
+            } else {
+                self.lexeme_ref(self.grammar.sym_lexeme(sym).unwrap())?
+            }
+        } else if self.grammar.sym_gen_grammar(sym).is_some() {
+            temperature = self.grammar.sym_gen_grammar(sym).unwrap().temperature;
+            self.grammar_ref(sym)?
+        } else {
+            ensure!(
+                max_tokens == usize::MAX,
+                "max_tokens= on rule {:?} can't be exported; \
+                 it's only supported on terminals and @subgrammars",
+                self.grammar.sym_name(sym)
+            );
+            let rules = self.grammar.sym_rules(sym).collect::<Vec<_>>();
+            match rules.as_slice() {
+                // `foo: (a | b)` -> `foo: a | b`
+                [[inner]]
+                    if self.can_inline(self.resolve_alias(*inner), true)
+                        && self.nullable_lexeme(*inner).is_none() =>
+                {
+                    self.alternatives(self.resolve_alias(*inner), "\n    | ")?
+                }
+                _ => self.alternatives(sym, "\n    | ")?,
+            }
+        };
+
+        if let Some(cap) = &props.capture_name {
+            attrs.push(format!("capture={}", lark_string(cap)));
+        }
+        if max_tokens < usize::MAX {
+            attrs.push(format!("max_tokens={}", max_tokens));
+        }
+        if let Some(t) = temperature {
+            attrs.push(format!("temperature={}", t));
+        }
+
+        if attrs.is_empty() {
+            Ok(format!("{}: {}", name, body))
+        } else {
+            Ok(format!("{}[{}]: {}", name, attrs.join(", "), body))
+        }
+    }
+
+    fn ignore_lines(&mut self, class: LexemeClass) -> Result<Vec<String>> {
+        let skip = &self.spec.lexeme_spec(self.spec.skip_id(class)).rx;
+        let parts = match skip {
+            RegexAst::Or(args) => args.iter().collect(),
+            RegexAst::NoMatch => vec![],
+            _ => vec![skip],
+        };
+        parts
+            .into_iter()
+            .map(|p| Ok(format!("%ignore {}", self.rx.render(p)?.lark.text)))
+            .collect()
+    }
+
+    fn export(
+        mut self,
+        class: LexemeClass,
+        start: SymIdx,
+        opts: LLGuidanceOptions,
+    ) -> Result<String> {
+        self.count_uses(start);
+        self.used_rule_names.insert("start".to_string());
+        self.rule_names.insert(start, "start".to_string());
+        self.pending.push(start);
+
+        let mut idx = 0;
+        while idx < self.pending.len() {
+            let sym = self.pending[idx];
+            let def = self.rule_def(sym)?;
+            self.rules.push(def);
+            idx += 1;
+        }
+
+        let mut res = String::new();
+        if opts.no_forcing || opts.allow_invalid_utf8 {
+            writeln!(res, "%llguidance {}", serde_json::to_string(&opts)?).unwrap();
+        }
+        let ignore = self.ignore_lines(class)?;
+        for line in &ignore {
+            writeln!(res, "{}", line).unwrap();
+        }
+        if !res.is_empty() {
+            res.push('\n');
+        }
+        for r in &self.rules {
+            writeln!(res, "{}", r).unwrap();
+        }
+        if !self.terminals.is_empty() {
+            res.push('\n');
+            for t in &self.terminals {
+                writeln!(res, "{}", t).unwrap();
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Render the grammar as a list of Lark grammars, one for each lexeme class.
+/// The first one is the top-level grammar.
+pub(crate) fn export_lark(
+    grammar: &Grammar,
+    lexer_spec: &LexerSpec,
+    sources: &GrammarSources,
+) -> Result<Vec<GrammarWithLexer>> {
+    let mut starts: HashMap<LexemeClass, SymIdx> = HashMap::default();
+    for sym in grammar.start_symbols() {
+        starts
+            .entry(grammar.sym_props(sym).grammar_id)
+            .or_insert(sym);
+    }
+
+    // name the grammars, so they can be referenced with @name
+    let mut names: HashMap<LexemeClass, String> = HashMap::default();
+    let mut used_names = HashSet::default();
+    let mut class_temperature = HashMap::default();
+    let mut candidates: Vec<(LexemeClass, String)> =
+        sources.names.iter().map(|(k, v)| (*k, v.clone())).collect();
+    candidates.sort_by_key(|(k, _)| k.as_usize());
+    for sym in grammar.symbols() {
+        if let Some(gg) = grammar.sym_gen_grammar(sym) {
+            if let Some(&target) = grammar.sym_rules(sym).next().and_then(|r| r.first()) {
+                let class = grammar.sym_props(target).grammar_id;
+                candidates.push((class, gg.grammar.to_string()));
+                if let Some(t) = gg.temperature {
+                    class_temperature.insert(class, t);
+                }
+            }
+        }
+    }
+    for (class, name) in candidates {
+        if names.contains_key(&class) {
+            continue;
+        }
+        let name: String = name
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+            .collect();
+        if !name.is_empty() && !used_names.contains(&name) {
+            used_names.insert(name.clone());
+            names.insert(class, name);
+        }
+    }
+
+    let mut classes = starts.keys().cloned().collect::<Vec<_>>();
+    classes.sort_by_key(|c| c.as_usize());
+    for &class in &classes {
+        names.entry(class).or_insert_with(|| {
+            fresh_name(&mut used_names, format!("grammar_{}", class.as_usize()))
+        });
+    }
+
+    let mut rx = RegexRenderer {
+        sources,
+        cache: HashMap::default(),
+        in_progress: HashSet::default(),
+    };
+
+    let mut res = vec![];
+    for class in classes {
+        let mut opts = sources
+            .options
+            .get(class.as_usize())
+            .cloned()
+            .unwrap_or_default();
+        opts.no_forcing = lexer_spec.no_forcing && class == LexemeClass::ROOT;
+        // regexes are always exported in Rust syntax
+        opts.python_re = false;
+        let lark = ClassExporter {
+            grammar,
+            spec: lexer_spec,
+            rx: &mut rx,
+            grammar_names: &names,
+            class_temperature: &class_temperature,
+            num_uses: HashMap::default(),
+            rule_names: HashMap::default(),
+            terminal_names: HashMap::default(),
+            used_rule_names: HashSet::default(),
+            used_terminal_names: HashSet::default(),
+            pending: vec![],
+            rules: vec![],
+            terminals: vec![],
+        }
+        .export(class, starts[&class], opts)?;
+        res.push(GrammarWithLexer {
+            name: Some(names[&class].clone()),
+            lark_grammar: Some(lark),
+            ..GrammarWithLexer::default()
+        });
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use toktrie::{ApproximateTokEnv, TokEnv, TokRxInfo, TokTrie};
+
+    use crate::{
+        api::{GrammarInit, GrammarWithLexer, ParserLimits, TopLevelGrammar},
+        earley::{lexerspec::LexerSpec, Grammar},
+        test_util::factory,
+    };
+
+    fn tok_env() -> TokEnv {
+        let words = ["a", "b", "\u{ff}<eos>", "\u{ff}<tool>"]
+            .iter()
+            .map(|w| match w.strip_prefix('\u{ff}') {
+                Some(w) => [&[0xffu8][..], w.as_bytes()].concat(),
+                None => w.as_bytes().to_vec(),
+            })
+            .collect::<Vec<_>>();
+        let trie = TokTrie::from(&TokRxInfo::new(words.len() as u32, 2), &words);
+        Arc::new(ApproximateTokEnv::new(trie))
+    }
+
+    fn export(grammars: Vec<GrammarWithLexer>) -> TopLevelGrammar {
+        GrammarInit::Serialized(TopLevelGrammar {
+            grammars,
+            max_tokens: None,
+        })
+        .to_lark(Some(tok_env()), ParserLimits::default())
+        .unwrap()
+    }
+
+    /// Export, and check that exporting the result again doesn't change it.
+    fn round_trip(grammar: GrammarWithLexer) -> Vec<String> {
+        let lark = |g: &TopLevelGrammar| {
+            g.grammars
+                .iter()
+                .map(|g| g.lark_grammar.clone().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let exported = export(vec![grammar]);
+        let res = lark(&exported);
+        assert_eq!(lark(&export(exported.grammars)), res);
+        res
+    }
+
+    #[test]
+    fn test_lark() {
+        let lark = r#"
+start: foo | bar+ baz
+foo[capture]: "a" WORD "b"?
+bar: /[0-9]+/ | "x" ("y" | "z")
+baz[stop="\n", max_tokens=5]: /.*/
+WORD: /[a-z]+(?![a-z])/
+%ignore /[ \t]+/
+"#;
+        assert_eq!(
+            round_trip(GrammarWithLexer::from_lark(lark.to_string())),
+            vec![
+                r#"%ignore /[ \t]+/
+
+start: foo
+    | plus baz
+foo[capture="foo"]: "a" WORD ["b"]
+plus: bar
+    | plus bar
+baz[stop="\n", max_tokens=5]: /.*/
+bar: /[0-9]+/
+    | "x" ("y" | "z")
+
+WORD: /[a-z]+(?![a-z])/
+"#
+            ]
+        );
+
+        let lark = r#"
+start: <tool> t <[0-1]> u* <eos>
+t[suffix="\n", capture]: /[ab]*/
+u[temperature=0.5]: /a+/
+"#;
+        assert_eq!(
+            round_trip(GrammarWithLexer::from_lark(lark.to_string())),
+            vec![
+                r#"start: <tool> t <[0-1]> star <eos>
+t[suffix="\n", capture="t"]: /[ab]*/
+star: ""
+    | star u
+u[temperature=0.5]: /a+/
+"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_token_terminals() {
+        let lark = "start: <[^0]> T\nT: <[1]>{1,2} \"a\" | <tool>";
+        assert_eq!(
+            round_trip(GrammarWithLexer::from_lark(lark.to_string())),
+            vec!["start: <[^0]> t\nt: <[1]> [<[1]>] \"a\"\n    | <tool>\n"]
+        );
+    }
+
+    #[test]
+    fn test_json_options() {
+        let expected = vec![
+            "start: @json---1\n".to_string(),
+            "start: \"[\" [star /-?(0|[1-9][0-9]*)/] \"]\"\n\
+             star: \"\"\n    | star /-?(0|[1-9][0-9]*)/ \", \"\n"
+                .to_string(),
+        ];
+        let lark = r#"start: %json(whitespace_flexible=false, item_separator=", ") {
+            "type": "array", "items": { "type": "integer" }
+        }"#;
+        let lark_of = |g: TopLevelGrammar| {
+            g.grammars
+                .into_iter()
+                .map(|g| g.lark_grammar.unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            lark_of(export(vec![GrammarWithLexer::from_lark(lark.to_string())])),
+            expected
+        );
+
+        let lark = r#"start: %json {
+            "x-guidance": { "whitespace_flexible": false, "item_separator": ", " },
+            "type": "array", "items": { "type": "integer" }
+        }"#;
+        assert_eq!(
+            lark_of(export(vec![GrammarWithLexer::from_lark(lark.to_string())])),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_scoped_ignore() {
+        let lark = r#"
+start: "text:" TEXT "|" sql ";"
+TEXT: /[a-z ]+/
+sql[ignore=/[ \t\n]+/]: "SELECT" ID ("," ID)* "FROM" ID | "(" sql ")"
+ID: /[a-z]+/
+"#;
+        assert_eq!(
+            round_trip(GrammarWithLexer::from_lark(lark.to_string())),
+            vec![
+                "start: \"text:\" /[a-z ]+/ \"|\" @sql \";\"\n",
+                "%ignore /[ \\t\\n]+/\n\n\
+                 start: sql\n\
+                 sql: \"SELECT\" /[a-z]+/ star \"FROM\" /[a-z]+/\n    | \"(\" sql \")\"\n\
+                 star: \"\"\n    | star \",\" /[a-z]+/\n"
+            ]
+        );
+
+        // the exported grammar skips whitespace in the same places
+        let f = factory();
+        let exported = export(vec![GrammarWithLexer::from_lark(lark.to_string())]);
+        for grm in [TopLevelGrammar::from_lark(lark.to_string()), exported] {
+            let ok = |text: &str| f.validate_text(grm.clone(), text.as_bytes()).is_ok();
+            assert!(ok("text:a b|SELECT a ,b\nFROM t;"));
+            assert!(ok("text:a|( SELECT a FROM t );"));
+            assert!(!ok("text:a| SELECT a FROM t;"));
+            assert!(!ok("text:a|SELECT a FROM t ;"));
+        }
+    }
+
+    #[test]
+    fn test_subgrammars() {
+        let lark = "start: x y\nx: %json{\"type\":\"boolean\"}\ny[lazy]: /.*/\n";
+        let res = round_trip(GrammarWithLexer::from_lark(lark.to_string()));
+        assert_eq!(
+            res,
+            vec![
+                "start: @json---1 y\ny[lazy]: /.*/\n",
+                "%ignore /[\\x20\\x0A\\x0D\\x09]+/\n\nstart: /true|false/\n",
+            ]
+        );
+
+        let res = round_trip(GrammarWithLexer::from_regex("(foo|ba[rz])+"));
+        assert_eq!(res, vec!["start: /(foo|ba[rz])+/\n"]);
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let res = round_trip(GrammarWithLexer::from_json_schema(json!({
+            "type": "array",
+            "items": {"anyOf": [{"enum": [true, null]}, {"type": "integer", "minimum": -3}]},
+        })));
+        assert_eq!(res.len(), 1);
+        assert_eq!(
+            res[0],
+            "%ignore /[\\x20\\x0A\\x0D\\x09]+/\n\n\
+             start: \"[\" [star LX] \"]\"\n\
+             star: \"\"\n    | star LX \",\"\n\n\
+             LX: /true|null|((-([1-3]))|(([0-9])|[1-9][0-9]{1,}))/\n"
+        );
+    }
+
+    #[test]
+    fn test_literal_set() {
+        let lits = (0..20).map(|i| format!("\"k{i}\"")).collect::<Vec<_>>();
+        let lark = format!("start: {}\n", lits.join(" | "));
+        let res = round_trip(GrammarWithLexer::from_lark(lark));
+        assert!(res[0].contains("LX: /k0|k1|k10|k11|"));
+    }
+
+    #[test]
+    fn test_case_insensitive_literals() {
+        let res = round_trip(GrammarWithLexer::from_lark(
+            "start: \"ü\"i \"x\"i\n".to_string(),
+        ));
+        assert!(res[0].contains("/(?i)(?:ü|u\u{308})/"), "{}", res[0]);
+        assert!(res[0].contains("/(?i)x/"), "{}", res[0]);
+    }
+
+    #[test]
+    fn test_fuzzy_substring() {
+        let lark = "start: %regex { \"substring_words\": \"a b\", \"max_edits\": 1 }\n";
+        assert_eq!(
+            round_trip(GrammarWithLexer::from_lark(lark.to_string())),
+            vec!["start: LX\n    | \"\"\n\nLX: %regex {\"substring_words\":\"a b\",\"max_edits\":1}\n"]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |lark: &str| {
+            GrammarInit::Serialized(TopLevelGrammar::from_lark(lark.to_string()))
+                .to_lark(None, ParserLimits::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            err("start: A\nA: /a(?=[\\u0080-\\u00BF])/\n"),
+            "lookahead splitting UTF-8 characters can't be exported"
+        );
+        assert!(err("start: A\nA: /a(?=é)/\n").contains("only some of the characters"));
+        // lazy quantifiers compile to intersection and negation
+        assert_eq!(
+            err("start: A\nA: /<a>.*?<\\/a>/\n"),
+            "negation of regexes (~) can't be exported"
+        );
+        let err_json = |schema: serde_json::Value| {
+            GrammarInit::Serialized(TopLevelGrammar::from_json_schema(schema))
+                .to_lark(None, ParserLimits::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            err_json(json!({"type": "string"})),
+            "JSON-quoted regexes can't be exported"
+        );
+        assert_eq!(
+            err_json(json!({"type": "number", "multipleOf": 0.5})),
+            "multipleOf regexes can't be exported"
+        );
+        let internal = GrammarInit::Internal(Grammar::new(None), LexerSpec::new().unwrap());
+        assert_eq!(
+            internal
+                .to_lark(None, ParserLimits::default())
+                .unwrap_err()
+                .to_string(),
+            "only serialized grammars can be exported"
+        );
+    }
+}
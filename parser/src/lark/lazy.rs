@@ -0,0 +1,310 @@
+// Support for lazy quantifiers (*?, +?, ??, {m,n}?) in terminal regexes.
+//
+// The lexer is a DFA, so there is no backtracking order to pick the "shortest"
+// match with. Instead, laziness restricts the language of the lexeme:
+// a lazy repetition R{m,n}? followed by S (the rest of the regex)
+// matches a string w from R{m,n} only if no proper prefix u of w (also from R{m,n})
+// can be followed by a non-empty match of S inside of w.
+// In other words, the lazy part ends at the first point where the rest can match.
+// For example, /<a>.*?<\/a>.*/ is compiled to <a>(.* & ~(.*<\/a>.*))<\/a>.*,
+// and /[0-9]+?[0-9]/ only matches two digits.
+// A trailing lazy repetition is followed by whatever comes after the lexeme,
+// so /a+?/ only matches "a".
+//
+// This is equivalent to leftmost-first (Python re, Rust regex) semantics for
+// the common case where S starts with a literal delimiter.
+
+use anyhow::{bail, Result};
+use derivre::RegexAst;
+use regex_syntax::{
+    ast::{self, Ast, GroupKind, RepetitionKind, RepetitionRange},
+    hir::{Hir, HirKind},
+};
+
+fn has_lazy(hir: &Hir) -> bool {
+    match hir.kind() {
+        HirKind::Repetition(r) if !r.greedy => true,
+        k => k.subs().iter().any(has_lazy),
+    }
+}
+
+fn has_look(hir: &Hir) -> bool {
+    match hir.kind() {
+        HirKind::Look(_) => true,
+        k => k.subs().iter().any(has_look),
+    }
+}
+
+fn ast_has_lazy(a: &Ast) -> bool {
+    match a {
+        Ast::Repetition(r) => !r.greedy || ast_has_lazy(&r.ast),
+        Ast::Group(g) => ast_has_lazy(&g.ast),
+        Ast::Alternation(a) => a.asts.iter().any(ast_has_lazy),
+        Ast::Concat(c) => c.asts.iter().any(ast_has_lazy),
+        _ => false,
+    }
+}
+
+fn swaps_greed(flags: &ast::Flags) -> bool {
+    flags.flag_state(ast::Flag::SwapGreed).is_some()
+}
+
+fn ast_swaps_greed(a: &Ast) -> bool {
+    match a {
+        Ast::Flags(f) => swaps_greed(&f.flags),
+        Ast::Repetition(r) => ast_swaps_greed(&r.ast),
+        Ast::Group(g) => {
+            matches!(&g.kind, GroupKind::NonCapturing(f) if swaps_greed(f))
+                || ast_swaps_greed(&g.ast)
+        }
+        Ast::Alternation(a) => a.asts.iter().any(ast_swaps_greed),
+        Ast::Concat(c) => c.asts.iter().any(ast_swaps_greed),
+        _ => false,
+    }
+}
+
+fn any_string() -> RegexAst {
+    RegexAst::Regex("(?s:.)*".to_string())
+}
+
+fn concat(mut args: Vec<RegexAst>) -> RegexAst {
+    if args.len() == 1 {
+        args.pop().unwrap()
+    } else {
+        RegexAst::Concat(args)
+    }
+}
+
+struct Translator<'a> {
+    rx: &'a str,
+}
+
+impl Translator<'_> {
+    fn text(&self, flags: &str, start: usize, end: usize) -> RegexAst {
+        RegexAst::Regex(format!("{}{}", flags, &self.rx[start..end]))
+    }
+
+    /// Translate `a` followed by `cont` (which is not part of the result);
+    /// `flags` are the flags in effect, like "(?i)".
+    fn to_ast(&self, a: &Ast, flags: &str, cont: &RegexAst) -> Result<RegexAst> {
+        if !ast_has_lazy(a) {
+            let span = a.span();
+            return Ok(self.text(flags, span.start.offset, span.end.offset));
+        }
+        let r = match a {
+            Ast::Group(g) => {
+                let flags = match &g.kind {
+                    GroupKind::CaptureName { name, .. } if name.name == "stop" => {
+                        bail!("lazy quantifiers are not supported inside of (?P<stop>...)");
+                    }
+                    GroupKind::NonCapturing(f) if !f.items.is_empty() => {
+                        format!(
+                            "{}(?{})",
+                            flags,
+                            &self.rx[f.span.start.offset..f.span.end.offset]
+                        )
+                    }
+                    _ => flags.to_string(),
+                };
+                self.to_ast(&g.ast, &flags, cont)?
+            }
+            Ast::Alternation(alts) => RegexAst::Or(
+                alts.asts
+                    .iter()
+                    .map(|a| self.to_ast(a, flags, cont))
+                    .collect::<Result<_>>()?,
+            ),
+            Ast::Concat(c) => {
+                // group maximal runs of elements without lazy quantifiers,
+                // and track (?flags) which apply until the end of the group
+                let mut runs: Vec<(String, &[Ast])> = vec![];
+                let mut flags = flags.to_string();
+                let mut run_start = 0;
+                for (idx, a) in c.asts.iter().enumerate() {
+                    if ast_has_lazy(a) {
+                        if run_start < idx {
+                            runs.push((flags.clone(), &c.asts[run_start..idx]));
+                        }
+                        runs.push((flags.clone(), &c.asts[idx..idx + 1]));
+                        run_start = idx + 1;
+                    } else if let Ast::Flags(f) = a {
+                        if run_start < idx {
+                            runs.push((flags.clone(), &c.asts[run_start..idx]));
+                        }
+                        let span = f.flags.span;
+                        flags = format!(
+                            "{}(?{})",
+                            flags,
+                            &self.rx[span.start.offset..span.end.offset]
+                        );
+                        run_start = idx + 1;
+                    }
+                }
+                if run_start < c.asts.len() {
+                    runs.push((flags, &c.asts[run_start..]));
+                }
+
+                let mut res = vec![];
+                let mut rest = vec![cont.clone()];
+                for (flags, run) in runs.iter().rev() {
+                    let ast = if run.len() == 1 {
+                        let cont = concat(rest.iter().rev().cloned().collect());
+                        self.to_ast(&run[0], flags, &cont)?
+                    } else {
+                        let start = run[0].span().start.offset;
+                        let end = run[run.len() - 1].span().end.offset;
+                        self.text(flags, start, end)
+                    };
+                    rest.push(ast.clone());
+                    res.push(ast);
+                }
+                res.reverse();
+                concat(res)
+            }
+            Ast::Repetition(r) => {
+                let (min, max) = match &r.op.kind {
+                    RepetitionKind::ZeroOrOne => (0, 1),
+                    RepetitionKind::ZeroOrMore => (0, u32::MAX),
+                    RepetitionKind::OneOrMore => (1, u32::MAX),
+                    RepetitionKind::Range(RepetitionRange::Exactly(n)) => (*n, *n),
+                    RepetitionKind::Range(RepetitionRange::AtLeast(n)) => (*n, u32::MAX),
+                    RepetitionKind::Range(RepetitionRange::Bounded(m, n)) => (*m, *n),
+                };
+                // inside of the repetition, the continuation is more repetitions
+                let span = r.ast.span();
+                let inner_cont = concat(vec![
+                    RegexAst::Repeat(
+                        Box::new(self.text(flags, span.start.offset, span.end.offset)),
+                        0,
+                        max,
+                    ),
+                    cont.clone(),
+                ]);
+                let rep =
+                    RegexAst::Repeat(Box::new(self.to_ast(&r.ast, flags, &inner_cont)?), min, max);
+                if r.greedy {
+                    return Ok(rep);
+                }
+                let non_empty_cont = RegexAst::And(vec![
+                    cont.clone(),
+                    RegexAst::Not(Box::new(RegexAst::EmptyString)),
+                ]);
+                let stops_earlier =
+                    RegexAst::Concat(vec![rep.clone(), non_empty_cont, any_string()]);
+                RegexAst::And(vec![rep, RegexAst::Not(Box::new(stops_earlier))])
+            }
+            // other nodes have no sub-expressions, so can't have lazy quantifiers
+            _ => unreachable!(),
+        };
+        Ok(r)
+    }
+}
+
+/// Compile a terminal regex (with flags already applied), giving
+/// lazy quantifiers shortest-match semantics.
+/// Regexes without lazy quantifiers (or with syntax errors) are passed through unchanged.
+pub fn expand_lazy(rx: &str) -> Result<RegexAst> {
+    let hir = match regex_syntax::ParserBuilder::new().build().parse(rx) {
+        Ok(hir) if has_lazy(&hir) => hir,
+        _ => return Ok(RegexAst::Regex(rx.to_string())),
+    };
+    if has_look(&hir) {
+        bail!("anchors (like ^ and $) can't be combined with lazy quantifiers");
+    }
+    let ast = ast::parse::Parser::new().parse(rx)?;
+    if ast_swaps_greed(&ast) {
+        bail!("the U flag can't be combined with lazy quantifiers");
+    }
+    Translator { rx }.to_ast(&ast, "", &any_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::expand_lazy;
+    use derivre::RegexBuilder;
+
+    fn matches(rx: &str, s: &str) -> bool {
+        let mut b = RegexBuilder::new();
+        let ast = expand_lazy(rx).unwrap();
+        let e = b.mk(&ast).unwrap();
+        let mut r = b.to_regex(e);
+        let mut state = r.initial_state();
+        for &byte in s.as_bytes() {
+            state = r.transition(state, byte);
+            if state.is_dead() {
+                return false;
+            }
+        }
+        r.is_accepting(state)
+    }
+
+    #[test]
+    fn test_lazy() {
+        let rx = r"<a>.*?</a>.*";
+        assert!(matches(rx, "<a>foo</a>"));
+        assert!(matches(rx, "<a>foo</a>bar</a>"));
+        assert!(matches(rx, "<a></a>x"));
+        assert!(!matches(rx, "<a>foo"));
+
+        assert!(!matches(r"<a>.*?</a>", "<a>x</a>y</a>"));
+        assert!(matches(r"-.*?:[^\n]*", "-a:b:c"));
+        assert!(!matches(r"-.*?:", "-a:b:"));
+
+        let rx = r"x.*?y";
+        assert!(matches(rx, "xfooy"));
+        assert!(!matches(rx, "xfooyy"));
+        assert!(!matches(rx, "xyay"));
+
+        assert!(matches(r"[0-9]+?[0-9]", "12"));
+        assert!(!matches(r"[0-9]+?[0-9]", "123"));
+        assert!(matches(r"a{2,5}?b", "aaaab"));
+        assert!(matches(r"a+?", "a"));
+        assert!(!matches(r"a+?", "aa"));
+        assert!(matches(r"a*?", ""));
+        assert!(!matches(r"a*?", "a"));
+
+        // laziness is scoped to the group, but sees the rest of the regex
+        let rx = r"(?:<b>.*?</b>)+!";
+        assert!(matches(rx, "<b>x</b><b>y</b>!"));
+        assert!(!matches(rx, "<b>x</b!b>!"));
+        assert!(matches(r"(?:a|b.*?c)d", "bxxcd"));
+        assert!(matches(r"(?:a|b.*?c)d", "bxcxcd"));
+        assert!(!matches(r"(?:a|b.*?c)d", "bxcdxcd"));
+
+        // flags apply to the pieces around the lazy quantifier
+        assert!(matches(r"(?i)x.*?y", "XaaY"));
+        assert!(!matches(r"(?i)x.*?y", "XaYy"));
+        assert!(matches(r"a(?i)x.*?y", "aXaaY"));
+        assert!(!matches(r"a(?i)x.*?y", "AXaaY"));
+        assert!(matches(r"(?i:x.*?y)z", "XaYz"));
+        assert!(!matches(r"(?i:x.*?y)z", "XaYZ"));
+        assert!(matches(r"(?s)x.*?y", "x\ny"));
+
+        // greedy quantifiers are unaffected
+        assert!(matches(r"x.*y", "xfooyy"));
+    }
+
+    #[test]
+    fn test_lazy_passthrough() {
+        assert!(matches!(
+            expand_lazy(r"a+b").unwrap(),
+            derivre::RegexAst::Regex(s) if s == "a+b"
+        ));
+        assert!(matches!(
+            expand_lazy(r"a+?(").unwrap(),
+            derivre::RegexAst::Regex(s) if s == "a+?("
+        ));
+        assert!(expand_lazy(r"^a+?b$")
+            .unwrap_err()
+            .to_string()
+            .contains("anchors"));
+        assert!(expand_lazy(r"(?U)a+b*?")
+            .unwrap_err()
+            .to_string()
+            .contains("U flag"));
+        assert!(expand_lazy(r"(?P<stop>a+?)")
+            .unwrap_err()
+            .to_string()
+            .contains("stop"));
+    }
+}
@@ -1,7 +1,10 @@
 use std::fmt::Display;
 
-use crate::{api::RegexExt, HashMap};
-use anyhow::{anyhow, bail, Result};
+use crate::{
+    api::{Diagnostic, GrammarError, RegexExt, Severity},
+    HashMap,
+};
+use anyhow::{anyhow, bail, ensure, Result};
 use derivre::RegexAst;
 use serde::de;
 use serde_json::{Deserializer, Value};
@@ -13,6 +16,7 @@ use crate::{
         lexer::{Lexer, LexerResult},
         lexerspec::LexerSpec,
     },
+    json::canonical_schema,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -89,6 +93,9 @@ pub struct Lexeme {
     pub value: LexemeValue,
     pub line: usize,
     pub column: usize,
+    /// byte offsets in the input
+    pub start: usize,
+    pub end: usize,
 }
 
 impl Lexeme {
@@ -98,6 +105,17 @@ impl Lexeme {
             value: std::mem::take(&mut self.value),
             line: self.line,
             column: self.column,
+            start: self.start,
+            end: self.end,
+        }
+    }
+
+    pub fn location(&self) -> Location {
+        Location {
+            line: self.line,
+            column: self.column,
+            start: self.start,
+            end: self.end,
         }
     }
 }
@@ -106,16 +124,38 @@ impl Lexeme {
 pub struct Location {
     pub line: usize,
     pub column: usize,
+    /// byte offsets in the input
+    pub start: usize,
+    pub end: usize,
 }
 
 impl Location {
-    pub fn augment(&self, err: impl Display) -> anyhow::Error {
-        let err = err.to_string();
-        if err.starts_with("at ") {
+    pub fn diagnostic(&self, severity: Severity, message: String) -> Diagnostic {
+        Diagnostic {
+            severity,
+            message,
+            line: self.line,
+            column: self.column,
+            span: if self.line > 0 {
+                Some((self.start, self.end))
+            } else {
+                None
+            },
+            code: None,
+        }
+    }
+
+    /// Attach location to the error, unless it already has one.
+    /// The result is a [`GrammarError`].
+    pub fn augment(&self, err: anyhow::Error) -> anyhow::Error {
+        if err.downcast_ref::<GrammarError>().is_some() {
             // don't add more location info
-            anyhow::anyhow!("{err}")
+            err
         } else {
-            anyhow::anyhow!("at {}({}): {}", self.line, self.column, err)
+            GrammarError {
+                diagnostics: vec![self.diagnostic(Severity::Error, err.to_string())],
+            }
+            .into()
         }
     }
 }
@@ -154,7 +194,7 @@ impl Token {
             Token::String,
             r#""(\\([\"\\\/bfnrt]|u[a-fA-F0-9]{4})|[^\"\\\x00-\x1F\x7F])*"(i|)"#,
         ),
-        (Token::Regexp, r#"/(\\.|[^/\\])+/[imslux]*"#),
+        (Token::Regexp, r#"/(\\.|[^/\\])+/[imsluxa]*"#),
         (Token::Number, r#"[+-]?[0-9]+(\.[0-9]*)?([eE][+-]?[0-9]+)?"#),
         (Token::Newline, r"(\r?\n)+[ \t]*"),
         (Token::SpecialToken, r"<[^<>\s]+>"),
@@ -184,6 +224,7 @@ pub fn lex_lark(input: &str) -> Result<Vec<Lexeme>> {
                 false,
                 None,
                 usize::MAX,
+                None,
             )
             .unwrap();
         lexeme_idx_to_token.insert(l, *token);
@@ -199,6 +240,8 @@ pub fn lex_lark(input: &str) -> Result<Vec<Lexeme>> {
         value: LexemeValue::default(),
         line: 1,
         column: 1,
+        start: 0,
+        end: 0,
     };
     let mut state = state0;
     let mut lexemes = Vec::new();
@@ -220,10 +263,10 @@ pub fn lex_lark(input: &str) -> Result<Vec<Lexeme>> {
 
         match res {
             LexerResult::Error => {
-                bail!("{}({}): lexer error", line_no, column_no);
+                return Err(error_at(line_no, column_no, idx, "lexer error"));
             }
             LexerResult::SpecialToken(_) => {
-                bail!("{}({}): lexer special token", line_no, column_no);
+                return Err(error_at(line_no, column_no, idx, "lexer special token"));
             }
             LexerResult::State(s, _) => {
                 state = s;
@@ -251,6 +294,10 @@ pub fn lex_lark(input: &str) -> Result<Vec<Lexeme>> {
                         let (v, n) = parse_json_prefix(inp_slice)
                             .map_err(|e| anyhow!("failed to parse %regex: {}", e))?;
                         (LexemeValue::Regex(v), n)
+                    } else if token == Token::KwJson {
+                        let (v, n) = parse_json_schema(inp_slice)
+                            .map_err(|e| anyhow!("failed to parse %json: {}", e))?;
+                        (LexemeValue::Json(v), n)
                     } else {
                         let (v, n) = parse_json_prefix(inp_slice)
                             .map_err(|e| anyhow!("failed to parse {:?}: {}", raw_value, e))?;
@@ -276,6 +323,7 @@ pub fn lex_lark(input: &str) -> Result<Vec<Lexeme>> {
                 };
 
                 start_idx = end_idx;
+                curr_lexeme.end = end_idx;
 
                 // println!("lex: {:?}", curr_lexeme);
 
@@ -288,6 +336,7 @@ pub fn lex_lark(input: &str) -> Result<Vec<Lexeme>> {
 
                 curr_lexeme.line = line_no;
                 curr_lexeme.column = column_no;
+                curr_lexeme.start = start_idx;
             }
         }
 
@@ -303,6 +352,131 @@ pub fn lex_lark(input: &str) -> Result<Vec<Lexeme>> {
     Ok(lexemes)
 }
 
+/// Lexeme sequence of the grammar, without whitespace, comments, and with
+/// JSON values in canonical form.
+/// Grammars that differ only in formatting get the same result.
+pub fn normalize_lark(input: &str) -> Result<String> {
+    let lexemes = lex_lark(input)?;
+    let mut res = String::new();
+    for (i, lexeme) in lexemes.iter().enumerate() {
+        if lexeme.token == Token::Newline {
+            // newlines between items don't matter, but they do before continuation lines
+            let prev = i.checked_sub(1).map(|j| lexemes[j].token);
+            let next = lexemes.get(i + 1).map(|l| l.token);
+            if prev.is_none() || (prev == Some(Token::Newline) && next != Some(Token::VBar)) {
+                continue;
+            }
+        }
+        let value = match &lexeme.value {
+            LexemeValue::None => String::new(),
+            LexemeValue::String(_) if lexeme.token == Token::Newline => String::new(),
+            LexemeValue::String(s) => s.clone(),
+            LexemeValue::Json(v) => serde_json::to_string(&canonical_schema(v))?,
+            LexemeValue::Regex(r) => serde_json::to_string(r)?,
+        };
+        res.push_str(&format!("{:?}{:?}\n", lexeme.token, value));
+    }
+    Ok(res)
+}
+
+fn skip_whitespace(data: &[u8], mut idx: usize) -> usize {
+    while idx < data.len() && data[idx].is_ascii_whitespace() {
+        idx += 1;
+    }
+    idx
+}
+
+/// Find the end of a JSON value, which is followed by ',' or ')'.
+fn option_value_end(data: &[u8]) -> usize {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut idx = 0;
+    while idx < data.len() {
+        match data[idx] {
+            b'\\' if in_string => idx += 1,
+            b'"' => in_string = !in_string,
+            _ if in_string => {}
+            b'[' | b'{' => depth += 1,
+            b']' | b'}' => depth -= 1,
+            b',' | b')' if depth == 0 => return idx,
+            _ => {}
+        }
+        idx += 1;
+    }
+    idx
+}
+
+/// Parse JSON schema following %json, optionally preceded by options,
+/// as in `%json(whitespace_flexible=false) { ... }`.
+/// The options are added to "x-guidance" in the schema.
+fn parse_json_schema(data: &[u8]) -> Result<(Value, usize)> {
+    let mut idx = skip_whitespace(data, 0);
+    if data.get(idx) != Some(&b'(') {
+        return parse_json_prefix(data);
+    }
+    idx += 1;
+
+    let mut options = serde_json::Map::new();
+    loop {
+        idx = skip_whitespace(data, idx);
+        if data.get(idx) == Some(&b')') && options.is_empty() {
+            idx += 1;
+            break;
+        }
+        let name_start = idx;
+        while idx < data.len() && (data[idx].is_ascii_alphanumeric() || data[idx] == b'_') {
+            idx += 1;
+        }
+        ensure!(idx > name_start, "expecting option name");
+        let name = String::from_utf8_lossy(&data[name_start..idx]).to_string();
+        idx = skip_whitespace(data, idx);
+        ensure!(data.get(idx) == Some(&b'='), "expecting '=' after {}", name);
+        idx += 1;
+        let len = option_value_end(&data[idx..]);
+        let value: Value = serde_json::from_slice(&data[idx..idx + len])
+            .map_err(|e| anyhow!("invalid value for {}: {}", name, e))?;
+        ensure!(
+            options.insert(name.clone(), value).is_none(),
+            "duplicate option {}",
+            name
+        );
+        idx += len;
+        match data.get(idx) {
+            Some(b',') => idx += 1,
+            Some(b')') => {
+                idx += 1;
+                break;
+            }
+            _ => bail!("unterminated options"),
+        }
+    }
+
+    let (mut schema, n): (Value, usize) = parse_json_prefix(&data[idx..])?;
+    if !options.is_empty() {
+        let obj = schema
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("options can only be used with an object schema"))?;
+        match obj
+            .entry("x-guidance")
+            .or_insert_with(|| Value::Object(serde_json::Map::new()))
+        {
+            Value::Object(x_guidance) => x_guidance.extend(options),
+            _ => bail!("x-guidance must be an object"),
+        }
+    }
+    Ok((schema, idx + n))
+}
+
+fn error_at(line: usize, column: usize, offset: usize, msg: &str) -> anyhow::Error {
+    Location {
+        line,
+        column,
+        start: offset,
+        end: offset + 1,
+    }
+    .augment(anyhow!("{}", msg))
+}
+
 fn parse_json_prefix<'de, T>(data: &[u8]) -> Result<(T, usize)>
 where
     T: de::Deserialize<'de>,
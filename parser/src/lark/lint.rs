@@ -0,0 +1,486 @@
+// Static checks ("lints") over the Lark AST.
+//
+// These never change how the grammar is compiled; they only report
+// warnings about constructs that are likely mistakes (unused or non-productive
+// rules), or known to be slow (see "Performance tips" in docs/syntax.md).
+// The grammar is assumed to compile without errors.
+
+use anyhow::Result;
+
+use crate::{
+    api::{Diagnostic, Severity},
+    HashMap, HashSet,
+};
+
+use super::{
+    ast::*,
+    lexer::Location,
+    parser::{parse_lark, ParsedLark},
+};
+
+/// Run lints on Lark grammar.
+/// Fails only if the grammar can't be parsed.
+pub fn lint_lark(lark: &str) -> Result<Vec<Diagnostic>> {
+    let parsed = parse_lark(lark)?;
+    Ok(Linter::new(&parsed).run())
+}
+
+struct Linter<'a> {
+    rules: HashMap<&'a str, &'a Rule>,
+    tokens: HashMap<&'a str, (&'a Location, Option<&'a Expansions>)>,
+    ignore: Vec<&'a Expansions>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+fn value_names<'a>(value: &'a Value, acc: &mut Vec<&'a str>) {
+    match value {
+        Value::Name(n) => acc.push(n),
+        Value::TemplateUsage { name, values } => {
+            acc.push(name);
+            for v in values {
+                value_names(v, acc);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn names_in(exp: &Expansions) -> Vec<&str> {
+    let mut acc = vec![];
+    walk_expansions(exp, &mut |expr| {
+        if let Atom::Value(v) = &expr.atom {
+            value_names(v, &mut acc);
+        }
+    });
+    acc
+}
+
+fn is_single_char_regex(rx: &str, flags: &str) -> bool {
+    let rx = if flags.is_empty() {
+        rx.to_string()
+    } else {
+        format!("(?{}){}", flags, rx)
+    };
+    match regex_syntax::parse(&rx) {
+        Ok(hir) => {
+            let props = hir.properties();
+            props.minimum_len() == Some(1) && props.maximum_len().is_some_and(|n| n <= 4)
+                || matches!(hir.kind(), regex_syntax::hir::HirKind::Class(_))
+        }
+        Err(_) => false,
+    }
+}
+
+impl<'a> Linter<'a> {
+    fn new(parsed: &'a ParsedLark) -> Self {
+        let mut rules = HashMap::default();
+        let mut tokens = HashMap::default();
+        let mut ignore = vec![];
+        for item in &parsed.items {
+            match item {
+                Item::Rule(rule) => {
+                    rules.insert(rule.name.as_str(), rule);
+                }
+                Item::Token(token) => {
+                    tokens.insert(
+                        token.name.as_str(),
+                        (&token.expansions.0, Some(&token.expansions)),
+                    );
+                }
+                Item::Statement(loc, stmt) => match stmt {
+                    Statement::Ignore(exp) => ignore.push(exp),
+                    Statement::Import { path, alias } => {
+                        let name = alias
+                            .as_deref()
+                            .unwrap_or_else(|| path.split('.').next_back().unwrap());
+                        tokens.insert(name, (loc, None));
+                    }
+                    Statement::MultiImport { names, .. } => {
+                        for n in names {
+                            tokens.insert(n.as_str(), (loc, None));
+                        }
+                    }
+                    _ => {}
+                },
+            }
+        }
+        Linter {
+            rules,
+            tokens,
+            ignore,
+            diagnostics: vec![],
+        }
+    }
+
+    fn warn(&mut self, loc: &Location, code: &str, message: String) {
+        self.diagnostics
+            .push(loc.diagnostic(Severity::Warning, message).with_code(code));
+    }
+
+    fn run(mut self) -> Vec<Diagnostic> {
+        let reachable = self.reachable();
+        self.check_unused(&reachable);
+        self.check_non_productive(&reachable);
+        self.check_regular_rules(&reachable);
+        self.check_slow_patterns(&reachable);
+        self.diagnostics.sort_by_key(|d| (d.line, d.column));
+        self.diagnostics
+    }
+
+    fn rule_names(&self, rule: &'a Rule) -> Vec<&'a str> {
+        let mut acc = names_in(&rule.expansions);
+        if let Some(v) = rule.stop_like() {
+            value_names(v, &mut acc);
+        }
+        if let Some(v) = &rule.ignore {
+            value_names(v, &mut acc);
+        }
+        acc
+    }
+
+    /// Names of rules and tokens reachable from the start rule or %ignore.
+    fn reachable(&self) -> HashSet<&'a str> {
+        let mut reachable = HashSet::default();
+        let mut todo = vec!["start"];
+        for exp in &self.ignore {
+            todo.extend(names_in(exp));
+        }
+        while let Some(name) = todo.pop() {
+            if !reachable.insert(name) {
+                continue;
+            }
+            if let Some(rule) = self.rules.get(name) {
+                todo.extend(self.rule_names(rule));
+            } else if let Some((_, Some(exp))) = self.tokens.get(name) {
+                todo.extend(names_in(exp));
+            }
+        }
+        reachable
+    }
+
+    fn sorted_rules(&self) -> Vec<&'a Rule> {
+        let mut rules = self.rules.values().copied().collect::<Vec<_>>();
+        rules.sort_by_key(|r| (r.expansions.0.line, r.expansions.0.column));
+        rules
+    }
+
+    fn check_unused(&mut self, reachable: &HashSet<&str>) {
+        for rule in self.sorted_rules() {
+            if !reachable.contains(rule.name.as_str()) {
+                self.warn(
+                    &rule.expansions.0,
+                    "unused-rule",
+                    format!("rule {:?} is not reachable from start", rule.name),
+                );
+            }
+        }
+        let mut unused = self
+            .tokens
+            .iter()
+            .filter(|(name, _)| !reachable.contains(*name))
+            .map(|(name, (loc, _))| (*name, *loc))
+            .collect::<Vec<_>>();
+        unused.sort_by_key(|(_, loc)| (loc.line, loc.column));
+        for (name, loc) in unused {
+            self.warn(
+                loc,
+                "unused-terminal",
+                format!("terminal {:?} is never used", name),
+            );
+        }
+    }
+
+    /// Find rules that can't match any finite input, like `a: "x" a`.
+    fn check_non_productive(&mut self, reachable: &HashSet<&str>) {
+        let mut productive: HashSet<&str> = HashSet::default();
+        loop {
+            let mut num_fix = 0;
+            for rule in self.rules.values() {
+                if !productive.contains(rule.name.as_str())
+                    && self.expansions_productive(&rule.expansions, &productive)
+                {
+                    productive.insert(rule.name.as_str());
+                    num_fix += 1;
+                }
+            }
+            if num_fix == 0 {
+                break;
+            }
+        }
+        for rule in self.sorted_rules() {
+            if reachable.contains(rule.name.as_str()) && !productive.contains(rule.name.as_str()) {
+                self.warn(
+                    &rule.expansions.0,
+                    "non-productive",
+                    format!(
+                        "rule {:?} can't match any input; it always leads to infinite recursion",
+                        rule.name
+                    ),
+                );
+            }
+        }
+    }
+
+    fn expansions_productive(&self, exp: &Expansions, productive: &HashSet<&str>) -> bool {
+        exp.1.iter().any(|alias| {
+            alias.expansion.0.iter().all(|expr| {
+                let optional = matches!(&expr.op, Some(Op(op)) if op == "*" || op == "?")
+                    || matches!(expr.range, Some((0, _)));
+                optional
+                    || match &expr.atom {
+                        Atom::Maybe(_) => true,
+                        Atom::Group(inner) => self.expansions_productive(inner, productive),
+                        Atom::Value(Value::Name(n)) => {
+                            !self.rules.contains_key(n.as_str()) || productive.contains(n.as_str())
+                        }
+                        Atom::Value(_) => true,
+                    }
+            })
+        })
+    }
+
+    fn is_terminal_value(&self, value: &Value, regular: &HashSet<&str>) -> bool {
+        match value {
+            Value::LiteralRange(_, _)
+            | Value::LiteralString(_, _)
+            | Value::LiteralRegex(_, _)
+            | Value::RegexExt(_) => true,
+            Value::Name(n) => self.tokens.contains_key(n.as_str()) || regular.contains(n.as_str()),
+            Value::GrammarRef(_)
+            | Value::SpecialToken(_)
+            | Value::Json(_)
+            | Value::TemplateUsage { .. } => false,
+        }
+    }
+
+    fn is_regular(&self, exp: &Expansions, regular: &HashSet<&str>) -> bool {
+        let mut ok = true;
+        walk_expansions(exp, &mut |expr| {
+            if let Atom::Value(v) = &expr.atom {
+                ok &= self.is_terminal_value(v, regular);
+            }
+        });
+        ok
+    }
+
+    /// Rules that define regular languages could be terminals.
+    /// This changes the meaning of the grammar when %ignore (or ignore=) is used,
+    /// so we only report it when there is none.
+    fn check_regular_rules(&mut self, reachable: &HashSet<&str>) {
+        if !self.ignore.is_empty() || self.rules.values().any(|r| r.ignore.is_some()) {
+            return;
+        }
+        let mut regular: HashSet<&str> = HashSet::default();
+        loop {
+            let mut num_fix = 0;
+            for rule in self.rules.values() {
+                let name = rule.name.as_str();
+                if name == "start"
+                    || regular.contains(name)
+                    || rule.stop_like().is_some()
+                    || rule.max_tokens.is_some()
+                    || rule.temperature.is_some()
+                    || rule.capture_name.is_some()
+                    || rule.params.is_some()
+                {
+                    continue;
+                }
+                // a rule referencing itself is not yet in 'regular', so it's not regular
+                if self.is_regular(&rule.expansions, &regular) {
+                    regular.insert(name);
+                    num_fix += 1;
+                }
+            }
+            if num_fix == 0 {
+                break;
+            }
+        }
+        for rule in self.sorted_rules() {
+            // alternatives of single lexemes are fine; sequences and repetitions are slow
+            let has_structure = {
+                let mut r = false;
+                walk_expansions(&rule.expansions, &mut |expr| {
+                    r |= expr.op.is_some() || expr.range.is_some();
+                });
+                r || rule.expansions.1.iter().any(|a| a.expansion.0.len() > 1)
+            };
+            if has_structure
+                && regular.contains(rule.name.as_str())
+                && reachable.contains(rule.name.as_str())
+            {
+                self.warn(
+                    &rule.expansions.0,
+                    "regular-rule",
+                    format!(
+                        "rule {:?} only uses terminals; it could be a terminal {:?}, which is much faster",
+                        rule.name,
+                        rule.name.to_uppercase()
+                    ),
+                );
+            }
+        }
+    }
+
+    fn is_single_char(&self, value: &Value) -> bool {
+        match value {
+            Value::LiteralString(s, _) => s.chars().count() == 1,
+            Value::LiteralRange(_, _) => true,
+            Value::LiteralRegex(rx, flags) => is_single_char_regex(rx, flags),
+            Value::Name(n) => match self.tokens.get(n.as_str()) {
+                Some((_, Some(exp))) => match exp.single_atom() {
+                    Some(Atom::Value(v)) if !matches!(v, Value::Name(_)) => self.is_single_char(v),
+                    _ => false,
+                },
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn is_json_like(&self, expr: &Expr) -> bool {
+        match &expr.atom {
+            Atom::Value(Value::Json(_)) => true,
+            Atom::Value(Value::Name(n)) => self
+                .rules
+                .get(n.as_str())
+                .and_then(|r| r.expansions.single_atom())
+                .is_some_and(|a| matches!(a, Atom::Value(Value::Json(_)))),
+            _ => false,
+        }
+    }
+
+    fn check_slow_patterns(&mut self, reachable: &HashSet<&str>) {
+        for rule in self.sorted_rules() {
+            if !reachable.contains(rule.name.as_str()) || rule.stop_like().is_some() {
+                continue;
+            }
+
+            let mut single_char_reps = vec![];
+            walk_expansions(&rule.expansions, &mut |expr| {
+                if let Atom::Value(v) = &expr.atom {
+                    if (expr.op.is_some() || expr.range.is_some()) && self.is_single_char(v) {
+                        single_char_reps.push(expr.loc.clone());
+                    }
+                }
+            });
+            for loc in single_char_reps {
+                self.warn(
+                    &loc,
+                    "single-char-repeat",
+                    format!(
+                        "repeating a single-character lexeme in rule {:?} is slow; \
+                        use a terminal like /[a-z]+/ instead",
+                        rule.name
+                    ),
+                );
+            }
+
+            let alts = &rule.expansions.1;
+            // with a single alternative, the rule is non-productive, reported above
+            let right_recursive = alts.len() > 1
+                && alts.iter().any(|a| {
+                    let exprs = &a.expansion.0;
+                    exprs.len() > 1
+                        && matches!(
+                            exprs.last().unwrap(),
+                            Expr { atom: Atom::Value(Value::Name(n)), op: None, range: None, .. }
+                                if *n == rule.name
+                        )
+                });
+            if right_recursive {
+                self.warn(
+                    &rule.expansions.0,
+                    "right-recursion",
+                    format!(
+                        "rule {:?} is right-recursive, which is slow; \
+                        use repetition (like one+ or one*) instead",
+                        rule.name
+                    ),
+                );
+            }
+
+            if alts.len() > 1
+                && alts
+                    .iter()
+                    .all(|a| a.expansion.0.first().is_some_and(|e| self.is_json_like(e)))
+            {
+                self.warn(
+                    &rule.expansions.0,
+                    "json-alternatives",
+                    format!(
+                        "alternatives of %json in rule {:?} don't work well; \
+                        use %json {{ \"anyOf\": [ ... ] }} instead",
+                        rule.name
+                    ),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::lint_lark;
+
+    fn lints(lark: &str) -> Vec<(String, usize)> {
+        lint_lark(lark)
+            .unwrap()
+            .into_iter()
+            .map(|d| (d.code.unwrap(), d.line))
+            .collect()
+    }
+
+    fn codes(lark: &str) -> Vec<String> {
+        lints(lark).into_iter().map(|(c, _)| c).collect()
+    }
+
+    #[test]
+    fn test_lint_unused() {
+        assert_eq!(
+            lints("start: a A\na: \"x\"\nb: \"y\"\nA: B\nB: \"z\"\nC: \"q\"\n"),
+            vec![
+                ("unused-rule".to_string(), 3),
+                ("unused-terminal".to_string(), 6)
+            ]
+        );
+        // %ignore, ignore= and stop= count as uses
+        assert!(
+            lints("start: a\na[stop=STOP]: /.*/\nSTOP: \"x\"\nWS: \" \"\n%ignore WS\n").is_empty()
+        );
+        assert!(lints("start: a\na[ignore=WS]: \"x\" \"y\"\nWS: \" \"\n").is_empty());
+    }
+
+    #[test]
+    fn test_lint_productive() {
+        assert_eq!(
+            lints("start: a | \"b\"\na: \"x\" a\n"),
+            vec![("non-productive".to_string(), 2)]
+        );
+        assert!(lints("start: a\na: \"x\" a?\n").is_empty());
+        assert!(lints("start: a\na: \"x\" a | \"y\"\n")
+            .iter()
+            .all(|(c, _)| c == "right-recursion"));
+    }
+
+    #[test]
+    fn test_lint_slow() {
+        assert_eq!(
+            codes("start: digits\ndigits: /[0-9]/+\n"),
+            vec!["regular-rule", "single-char-repeat"]
+        );
+        // with %ignore, regular rules are not the same as terminals
+        assert_eq!(
+            codes("start: digits\ndigits: /[0-9]/+\n%ignore \" \"\n"),
+            vec!["single-char-repeat"]
+        );
+        assert!(codes("start: op\nop: \"+\" | \"-\"\n").is_empty());
+        assert_eq!(
+            codes("start: one \";\" many\nmany: one many | one\none: <|x|>\n"),
+            vec!["right-recursion"]
+        );
+        assert_eq!(
+            codes("start: f1 | f2\nf1: %json {}\nf2: %json {}\n"),
+            vec!["json-alternatives"]
+        );
+    }
+}
@@ -0,0 +1,451 @@
+// Support for (a subset of) lookaround assertions in terminal regexes.
+//
+// derivre (and regex_syntax) do not support lookarounds, but a common
+// use in Lark (and Python re) grammars is a trailing assertion, like
+// /[a-z]+(?![a-z0-9_])/ for keywords or identifiers.
+// We handle these as follows:
+//   - trailing lookbehind R(?<=S) is compiled to R & (.*S), and R(?<!S) to R & ~(.*S);
+//     the lookbehind can thus only inspect the text of the lexeme itself
+//   - trailing lookahead R(?=C) or R(?!C), where C matches a single character,
+//     becomes a follow set on the lexeme - the byte following the lexeme is checked
+//     when the (greedy) lexeme ends; negative lookahead also allows end of input
+//   - since only the first byte is checked, C has to match either all or none
+//     of the characters starting with any given UTF-8 byte; in practice this means
+//     ASCII classes, and classes like [^a-z] or . that contain all non-ASCII characters;
+//     the non-ASCII case variants of 's' and 'k' (from Unicode case folding in
+//     (?i)[a-z]) are ignored, so that case-insensitive ASCII classes work
+// Everything else results in an error.
+
+use anyhow::{bail, ensure, Result};
+use derivre::RegexAst;
+use regex_syntax::hir::{Class, Hir, HirKind};
+use toktrie::SimpleVob;
+
+use super::lazy::expand_lazy;
+use crate::earley::lexerspec::LexemeFollow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Ahead,
+    NotAhead,
+    Behind,
+    NotBehind,
+}
+
+const KINDS: &[(&str, Kind)] = &[
+    ("(?=", Kind::Ahead),
+    ("(?!", Kind::NotAhead),
+    ("(?<=", Kind::Behind),
+    ("(?<!", Kind::NotBehind),
+];
+
+#[derive(Debug, PartialEq, Eq)]
+struct Lookaround {
+    kind: Kind,
+    rx: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct SplitRegex {
+    // leading global flags like "(?i)", if any
+    flags: String,
+    body: String,
+    lookarounds: Vec<Lookaround>,
+}
+
+fn lookaround_kind(rest: &str) -> Option<(Kind, usize)> {
+    KINDS
+        .iter()
+        .find(|(pref, _)| rest.starts_with(pref))
+        .map(|(pref, kind)| (*kind, pref.len()))
+}
+
+fn leading_flags(rx: &str) -> &str {
+    if let Some(rest) = rx.strip_prefix("(?") {
+        let n = rest
+            .bytes()
+            .take_while(|b| b.is_ascii_alphabetic() || *b == b'-')
+            .count();
+        if n > 0 && rest.as_bytes().get(n) == Some(&b')') {
+            return &rx[0..n + 3];
+        }
+    }
+    ""
+}
+
+/// Split off trailing lookaround assertions from the regex.
+/// Syntax errors unrelated to lookarounds are left for the regex compiler to report.
+fn split_lookarounds(rx: &str) -> Result<SplitRegex> {
+    let no_split = || SplitRegex {
+        flags: String::new(),
+        body: rx.to_string(),
+        lookarounds: vec![],
+    };
+
+    let bytes = rx.as_bytes();
+    let mut stack: Vec<(usize, Option<(Kind, usize)>)> = vec![];
+    // (start, end, kind, prefix_len) of top-level lookarounds
+    let mut top_level = vec![];
+    let mut has_alternation = false;
+    let mut class_depth = 0;
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let c = bytes[idx];
+        if c == b'\\' {
+            idx += 2;
+            continue;
+        }
+        if class_depth > 0 {
+            match c {
+                b'[' => class_depth += 1,
+                b']' => class_depth -= 1,
+                _ => {}
+            }
+            idx += 1;
+            continue;
+        }
+        match c {
+            b'[' => {
+                class_depth = 1;
+                idx += 1;
+                // ']' right after '[' or '[^' is a literal
+                if bytes.get(idx) == Some(&b'^') {
+                    idx += 1;
+                }
+                if bytes.get(idx) == Some(&b']') {
+                    idx += 1;
+                }
+                continue;
+            }
+            b'(' => {
+                let kind = lookaround_kind(&rx[idx..]);
+                if kind.is_some() && !stack.is_empty() {
+                    bail!(
+                        "lookaround assertions are only supported at the end of a regex, \
+                        not inside of groups"
+                    );
+                }
+                stack.push((idx, kind));
+            }
+            b')' => match stack.pop() {
+                Some((start, Some((kind, prefix_len)))) => {
+                    top_level.push((start, idx + 1, kind, prefix_len))
+                }
+                Some(_) => {}
+                None => return Ok(no_split()),
+            },
+            b'|' if stack.is_empty() => has_alternation = true,
+            _ => {}
+        }
+        idx += 1;
+    }
+
+    if top_level.is_empty() || class_depth > 0 || !stack.is_empty() {
+        return Ok(no_split());
+    }
+
+    ensure!(
+        !has_alternation,
+        "lookaround assertions can't be used with top-level alternatives (|); \
+        wrap the alternatives in a group, e.g., (?:a|b)(?!c)"
+    );
+
+    let mut end = rx.len();
+    let mut lookarounds = vec![];
+    for &(start, la_end, kind, prefix_len) in top_level.iter().rev() {
+        if la_end != end {
+            if start == 0 && matches!(kind, Kind::Behind | Kind::NotBehind) {
+                bail!("lookbehind at the start of a regex is not supported; it would need to inspect the previous lexeme");
+            }
+            bail!("lookaround assertions are only supported at the end of a regex");
+        }
+        lookarounds.push(Lookaround {
+            kind,
+            rx: rx[start + prefix_len..la_end - 1].to_string(),
+        });
+        end = start;
+    }
+    lookarounds.reverse();
+
+    let body = &rx[0..end];
+    let flags = leading_flags(body);
+    ensure!(
+        body.len() > flags.len(),
+        "regex consisting only of lookaround assertions is not supported"
+    );
+
+    Ok(SplitRegex {
+        flags: flags.to_string(),
+        body: body.to_string(),
+        lookarounds,
+    })
+}
+
+/// Collect the characters matched by a single-character regex,
+/// as inclusive ranges of code points, or of bytes for `(?-u)` classes.
+fn add_hir_chars(chars: &mut Vec<(u32, u32)>, bytes: &mut Vec<(u8, u8)>, hir: &Hir) -> bool {
+    match hir.kind() {
+        HirKind::Literal(lit) => match std::str::from_utf8(&lit.0) {
+            Ok(s) if s.chars().count() == 1 => {
+                let c = s.chars().next().unwrap() as u32;
+                chars.push((c, c));
+            }
+            _ if lit.0.len() == 1 => bytes.push((lit.0[0], lit.0[0])),
+            _ => return false,
+        },
+        HirKind::Class(Class::Unicode(cls)) => {
+            chars.extend(
+                cls.ranges()
+                    .iter()
+                    .map(|r| (r.start() as u32, r.end() as u32)),
+            );
+        }
+        HirKind::Class(Class::Bytes(cls)) => {
+            bytes.extend(cls.ranges().iter().map(|r| (r.start(), r.end())));
+        }
+        HirKind::Capture(cap) => return add_hir_chars(chars, bytes, &cap.sub),
+        HirKind::Alternation(alts) => return alts.iter().all(|h| add_hir_chars(chars, bytes, h)),
+        _ => return false,
+    }
+    true
+}
+
+/// Range of code points whose UTF-8 encoding starts with `byte`.
+fn lead_byte_range(byte: u8) -> Option<(u32, u32)> {
+    let (lo, hi) = match byte {
+        0x00..=0x7F => (byte as u32, byte as u32),
+        0xC2..=0xDF => (
+            (byte as u32 & 0x1F) << 6,
+            ((byte as u32 & 0x1F) << 6) | 0x3F,
+        ),
+        0xE0 => (0x800, 0xFFF),
+        // surrogates are not characters
+        0xED => (0xD000, 0xD7FF),
+        0xE1..=0xEF => (
+            (byte as u32 & 0x0F) << 12,
+            ((byte as u32 & 0x0F) << 12) | 0xFFF,
+        ),
+        0xF0 => (0x10000, 0x3FFFF),
+        0xF1..=0xF3 => (
+            (byte as u32 & 0x07) << 18,
+            ((byte as u32 & 0x07) << 18) | 0x3FFFF,
+        ),
+        0xF4 => (0x100000, 0x10FFFF),
+        _ => return None,
+    };
+    Some((lo, hi))
+}
+
+/// Compute the set of bytes that can start a match of single-character regex `rx`.
+/// Fails if for some non-ASCII first byte only some of the characters are matched.
+fn first_bytes(rx: &str) -> Result<SimpleVob> {
+    let hir = regex_syntax::ParserBuilder::new()
+        .utf8(false)
+        .build()
+        .parse(rx)
+        .map_err(|e| anyhow::anyhow!("invalid lookahead {:?}: {}", rx, e))?;
+    let mut chars = vec![];
+    let mut bytes = vec![];
+    ensure!(
+        add_hir_chars(&mut chars, &mut bytes, &hir),
+        "lookahead {:?} has to match a single character, like [a-z0-9_]",
+        rx
+    );
+
+    // (?i)[a-z] also matches U+017F (long s) and U+212A (Kelvin sign)
+    let has_char = |chars: &[(u32, u32)], c: u32| chars.iter().any(|&(a, b)| a <= c && c <= b);
+    for (folded, ascii) in [(0x17F, 's'), (0x212A, 'k')] {
+        if has_char(&chars, ascii as u32) {
+            chars = chars
+                .into_iter()
+                .flat_map(|(a, b)| {
+                    if a <= folded && folded <= b {
+                        vec![(a, folded - 1), (folded + 1, b)]
+                    } else {
+                        vec![(a, b)]
+                    }
+                })
+                .filter(|(a, b)| a <= b)
+                .collect();
+        }
+    }
+
+    let mut set = SimpleVob::alloc(256);
+    for (a, b) in bytes {
+        set.allow_range(a as u32..=b as u32);
+    }
+    for byte in 0..=255u8 {
+        let Some((lo, hi)) = lead_byte_range(byte) else {
+            continue;
+        };
+        let covered: u32 = chars
+            .iter()
+            .map(|&(a, b)| (b.min(hi) + 1).saturating_sub(a.max(lo)))
+            .sum();
+        if covered == 0 {
+            continue;
+        }
+        ensure!(
+            covered > hi - lo,
+            "lookahead {:?} matches only some of the characters starting with byte 0x{:02X}; \
+            only the first byte after the lexeme is checked, so use an ASCII class \
+            (e.g., [a-zA-Z0-9_] or (?-u:\\w) instead of \\w)",
+            rx,
+            byte
+        );
+        set.allow_token(byte as u32);
+    }
+    Ok(set)
+}
+
+/// Compile a terminal regex (with flags already applied), handling
+/// trailing lookaround assertions.
+/// Returns the regex and a follow set for the lexeme, if it had lookaheads.
+pub fn compile_lookarounds(rx: &str) -> Result<(RegexAst, Option<LexemeFollow>)> {
+    let split = split_lookarounds(rx)?;
+    let body = expand_lazy(&split.body)?;
+    if split.lookarounds.is_empty() {
+        return Ok((body, None));
+    }
+
+    let mut behind = vec![body];
+    let mut follow: Option<LexemeFollow> = None;
+    for la in split.lookarounds {
+        let inner = format!("{}{}", split.flags, la.rx);
+        match la.kind {
+            Kind::Behind | Kind::NotBehind => {
+                let ends_with = RegexAst::Concat(vec![
+                    RegexAst::Regex("(?s:.)*".to_string()),
+                    RegexAst::Regex(inner),
+                ]);
+                behind.push(if la.kind == Kind::Behind {
+                    ends_with
+                } else {
+                    RegexAst::Not(Box::new(ends_with))
+                });
+            }
+            Kind::Ahead | Kind::NotAhead => {
+                let mut bytes = first_bytes(&inner)?;
+                let eos = la.kind == Kind::NotAhead;
+                if eos {
+                    bytes = bytes.negated();
+                }
+                follow = Some(match follow {
+                    Some(mut f) => {
+                        f.bytes.and(&bytes);
+                        f.eos &= eos;
+                        f
+                    }
+                    None => LexemeFollow { bytes, eos },
+                });
+            }
+        }
+    }
+
+    let rx = if behind.len() == 1 {
+        behind.pop().unwrap()
+    } else {
+        RegexAst::And(behind)
+    };
+    Ok((rx, follow))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compile_lookarounds, split_lookarounds, Kind, Lookaround};
+
+    fn split(rx: &str) -> (String, Vec<(Kind, String)>) {
+        let r = split_lookarounds(rx).unwrap();
+        (
+            r.body,
+            r.lookarounds
+                .into_iter()
+                .map(|Lookaround { kind, rx }| (kind, rx))
+                .collect(),
+        )
+    }
+
+    fn split_err(rx: &str) -> String {
+        split_lookarounds(rx).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_split_lookarounds() {
+        assert_eq!(split("[a-z]+"), ("[a-z]+".to_string(), vec![]));
+        assert_eq!(split(r"\(?!x"), (r"\(?!x".to_string(), vec![]));
+        assert_eq!(split(r"[(?!x]"), (r"[(?!x]".to_string(), vec![]));
+        assert_eq!(
+            split("[a-z]+(?![a-z0-9_])"),
+            (
+                "[a-z]+".to_string(),
+                vec![(Kind::NotAhead, "[a-z0-9_]".to_string())]
+            )
+        );
+        assert_eq!(
+            split(r"(?:a|b)+(?<=b)(?=[)])"),
+            (
+                "(?:a|b)+".to_string(),
+                vec![
+                    (Kind::Behind, "b".to_string()),
+                    (Kind::Ahead, "[)]".to_string())
+                ]
+            )
+        );
+        assert_eq!(
+            split(r"\w+(?<!_)"),
+            (r"\w+".to_string(), vec![(Kind::NotBehind, "_".to_string())])
+        );
+    }
+
+    #[test]
+    fn test_split_lookarounds_errors() {
+        assert!(split_err("(?<=a)b").contains("previous lexeme"));
+        assert!(split_err("a(?!b)c").contains("at the end"));
+        assert!(split_err("a(?!b)*").contains("at the end"));
+        assert!(split_err("(?:a(?!b))c").contains("inside of groups"));
+        assert!(split_err("a|b(?!c)").contains("alternatives"));
+        assert!(split_err("(?i)(?!c)").contains("only of lookaround"));
+    }
+
+    #[test]
+    fn test_follow_sets() {
+        let (_, f) = compile_lookarounds("[a-z]+(?![a-z0-9_])").unwrap();
+        let f = f.unwrap();
+        assert!(f.eos);
+        assert!(f.allows(Some(b' ')));
+        assert!(f.allows(Some(b'(')));
+        assert!(!f.allows(Some(b'x')));
+        assert!(!f.allows(Some(b'_')));
+        assert!(!f.allows(Some(b'7')));
+
+        let (_, f) = compile_lookarounds("(?i)if(?=[a-z]|\\()").unwrap();
+        let f = f.unwrap();
+        assert!(!f.eos);
+        assert!(!f.allows(None));
+        assert!(f.allows(Some(b'Q')));
+        assert!(f.allows(Some(b'(')));
+        assert!(!f.allows(Some(b' ')));
+
+        let (_, f) = compile_lookarounds("a+(?<=aa)").unwrap();
+        assert!(f.is_none());
+
+        assert!(compile_lookarounds("a(?!bc)")
+            .unwrap_err()
+            .to_string()
+            .contains("single character"));
+
+        // only the first byte is checked, so it has to decide the character class
+        let (_, f) = compile_lookarounds("[a-z]+(?![a-z])").unwrap();
+        assert!(f.unwrap().allows(Some(0xC3)));
+        let (_, f) = compile_lookarounds("[a-z]+(?=[^a-z])").unwrap();
+        assert!(f.unwrap().allows(Some(0xF0)));
+        let (_, f) = compile_lookarounds("[a-z]+(?!(?-u:\\w))").unwrap();
+        assert!(f.unwrap().allows(Some(0xC3)));
+        for rx in ["[a-z]+(?!é)", "[a-z]+(?=é)", "[a-z]+(?!\\w)"] {
+            assert!(compile_lookarounds(rx)
+                .unwrap_err()
+                .to_string()
+                .contains("only some of the characters"));
+        }
+    }
+}
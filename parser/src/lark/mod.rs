@@ -1,7 +1,15 @@
 mod ast;
 mod common;
 mod compiler;
+mod export;
+mod lazy;
 mod lexer;
+mod lint;
+mod lookaround;
 mod parser;
+mod python_re;
 
 pub use compiler::{lark_regex_quote, lark_to_llguidance};
+pub(crate) use export::export_lark;
+pub(crate) use lexer::normalize_lark;
+pub use lint::lint_lark;
@@ -2,6 +2,7 @@ use super::{
     ast::*,
     lexer::{lex_lark, Lexeme, LexemeValue, Location, Token},
 };
+use crate::api::{Diagnostic, GrammarError, Severity};
 use anyhow::{anyhow, bail, ensure, Result};
 
 /// The parser struct that holds the tokens and current position.
@@ -17,17 +18,54 @@ impl Parser {
     }
 
     /// Parses the start symbol of the grammar.
+    /// On syntax errors, skips to the next item, so that all errors are reported.
     pub fn parse_start(&mut self) -> Result<ParsedLark> {
         let mut items = Vec::new();
+        let mut errors = Vec::new();
         while !self.is_at_end() {
             self.consume_newlines();
             if self.is_at_end() {
                 break;
             }
-            items.push(self.parse_item()?);
+            match self.parse_item() {
+                Ok(item) => items.push(item),
+                Err(e) => {
+                    errors.push(self.error_diagnostic(e));
+                    self.skip_item();
+                }
+            }
             self.consume_newlines();
         }
-        Ok(ParsedLark { items })
+        if errors.is_empty() {
+            Ok(ParsedLark { items })
+        } else {
+            Err(GrammarError {
+                diagnostics: errors,
+            }
+            .into())
+        }
+    }
+
+    fn error_diagnostic(&self, e: anyhow::Error) -> Diagnostic {
+        if let Some(tok) = self.peek_token() {
+            tok.location().diagnostic(
+                Severity::Error,
+                format!("{} (at {} ({:?}))", e, tok.value, tok.token),
+            )
+        } else {
+            Diagnostic::error(format!("at EOF: {}", e))
+        }
+    }
+
+    /// Skip to the end of the current item, that is the next newline
+    /// not followed by a continuation line ("| ...").
+    fn skip_item(&mut self) {
+        while !self.is_at_end() {
+            if self.match_token(Token::Newline) && !self.has_token(Token::VBar) {
+                break;
+            }
+            self.advance();
+        }
     }
 
     /// Parses an item (rule, token, or statement).
@@ -44,15 +82,25 @@ impl Parser {
 
     fn location(&self) -> Location {
         if let Some(t) = self.peek_token() {
+            t.location()
+        } else {
             Location {
-                line: t.line,
-                column: t.column,
+                line: 0,
+                column: 0,
+                start: 0,
+                end: 0,
             }
-        } else {
-            Location { line: 0, column: 0 }
         }
     }
 
+    /// Extend location to the end of the last consumed token.
+    fn extend_location(&self, mut loc: Location) -> Location {
+        if self.pos > 0 {
+            loc.end = std::cmp::max(loc.end, self.tokens[self.pos - 1].end);
+        }
+        loc
+    }
+
     /// Parses a rule definition.
     fn parse_rule(&mut self) -> Result<Rule> {
         let name = self.expect_token_val(Token::Rule)?;
@@ -90,6 +138,7 @@ impl Parser {
             temperature: None,
             capture_name: None,
             stop_capture_name: None,
+            ignore: None,
         };
 
         if self.has_token(Token::LBracket) {
@@ -181,6 +230,11 @@ impl Parser {
                             );
                             rule.suffix = Some(value);
                         }
+                        "ignore" => {
+                            let value = self.parse_value()?;
+                            ensure!(rule.ignore.is_none(), "Cannot have multiple ignore= values");
+                            rule.ignore = Some(value);
+                        }
                         "max_tokens" => {
                             let value = self.expect_token_val(Token::Number)?.parse::<usize>()?;
                             rule.max_tokens = Some(value);
@@ -299,7 +353,7 @@ impl Parser {
         while self.match_vbar() {
             aliases.push(self.parse_alias()?);
         }
-        Ok(Expansions(loc, aliases))
+        Ok(Expansions(self.extend_location(loc), aliases))
     }
 
     fn match_vbar(&mut self) -> bool {
@@ -345,6 +399,7 @@ impl Parser {
 
     /// Parses an expression.
     fn parse_expr(&mut self) -> Result<Expr> {
+        let loc = self.location();
         let atom = self.parse_atom()?;
         let mut op = None;
         let mut range = None;
@@ -380,7 +435,12 @@ impl Parser {
             }
             range = Some((start_num, end_num));
         }
-        Ok(Expr { atom, op, range })
+        Ok(Expr {
+            loc: self.extend_location(loc),
+            atom,
+            op,
+            range,
+        })
     }
 
     /// Parses an atom.
@@ -630,18 +690,5 @@ pub struct ParsedLark {
 pub fn parse_lark(input: &str) -> Result<ParsedLark> {
     let tokens = lex_lark(input)?;
     let mut parser = Parser::new(tokens);
-    parser.parse_start().map_err(|e| {
-        if let Some(tok) = parser.peek_token() {
-            anyhow!(
-                "{}({}): {} (at {} ({:?}))",
-                tok.line,
-                tok.column,
-                e,
-                tok.value,
-                tok.token
-            )
-        } else {
-            anyhow!("at EOF: {}", e)
-        }
-    })
+    parser.parse_start()
 }
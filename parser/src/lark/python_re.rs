@@ -0,0 +1,553 @@
+// Translation of Python `re` syntax to the Rust `regex` syntax used by derivre.
+//
+// This is used for terminal regexes when `%llguidance { "python_re": true }` is set,
+// so that grammars written for upstream Lark (or Outlines) can be used unchanged.
+// The translation keeps the Python semantics where the two differ:
+//   - `\Z` is end of input, `\uXXXX`, `\UXXXXXXXX` and octal escapes are supported
+//   - `(?x)` verbose mode keeps whitespace and `#` inside of character classes
+//   - `[` inside a class is a literal (so `[[:alpha:]]` is not a POSIX class),
+//     and so are `&&`, `--` and `~~`
+//   - `{` that doesn't start a valid repetition is a literal, `{,n}` means `{0,n}`
+//   - the `a` (ASCII) flag restricts `\d`, `\w` and `\s` to ASCII
+//   - named groups `(?P<name>...)` are just groups
+// Constructs that can't be represented as a DFA (backreferences, word boundaries,
+// atomic groups, possessive quantifiers, conditionals) result in an error.
+// Lookarounds are passed through, and later handled in `lookaround.rs`.
+
+use anyhow::{bail, ensure, Result};
+
+#[derive(Clone, Copy, Default)]
+struct Flags {
+    verbose: bool,
+    ascii: bool,
+}
+
+struct Translator<'a> {
+    rx: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+    out: String,
+    flags: Flags,
+    /// Flags to restore at the end of each open group.
+    groups: Vec<Flags>,
+}
+
+const ASCII_DIGIT: &str = "0-9";
+const ASCII_WORD: &str = "0-9A-Za-z_";
+const ASCII_SPACE: &str = r" \t\n\r\f\v";
+
+impl Translator<'_> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars()
+            .enumerate()
+            .all(|(i, c)| self.peek_at(i) == Some(c))
+    }
+
+    fn next(&mut self) -> Result<char> {
+        match self.peek() {
+            Some(c) => {
+                self.pos += 1;
+                Ok(c)
+            }
+            None => bail!("unexpected end of regex /{}/", self.rx),
+        }
+    }
+
+    fn hex(&mut self, len: usize, esc: char) -> Result<u32> {
+        let mut s = String::new();
+        for _ in 0..len {
+            match self.peek() {
+                Some(c) if c.is_ascii_hexdigit() => {
+                    s.push(c);
+                    self.pos += 1;
+                }
+                _ => bail!("incomplete escape \\{}{}", esc, s),
+            }
+        }
+        Ok(u32::from_str_radix(&s, 16).unwrap())
+    }
+
+    fn octal(&mut self, first: char, max_len: usize) -> Result<u32> {
+        let mut s = first.to_string();
+        while s.len() < max_len && matches!(self.peek(), Some('0'..='7')) {
+            s.push(self.next()?);
+        }
+        let v = u32::from_str_radix(&s, 8).unwrap();
+        ensure!(
+            v <= 0o377,
+            "octal escape value \\{} outside of range 0-0o377",
+            s
+        );
+        Ok(v)
+    }
+
+    fn char_escape(code: u32) -> Result<String> {
+        match char::from_u32(code) {
+            Some(c) if c.is_control() => Ok(format!("\\x{{{:X}}}", code)),
+            Some(c) => Ok(regex_syntax::escape(&c.to_string())),
+            None => bail!("invalid Unicode code point 0x{:x}", code),
+        }
+    }
+
+    /// Translate escape sequence (after the backslash) that means the same inside
+    /// and outside of character class.
+    fn common_escape(&mut self, c: char) -> Result<Option<String>> {
+        let r = match c {
+            'a' | 'f' | 'n' | 'r' | 't' | 'v' => format!("\\{}", c),
+            'x' => Self::char_escape(self.hex(2, c)?)?,
+            'u' => Self::char_escape(self.hex(4, c)?)?,
+            'U' => Self::char_escape(self.hex(8, c)?)?,
+            'N' => {
+                bail!("named Unicode escapes (\\N{{...}}) are not supported; use \\uXXXX instead")
+            }
+            'd' | 'D' | 'w' | 'W' | 's' | 'S' => {
+                if self.flags.ascii {
+                    let cls = match c.to_ascii_lowercase() {
+                        'd' => ASCII_DIGIT,
+                        'w' => ASCII_WORD,
+                        _ => ASCII_SPACE,
+                    };
+                    let neg = if c.is_ascii_uppercase() { "^" } else { "" };
+                    format!("[{}{}]", neg, cls)
+                } else {
+                    format!("\\{}", c)
+                }
+            }
+            _ if c.is_ascii_alphanumeric() => return Ok(None),
+            // escaped punctuation, whitespace etc. is a literal
+            _ => regex_syntax::escape(&c.to_string()),
+        };
+        Ok(Some(r))
+    }
+
+    fn escape(&mut self) -> Result<()> {
+        let c = self.next()?;
+        let r = match c {
+            'A' => "\\A".to_string(),
+            'Z' => "\\z".to_string(),
+            'b' | 'B' => bail!(
+                "word boundary \\{} can't be used in lexer regexes; \
+                 use a trailing lookahead like (?![a-zA-Z0-9_]) instead",
+                c
+            ),
+            '0' => Self::char_escape(self.octal(c, 3)?)?,
+            '1'..='9' => {
+                let is_octal = c <= '7'
+                    && matches!(self.peek(), Some('0'..='7'))
+                    && matches!(self.peek_at(1), Some('0'..='7'));
+                if is_octal {
+                    Self::char_escape(self.octal(c, 3)?)?
+                } else {
+                    let mut n = c.to_string();
+                    if let Some(d) = self.peek().filter(|d| d.is_ascii_digit()) {
+                        n.push(d);
+                    }
+                    bail!("backreference \\{} can't be represented as a DFA", n)
+                }
+            }
+            _ => match self.common_escape(c)? {
+                Some(r) => r,
+                None => bail!("bad escape \\{}", c),
+            },
+        };
+        self.out.push_str(&r);
+        Ok(())
+    }
+
+    /// Parse a single item of a character class; returns the translated text,
+    /// and the character if the item is a single character (can be part of a range).
+    fn class_item(&mut self) -> Result<(String, Option<char>)> {
+        let c = self.next()?;
+        if c != '\\' {
+            return Ok((regex_syntax::escape(&c.to_string()), Some(c)));
+        }
+        let c = self.next()?;
+        let code = match c {
+            'b' => 0x08,
+            '0'..='7' => self.octal(c, 3)?,
+            'x' => self.hex(2, c)?,
+            'u' => self.hex(4, c)?,
+            'U' => self.hex(8, c)?,
+            'a' => 0x07,
+            'f' => 0x0c,
+            'n' => 0x0a,
+            'r' => 0x0d,
+            't' => 0x09,
+            'v' => 0x0b,
+            _ => {
+                return match self.common_escape(c)? {
+                    Some(r) if !c.is_ascii_alphanumeric() => Ok((r, Some(c))),
+                    Some(r) => Ok((r, None)),
+                    None => bail!("bad escape \\{} in character class", c),
+                };
+            }
+        };
+        match char::from_u32(code) {
+            Some(ch) => Ok((Self::char_escape(code)?, Some(ch))),
+            None => bail!("invalid Unicode code point 0x{:x}", code),
+        }
+    }
+
+    fn class(&mut self) -> Result<()> {
+        let start = self.pos - 1;
+        self.out.push('[');
+        if self.peek() == Some('^') {
+            self.pos += 1;
+            self.out.push('^');
+        }
+        let mut first = true;
+        loop {
+            match self.peek() {
+                None => bail!(
+                    "unterminated character set at position {} in /{}/",
+                    start,
+                    self.rx
+                ),
+                // ']' is a literal only as the first item
+                Some(']') if !first => {
+                    self.pos += 1;
+                    break;
+                }
+                _ => {}
+            }
+            first = false;
+            let (lo, lo_ch) = self.class_item()?;
+            if self.peek() == Some('-') && !matches!(self.peek_at(1), Some(']') | None) {
+                self.pos += 1;
+                let (hi, hi_ch) = self.class_item()?;
+                match (lo_ch, hi_ch) {
+                    (Some(a), Some(b)) => {
+                        ensure!(a <= b, "bad character range {}-{}", a, b);
+                        self.out.push_str(&format!("{}-{}", lo, hi));
+                    }
+                    _ => bail!("bad character range {}-{}", lo, hi),
+                }
+            } else {
+                self.out.push_str(&lo);
+            }
+        }
+        self.out.push(']');
+        Ok(())
+    }
+
+    /// Parse flag letters, like "ims" in "(?ims)" or "(?i-s:...)".
+    /// Returns flags to pass to Rust regex.
+    fn flag_letters(&mut self, flags: &mut Flags, allow_off: bool) -> Result<String> {
+        let mut on = String::new();
+        let mut off = String::new();
+        let mut is_off = false;
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => bail!("missing ) after inline flags"),
+            };
+            match c {
+                '-' if allow_off && !is_off => is_off = true,
+                'i' | 'm' | 's' => {
+                    if is_off {
+                        off.push(c)
+                    } else {
+                        on.push(c)
+                    }
+                }
+                'x' => flags.verbose = !is_off,
+                'a' if !is_off => flags.ascii = true,
+                'u' if !is_off => {}
+                'L' => bail!("the L (LOCALE) flag is only supported for bytes patterns"),
+                ':' | ')' => break,
+                _ => bail!("unknown inline flag {:?}", c),
+            }
+            self.pos += 1;
+        }
+        if off.is_empty() {
+            Ok(on)
+        } else {
+            Ok(format!("{}-{}", on, off))
+        }
+    }
+
+    fn group(&mut self) -> Result<()> {
+        let saved = self.flags;
+        if self.peek() != Some('?') {
+            self.out.push('(');
+        } else if self.starts_with("?#") {
+            // comment
+            while self.next()? != ')' {}
+            return Ok(());
+        } else if self.starts_with("?P<") {
+            // named groups are not captured anyway; in particular, (?P<stop>...) has
+            // special meaning for derivre
+            while self.next()? != '>' {}
+            self.out.push_str("(?:");
+        } else if self.starts_with("?P=") {
+            let start = self.pos + 3;
+            while self.next()? != ')' {}
+            let name: String = self.chars[start..self.pos - 1].iter().collect();
+            bail!("backreference (?P={}) can't be represented as a DFA", name);
+        } else if self.starts_with("?>") {
+            bail!("atomic groups (?>...) are not supported");
+        } else if self.starts_with("?(") {
+            bail!("conditional groups (?(...)...) can't be represented as a DFA");
+        } else if let Some(p) = ["?:", "?=", "?!", "?<=", "?<!"]
+            .iter()
+            .find(|p| self.starts_with(p))
+        {
+            self.pos += p.len();
+            self.out.push('(');
+            self.out.push_str(p);
+        } else {
+            self.pos += 1;
+            let mut flags = self.flags;
+            let rust_flags = self.flag_letters(&mut flags, true)?;
+            if self.next()? == ')' {
+                bail!("global flags not at the start of the expression");
+            }
+            self.flags = flags;
+            self.out.push_str(&format!("(?{}:", rust_flags));
+        }
+        self.groups.push(saved);
+        Ok(())
+    }
+
+    /// Parse `{m,n}` after `{`; returns None if it's not a valid repetition.
+    fn repetition(&mut self) -> Option<String> {
+        let start = self.pos;
+        let digits = |t: &mut Self| {
+            let mut s = String::new();
+            while let Some(c) = t.peek().filter(|c| c.is_ascii_digit()) {
+                s.push(c);
+                t.pos += 1;
+            }
+            s
+        };
+        if self.peek() == Some('}') {
+            return None;
+        }
+        let lo = digits(self);
+        let hi = if self.peek() == Some(',') {
+            self.pos += 1;
+            Some(digits(self))
+        } else {
+            None
+        };
+        if self.peek() != Some('}') {
+            self.pos = start;
+            return None;
+        }
+        self.pos += 1;
+        let lo = if lo.is_empty() { "0".to_string() } else { lo };
+        Some(match hi {
+            None => format!("{{{}}}", lo),
+            Some(hi) => format!("{{{},{}}}", lo, hi),
+        })
+    }
+
+    /// Handle `?` (lazy) or `+` (possessive) after a quantifier.
+    fn quantifier_suffix(&mut self) -> Result<()> {
+        match self.peek() {
+            Some('?') => {
+                self.pos += 1;
+                self.out.push('?');
+            }
+            Some('+') => bail!("possessive quantifiers (like a*+) are not supported"),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Check for `(?flags)` (as opposed to `(?flags:...)`).
+    fn at_global_flags(&self) -> bool {
+        if !self.starts_with("(?") {
+            return false;
+        }
+        let mut i = 2;
+        while self.peek_at(i).is_some_and(|c| "aiLmsux".contains(c)) {
+            i += 1;
+        }
+        i > 2 && self.peek_at(i) == Some(')')
+    }
+
+    fn skip_verbose(&mut self) {
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() {
+                self.pos += 1;
+            } else if c == '#' {
+                while let Some(c) = self.peek() {
+                    self.pos += 1;
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn translate(&mut self) -> Result<()> {
+        loop {
+            if self.flags.verbose {
+                self.skip_verbose();
+            }
+            let c = match self.peek() {
+                Some(c) => c,
+                None => break,
+            };
+            self.pos += 1;
+            match c {
+                '\\' => self.escape()?,
+                '[' => self.class()?,
+                '(' => self.group()?,
+                ')' => {
+                    match self.groups.pop() {
+                        Some(flags) => self.flags = flags,
+                        None => bail!("unbalanced parenthesis in /{}/", self.rx),
+                    }
+                    self.out.push(')');
+                }
+                '*' | '+' | '?' => {
+                    self.out.push(c);
+                    self.quantifier_suffix()?;
+                }
+                '{' => match self.repetition() {
+                    Some(rep) => {
+                        self.out.push_str(&rep);
+                        self.quantifier_suffix()?;
+                    }
+                    None => self.out.push_str("\\{"),
+                },
+                '}' => self.out.push_str("\\}"),
+                ']' => self.out.push_str("\\]"),
+                _ => self.out.push(c),
+            }
+        }
+        ensure!(
+            self.groups.is_empty(),
+            "missing ), unterminated subpattern in /{}/",
+            self.rx
+        );
+        Ok(())
+    }
+}
+
+/// Translate Python `re` regex `rx` with Lark flags (like "i" in `/foo/i`)
+/// to Rust `regex` syntax.
+pub fn python_re_to_rust(rx: &str, lark_flags: &str) -> Result<String> {
+    let mut t = Translator {
+        rx,
+        chars: rx.chars().collect(),
+        pos: 0,
+        out: String::new(),
+        flags: Flags::default(),
+        groups: vec![],
+    };
+
+    let mut global = String::new();
+    for c in lark_flags.chars() {
+        match c {
+            'i' | 'm' | 's' => global.push(c),
+            'x' => t.flags.verbose = true,
+            'a' => t.flags.ascii = true,
+            'u' => {}
+            'l' => bail!("the l (LOCALE) flag is only supported for bytes patterns"),
+            _ => bail!("unknown regex flag {:?}", c),
+        }
+    }
+
+    // global inline flags, like (?x) or (?im), can only be at the start
+    while t.at_global_flags() {
+        t.pos += 2;
+        let mut flags = t.flags;
+        global.push_str(&t.flag_letters(&mut flags, false)?);
+        t.flags = flags;
+        t.pos += 1;
+    }
+
+    t.translate()?;
+
+    if global.is_empty() {
+        Ok(t.out)
+    } else {
+        let mut chars = global.chars().collect::<Vec<_>>();
+        chars.sort();
+        chars.dedup();
+        Ok(format!(
+            "(?{}){}",
+            chars.into_iter().collect::<String>(),
+            t.out
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::python_re_to_rust;
+
+    fn tr(rx: &str) -> String {
+        python_re_to_rust(rx, "").unwrap()
+    }
+
+    fn err(rx: &str) -> String {
+        python_re_to_rust(rx, "").unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(tr(r"[a-z]+\Z"), r"[a-z]+\z");
+        assert_eq!(tr(r"(?P<num>\d+)"), r"(?:\d+)");
+        assert_eq!(tr(r"[[:alpha:]]"), r"[\[:alpha:]\]");
+        assert_eq!(tr(r"[]a]"), r"[\]a]");
+        assert_eq!(tr(r"[a&&b~~-]"), r"[a\&\&b\~\~\-]");
+        assert_eq!(tr(r"[--/]"), r"[\--/]");
+        assert_eq!(tr(r"[\b\x41-\x5a\u00e9\101]"), r"[\x{8}A-ZéA]");
+        assert_eq!(tr(r"a{,3}b{2,}c{}d{x}"), r"a{0,3}b{2,}c\{\}d\{x\}");
+        assert_eq!(tr(r"a*?b+?c??d{1,2}?"), r"a*?b+?c??d{1,2}?");
+        assert_eq!(tr(r"\0\012\101"), r"\x{0}\x{A}A");
+        assert_eq!(tr(r"\-\<\>\#"), r"\-<>\#");
+        assert_eq!(tr(r"a(?#comment)b"), r"ab");
+        assert_eq!(tr(r"(?i)ab(?s:.)"), r"(?i)ab(?s:.)");
+        assert_eq!(tr(r"(?-i:a)"), r"(?-i:a)");
+        assert_eq!(tr(r"[a-z]+(?![a-z])"), r"[a-z]+(?![a-z])");
+        assert_eq!(tr("(?x) [a-z] +  # letters\n [ #]\\ "), r"[a-z]+[ \#] ");
+        assert_eq!(tr(r"(?a)\w+\S"), r"[0-9A-Za-z_]+[^ \t\n\r\f\v]");
+        assert_eq!(tr(r"(?a:\d)\d"), r"(?:[0-9])\d");
+        assert_eq!(python_re_to_rust("a b", "xi").unwrap(), "(?i)ab");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            err(r"(a)\1"),
+            "backreference \\1 can't be represented as a DFA"
+        );
+        assert_eq!(
+            err(r"(?P<q>a)(?P=q)"),
+            "backreference (?P=q) can't be represented as a DFA"
+        );
+        assert!(err(r"\bfoo").starts_with("word boundary \\b can't be used"));
+        assert_eq!(
+            err(r"a*+"),
+            "possessive quantifiers (like a*+) are not supported"
+        );
+        assert_eq!(err(r"(?>a)"), "atomic groups (?>...) are not supported");
+        assert!(err(r"(?(1)a|b)").starts_with("conditional groups"));
+        assert_eq!(
+            err(r"a(?i)b"),
+            "global flags not at the start of the expression"
+        );
+        assert_eq!(err(r"\q"), "bad escape \\q");
+        assert!(err(r"\N{DASH}").starts_with("named Unicode escapes"));
+        assert_eq!(
+            python_re_to_rust("a", "l").unwrap_err().to_string(),
+            "the l (LOCALE) flag is only supported for bytes patterns"
+        );
+    }
+}
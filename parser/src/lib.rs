@@ -15,12 +15,15 @@ pub use toktrie;
 pub mod panic_utils;
 
 mod constraint;
+mod snapshot;
 mod stop_controller;
 mod tokenizer_json;
-pub use constraint::{CommitResult, Constraint};
+pub use constraint::{CommitResult, Constraint, DraftMasks};
 
 mod factory;
-pub use factory::ParserFactory;
+pub use factory::{GrammarCacheStats, ParserFactory};
+mod mask_cache;
+pub use mask_cache::MaskCacheStats;
 
 mod logging;
 pub use logging::Logger;
@@ -32,11 +35,16 @@ pub mod ffi;
 #[cfg(feature = "rayon")]
 mod ffi_par;
 
+mod abnf;
+mod gbnf;
 mod grammar_builder;
 mod json;
 #[cfg(feature = "jsonschema_validation")]
 mod json_validation;
+mod normalize;
 pub mod substring;
+#[cfg(test)]
+mod test_util;
 pub use grammar_builder::{GrammarBuilder, NodeRef};
 pub use json::compiler::JsonCompileOptions;
 pub use stop_controller::StopController;
@@ -0,0 +1,377 @@
+use std::sync::Mutex;
+
+use derivre::StateID;
+use toktrie::SimpleVob;
+
+use crate::{
+    earley::regexvec::{LexemeSet, RegexVec},
+    HashMap,
+};
+
+/// Statistics of the mask caches in [`crate::ParserFactory`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaskCacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+    /// Number of masks currently cached.
+    pub entries: usize,
+    /// Approximate memory used by the cached masks.
+    pub bytes: usize,
+    /// Limit on `bytes`, per compiled grammar.
+    pub max_bytes: usize,
+}
+
+impl MaskCacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+
+    pub(crate) fn add(&mut self, other: &MaskCacheStats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.evictions += other.evictions;
+        self.entries += other.entries;
+        self.bytes += other.bytes;
+        self.max_bytes += other.max_bytes;
+    }
+}
+
+/// The state of a parser, as far as token masks are concerned
+/// (see `Parser::mask_state()`).
+/// The rows and the lexer state are numbered by [`StateIds`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct MaskState {
+    pub rows_id: u64,
+    pub lexer_state: u64,
+    pub pending_bytes: bool,
+    pub top_eos: bool,
+}
+
+/// Full key of a cached mask; it's compared on lookup.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct MaskKey {
+    state: MaskState,
+    token_prefix: Vec<u8>,
+}
+
+impl MaskKey {
+    pub fn new(state: MaskState, token_prefix: &[u8]) -> Self {
+        MaskKey {
+            state,
+            token_prefix: token_prefix.to_vec(),
+        }
+    }
+
+    fn num_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.token_prefix.len()
+    }
+}
+
+/// Masks computed by parsers of one compiled grammar, keyed by the state
+/// of the parser and the token prefix.
+///
+/// Eviction is approximate LRU: masks live in two generations,
+/// and when the current one fills half of the budget, the previous one is dropped.
+pub(crate) struct MaskCache {
+    inner: Mutex<MaskCacheInner>,
+    ids: Mutex<StateIds>,
+}
+
+#[derive(Default)]
+struct MaskCacheInner {
+    curr: HashMap<MaskKey, SimpleVob>,
+    prev: HashMap<MaskKey, SimpleVob>,
+    curr_bytes: usize,
+    prev_bytes: usize,
+    stats: MaskCacheStats,
+}
+
+fn entry_bytes(key: &MaskKey, mask: &SimpleVob) -> usize {
+    key.num_bytes() + mask.as_slice().len() * 4 + 32
+}
+
+impl MaskCache {
+    /// `dfa` is the DFA of the lexer of the grammar, see [`StateIds`].
+    pub fn new(max_bytes: usize, dfa: RegexVec) -> Self {
+        MaskCache {
+            inner: Mutex::new(MaskCacheInner {
+                stats: MaskCacheStats {
+                    max_bytes,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            ids: Mutex::new(StateIds::new(dfa, max_bytes)),
+        }
+    }
+
+    pub fn ids(&self) -> &Mutex<StateIds> {
+        &self.ids
+    }
+
+    pub fn get(&self, key: &MaskKey) -> Option<SimpleVob> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(m) = inner.curr.get(key) {
+            let m = m.clone();
+            inner.stats.hits += 1;
+            return Some(m);
+        }
+        if let Some((key, m)) = inner.prev.remove_entry(key) {
+            inner.stats.hits += 1;
+            let n = entry_bytes(&key, &m);
+            inner.prev_bytes -= n;
+            inner.add(key, m.clone(), n);
+            return Some(m);
+        }
+        inner.stats.misses += 1;
+        None
+    }
+
+    pub fn insert(&self, key: MaskKey, mask: SimpleVob) {
+        let n = entry_bytes(&key, &mask);
+        let mut inner = self.inner.lock().unwrap();
+        if n * 2 > inner.stats.max_bytes || inner.curr.contains_key(&key) {
+            return;
+        }
+        if let Some((k, m)) = inner.prev.remove_entry(&key) {
+            inner.prev_bytes -= entry_bytes(&k, &m);
+        }
+        inner.add(key, mask, n);
+    }
+
+    pub fn stats(&self) -> MaskCacheStats {
+        self.inner.lock().unwrap().stats.clone()
+    }
+}
+
+impl MaskCacheInner {
+    fn add(&mut self, key: MaskKey, mask: SimpleVob, num_bytes: usize) {
+        if (self.curr_bytes + num_bytes) * 2 > self.stats.max_bytes {
+            self.stats.evictions += self.prev.len();
+            self.prev = std::mem::take(&mut self.curr);
+            self.prev_bytes = self.curr_bytes;
+            self.curr_bytes = 0;
+        }
+        self.curr.insert(key, mask);
+        self.curr_bytes += num_bytes;
+        self.stats.entries = self.curr.len() + self.prev.len();
+        self.stats.bytes = self.curr_bytes + self.prev_bytes;
+    }
+}
+
+/// Numbers of lexer states and Earley rows, comparable between parsers
+/// of one grammar, each with its own lexer.
+///
+/// Lexer states are numbered by a copy of the DFA of the grammar, which is
+/// extended with the states the parsers run into (see `Lexer::shared_id()`),
+/// so e.g. all positions inside of a JSON string get the same number,
+/// whatever the string contains so far.
+/// Rows are numbered by their contents, including the number of the row before,
+/// so equal numbers mean equal rows, and there are no hash collisions.
+///
+/// When the copy of the DFA and the rows grow over `max_bytes`, numbering starts over;
+/// numbers are not reused, so the ones handed out before stay valid
+/// (they just stop matching the new ones).
+pub(crate) struct StateIds {
+    template: RegexVec,
+    template_bytes: usize,
+    dfa: RegexVec,
+    epoch: u64,
+    rows: HashMap<Vec<u64>, u64>,
+    rows_bytes: usize,
+    next_row_id: u64,
+    max_bytes: usize,
+}
+
+impl StateIds {
+    pub fn new(dfa: RegexVec, max_bytes: usize) -> Self {
+        StateIds {
+            template_bytes: dfa.num_bytes(),
+            template: dfa.clone(),
+            dfa,
+            epoch: 1,
+            rows: HashMap::default(),
+            rows_bytes: 0,
+            next_row_id: 1,
+            max_bytes,
+        }
+    }
+
+    fn lexer_id(&self, state: StateID) -> u64 {
+        (self.epoch << 33) | ((state.as_u32() as u64) << 1) | state.has_lowest_match() as u64
+    }
+
+    /// Whether `id` was handed out since numbering last started over.
+    pub fn is_current(&self, id: u64) -> bool {
+        id >> 33 == self.epoch
+    }
+
+    fn lexer_state(&self, id: u64) -> StateID {
+        assert!(self.is_current(id));
+        let state = StateID::new((id as u32) >> 1);
+        if id & 1 != 0 {
+            state._set_lowest_match()
+        } else {
+            state
+        }
+    }
+
+    /// None if the DFA has grown too large; numbering then starts over.
+    fn checked_lexer_id(&mut self, state: StateID) -> Option<u64> {
+        if self.dfa.has_error() || self.num_bytes() > self.max_bytes {
+            self.start_over();
+            None
+        } else {
+            Some(self.lexer_id(state))
+        }
+    }
+
+    pub fn initial_state(&mut self, lexemes: &LexemeSet) -> Option<u64> {
+        let state = self.dfa.initial_state(lexemes);
+        self.checked_lexer_id(state)
+    }
+
+    pub fn transition(&mut self, id: u64, byte: u8) -> Option<u64> {
+        let state = self.dfa.transition(self.lexer_state(id), byte);
+        self.checked_lexer_id(state)
+    }
+
+    /// Number of the row with the given contents.
+    pub fn row_id(&mut self, row: Vec<u64>) -> u64 {
+        if let Some(&id) = self.rows.get(&row) {
+            return id;
+        }
+        let n = row.len() * 8 + 32;
+        if self.num_bytes() + n > self.max_bytes {
+            self.start_over();
+        }
+        let id = self.next_row_id;
+        self.next_row_id += 1;
+        self.rows_bytes += n;
+        self.rows.insert(row, id);
+        id
+    }
+
+    fn num_bytes(&self) -> usize {
+        self.dfa.num_bytes().saturating_sub(self.template_bytes) + self.rows_bytes
+    }
+
+    fn start_over(&mut self) {
+        self.dfa = self.template.clone();
+        self.rows.clear();
+        self.rows_bytes = 0;
+        self.epoch += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::ParserLimits,
+        earley::lexerspec::{LexemeIdx, LexerSpec},
+    };
+    use derivre::RegexAst;
+
+    fn state_ids(max_bytes: usize) -> StateIds {
+        let mut spec = LexerSpec::new().unwrap();
+        spec.new_lexeme_class(RegexAst::NoMatch).unwrap();
+        spec.add_greedy_lexeme(
+            "WORD".to_string(),
+            RegexAst::Regex("[a-z]+".to_string()),
+            false,
+            None,
+            usize::MAX,
+            None,
+        )
+        .unwrap();
+        let dfa = spec.to_regex_vec(&mut ParserLimits::default()).unwrap();
+        StateIds::new(dfa, max_bytes)
+    }
+
+    fn mask(tok: u32) -> SimpleVob {
+        let mut m = SimpleVob::alloc(1000);
+        m.allow_token(tok);
+        m
+    }
+
+    fn key(rows_id: u64, token_prefix: &[u8]) -> MaskKey {
+        let state = MaskState {
+            rows_id,
+            lexer_state: 7,
+            pending_bytes: true,
+            top_eos: false,
+        };
+        MaskKey::new(state, token_prefix)
+    }
+
+    #[test]
+    fn test_mask_cache() {
+        let n = entry_bytes(&key(0, b""), &mask(0));
+        let cache = MaskCache::new(n * 6, state_ids(0).dfa);
+        assert_ne!(key(1, b"a"), key(1, b"b"));
+        assert_ne!(key(1, b""), key(2, b""));
+
+        assert!(cache.get(&key(1, b"")).is_none());
+        cache.insert(key(1, b""), mask(1));
+        assert!(cache.get(&key(1, b"")).unwrap().is_allowed(1));
+        // the full key is compared
+        let mut other = key(1, b"");
+        other.state.lexer_state = 8;
+        assert!(cache.get(&other).is_none());
+
+        // fill up the first generation and move to the second
+        cache.insert(key(2, b""), mask(2));
+        cache.insert(key(3, b""), mask(3));
+        cache.insert(key(4, b""), mask(4));
+        assert_eq!(cache.stats().evictions, 0);
+        // recently used entries are brought back to the current generation
+        assert!(cache.get(&key(1, b"")).is_some());
+        cache.insert(key(5, b""), mask(5));
+        cache.insert(key(6, b""), mask(6));
+        let stats = cache.stats();
+        assert!(stats.bytes <= stats.max_bytes);
+        assert!(stats.evictions > 0);
+        assert!(cache.get(&key(1, b"")).is_some());
+        assert!(cache.get(&key(2, b"")).is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 3));
+        assert!((stats.hit_rate() - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_state_ids() {
+        let mut ids = state_ids(1_000_000);
+        let mut lexemes = LexemeSet::new(2);
+        lexemes.add(LexemeIdx::new(1));
+        let s0 = ids.initial_state(&lexemes).unwrap();
+        let a = ids.transition(s0, b'a').unwrap();
+        assert_ne!(a, s0);
+        // the DFA doesn't remember the bytes of the word
+        assert_eq!(ids.transition(a, b'b').unwrap(), a);
+        assert_eq!(ids.transition(s0, b'x').unwrap(), a);
+        assert_eq!(ids.initial_state(&lexemes).unwrap(), s0);
+
+        let r0 = ids.row_id(vec![0, s0]);
+        let r1 = ids.row_id(vec![r0, a]);
+        assert_ne!(r0, r1);
+        assert_eq!(ids.row_id(vec![0, s0]), r0);
+
+        // over the budget, numbering starts over, without reusing numbers
+        let mut ids = state_ids(0);
+        assert!(ids.initial_state(&lexemes).is_none());
+        let r0 = ids.row_id(vec![1]);
+        assert_eq!(ids.row_id(vec![1]), r0);
+        let r1 = ids.row_id(vec![2]);
+        let r2 = ids.row_id(vec![1]);
+        assert!(r0 != r1 && r1 != r2 && r0 != r2);
+        assert!(!ids.is_current(s0));
+    }
+}
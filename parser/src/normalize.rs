@@ -0,0 +1,120 @@
+use regex_syntax::hir::{ClassUnicode, ClassUnicodeRange};
+use unicode_normalization::UnicodeNormalization;
+
+fn nfc(s: &str) -> String {
+    s.nfc().collect()
+}
+
+fn nfd(s: &str) -> String {
+    s.nfd().collect()
+}
+
+/// All the ways a single NFC character can be spelled: precomposed,
+/// fully decomposed, and partially composed (e.g., 'ǖ' as "ü" + U+0304).
+fn spellings(c: char) -> Vec<String> {
+    let decomposed: Vec<char> = nfd(&c.to_string()).chars().collect();
+    let mut r = vec![c.to_string()];
+    for k in 1..=decomposed.len() {
+        let head: String = decomposed[..k].iter().collect();
+        let tail: String = decomposed[k..].iter().collect();
+        let s = nfc(&head) + &tail;
+        if !r.contains(&s) {
+            r.push(s);
+        }
+    }
+    r
+}
+
+/// Regex (in regex_syntax format) matching `s` case-insensitively
+/// in both NFC and NFD (and mixed) normalization forms.
+///
+/// Case folding is the simple (one-to-one) Unicode folding,
+/// so e.g., "ß" does not match "SS".
+pub fn case_insensitive_literal_regex(s: &str) -> String {
+    let mut rx = "(?i)".to_string();
+    for c in nfc(s).chars() {
+        let alts = spellings(c);
+        if alts.len() == 1 {
+            rx.push_str(&regex_syntax::escape(&alts[0]));
+        } else {
+            rx.push_str("(?:");
+            for (i, a) in alts.iter().enumerate() {
+                if i > 0 {
+                    rx.push('|');
+                }
+                rx.push_str(&regex_syntax::escape(a));
+            }
+            rx.push(')');
+        }
+    }
+    rx
+}
+
+/// Strings with the same key match the same [`case_insensitive_literal_regex()`]:
+/// the NFC form, with every character replaced by the smallest one
+/// it's equal to under simple case folding.
+pub fn case_fold_key(s: &str) -> String {
+    nfc(s)
+        .chars()
+        .map(|c| {
+            let mut cls = ClassUnicode::new([ClassUnicodeRange::new(c, c)]);
+            cls.case_fold_simple();
+            cls.ranges()[0].start()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, s: &str) -> bool {
+        regex::Regex::new(&format!("^(?:{})$", pattern))
+            .unwrap()
+            .is_match(s)
+    }
+
+    #[test]
+    fn test_case_insensitive_literal() {
+        let rx = case_insensitive_literal_regex("Zürich");
+        for s in [
+            "Zürich",
+            "zürich",
+            "ZÜRICH",
+            "Zu\u{308}rich",
+            "ZU\u{308}RICH",
+            "zU\u{308}rIcH",
+        ] {
+            assert!(matches(&rx, s), "{:?} should match {}", s, rx);
+        }
+        for s in ["Zurich", "Zürichs", "Zu\u{308}\u{308}rich", ""] {
+            assert!(!matches(&rx, s), "{:?} should not match {}", s, rx);
+        }
+
+        // input in NFD is normalized first
+        assert_eq!(rx, case_insensitive_literal_regex("Zu\u{308}rich"));
+
+        // partially composed forms
+        let rx = case_insensitive_literal_regex("\u{1d6}");
+        for s in [
+            "\u{1d6}",
+            "\u{fc}\u{304}",
+            "u\u{308}\u{304}",
+            "U\u{308}\u{304}",
+        ] {
+            assert!(matches(&rx, s), "{:?} should match {}", s, rx);
+        }
+
+        assert_eq!(case_insensitive_literal_regex("a.b"), "(?i)a\\.b");
+    }
+
+    #[test]
+    fn test_case_fold_key() {
+        assert_eq!(case_fold_key("Foo"), case_fold_key("fOO"));
+        assert_eq!(case_fold_key("Zu\u{308}rich"), case_fold_key("ZÜRICH"));
+        // Kelvin sign and long s
+        assert_eq!(case_fold_key("\u{212a}\u{17f}"), case_fold_key("ks"));
+        assert_ne!(case_fold_key("Foo"), case_fold_key("Fo"));
+        assert_ne!(case_fold_key("ß"), case_fold_key("ss"));
+    }
+}
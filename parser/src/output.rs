@@ -1,8 +1,16 @@
 use crate::HashSet;
+use anyhow::{ensure, Result};
 use serde::{Deserialize, Serialize};
 use toktrie::{bytes::to_hex_string, StepResult};
 
-use crate::{api::StopReason, earley, TokenParser};
+use crate::{
+    api::StopReason,
+    earley,
+    snapshot::{SnapshotReader, SnapshotWriter},
+    stop_controller::valid_utf8_len,
+    substring::chunk_into_words,
+    TokenParser,
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct BytesOutput {
@@ -10,8 +18,10 @@ pub struct BytesOutput {
     pub hex: String,
 }
 
+/// New kinds of output may be added in future, so matches need a wildcard arm.
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "object", rename_all = "snake_case")]
+#[non_exhaustive]
 pub enum ParserOutput {
     Capture {
         name: String,
@@ -19,6 +29,32 @@ pub enum ParserOutput {
         bytes: BytesOutput,
         log_prob: f64,
     },
+    /// Follows the `Capture` of a `substring_documents` lexeme,
+    /// when the quoted text was found.
+    CaptureProvenance {
+        name: String,
+        #[serde(flatten)]
+        provenance: CaptureProvenance,
+    },
+    /// A capture started; only reported with [`Reporter::set_stream_captures()`].
+    /// `path` lists the names of enclosing captures, outermost first.
+    CaptureStart { name: String, path: Vec<String> },
+    /// More text of a capture in progress.
+    CaptureDelta {
+        name: String,
+        path: Vec<String>,
+        #[serde(flatten)]
+        bytes: BytesOutput,
+    },
+    /// The capture is no longer in progress.
+    /// If `completed`, it is followed by a `Capture` with the full value;
+    /// otherwise it turned out not to be a capture after all
+    /// (e.g., the tokens were rolled back), and its text should be discarded.
+    CaptureEnd {
+        name: String,
+        path: Vec<String>,
+        completed: bool,
+    },
     FinalText {
         #[serde(flatten)]
         bytes: BytesOutput,
@@ -34,6 +70,173 @@ pub enum ParserOutput {
     },
 }
 
+/// Where the text of a capture was quoted from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureProvenance {
+    /// Index of the document in `substring_documents`.
+    pub document: usize,
+    /// Byte range of the capture in the document.
+    pub start: usize,
+    pub end: usize,
+}
+
+impl CaptureProvenance {
+    /// Find the first occurrence of non-empty `bytes` in `documents`.
+    /// The lexeme only matches whole chunks (see [`chunk_into_words()`]),
+    /// so the occurrence has to start and end at chunk boundaries.
+    pub fn find(documents: &[String], bytes: &[u8]) -> Option<Self> {
+        if bytes.is_empty() {
+            return None;
+        }
+        documents.iter().enumerate().find_map(|(document, doc)| {
+            let mut boundaries = vec![0];
+            for chunk in chunk_into_words(doc) {
+                boundaries.push(boundaries.last().unwrap() + chunk.len());
+            }
+            boundaries.iter().find_map(|&start| {
+                let end = start + bytes.len();
+                (doc.as_bytes().get(start..end) == Some(bytes)
+                    && boundaries.binary_search(&end).is_ok())
+                .then_some(CaptureProvenance {
+                    document,
+                    start,
+                    end,
+                })
+            })
+        })
+    }
+}
+
+/// What the parser can accept at the current position;
+/// see [`TokenParser::expected_terminals()`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ExpectedTerminals {
+    /// Lexemes that can continue from here, not counting whitespace and other ignored lexemes.
+    /// If a lexeme is partially matched, these are the lexemes that can complete it,
+    /// and the ones that can follow if it ends here.
+    pub lexemes: Vec<ExpectedLexeme>,
+    /// Incomplete rules with the dot at the current position, like `a ::= b • c`.
+    /// Rules that were only predicted here are left out, except for the start rule.
+    pub rules: Vec<String>,
+    /// Whether the end of sequence is allowed here.
+    pub eos_allowed: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedLexeme {
+    pub name: String,
+    /// The regex of the lexeme as compiled (or the token range for special tokens).
+    pub regex: String,
+}
+
+/// Why the text passed to [`TokenParser::validate_text()`] doesn't match the grammar.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TextValidationError {
+    /// Offset of the first byte that can't be accepted,
+    /// or the length of the text if it's fine but incomplete.
+    pub offset: usize,
+    /// The lexeme being scanned right before `offset` (lossily decoded; possibly empty).
+    pub partial_lexeme: String,
+    /// What the parser would accept at `offset`.
+    pub expected: ExpectedTerminals,
+    pub message: String,
+}
+
+impl std::fmt::Display for TextValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for TextValidationError {}
+
+/// Why [`ParserFactory::validate_text()`](crate::ParserFactory::validate_text) failed.
+#[derive(Debug)]
+pub enum ValidateTextError {
+    /// The grammar failed to compile.
+    Grammar(anyhow::Error),
+    /// The text doesn't match the grammar.
+    Text(TextValidationError),
+}
+
+impl std::fmt::Display for ValidateTextError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidateTextError::Grammar(e) => write!(f, "{}", e),
+            ValidateTextError::Text(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ValidateTextError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ValidateTextError::Grammar(e) => Some(e.as_ref()),
+            ValidateTextError::Text(e) => Some(e),
+        }
+    }
+}
+
+impl From<TextValidationError> for ValidateTextError {
+    fn from(e: TextValidationError) -> Self {
+        ValidateTextError::Text(e)
+    }
+}
+
+/// A derivation of the text accepted so far; see [`TokenParser::parse_trees()`].
+/// Nodes are rules, leaves are lexemes; whitespace and other ignored lexemes are left out.
+///
+/// Unlike Lark's `Tree`, not every rule gets a node: the grammar optimizer inlines rules
+/// that are just another symbol (like `item: NUM` or `items: item*`), and rules
+/// with a single alternative used only once, so their children show up in the parent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ParseTree {
+    /// Rule name, or lexeme name for leaves (the regex for anonymous lexemes).
+    pub name: String,
+    /// Byte range of the node in the text.
+    pub start: usize,
+    pub end: usize,
+    /// Text of the lexeme (lossily decoded); `None` for rules.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub text: Option<String>,
+    pub children: Vec<ParseTree>,
+}
+
+impl ParseTree {
+    pub fn is_lexeme(&self) -> bool {
+        self.text.is_some()
+    }
+
+    /// Render like `(start (item NUM:"1") "," (item NUM:"2"))`, for debugging and tests.
+    pub fn to_sexpr(&self) -> String {
+        let mut res = String::new();
+        self.write_sexpr(&mut res);
+        res
+    }
+
+    fn write_sexpr(&self, res: &mut String) {
+        if let Some(text) = &self.text {
+            res.push_str(&format!("{}:{:?}", self.name, text));
+        } else {
+            res.push('(');
+            res.push_str(&self.name);
+            for ch in &self.children {
+                res.push(' ');
+                ch.write_sexpr(res);
+            }
+            res.push(')');
+        }
+    }
+
+    pub(crate) fn shift_left(&mut self, delta: usize) {
+        self.start = self.start.saturating_sub(delta);
+        self.end = self.end.saturating_sub(delta);
+        for ch in &mut self.children {
+            ch.shift_left(delta);
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ParserStats {
     runtime_us: u64,
@@ -58,11 +261,29 @@ impl BytesOutput {
 
 #[derive(Clone, Default)]
 pub struct Reporter {
+    // captures pushed before the last report (see Parser::num_captures_pushed());
+    // the later ones are new, even if earlier ones were rolled back in between
     reported_captures: usize,
     text_ptr: usize,
     token_ptr: usize,
     prev_stats: earley::ParserStats,
     is_generated: bool,
+    stream_captures: bool,
+    // open captures reported so far, outermost first
+    streamed: Vec<StreamedCapture>,
+    // parser bytes when `streamed` was last updated
+    bytes_version: Option<earley::BytesVersion>,
+}
+
+#[derive(Clone)]
+struct StreamedCapture {
+    name: String,
+    start_row: usize,
+    // where the text reported so far (including `held`) ends
+    pos: earley::CapturePos,
+    sent_len: usize,
+    // incomplete UTF-8 character at the end
+    held: Vec<u8>,
 }
 
 impl Reporter {
@@ -92,26 +313,115 @@ impl Reporter {
         self.is_generated = is_generated;
     }
 
+    /// Also report captures while they are generated, as `CaptureStart`,
+    /// `CaptureDelta` and `CaptureEnd`.
+    pub fn set_stream_captures(&mut self, stream_captures: bool) {
+        self.stream_captures = stream_captures;
+    }
+
+    // the numbering of captures isn't preserved by the snapshot,
+    // so it stores the number of entries in captures() already reported
+    pub(crate) fn write_snapshot(&self, tok_parser: &TokenParser, w: &mut SnapshotWriter) {
+        w.usize(
+            tok_parser
+                .parser
+                .captures_pushed_after(self.reported_captures),
+        );
+        w.usize(self.text_ptr);
+        w.usize(self.token_ptr);
+        w.bool(self.is_generated);
+        w.bool(self.stream_captures);
+        // the parser is restored in the current state, so the captures
+        // continue from there, unless it was rolled back past them already
+        w.bool(
+            self.bytes_version
+                .is_some_and(|v| tok_parser.parser.bytes_unchanged_since(&v)),
+        );
+        w.usize(self.streamed.len());
+        for c in &self.streamed {
+            w.string(&c.name);
+            w.usize(c.start_row);
+            w.usize(c.pos.row);
+            w.usize(c.pos.offset);
+            w.usize(c.sent_len);
+            w.bytes(&c.held);
+        }
+    }
+
+    /// `tok_parser` has to be restored already.
+    pub(crate) fn read_snapshot(
+        &mut self,
+        tok_parser: &TokenParser,
+        r: &mut SnapshotReader,
+    ) -> Result<()> {
+        let num_reported = r.usize()?;
+        ensure!(
+            num_reported <= tok_parser.parser.captures().len(),
+            "invalid snapshot: too many reported captures"
+        );
+        self.reported_captures = match num_reported {
+            0 => 0,
+            n => tok_parser.parser.capture_seq(n - 1) + 1,
+        };
+        self.text_ptr = r.usize()?;
+        self.token_ptr = r.usize()?;
+        self.is_generated = r.bool()?;
+        self.stream_captures = r.bool()?;
+        self.bytes_version = r.bool()?.then(|| tok_parser.parser.bytes_version());
+        let num_streamed = r.usize()?;
+        self.streamed = (0..num_streamed)
+            .map(|_| {
+                Ok(StreamedCapture {
+                    name: r.string()?,
+                    start_row: r.usize()?,
+                    pos: earley::CapturePos {
+                        row: r.usize()?,
+                        offset: r.usize()?,
+                    },
+                    sent_len: r.usize()?,
+                    held: r.bytes()?.to_vec(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+
     pub fn get_progress_core(&mut self, tok_parser: &TokenParser) -> Vec<ParserOutput> {
         let mut res = vec![];
 
         // start with captures
-        let captures = &tok_parser.parser.captures()[self.reported_captures..];
-        self.reported_captures += captures.len();
+        let all_captures = tok_parser.parser.captures();
+        let first = tok_parser
+            .parser
+            .captures_pushed_after(self.reported_captures);
+        self.reported_captures = tok_parser.parser.num_captures_pushed();
 
         // remove duplicate names
         let mut seen = HashSet::default();
-        let captures = captures
-            .iter()
+        let captures = (first..all_captures.len())
             .rev()
-            .filter(|(name, _)| seen.insert(name))
+            .filter(|&idx| seen.insert(&all_captures[idx].0))
             .collect::<Vec<_>>();
-        for (name, val) in captures.iter().rev() {
+        if self.stream_captures {
+            let named = captures
+                .iter()
+                .map(|&idx| &all_captures[idx])
+                .collect::<Vec<_>>();
+            self.push_capture_events(tok_parser, &named, &mut res);
+        }
+        for &idx in captures.iter().rev() {
+            let (name, val) = &all_captures[idx];
             res.push(ParserOutput::Capture {
                 name: name.clone(),
                 bytes: val.as_slice().into(),
                 log_prob: 0.0, // TODO
             });
+            if let Some(provenance) = tok_parser.capture_provenance(idx) {
+                res.push(ParserOutput::CaptureProvenance {
+                    name: name.clone(),
+                    provenance,
+                });
+            }
         }
 
         // compute stats
@@ -138,4 +448,150 @@ impl Reporter {
 
         res
     }
+
+    fn push_capture_events(
+        &mut self,
+        tok_parser: &TokenParser,
+        finished: &[&(String, Vec<u8>)],
+        res: &mut Vec<ParserOutput>,
+    ) {
+        let parser = &tok_parser.parser;
+        let unchanged = self
+            .bytes_version
+            .is_some_and(|v| parser.bytes_unchanged_since(&v));
+        self.bytes_version = Some(parser.bytes_version());
+        let open = parser.open_captures();
+        let path = |streamed: &[StreamedCapture], idx: usize| {
+            streamed[..idx]
+                .iter()
+                .map(|c| c.name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        // the captures still in progress are a prefix of the ones already reported,
+        // which continue where they left off, unless the parser was rolled back
+        let mut texts = vec![];
+        if unchanged {
+            for (s, o) in self.streamed.iter().zip(open.iter()) {
+                if s.name != o.name || s.start_row != o.start_row {
+                    break;
+                }
+                match parser.capture_text(s.pos) {
+                    Some(text) => texts.push(text),
+                    None => break,
+                }
+            }
+        }
+        let num_kept = texts.len();
+
+        // end the others, innermost first
+        while self.streamed.len() > num_kept {
+            let idx = self.streamed.len() - 1;
+            let c = &self.streamed[idx];
+            let path = path(&self.streamed, idx);
+            // send the rest of the text, if the capture is complete
+            let completed = finished
+                .iter()
+                .find(|(name, val)| unchanged && *name == c.name && val.len() >= c.sent_len);
+            if let Some((_, val)) = completed {
+                if val.len() > c.sent_len {
+                    res.push(ParserOutput::CaptureDelta {
+                        name: c.name.clone(),
+                        path: path.clone(),
+                        bytes: val[c.sent_len..].into(),
+                    });
+                }
+            }
+            res.push(ParserOutput::CaptureEnd {
+                name: c.name.clone(),
+                path,
+                completed: completed.is_some(),
+            });
+            self.streamed.pop();
+        }
+
+        for (idx, o) in open.into_iter().enumerate() {
+            if idx >= num_kept {
+                res.push(ParserOutput::CaptureStart {
+                    name: o.name.clone(),
+                    path: path(&self.streamed, idx),
+                });
+                let start = earley::CapturePos::start(&o);
+                texts.push(parser.capture_text(start).unwrap_or((vec![], start)));
+                self.streamed.push(StreamedCapture {
+                    name: o.name,
+                    start_row: o.start_row,
+                    pos: start,
+                    sent_len: 0,
+                    held: vec![],
+                });
+            }
+            let (text, pos) = &texts[idx];
+            let c = &mut self.streamed[idx];
+            c.pos = *pos;
+            c.held.extend_from_slice(text);
+            // don't split UTF-8 characters
+            let end = valid_utf8_len(&c.held);
+            if end > 0 {
+                let delta = c.held.drain(..end).collect::<Vec<_>>();
+                c.sent_len += end;
+                let name = c.name.clone();
+                res.push(ParserOutput::CaptureDelta {
+                    name,
+                    path: path(&self.streamed, idx),
+                    bytes: delta.as_slice().into(),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CaptureProvenance;
+
+    #[test]
+    fn test_find_provenance() {
+        let docs = vec!["foo bar".to_string(), "baz bar qux".to_string()];
+        let find = |s: &str| CaptureProvenance::find(&docs, s.as_bytes());
+        assert_eq!(
+            find("bar"),
+            Some(CaptureProvenance {
+                document: 0,
+                start: 4,
+                end: 7
+            })
+        );
+        assert_eq!(
+            find("bar qux"),
+            Some(CaptureProvenance {
+                document: 1,
+                start: 4,
+                end: 11
+            })
+        );
+        assert_eq!(find("foo qux"), None);
+        assert_eq!(find(""), None);
+
+        // only whole chunks are matched
+        let docs = vec!["the cats".to_string(), "cat a".to_string()];
+        let find = |s: &str| CaptureProvenance::find(&docs, s.as_bytes());
+        assert_eq!(
+            find("cat"),
+            Some(CaptureProvenance {
+                document: 1,
+                start: 0,
+                end: 3
+            })
+        );
+        assert_eq!(
+            find("a"),
+            Some(CaptureProvenance {
+                document: 1,
+                start: 4,
+                end: 5
+            })
+        );
+        assert_eq!(find("at"), None);
+    }
 }
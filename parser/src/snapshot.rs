@@ -0,0 +1,324 @@
+use anyhow::{bail, ensure, Result};
+use std::hash::{Hash, Hasher};
+use toktrie::{TokEnv, TokenId};
+
+use crate::{api::StopReason, earley::CGrammar};
+
+const MAGIC: &[u8; 4] = b"LLGS";
+const VERSION: u8 = 4;
+
+// Snapshots store the Earley rows, the lexer stack and the captures as they are.
+// Lexer states are indices into a lazily constructed DFA, and thus only
+// meaningful within one process; they are stored as the list of DFA steps
+// that built them (see `Lexer::write_snapshot_states()`), which are redone on restore.
+
+/// Fingerprint of the compiled grammar and the tokenizer
+/// (given by [`tokenizer_fingerprint()`]).
+/// Snapshots can only be restored when fingerprints match.
+pub(crate) fn fingerprint(grammar: &CGrammar, tokenizer_fingerprint: u64) -> u64 {
+    let mut h = Fnv64::default();
+    grammar.hash_into(&mut h);
+    h.write_u64(tokenizer_fingerprint);
+    h.finish()
+}
+
+/// This hashes the whole vocabulary, so it's computed lazily,
+/// and shared by all parsers of a `ParserFactory`.
+pub(crate) fn tokenizer_fingerprint(tok_env: &TokEnv) -> u64 {
+    let mut h = Fnv64::default();
+    let trie = tok_env.tok_trie();
+    h.write_usize(trie.vocab_size());
+    h.write_u32(trie.eos_token());
+    for tok in 0..trie.vocab_size() as u32 {
+        trie.token(tok).hash(&mut h);
+    }
+    h.finish()
+}
+
+/// FNV-1a; unlike `DefaultHasher`, it's stable across processes and Rust versions.
+/// Integers are hashed as little-endian 64-bit values, so the result doesn't
+/// depend on the platform.
+struct Fnv64(u64);
+
+impl Default for Fnv64 {
+    fn default() -> Self {
+        Fnv64(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv64 {
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_u8(&mut self, v: u8) {
+        self.write(&[v]);
+    }
+
+    fn write_u16(&mut self, v: u16) {
+        self.write_u64(v as u64);
+    }
+
+    fn write_u32(&mut self, v: u32) {
+        self.write_u64(v as u64);
+    }
+
+    fn write_u64(&mut self, v: u64) {
+        self.write(&v.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, v: usize) {
+        self.write_u64(v as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Writes snapshots in a compact binary format (LEB128 integers).
+pub(crate) struct SnapshotWriter {
+    data: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn new(fingerprint: u64) -> Self {
+        let mut w = SnapshotWriter {
+            data: MAGIC.to_vec(),
+        };
+        w.data.push(VERSION);
+        w.data.extend_from_slice(&fingerprint.to_le_bytes());
+        w
+    }
+
+    /// Writer without the header, to be appended to another one.
+    pub fn nested() -> Self {
+        SnapshotWriter { data: vec![] }
+    }
+
+    pub fn append(&mut self, other: SnapshotWriter) {
+        self.data.extend_from_slice(&other.data);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u64(&mut self, mut v: u64) {
+        loop {
+            let b = (v & 0x7f) as u8;
+            v >>= 7;
+            if v == 0 {
+                self.data.push(b);
+                break;
+            }
+            self.data.push(b | 0x80);
+        }
+    }
+
+    pub fn usize(&mut self, v: usize) {
+        self.u64(v as u64);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.data.push(v as u8);
+    }
+
+    pub fn f32(&mut self, v: f32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.usize(v.len());
+        self.data.extend_from_slice(v);
+    }
+
+    pub fn tokens(&mut self, v: &[TokenId]) {
+        self.usize(v.len());
+        for &t in v {
+            self.u64(t as u64);
+        }
+    }
+
+    pub fn string(&mut self, v: &str) {
+        self.bytes(v.as_bytes());
+    }
+
+    pub fn opt_string(&mut self, v: &Option<String>) {
+        self.bool(v.is_some());
+        if let Some(s) = v {
+            self.string(s);
+        }
+    }
+
+    pub fn stop_reason(&mut self, v: StopReason) {
+        self.bytes(v.to_string().as_bytes());
+    }
+}
+
+pub(crate) struct SnapshotReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    /// Check the header of the snapshot, including the fingerprint.
+    pub fn new(data: &'a [u8], fingerprint: u64) -> Result<Self> {
+        ensure!(
+            data.len() >= MAGIC.len() + 1 + 8 && data.starts_with(MAGIC),
+            "invalid parser snapshot"
+        );
+        let mut r = SnapshotReader {
+            data,
+            pos: MAGIC.len(),
+        };
+        let version = r.byte()?;
+        ensure!(
+            version == VERSION,
+            "unsupported parser snapshot version: {}",
+            version
+        );
+        let fp = u64::from_le_bytes(r.slice(8)?.try_into().unwrap());
+        ensure!(
+            fp == fingerprint,
+            "parser snapshot was taken with a different grammar or tokenizer"
+        );
+        Ok(r)
+    }
+
+    pub fn finish(self) -> Result<()> {
+        ensure!(
+            self.pos == self.data.len(),
+            "trailing data in parser snapshot"
+        );
+        Ok(())
+    }
+
+    /// Number of bytes left; an upper bound on the length of lists still to read.
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.slice(1)?[0])
+    }
+
+    fn slice(&mut self, len: usize) -> Result<&'a [u8]> {
+        ensure!(
+            len <= self.data.len() - self.pos,
+            "truncated parser snapshot"
+        );
+        let r = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(r)
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        bail!("invalid integer in parser snapshot")
+    }
+
+    pub fn usize(&mut self) -> Result<usize> {
+        Ok(usize::try_from(self.u64()?)?)
+    }
+
+    pub fn token(&mut self) -> Result<TokenId> {
+        Ok(TokenId::try_from(self.u64()?)?)
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => bail!("invalid bool in parser snapshot: {}", b),
+        }
+    }
+
+    pub fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.slice(4)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.usize()?;
+        self.slice(len)
+    }
+
+    pub fn tokens(&mut self) -> Result<Vec<TokenId>> {
+        let len = self.usize()?;
+        (0..len).map(|_| self.token()).collect()
+    }
+
+    pub fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.bytes()?.to_vec())?)
+    }
+
+    pub fn opt_string(&mut self) -> Result<Option<String>> {
+        if self.bool()? {
+            Ok(Some(self.string()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn stop_reason(&mut self) -> Result<StopReason> {
+        let s = std::str::from_utf8(self.bytes()?)?;
+        Ok(serde_json::from_value(serde_json::Value::String(
+            s.to_string(),
+        ))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let tokens = vec![1, 200, 70000, u32::MAX];
+        let mut w = SnapshotWriter::new(42);
+        let mut nested = SnapshotWriter::nested();
+        nested.tokens(&tokens);
+        w.append(nested);
+        w.opt_string(&Some("błąd".to_string()));
+        w.opt_string(&None);
+        w.stop_reason(StopReason::EndOfSentence);
+        w.f32(0.5);
+        w.u64(u64::MAX);
+        let data = w.finish();
+
+        let mut r = SnapshotReader::new(&data, 42).unwrap();
+        assert_eq!(r.tokens().unwrap(), tokens);
+        assert_eq!(r.opt_string().unwrap(), Some("błąd".to_string()));
+        assert_eq!(r.opt_string().unwrap(), None);
+        assert_eq!(r.stop_reason().unwrap(), StopReason::EndOfSentence);
+        assert_eq!(r.f32().unwrap(), 0.5);
+        assert_eq!(r.u64().unwrap(), u64::MAX);
+        r.finish().unwrap();
+
+        let err = |data: &[u8]| SnapshotReader::new(data, 42).err().unwrap().to_string();
+        assert!(err(&data[..5]).contains("invalid parser snapshot"));
+        assert!(SnapshotReader::new(&data, 43)
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("different grammar"));
+        let mut r = SnapshotReader::new(&data[..data.len() - 1], 42).unwrap();
+        r.tokens().unwrap();
+        r.opt_string().unwrap();
+        r.opt_string().unwrap();
+        r.stop_reason().unwrap();
+        r.f32().unwrap();
+        assert!(r.u64().is_err());
+        assert!(err(b"LLGS\x02").contains("invalid parser snapshot"));
+    }
+}
@@ -66,6 +66,7 @@ impl StopController {
                 vec![RxLexeme {
                     rx,
                     lazy: true,
+                    has_follow: false,
                     priority: 0,
                 }],
                 None,
@@ -150,7 +151,7 @@ impl StopController {
     }
 }
 
-fn valid_utf8_len(data: &[u8]) -> usize {
+pub(crate) fn valid_utf8_len(data: &[u8]) -> usize {
     if data.is_empty() {
         return 0;
     }
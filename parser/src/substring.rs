@@ -1,5 +1,5 @@
-use anyhow::Result;
-use derivre::{ExprRef, RegexBuilder};
+use anyhow::{ensure, Result};
+use derivre::{ExprRef, RegexAst, RegexBuilder};
 use std::collections::{hash_map::Entry, HashMap};
 
 #[derive(Debug)]
@@ -133,6 +133,101 @@ pub fn substring(builder: &mut RegexBuilder, chunks: Vec<&str>) -> Result<ExprRe
     Ok(sa.states[0].regex.unwrap())
 }
 
+/// Chunks that [`fuzzy_substring`] can skip without counting it as an edit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gaps {
+    /// No chunks can be skipped.
+    None,
+    /// Any chunk can be skipped.
+    AnyChunk,
+    /// Only words (see [`chunk_into_words`]) can be skipped,
+    /// together with the whitespace following them,
+    /// so that kept words stay separated by whitespace.
+    Words,
+}
+
+/// Like [`substring`], but allows up to `max_edits` edits of chunks,
+/// where inserted or substituted chunks are strings matching `wildcard` regex.
+/// Chunks can also be skipped without counting as edits, as specified by `gaps`.
+///
+/// Chunk positions and edits used form an acyclic automaton (Levenshtein automaton),
+/// which is built bottom-up, one expression per state.
+pub fn fuzzy_substring(
+    builder: &mut RegexBuilder,
+    chunks: Vec<&str>,
+    wildcard: Option<&str>,
+    max_edits: u32,
+    gaps: Gaps,
+) -> Result<ExprRef> {
+    let wildcard = match wildcard {
+        Some(rx) => Some(builder.mk_regex(rx)?),
+        None => {
+            ensure!(max_edits == 0, "edits are not supported for these chunks");
+            None
+        }
+    };
+    let n = chunks.len();
+    let max_edits = max_edits as usize;
+    let is_type = |i: usize, tp: TokenType| {
+        chunks
+            .get(i)
+            .and_then(|c| c.chars().next())
+            .is_some_and(|c| classify(c) == tp)
+    };
+    // where to continue after skipping chunk i, if it can be skipped
+    let skip_to = |i: usize| match gaps {
+        Gaps::None => None,
+        Gaps::AnyChunk => Some(i + 1),
+        Gaps::Words if !is_type(i, TokenType::Word) => None,
+        Gaps::Words if is_type(i + 1, TokenType::Whitespace) => Some(i + 2),
+        Gaps::Words => Some(i + 1),
+    };
+
+    let mut next_row: Vec<ExprRef> = vec![];
+    for edits in (0..=max_edits).rev() {
+        // row[i] - rest of the match, starting at chunk i with `edits` edits already used
+        let mut row = vec![ExprRef::NO_MATCH; n + 1];
+        for i in (0..=n).rev() {
+            let mut alts = vec![RegexAst::EmptyString];
+            if i < n {
+                alts.push(RegexAst::Concat(vec![
+                    RegexAst::Literal(chunks[i].to_string()),
+                    RegexAst::ExprRef(row[i + 1]),
+                ]));
+                if let Some(j) = skip_to(i) {
+                    alts.push(RegexAst::ExprRef(row[j]));
+                }
+            }
+            if let Some(w) = wildcard.filter(|_| edits < max_edits) {
+                // insertion
+                alts.push(RegexAst::Concat(vec![
+                    RegexAst::ExprRef(w),
+                    RegexAst::ExprRef(next_row[i]),
+                ]));
+                if i < n {
+                    // substitution
+                    alts.push(RegexAst::Concat(vec![
+                        RegexAst::ExprRef(w),
+                        RegexAst::ExprRef(next_row[i + 1]),
+                    ]));
+                    // deletion
+                    alts.push(RegexAst::ExprRef(next_row[i + 1]));
+                }
+            }
+            row[i] = builder.mk(&RegexAst::Or(alts))?;
+        }
+        next_row = row;
+    }
+
+    if gaps == Gaps::AnyChunk {
+        // skipping chunks at the start is already allowed
+        Ok(next_row[0])
+    } else {
+        let starts = next_row.into_iter().map(RegexAst::ExprRef).collect();
+        builder.mk(&RegexAst::Or(starts))
+    }
+}
+
 pub fn chunk_into_chars(input: &str) -> Vec<&str> {
     let mut chunks = vec![];
     let mut char_indices = input.char_indices().peekable();
@@ -186,7 +281,7 @@ pub fn chunk_into_words(input: &str) -> Vec<&str> {
 
 #[cfg(test)]
 mod test {
-    use super::{chunk_into_chars, chunk_into_words, substring};
+    use super::{chunk_into_chars, chunk_into_words, fuzzy_substring, substring, Gaps};
     use derivre::{ExprRef, Regex, RegexBuilder};
 
     fn to_regex(builder: RegexBuilder, expr: ExprRef) -> Regex {
@@ -327,4 +422,89 @@ mod test {
         assert!(regex.is_match("뛰어넘었다."));
         assert!(!regex.is_match("갈색 여가"));
     }
+
+    #[test]
+    fn test_fuzzy_substring_chars() {
+        let mut builder = RegexBuilder::new();
+        let expr = fuzzy_substring(
+            &mut builder,
+            chunk_into_chars("The quick brown fox"),
+            Some("(?s:.)"),
+            1,
+            Gaps::None,
+        )
+        .unwrap();
+        let mut regex = to_regex(builder, expr);
+        assert!(regex.is_match("quick brown"));
+        assert!(regex.is_match("quack brown"));
+        assert!(regex.is_match("quik brown"));
+        assert!(regex.is_match("quicck brown"));
+        assert!(regex.is_match(""));
+        assert!(!regex.is_match("quack browm"));
+        assert!(!regex.is_match("brown quick"));
+    }
+
+    #[test]
+    fn test_fuzzy_substring_words() {
+        let mut builder = RegexBuilder::new();
+        let expr = fuzzy_substring(
+            &mut builder,
+            chunk_into_words("The quick brown fox jumps."),
+            Some(r"\w+|\s+|[^\w\s]+"),
+            1,
+            Gaps::None,
+        )
+        .unwrap();
+        let mut regex = to_regex(builder, expr);
+        assert!(regex.is_match("quick brown fox"));
+        assert!(regex.is_match("quick brawn fox"));
+        assert!(regex.is_match("quick fox"));
+        assert!(regex.is_match("quick  fox"));
+        assert!(!regex.is_match("quick brawn fax"));
+        assert!(regex.is_match("he quick"));
+        assert!(!regex.is_match("he quack"));
+    }
+
+    #[test]
+    fn test_subsequence() {
+        let mut builder = RegexBuilder::new();
+        let expr = fuzzy_substring(
+            &mut builder,
+            chunk_into_words("The quick brown fox"),
+            None,
+            0,
+            Gaps::Words,
+        )
+        .unwrap();
+        let mut regex = to_regex(builder, expr);
+        assert!(regex.is_match("The quick brown fox"));
+        assert!(regex.is_match("The brown fox"));
+        assert!(regex.is_match("The fox"));
+        assert!(regex.is_match("quick fox"));
+        assert!(regex.is_match(" brown"));
+        assert!(regex.is_match(""));
+        assert!(!regex.is_match("fox quick"));
+        assert!(!regex.is_match("The quack"));
+        // separators can't be skipped on their own
+        assert!(!regex.is_match("Thequick"));
+        assert!(!regex.is_match("The  brown"));
+        assert!(!regex.is_match("The  fox"));
+
+        let mut builder = RegexBuilder::new();
+        let expr = fuzzy_substring(
+            &mut builder,
+            chunk_into_chars("abcd"),
+            None,
+            0,
+            Gaps::AnyChunk,
+        )
+        .unwrap();
+        let mut regex = to_regex(builder, expr);
+        assert!(regex.is_match("abd"));
+        assert!(regex.is_match("bd"));
+        assert!(!regex.is_match("ba"));
+
+        let mut builder = RegexBuilder::new();
+        assert!(fuzzy_substring(&mut builder, vec!["a"], None, 1, Gaps::AnyChunk).is_err());
+    }
 }
@@ -0,0 +1,49 @@
+use std::sync::Arc;
+
+use toktrie::{
+    ApproximateTokEnv, InferenceCapabilities, TokEnv, TokRxInfo, TokTrie, TokenId, TokenizerEnv,
+};
+
+use crate::{api::TopLevelGrammar, ParserFactory};
+
+// like ApproximateTokEnv, but claims the greedy tokenization is canonical
+struct GreedyTokEnv(TokTrie);
+
+impl TokenizerEnv for GreedyTokEnv {
+    fn tok_trie(&self) -> &TokTrie {
+        &self.0
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        self.0.greedy_tokenize(s)
+    }
+
+    fn tokenize_is_canonical(&self) -> bool {
+        true
+    }
+}
+
+/// Factory with a token for every byte, `<eos>` (token 256) and `extra_words` after it.
+/// With ff_tokens, the greedy tokenization is treated as canonical.
+pub(crate) fn factory_ext(extra_words: &[&str], caps: InferenceCapabilities) -> ParserFactory {
+    let mut words = (0..=255u8).map(|b| vec![b]).collect::<Vec<_>>();
+    words.push(b"<eos>".to_vec());
+    words.extend(extra_words.iter().map(|w| w.as_bytes().to_vec()));
+    let trie = TokTrie::from(&TokRxInfo::new(words.len() as u32, 256), &words);
+    let tok_env: TokEnv = if caps.ff_tokens {
+        Arc::new(GreedyTokEnv(trie))
+    } else {
+        Arc::new(ApproximateTokEnv::new(trie))
+    };
+    let mut f = ParserFactory::new(&tok_env, caps, &[]).unwrap();
+    f.quiet();
+    f
+}
+
+pub(crate) fn factory() -> ParserFactory {
+    factory_ext(&[], InferenceCapabilities::default())
+}
+
+pub(crate) fn lark(s: &str) -> TopLevelGrammar {
+    TopLevelGrammar::from_lark(s.to_string())
+}
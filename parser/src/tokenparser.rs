@@ -1,13 +1,33 @@
-use std::{hint::black_box, panic::AssertUnwindSafe, sync::Arc, time::Duration};
+use std::{
+    hint::black_box,
+    panic::AssertUnwindSafe,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
 use crate::{
     api::{GrammarInit, ParserLimits, StopReason, TopLevelGrammar},
-    earley::{BiasComputer, DefaultBiasComputer, Parser, ParserError, ParserStats},
-    infoln, panic_utils, warn, Instant, Logger,
+    earley::{BiasComputer, DefaultBiasComputer, Parser, ParserError, ParserMark, ParserStats},
+    infoln,
+    mask_cache::{MaskCache, MaskKey},
+    output::{CaptureProvenance, ExpectedTerminals, ParseTree, TextValidationError},
+    panic_utils,
+    snapshot::{fingerprint, tokenizer_fingerprint, SnapshotReader, SnapshotWriter},
+    warn, Instant, Logger,
 };
 use anyhow::{ensure, Result};
 use toktrie::{InferenceCapabilities, SimpleVob, TokEnv, TokenId, INVALID_TOKEN};
 
+#[derive(Clone)]
+struct TokenMark {
+    llm_bytes: usize,
+    // for tokens from the prompt, the parser mark is the one after the prompt,
+    // and this many bytes need to be un-applied on top of it
+    prompt_bytes: usize,
+    parser: ParserMark,
+    max_tokens_total: usize,
+}
+
 #[derive(Clone)]
 pub struct TokenParser {
     pub token_env: TokEnv,
@@ -30,9 +50,22 @@ pub struct TokenParser {
     // tokens currently in KV cache
     llm_tokens: Vec<TokenId>,
     llm_bytes: Vec<u8>,
+    // state before each of llm_tokens, for rollback();
+    // the first marks_base tokens don't have marks any more
+    token_marks: Vec<TokenMark>,
+    marks_base: usize,
+    max_rollback_tokens: usize,
 
     grm_prefix: Vec<u8>,
     is_fresh: bool,
+
+    // computed on first use, since it hashes the whole grammar; shared by clones
+    pub(crate) grammar_fingerprint: Arc<OnceLock<u64>>,
+    // shared by parsers of a ParserFactory, since it hashes the whole vocabulary
+    pub(crate) tokenizer_fingerprint: Arc<OnceLock<u64>>,
+
+    // shared by parsers of the same cached grammar (and lexer)
+    pub(crate) mask_cache: Option<Arc<MaskCache>>,
 }
 
 impl TokenParser {
@@ -90,6 +123,10 @@ impl TokenParser {
             !inference_caps.backtrack || inference_caps.ff_tokens,
             "backtrack requires ff_tokens"
         );
+        ensure!(
+            !inference_caps.conditional_ff_tokens || inference_caps.ff_tokens,
+            "conditional_ff_tokens requires ff_tokens"
+        );
 
         let compute_mask_start_time = Instant::now();
         let mut max_tokens = usize::MAX;
@@ -123,10 +160,16 @@ impl TokenParser {
             eos_token,
             llm_tokens: Vec::new(),
             llm_bytes: Vec::new(),
+            token_marks: Vec::new(),
+            marks_base: 0,
+            max_rollback_tokens: 1000,
             grm_prefix: Vec::new(),
             max_tokens_total: max_tokens,
             last_bias_time: Duration::from_secs(0),
             is_fresh: true,
+            grammar_fingerprint: Arc::new(OnceLock::new()),
+            tokenizer_fingerprint: Arc::new(OnceLock::new()),
+            mask_cache: None,
         })
     }
 
@@ -134,6 +177,23 @@ impl TokenParser {
         self.parser.get_capture(name)
     }
 
+    /// For captures of `substring_documents` lexemes, return the document
+    /// the captured text was quoted from (and where in it).
+    pub fn get_capture_provenance(&self, name: &str) -> Option<CaptureProvenance> {
+        let idx = self
+            .parser
+            .captures()
+            .iter()
+            .rposition(|(n, _)| n == name)?;
+        self.capture_provenance(idx)
+    }
+
+    /// Provenance of `captures()[idx]`.
+    pub(crate) fn capture_provenance(&self, idx: usize) -> Option<CaptureProvenance> {
+        let documents = self.parser.capture_documents(idx)?;
+        CaptureProvenance::find(documents, &self.parser.captures()[idx].1)
+    }
+
     // regular .clone() uses a shared lexer state
     pub fn deep_clone(&self) -> Self {
         let mut copy = self.clone();
@@ -141,6 +201,98 @@ impl TokenParser {
         copy
     }
 
+    /// Fingerprint of the compiled grammar and the tokenizer; snapshots
+    /// can only be restored into parsers with the same fingerprint.
+    pub fn grammar_fingerprint(&self) -> u64 {
+        *self.grammar_fingerprint.get_or_init(|| {
+            let tok = *self
+                .tokenizer_fingerprint
+                .get_or_init(|| tokenizer_fingerprint(&self.token_env));
+            fingerprint(self.parser.grammar(), tok)
+        })
+    }
+
+    /// Serialize the state of the parser into a compact byte blob,
+    /// which can be restored with restore_snapshot(), possibly in another process.
+    /// The snapshot holds the Earley rows and the bytes of the lexemes,
+    /// so its size grows with the length of the input, but restoring doesn't
+    /// run the parser again.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut w = SnapshotWriter::new(self.grammar_fingerprint());
+        self.write_snapshot(&mut w);
+        w.finish()
+    }
+
+    /// Restore state from snapshot() into a fresh parser, created from
+    /// the same grammar and tokenizer.
+    pub fn restore_snapshot(&mut self, snapshot: &[u8]) -> Result<()> {
+        let mut r = SnapshotReader::new(snapshot, self.grammar_fingerprint())?;
+        self.read_snapshot(&mut r)?;
+        r.finish()
+    }
+
+    pub(crate) fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        self.parser.write_snapshot(w);
+        w.tokens(&self.llm_tokens);
+        w.bytes(&self.llm_bytes);
+        w.usize(self.token_marks.len());
+        for mark in &self.token_marks {
+            w.usize(mark.llm_bytes);
+            w.usize(mark.prompt_bytes);
+            mark.parser.write_snapshot(w);
+            w.usize(mark.max_tokens_total);
+        }
+        w.usize(self.marks_base);
+        w.bytes(&self.grm_prefix);
+        w.bool(self.is_fresh);
+        w.stop_reason(self.stop_reason);
+        w.opt_string(&self.error_message);
+        w.usize(self.max_tokens_total);
+    }
+
+    pub(crate) fn read_snapshot(&mut self, r: &mut SnapshotReader) -> Result<()> {
+        ensure!(
+            self.is_fresh,
+            "restoring snapshot into a parser that was already used"
+        );
+        panic_utils::catch_unwind(AssertUnwindSafe(|| self.parser.read_snapshot(r)))?;
+        let llm_tokens = r.tokens()?;
+        let vocab_size = self.tok_trie().vocab_size();
+        ensure!(
+            llm_tokens.iter().all(|&t| (t as usize) < vocab_size),
+            "invalid token in parser snapshot"
+        );
+        let llm_bytes = r.bytes()?.to_vec();
+        let mut token_marks = vec![];
+        for _ in 0..r.usize()? {
+            token_marks.push(TokenMark {
+                llm_bytes: r.usize()?,
+                prompt_bytes: r.usize()?,
+                parser: ParserMark::read_snapshot(r)?,
+                max_tokens_total: r.usize()?,
+            });
+        }
+        let marks_base = r.usize()?;
+        ensure!(
+            token_marks.len().checked_add(marks_base) == Some(llm_tokens.len())
+                && token_marks.iter().all(|m| {
+                    m.llm_bytes <= llm_bytes.len() && self.parser.is_valid_mark(&m.parser)
+                }),
+            "invalid token marks in parser snapshot"
+        );
+        self.llm_tokens = llm_tokens;
+        self.llm_bytes = llm_bytes;
+        self.token_marks = token_marks;
+        self.marks_base = marks_base;
+        self.grm_prefix = r.bytes()?.to_vec();
+        self.is_fresh = r.bool()?;
+        self.stop_reason = r.stop_reason()?;
+        self.error_message = r.opt_string()?;
+        self.max_tokens_total = r.usize()?;
+        self.is_accepting_cache = None;
+        Ok(())
+    }
+
     pub fn stop_reason(&self) -> StopReason {
         self.stop_reason
     }
@@ -161,6 +313,11 @@ impl TokenParser {
         &self.max_step_stats
     }
 
+    /// How many more tokens can be consumed before max_tokens is reached.
+    pub(crate) fn tokens_left(&self) -> usize {
+        self.max_tokens_total
+    }
+
     pub fn num_tokens(&self) -> usize {
         self.llm_tokens.len()
     }
@@ -179,6 +336,68 @@ impl TokenParser {
         }
     }
 
+    /// Lexemes and rules that can continue from the current position,
+    /// and whether EOS is allowed (which also takes pending forced bytes into account).
+    pub fn expected_terminals(&mut self) -> ExpectedTerminals {
+        let mut res = self.parser.expected_terminals();
+        res.eos_allowed = self.is_accepting();
+        res
+    }
+
+    /// Up to `max_trees` derivations of the text generated so far, with byte offsets
+    /// into [`Self::final_bytes()`]; empty if the grammar doesn't accept the text (yet).
+    /// Rules inlined when optimizing the grammar don't get their own nodes (see [`ParseTree`]).
+    pub fn parse_trees(&self, max_trees: usize) -> Vec<ParseTree> {
+        let mut trees = self.parser.parse_trees(max_trees);
+        for tree in &mut trees {
+            tree.shift_left(self.grm_prefix.len());
+        }
+        trees
+    }
+
+    /// Check if `text` (bytes, not tokens) is accepted by the grammar,
+    /// continuing from the current state of the parser (which is not modified).
+    /// The text is tokenized first, so that max_tokens limits apply roughly
+    /// as they would for generated text.
+    pub fn validate_text(&self, text: &[u8]) -> Result<(), TextValidationError> {
+        let trie = self.token_env.tok_trie();
+        let mut parser = self.parser.deep_clone();
+        let mut offset = 0;
+        for tok in self.token_env.tokenize_bytes(text) {
+            let tok_bytes = trie.decode_raw(&[tok]);
+            // only the last mark is ever rolled back to
+            parser.trim_undo_log(None);
+            let mark = parser.mark();
+            if parser.apply_token(&tok_bytes).is_ok() {
+                offset += tok_bytes.len();
+                continue;
+            }
+            // find the exact byte that fails; failed apply_token() can leave
+            // the parser half-way, so always go back to the mark
+            let mut mark = mark;
+            for &b in &tok_bytes {
+                if let Err(e) = parser.rollback_to(&mark) {
+                    return Err(text_error(&mut parser, offset, e.to_string()));
+                }
+                if parser.apply_token(&[b]).is_err() {
+                    let mut msg =
+                        format!("unexpected {} at offset {}", char_at(text, offset), offset);
+                    if let Err(e) = parser.rollback_to(&mark) {
+                        msg = format!("{}; {}", msg, e);
+                    }
+                    return Err(text_error(&mut parser, offset, msg));
+                }
+                mark = parser.mark();
+                offset += 1;
+            }
+        }
+        if !parser.currently_forced_bytes().is_empty() || !parser.is_accepting() {
+            let msg = format!("text ends at offset {} before the grammar is done", offset);
+            return Err(text_error(&mut parser, offset, msg));
+        }
+        Ok(())
+    }
+
     pub fn bytes_since(&self, mut idx: usize) -> &[u8] {
         idx += self.grm_prefix.len();
         let endp = std::cmp::min(self.llm_bytes.len(), self.parser.hidden_start());
@@ -264,6 +483,20 @@ impl TokenParser {
                 self.grm_prefix = decoded[0..1].to_vec();
                 self.llm_bytes = decoded;
             }
+            // prompt tokens can only be rolled back to the state after the prompt
+            self.parser.trim_undo_log(None);
+            let mark = self.parser.mark();
+            let num_applied = self.llm_bytes.len() - self.grm_prefix.len();
+            let mut llm_bytes = 0;
+            for &t in &self.llm_tokens {
+                self.token_marks.push(TokenMark {
+                    llm_bytes,
+                    prompt_bytes: num_applied - llm_bytes.saturating_sub(self.grm_prefix.len()),
+                    parser: mark.clone(),
+                    max_tokens_total: self.max_tokens_total,
+                });
+                llm_bytes += trie.token_len(t);
+            }
             infoln!(self, "ini_tokens: {}", trie.tokens_dbg(&self.llm_tokens));
         } else {
             // pretend the final bit of prompt was the prefix of the grammar
@@ -278,6 +511,7 @@ impl TokenParser {
         }
 
         infoln!(self, "res_prompt: {}", trie.tokens_dbg(&res_prompt));
+        self.trim_token_marks();
         res_prompt
     }
 
@@ -315,6 +549,24 @@ impl TokenParser {
         self.validate_tokens_raw(&[token]).map(|n| n > 0)
     }
 
+    /// Go back to the state when the parser had `num_tokens` tokens, even if the tokens
+    /// consumed since stopped the parser (for example, with an error).
+    /// This is used to undo speculatively consumed tokens.
+    pub(crate) fn undo_tokens(&mut self, num_tokens: usize) -> Result<()> {
+        ensure!(
+            num_tokens <= self.llm_tokens.len(),
+            "undo_tokens: {} > {}",
+            num_tokens,
+            self.llm_tokens.len()
+        );
+        self.stop_reason = StopReason::NotStopped;
+        self.error_message = None;
+        self.rollback(self.llm_tokens.len() - num_tokens)
+    }
+
+    /// Remove the last n_tokens tokens. The parser goes back to the state it was in
+    /// the last time it had that many tokens (this matters when stop=... bytes
+    /// removed some tokens in the meantime).
     pub fn rollback(&mut self, n_tokens: usize) -> Result<()> {
         if n_tokens == 0 {
             return Ok(());
@@ -337,29 +589,21 @@ impl TokenParser {
         self.check_initialized("rollback")?;
 
         let new_len = self.llm_tokens.len() - n_tokens;
-        let mut bytes_to_drop = 0;
-        for tok in &self.llm_tokens[new_len..] {
-            if *tok == self.eos_token {
-                // doesn't count; we hope it's last though...
-                bytes_to_drop += 0;
-            } else {
-                bytes_to_drop += self.tok_trie().token_len(*tok);
-            }
-        }
         ensure!(
-            bytes_to_drop <= self.llm_bytes.len(),
-            "rollback bytes: {} > {}",
-            bytes_to_drop,
-            self.llm_bytes.len()
+            new_len >= self.marks_base,
+            "rollback: can't roll back {} tokens, only the last {} (max_rollback_tokens)",
+            n_tokens,
+            self.llm_tokens.len() - self.marks_base
         );
-
-        self.parser.rollback(bytes_to_drop)?;
+        let mark = self.token_marks[new_len - self.marks_base].clone();
+        self.parser.rollback_to(&mark.parser)?;
+        self.parser.additional_backtrack(mark.prompt_bytes);
 
         self.is_accepting_cache = None;
-        self.max_tokens_total = self.max_tokens_total.saturating_add(n_tokens);
+        self.max_tokens_total = mark.max_tokens_total;
         self.llm_tokens.truncate(new_len);
-        self.llm_bytes
-            .truncate(self.llm_bytes.len() - bytes_to_drop);
+        self.token_marks.truncate(new_len - self.marks_base);
+        self.llm_bytes.truncate(mark.llm_bytes);
 
         Ok(())
     }
@@ -446,9 +690,9 @@ impl TokenParser {
     }
 
     fn apply_token(&mut self, tok_id: TokenId) -> Result<usize> {
-        let trie = self.token_env.tok_trie();
         self.is_accepting_cache = None;
-        self.llm_tokens.push(tok_id);
+        self.push_llm_token(tok_id);
+        let trie = self.token_env.tok_trie();
 
         let tok_bytes = trie.decode_raw(&[tok_id]);
 
@@ -546,6 +790,9 @@ impl TokenParser {
                         self.parser.additional_backtrack(additional_backtrack_bytes);
                     }
                     self.llm_tokens.truncate(token_ptr);
+                    self.token_marks
+                        .truncate(token_ptr.saturating_sub(self.marks_base));
+                    self.marks_base = self.marks_base.min(token_ptr);
                     return Ok(backtrack_tokens);
                 }
             }
@@ -554,6 +801,46 @@ impl TokenParser {
         Ok(0)
     }
 
+    // only called from consume_token(), after it was counted against max_tokens_total
+    fn push_llm_token(&mut self, tok_id: TokenId) {
+        if self.token_marks.is_empty() {
+            // nothing to roll back to before this token
+            self.parser.trim_undo_log(None);
+        }
+        self.token_marks.push(TokenMark {
+            llm_bytes: self.llm_bytes.len(),
+            prompt_bytes: 0,
+            parser: self.parser.mark(),
+            max_tokens_total: self.max_tokens_total + 1,
+        });
+        self.llm_tokens.push(tok_id);
+        self.trim_token_marks();
+    }
+
+    /// How many of the last tokens can always be rolled back (default 1000).
+    /// Older token marks, and the parser undo log entries they need, are dropped
+    /// in batches, so sometimes up to twice as many tokens can be rolled back.
+    pub fn max_rollback_tokens(&self) -> usize {
+        self.max_rollback_tokens
+    }
+
+    /// See [`Self::max_rollback_tokens()`].
+    pub fn set_max_rollback_tokens(&mut self, max_rollback_tokens: usize) {
+        self.max_rollback_tokens = max_rollback_tokens;
+        self.trim_token_marks();
+    }
+
+    fn trim_token_marks(&mut self) {
+        if self.token_marks.len() <= 2 * self.max_rollback_tokens {
+            return;
+        }
+        let n = self.token_marks.len() - self.max_rollback_tokens;
+        self.token_marks.drain(..n);
+        self.marks_base += n;
+        self.parser
+            .trim_undo_log(self.token_marks.first().map(|m| &m.parser));
+    }
+
     fn pending_grm_prefix(&self) -> &[u8] {
         &self.grm_prefix[std::cmp::min(self.grm_prefix.len(), self.llm_bytes.len())..]
     }
@@ -664,12 +951,34 @@ impl TokenParser {
     }
 
     fn compute_bias(&mut self, token_prefix: &[u8]) -> SimpleVob {
+        let cache_key = match &self.mask_cache {
+            Some(mc) => self
+                .parser
+                .mask_state(mc.ids())
+                .map(|state| MaskKey::new(state, token_prefix)),
+            None => None,
+        };
+        if let Some(key) = &cache_key {
+            if let Some(set) = self.mask_cache.as_ref().unwrap().get(key) {
+                self.last_bias_time = Duration::from_secs(0);
+                self.last_step_stats = ParserStats::default();
+                return set;
+            }
+        }
+
         let pre_stats = self.parser.stats().clone();
         let set = self.parser.compute_bias(&*self.bias_computer, token_prefix);
         let p_stats = self.parser.stats().delta(&pre_stats);
         self.last_bias_time = Duration::from_micros(p_stats.compute_time_us);
         self.last_step_stats = p_stats.clone();
         self.max_step_stats = self.max_step_stats.max(&p_stats);
+
+        // empty masks have side-effects on the parser, and errors may be transient
+        if let Some(key) = cache_key {
+            if !set.is_zero() && self.parser.get_error().is_none() {
+                self.mask_cache.as_ref().unwrap().insert(key, set.clone());
+            }
+        }
         set
     }
 
@@ -728,7 +1037,7 @@ impl TokenParser {
                     accepting
                 );
                 if accepting {
-                    self.llm_tokens.push(token);
+                    self.push_llm_token(token);
                     return Ok(0);
                 }
             }
@@ -859,3 +1168,26 @@ impl TokenParser {
         Ok(())
     }
 }
+
+/// The character starting at `text[offset]` like `'é'`, or `'\xNN'` if there
+/// is no valid UTF-8 there.
+fn char_at(text: &[u8], offset: usize) -> String {
+    let rest = &text[offset..];
+    match rest
+        .utf8_chunks()
+        .next()
+        .and_then(|c| c.valid().chars().next())
+    {
+        Some(c) => format!("{:?}", c),
+        None => format!("'\\x{:02x}'", rest[0]),
+    }
+}
+
+fn text_error(parser: &mut Parser, offset: usize, message: String) -> TextValidationError {
+    TextValidationError {
+        offset,
+        partial_lexeme: String::from_utf8_lossy(&parser.pending_lexeme_bytes()).to_string(),
+        expected: parser.expected_terminals(),
+        message,
+    }
+}
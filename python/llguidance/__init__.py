@@ -5,6 +5,10 @@ from ._lib import (
     LarkCompiler,
     RegexCompiler,
     LLExecutor,
+    GrammarDiagnostic,
+    ExpectedTerminals,
+    TextValidationError,
+    ParseTree,
 )
 from ._tokenizer import TokenizerWrapper
 
@@ -12,6 +16,10 @@ __all__ = [
     "LLTokenizer",
     "LLInterpreter",
     "LLExecutor",
+    "GrammarDiagnostic",
+    "ExpectedTerminals",
+    "TextValidationError",
+    "ParseTree",
     "JsonCompiler",
     "LarkCompiler",
     "RegexCompiler",
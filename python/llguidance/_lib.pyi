@@ -78,6 +78,7 @@ class LLInterpreter:
         enable_backtrack: bool = True,
         enable_ff_tokens: bool = True,
         log_level: int = 1,
+        enable_conditional_ff_tokens: bool = False,
     ) -> "LLInterpreter":
         """
         Create a new interpreter.
@@ -88,6 +89,48 @@ class LLInterpreter:
             enable_ff_tokens: bool - whether to enable fast-forwarded tokens in the interpreter
             log_level: int - the verbosity level of the interpreter
                 0 is silent, 1 is warnings, 2 is verbose
+            enable_conditional_ff_tokens: bool - whether to compute conditional splices
+                (requires enable_ff_tokens); they are returned in the "splices" field
+                of the JSON result of compute_mask()
+        """
+
+    @staticmethod
+    def validate_grammar(
+        tokenizer: LLTokenizer, grammar: str
+    ) -> List["GrammarDiagnostic"]:
+        """
+        Compile the grammar and return all errors and warnings found,
+        without creating an interpreter.
+        The list is empty if the grammar is fine.
+        Args:
+            tokenizer: LLTokenizer - the tokenizer to use
+            grammar: str - either a Lark grammar or stringified JSON representation of LLGuidance grammar
+        """
+
+    @staticmethod
+    def lint_grammar(tokenizer: LLTokenizer, grammar: str) -> List["GrammarDiagnostic"]:
+        """
+        Same as validate_grammar(), but also report unused rules and terminals,
+        rules that can't match anything, and constructs known to be slow.
+        """
+
+    @staticmethod
+    def validate_text(
+        tokenizer: LLTokenizer, grammar: str, text: bytes
+    ) -> Optional["TextValidationError"]:
+        """
+        Check if the text (for example, generated without constraints) matches the grammar.
+        Returns None if it does, and the position and reason of the mismatch otherwise.
+        Raises ValueError if the grammar doesn't compile.
+        """
+
+    @staticmethod
+    def to_lark(tokenizer: LLTokenizer, grammar: str) -> str:
+        """
+        Compile the grammar (Lark, JSON schema, GBNF, etc.) and render it back as Lark.
+        If the grammar has subgrammars (eg. from %json), the result is
+        a stringified JSON representation of LLGuidance grammar,
+        with one Lark grammar per subgrammar.
         """
 
     def deep_copy(self) -> "LLInterpreter":
@@ -138,6 +181,10 @@ class LLInterpreter:
         """
         Perform next parsing step.
         Returns: optional token mask and a JSON string.
+        With enable_conditional_ff_tokens, the JSON has a "splices" list
+        ({"when_sampled": [...], "backtrack": n, "ff_tokens": [...]}) saying what
+        commit_token() will return for some of the allowed tokens,
+        and "splices_truncated": true if only some candidate tokens were tried.
         """
 
     def compute_mask_into(self, trg: bytearray) -> str:
@@ -152,6 +199,21 @@ class LLInterpreter:
         Returns: a JSON string.
         """
 
+    def compute_draft_masks(
+        self, draft: List[TokenId]
+    ) -> Tuple[int, List[Optional[bytes]], str]:
+        """
+        Validate draft tokens (for speculative decoding) and compute masks
+        at each position, without changing the state of the interpreter.
+        Returns the number of leading draft tokens accepted by the grammar,
+        num_accepted + 1 masks (in the format of compute_mask(); None means stop),
+        and the JSON string for the first step.
+        With enable_ff_tokens=True, no further masks are computed after a draft token
+        for which commit_token() would backtrack or append forced tokens,
+        and the mask after it is None.
+        Afterwards, the interpreter is in the same state as after compute_mask().
+        """
+
     def commit_token(
         self, sampled_token: Optional[TokenId]
     ) -> Tuple[int, List[TokenId]]:
@@ -168,12 +230,112 @@ class LLInterpreter:
         If true, next compute_mask() call will return stop
         """
 
+    def set_stream_captures(self, enabled: bool) -> None:
+        """
+        If enabled, the progress also reports captures while they are being generated,
+        as "capture_start", "capture_delta" and "capture_end" objects,
+        with the capture name and the names of enclosing captures ("path").
+        The final "capture" object is still reported when the capture completes.
+        "capture_end" has "completed": false if the capture turned out not to be one
+        (e.g., after a rollback); its text should be discarded then.
+        """
+
+    def expected_terminals(self) -> "ExpectedTerminals":
+        """
+        Report what can come next at the current position;
+        useful when a generation was rejected, or when debugging a grammar.
+        """
+
+    def parse_trees(self, max_trees: int = 1) -> List["ParseTree"]:
+        """
+        Reconstruct parse trees of the text generated so far (empty if it's not complete yet).
+        If the grammar is ambiguous, up to max_trees derivations are returned,
+        the first one being chosen deterministically.
+        """
+
+class GrammarDiagnostic:
+    severity: str
+    """
+    Either "error" or "warning".
+    """
+    message: str
+    line: int
+    """
+    1-based line number in the grammar source; 0 if unknown.
+    """
+    column: int
+    """
+    1-based column number in the grammar source; 0 if unknown.
+    """
+    span: Optional[Tuple[int, int]]
+    """
+    Byte range (start, end) in the grammar source, if known.
+    """
+    code: Optional[str]
+    """
+    Kind of lint warning, like "unused-rule"; None for compilation errors and warnings.
+    """
+
+class ExpectedTerminals:
+    lexemes: List[Tuple[str, str]]
+    """
+    (name, regex) of lexemes that can come next, not counting ignored whitespace.
+    If a lexeme is partially matched, these are the lexemes that can complete it.
+    """
+    rules: List[str]
+    """
+    Rules in progress, with the dot at the current position, like "a ::= b • c".
+    """
+    eos_allowed: bool
+    """
+    Whether the end of sequence is allowed here.
+    """
+
+class TextValidationError:
+    offset: int
+    """
+    Offset of the first byte that can't be accepted,
+    or the length of the text if it's fine but incomplete.
+    """
+    partial_lexeme: str
+    """
+    The lexeme being scanned right before offset (possibly empty).
+    """
+    expected: ExpectedTerminals
+    """
+    What would be accepted at offset.
+    """
+    message: str
+
+class ParseTree:
+    """
+    Unlike Lark's Tree, rules inlined by the grammar optimizer don't get their own nodes:
+    rules that are just another symbol (like `item: NUM` or `items: item*`),
+    and rules with a single alternative used only once.
+    """
+
+    name: str
+    """
+    Rule name, or lexeme name for leaves.
+    """
+    start: int
+    end: int
+    """
+    Byte range of the node in the generated text.
+    """
+    text: Optional[str]
+    """
+    Text of the lexeme; None for rules.
+    """
+    children: List["ParseTree"]
+
 class JsonCompiler:
     def __new__(
         cls,
         separators: Optional[Tuple[str, str]] = None,
         whitespace_flexible: bool = False,
         coerce_one_of: bool = False,
+        case_insensitive_enums: bool = False,
     ) -> "JsonCompiler":
         """
         Create a new JSON compiler.
@@ -18,6 +18,16 @@ def main() -> None:
     parser.add_argument("--log-level", help="Log level", default=1, type=int)
     parser.add_argument("--ff-tokens", help="Enable fast-forward tokens", action="store_true")
     parser.add_argument("--backtrack", help="Enable backtracking", action="store_true")
+    parser.add_argument(
+        "--lint",
+        help="Print grammar errors and lint warnings as JSON and exit",
+        action="store_true",
+    )
+    parser.add_argument(
+        "--to-lark",
+        help="Print the compiled grammar in Lark syntax and exit",
+        action="store_true",
+    )
     args = parser.parse_args()
     tokenizer: str = args.tokenizer
 
@@ -37,6 +47,32 @@ def main() -> None:
     if not grm:
         raise ValueError("No grammar provided; need --lark or --json-schema")
 
+    if args.lint:
+        diagnostics = llguidance.LLInterpreter.lint_grammar(
+            tok, json.dumps({"grammars": [grm]})
+        )
+        print(
+            json.dumps(
+                [
+                    {
+                        "severity": d.severity,
+                        "message": d.message,
+                        "line": d.line,
+                        "column": d.column,
+                        "span": d.span,
+                        "code": d.code,
+                    }
+                    for d in diagnostics
+                ],
+                indent=2,
+            )
+        )
+        return
+
+    if args.to_lark:
+        print(llguidance.LLInterpreter.to_lark(tok, json.dumps({"grammars": [grm]})))
+        return
+
     tokens = []
 
     if args.text:
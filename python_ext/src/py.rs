@@ -2,13 +2,17 @@ use std::fmt::Display;
 use std::ops::DerefMut;
 use std::{borrow::Cow, sync::Arc};
 
-use llguidance::api::{GrammarInit, ParserLimits};
+use llguidance::api::{Diagnostic, GrammarInit, ParserLimits, Severity};
 use llguidance::earley::SlicedBiasComputer;
 use llguidance::toktrie::{
-    self, ApproximateTokEnv, InferenceCapabilities, TokEnv, TokRxInfo, TokTrie, TokenId,
+    self, ApproximateTokEnv, InferenceCapabilities, Splice, TokEnv, TokRxInfo, TokTrie, TokenId,
     TokenizerEnv,
 };
-use llguidance::{api::TopLevelGrammar, output::ParserOutput, TokenParser};
+use llguidance::{
+    api::TopLevelGrammar,
+    output::{self, ParserOutput},
+    TokenParser,
+};
 use llguidance::{
     token_bytes_from_tokenizer_json, Constraint, JsonCompileOptions, Logger, ParserFactory,
 };
@@ -125,6 +129,160 @@ impl LLExecutor {
     }
 }
 
+#[derive(Clone)]
+#[pyclass(get_all)]
+struct GrammarDiagnostic {
+    severity: String,
+    message: String,
+    line: usize,
+    column: usize,
+    span: Option<(usize, usize)>,
+    code: Option<String>,
+}
+
+impl From<Diagnostic> for GrammarDiagnostic {
+    fn from(d: Diagnostic) -> Self {
+        GrammarDiagnostic {
+            severity: match d.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            }
+            .to_string(),
+            message: d.message,
+            line: d.line,
+            column: d.column,
+            span: d.span,
+            code: d.code,
+        }
+    }
+}
+
+#[pymethods]
+impl GrammarDiagnostic {
+    fn __repr__(&self) -> String {
+        format!(
+            "GrammarDiagnostic(severity={:?}, message={:?}, line={}, column={}, span={:?}, code={:?})",
+            self.severity, self.message, self.line, self.column, self.span, self.code
+        )
+    }
+
+    fn __str__(&self) -> String {
+        if self.line > 0 {
+            format!("at {}({}): {}", self.line, self.column, self.message)
+        } else {
+            self.message.clone()
+        }
+    }
+}
+
+#[derive(Clone)]
+#[pyclass(get_all)]
+struct ExpectedTerminals {
+    lexemes: Vec<(String, String)>,
+    rules: Vec<String>,
+    eos_allowed: bool,
+}
+
+impl From<output::ExpectedTerminals> for ExpectedTerminals {
+    fn from(e: output::ExpectedTerminals) -> Self {
+        ExpectedTerminals {
+            lexemes: e.lexemes.into_iter().map(|l| (l.name, l.regex)).collect(),
+            rules: e.rules,
+            eos_allowed: e.eos_allowed,
+        }
+    }
+}
+
+#[pymethods]
+impl ExpectedTerminals {
+    fn __repr__(&self) -> String {
+        format!(
+            "ExpectedTerminals(lexemes={:?}, rules={:?}, eos_allowed={})",
+            self.lexemes, self.rules, self.eos_allowed
+        )
+    }
+}
+
+#[derive(Clone)]
+#[pyclass(get_all)]
+struct TextValidationError {
+    offset: usize,
+    partial_lexeme: String,
+    expected: ExpectedTerminals,
+    message: String,
+}
+
+impl From<output::TextValidationError> for TextValidationError {
+    fn from(e: output::TextValidationError) -> Self {
+        TextValidationError {
+            offset: e.offset,
+            partial_lexeme: e.partial_lexeme,
+            expected: e.expected.into(),
+            message: e.message,
+        }
+    }
+}
+
+#[pymethods]
+impl TextValidationError {
+    fn __repr__(&self) -> String {
+        format!(
+            "TextValidationError(offset={}, partial_lexeme={:?}, expected={}, message={:?})",
+            self.offset,
+            self.partial_lexeme,
+            self.expected.__repr__(),
+            self.message
+        )
+    }
+
+    fn __str__(&self) -> String {
+        self.message.clone()
+    }
+}
+
+#[derive(Clone)]
+#[pyclass(get_all)]
+struct ParseTree {
+    name: String,
+    start: usize,
+    end: usize,
+    text: Option<String>,
+    children: Vec<ParseTree>,
+}
+
+impl From<output::ParseTree> for ParseTree {
+    fn from(t: output::ParseTree) -> Self {
+        ParseTree {
+            name: t.name,
+            start: t.start,
+            end: t.end,
+            text: t.text,
+            children: t.children.into_iter().map(ParseTree::from).collect(),
+        }
+    }
+}
+
+#[pymethods]
+impl ParseTree {
+    fn __repr__(&self) -> String {
+        match &self.text {
+            Some(text) => format!("ParseTree({:?}, text={:?})", self.name, text),
+            None => format!(
+                "ParseTree({:?}, [{}])",
+                self.name,
+                self.children
+                    .iter()
+                    .map(|c| c.__repr__())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+// token mask in the format of LLInterpreter.compute_mask(); None means stop
+type PyMask<'a> = Option<Cow<'a, [u8]>>;
+
 #[derive(Clone)]
 #[pyclass]
 struct LLTokenizer {
@@ -137,6 +295,8 @@ impl LLInterpreter {
             progress: self.inner.flush_progress(),
             stop: self.inner.step_result().is_stop(),
             temperature: self.inner.temperature,
+            splices: self.inner.step_result().splices.clone(),
+            splices_truncated: self.inner.splices_truncated(),
         };
         serde_json::to_string(&res).unwrap()
     }
@@ -145,10 +305,10 @@ impl LLInterpreter {
         if mask_ptr == 0 {
             return Err(PyValueError::new_err("Null pointer"));
         }
-        if mask_ptr % 4 != 0 {
+        if !mask_ptr.is_multiple_of(4) {
             return Err(PyValueError::new_err("Pointer not aligned"));
         }
-        let n_words = (self.inner.tok_trie().vocab_size() + 31) / 32;
+        let n_words = self.inner.tok_trie().vocab_size().div_ceil(32);
         if mask_bytes != n_words * 4 {
             return Err(PyValueError::new_err("Invalid buffer size"));
         }
@@ -160,13 +320,14 @@ impl LLInterpreter {
 #[pymethods]
 impl LLInterpreter {
     #[new]
-    #[pyo3(signature = (tokenizer, grammar, enable_backtrack=None, enable_ff_tokens=None, log_level=None))]
+    #[pyo3(signature = (tokenizer, grammar, enable_backtrack=None, enable_ff_tokens=None, log_level=None, enable_conditional_ff_tokens=None))]
     fn py_new(
         tokenizer: &LLTokenizer,
         grammar: &str,
         enable_backtrack: Option<bool>,
         enable_ff_tokens: Option<bool>,
         log_level: Option<isize>,
+        enable_conditional_ff_tokens: Option<bool>,
     ) -> PyResult<Self> {
         let fact = &tokenizer.factory;
         let arg = TopLevelGrammar::from_lark_or_json_schema(grammar).map_err(val_error)?;
@@ -174,7 +335,7 @@ impl LLInterpreter {
         let inference_caps = InferenceCapabilities {
             backtrack: enable_backtrack.unwrap_or(true),
             ff_tokens: enable_ff_tokens.unwrap_or(true),
-            conditional_ff_tokens: enable_ff_tokens.unwrap_or(true),
+            conditional_ff_tokens: enable_conditional_ff_tokens.unwrap_or(false),
             fork: false,
         };
         let logger = Logger::new(0, std::cmp::max(0, log_level) as u32);
@@ -196,6 +357,56 @@ impl LLInterpreter {
         })
     }
 
+    #[staticmethod]
+    fn validate_grammar(
+        tokenizer: &LLTokenizer,
+        grammar: &str,
+    ) -> PyResult<Vec<GrammarDiagnostic>> {
+        let arg = TopLevelGrammar::from_lark_or_json_schema(grammar).map_err(val_error)?;
+        Ok(tokenizer
+            .factory
+            .validate_grammar(arg)
+            .into_iter()
+            .map(GrammarDiagnostic::from)
+            .collect())
+    }
+
+    #[staticmethod]
+    fn lint_grammar(tokenizer: &LLTokenizer, grammar: &str) -> PyResult<Vec<GrammarDiagnostic>> {
+        let arg = TopLevelGrammar::from_lark_or_json_schema(grammar).map_err(val_error)?;
+        Ok(tokenizer
+            .factory
+            .lint_grammar(arg)
+            .into_iter()
+            .map(GrammarDiagnostic::from)
+            .collect())
+    }
+
+    #[staticmethod]
+    fn validate_text(
+        tokenizer: &LLTokenizer,
+        grammar: &str,
+        text: &[u8],
+    ) -> PyResult<Option<TextValidationError>> {
+        let arg = TopLevelGrammar::from_lark_or_json_schema(grammar).map_err(val_error)?;
+        let parser = tokenizer.factory.create_parser(arg).map_err(val_error)?;
+        Ok(parser
+            .validate_text(text)
+            .err()
+            .map(TextValidationError::from))
+    }
+
+    #[staticmethod]
+    fn to_lark(tokenizer: &LLTokenizer, grammar: &str) -> PyResult<String> {
+        let arg = TopLevelGrammar::from_lark_or_json_schema(grammar).map_err(val_error)?;
+        let res = tokenizer.factory.export_lark(arg).map_err(val_error)?;
+        if res.grammars.len() == 1 {
+            Ok(res.grammars[0].lark_grammar.clone().unwrap())
+        } else {
+            serde_json::to_string(&res).map_err(val_error)
+        }
+    }
+
     fn deep_copy(&self) -> Self {
         Self {
             inner: self.inner.clone(),
@@ -263,7 +474,7 @@ impl LLInterpreter {
         Ok(self.json_py_result())
     }
 
-    fn compute_mask(&mut self, py: Python<'_>) -> PyResult<(Option<Cow<[u8]>>, String)> {
+    fn compute_mask(&mut self, py: Python<'_>) -> PyResult<(Option<Cow<'_, [u8]>>, String)> {
         let r = py
             .allow_threads(|| self.inner.compute_mask())
             .map_err(val_error)?
@@ -283,6 +494,28 @@ impl LLInterpreter {
         Ok((mask, self.json_py_result()))
     }
 
+    fn compute_draft_masks(
+        &mut self,
+        py: Python<'_>,
+        draft: Vec<TokenId>,
+    ) -> PyResult<(usize, Vec<PyMask<'_>>, String)> {
+        let r = py
+            .allow_threads(|| self.inner.compute_draft_masks(&draft))
+            .map_err(val_error)?;
+        let masks = r
+            .results
+            .iter()
+            .map(|r| {
+                r.sample_mask.as_ref().map(|m| {
+                    let mut res = vec![0u8; m.len()];
+                    m.iter_set_entries(|i| res[i] = 200);
+                    Cow::Owned(res)
+                })
+            })
+            .collect();
+        Ok((r.num_accepted, masks, self.json_py_result()))
+    }
+
     #[pyo3(signature = (sampled_token))]
     fn commit_token(&mut self, sampled_token: Option<TokenId>) -> PyResult<(u32, Vec<TokenId>)> {
         let pres = self.inner.commit_token(sampled_token).map_err(val_error)?;
@@ -299,6 +532,23 @@ impl LLInterpreter {
     fn has_pending_stop(&self) -> bool {
         self.inner.has_pending_stop()
     }
+
+    fn set_stream_captures(&mut self, enabled: bool) {
+        self.inner.set_stream_captures(enabled);
+    }
+
+    fn expected_terminals(&mut self) -> ExpectedTerminals {
+        self.inner.expected_terminals().into()
+    }
+
+    #[pyo3(signature = (max_trees=1))]
+    fn parse_trees(&self, max_trees: usize) -> Vec<ParseTree> {
+        self.inner
+            .parse_trees(max_trees)
+            .into_iter()
+            .map(ParseTree::from)
+            .collect()
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -306,6 +556,11 @@ struct PyMidProcessResult {
     progress: Vec<ParserOutput>,
     stop: bool,
     temperature: f32,
+    /// Conditional splices of the last compute_mask(), if enabled.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    splices: Vec<Splice>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    splices_truncated: bool,
 }
 
 #[pymethods]
@@ -414,7 +669,7 @@ impl LLTokenizer {
         self.tok_trie().decode_str(&tokens)
     }
 
-    fn decode_bytes(&self, tokens: Vec<u32>) -> Cow<[u8]> {
+    fn decode_bytes(&self, tokens: Vec<u32>) -> Cow<'_, [u8]> {
         let r = self.tok_trie().decode(&tokens);
         Cow::Owned(r)
     }
@@ -520,16 +775,18 @@ struct JsonCompiler {
     key_separator: String,
     whitespace_flexible: bool,
     coerce_one_of: bool,
+    case_insensitive_enums: bool,
 }
 
 #[pymethods]
 impl JsonCompiler {
     #[new]
-    #[pyo3(signature = (separators = None, whitespace_flexible = false, coerce_one_of = false))]
+    #[pyo3(signature = (separators = None, whitespace_flexible = false, coerce_one_of = false, case_insensitive_enums = false))]
     fn py_new(
         separators: Option<(String, String)>,
         whitespace_flexible: bool,
         coerce_one_of: bool,
+        case_insensitive_enums: bool,
     ) -> Self {
         let (item_separator, key_separator) = separators.unwrap_or_else(|| {
             if whitespace_flexible {
@@ -543,6 +800,7 @@ impl JsonCompiler {
             key_separator,
             whitespace_flexible,
             coerce_one_of,
+            case_insensitive_enums,
         }
     }
     fn compile(&self, schema: &str) -> PyResult<String> {
@@ -552,6 +810,7 @@ impl JsonCompiler {
             key_separator: self.key_separator.clone(),
             whitespace_flexible: self.whitespace_flexible,
             coerce_one_of: self.coerce_one_of,
+            case_insensitive_enums: self.case_insensitive_enums,
             retriever: None,
         };
         compile_options.apply_to(&mut schema);
@@ -605,6 +864,10 @@ pub(crate) fn init(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<JsonCompiler>()?;
     m.add_class::<LarkCompiler>()?;
     m.add_class::<RegexCompiler>()?;
+    m.add_class::<GrammarDiagnostic>()?;
+    m.add_class::<ExpectedTerminals>()?;
+    m.add_class::<TextValidationError>()?;
+    m.add_class::<ParseTree>()?;
     Ok(())
 }
 
@@ -442,7 +442,7 @@ impl TokTrie {
                 String::from_utf8_lossy(&bytes[1..]).to_string()
             } else {
                 let s = String::from_utf8_lossy(bytes);
-                if s.len() == 0 {
+                if s.is_empty() {
                     format!("≺EMPTY[{}]≻", idx)
                 } else if !s.contains('\u{fffd}') {
                     let mut s = format!("{:?}", s).replace("\\\"", "\"");
@@ -753,7 +753,7 @@ impl TokTrie {
         r
     }
 
-    pub fn node_children(&self, n: &TrieNode) -> NodeChildren {
+    pub fn node_children(&self, n: &TrieNode) -> NodeChildren<'_> {
         let off = self.node_offset(n);
         NodeChildren {
             trie: self,